@@ -0,0 +1,429 @@
+use std::{
+  env,
+  io::{stderr, IsTerminal},
+  path::PathBuf,
+};
+
+use trees::{
+  compile::{Charset, CompileConfig},
+  messages::{self, Lang},
+  structs::{Capability, ExecuteOptions, LogLevel},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+  Run {
+    file: String,
+  },
+  Explain {
+    code: Option<String>,
+  },
+  /// プログラムの木を DOT 形式で標準出力に書く
+  Graph {
+    file: String,
+  },
+  /// ソースの図を SVG で描く
+  Render {
+    file: String,
+  },
+  /// 端末上でブロックを編集する
+  Edit {
+    file: String,
+  },
+  /// trees.toml のあるディレクトリのファイルをまとめてコンパイルする
+  Build {
+    dir: String,
+  },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorChoice {
+  Auto,
+  Always,
+  Never,
+}
+
+impl ColorChoice {
+  fn parse(str: &str) -> Option<ColorChoice> {
+    match str {
+      "auto" => Some(ColorChoice::Auto),
+      "always" => Some(ColorChoice::Always),
+      "never" => Some(ColorChoice::Never),
+      _ => None,
+    }
+  }
+
+  /// 診断は標準エラー出力に書くので、auto では標準エラー出力が端末かどうかで決める
+  pub fn enabled(&self) -> bool {
+    match self {
+      ColorChoice::Auto => env::var_os("NO_COLOR").is_none() && stderr().is_terminal(),
+      ColorChoice::Always => true,
+      ColorChoice::Never => false,
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+  pub lang: Option<Lang>,
+  pub color: ColorChoice,
+  pub execute: ExecuteOptions,
+  /// -I で指定された include の検索パス (指定順)
+  pub include_paths: Vec<PathBuf>,
+  /// --plugin で指定された共有ライブラリ
+  pub plugins: Vec<PathBuf>,
+  pub compile: CompileConfig,
+  /// --check: コンパイルして診断を表示するだけで実行しない
+  pub check: bool,
+  /// -o で指定された出力先 (省略時は標準出力)
+  pub output: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cli {
+  pub command: Command,
+  pub options: Options,
+}
+
+pub fn parse_args(args: &[String]) -> Result<Cli, String> {
+  let mut options = Options {
+    lang: None,
+    color: ColorChoice::Auto,
+    execute: ExecuteOptions::default(),
+    include_paths: vec![],
+    plugins: vec![],
+    compile: CompileConfig::default(),
+    check: false,
+    output: None,
+  };
+  let mut positionals: Vec<String> = vec![];
+
+  let mut iter = args.iter();
+  while let Some(arg) = iter.next() {
+    if arg == "--" {
+      // 以降はすべてプログラムへの引数
+      options.execute.program_args.extend(iter.by_ref().cloned());
+    } else if let Some(option) = arg.strip_prefix("--") {
+      let (name, inline_value) = match option.split_once('=') {
+        Some((name, value)) => (name, Some(value.to_owned())),
+        None => (option, None),
+      };
+      let mut value = || inline_value.clone().or_else(|| iter.next().cloned()).ok_or(messages::usage());
+
+      match name {
+        "lang" => {
+          let value = value()?;
+          options.lang = Some(Lang::parse(&value).ok_or(messages::invalid_option_value("--lang", &value))?);
+        }
+        "color" => {
+          let value = value()?;
+          options.color = ColorChoice::parse(&value).ok_or(messages::invalid_option_value("--color", &value))?;
+        }
+        "max-depth" => {
+          let value = value()?;
+          options.execute.max_depth =
+            Some(value.parse().map_err(|_| messages::invalid_option_value("--max-depth", &value))?);
+        }
+        "max-steps" => {
+          let value = value()?;
+          options.execute.max_steps =
+            Some(value.parse().map_err(|_| messages::invalid_option_value("--max-steps", &value))?);
+        }
+        "seed" => {
+          let value = value()?;
+          options.execute.seed = Some(value.parse().map_err(|_| messages::invalid_option_value("--seed", &value))?);
+        }
+        "sandbox" => options.execute.denied.extend(Capability::ALL),
+        "deny" => {
+          let value = value()?;
+          for name in value.split(',') {
+            let capability = Capability::parse(name.trim()).ok_or(messages::invalid_option_value("--deny", &value))?;
+            options.execute.denied.insert(capability);
+          }
+        }
+        "module-separator" => {
+          let value = value()?;
+          if value.is_empty() {
+            return Err(messages::invalid_option_value("--module-separator", &value));
+          }
+          options.execute.module_separator = value;
+        }
+        "tab-width" => {
+          let value = value()?;
+          options.compile.tab_width =
+            value.parse().map_err(|_| messages::invalid_option_value("--tab-width", &value))?;
+        }
+        "charset" => {
+          let value = value()?;
+          options.compile.charset =
+            Charset::from_profile(&value).ok_or(messages::invalid_option_value("--charset", &value))?;
+        }
+        "log-level" => {
+          let value = value()?;
+          options.execute.log_level =
+            LogLevel::parse(&value).ok_or(messages::invalid_option_value("--log-level", &value))?;
+        }
+        "trace" => options.execute.trace = true,
+        "qualified-only" => options.execute.qualified_only = true,
+        "check" => options.check = true,
+        "plugin" => options.plugins.push(PathBuf::from(value()?)),
+        _ => return Err(messages::unknown_option(arg)),
+      }
+    } else if arg == "-o" {
+      options.output = Some(PathBuf::from(iter.next().ok_or(messages::usage())?));
+    } else if let Some(path) = arg.strip_prefix("-I") {
+      let path = if path.is_empty() {
+        iter.next().cloned().ok_or(messages::usage())?
+      } else {
+        path.to_owned()
+      };
+      options.include_paths.push(PathBuf::from(path));
+    } else {
+      positionals.push(arg.clone());
+    }
+  }
+
+  let command = match positionals.first().map(|s| s.as_str()) {
+    Some("explain") => Command::Explain {
+      code: positionals.get(1).cloned(),
+    },
+    Some("graph") => Command::Graph {
+      file: positionals.get(1).cloned().ok_or(messages::usage())?,
+    },
+    Some("render") => Command::Render {
+      file: positionals.get(1).cloned().ok_or(messages::usage())?,
+    },
+    Some("edit") => Command::Edit {
+      file: positionals.get(1).cloned().ok_or(messages::usage())?,
+    },
+    Some("build") => Command::Build {
+      dir: positionals.get(1).cloned().unwrap_or(".".to_owned()),
+    },
+    Some("run") => Command::Run {
+      file: positionals.get(1).cloned().ok_or(messages::usage())?,
+    },
+    Some(file) => Command::Run { file: file.to_owned() },
+    None => return Err(messages::usage()),
+  };
+
+  Ok(Cli { command, options })
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{collections::HashSet, path::PathBuf};
+
+  use super::{parse_args, ColorChoice, Command};
+  use trees::compile::Charset;
+  use trees::messages::Lang;
+  use trees::structs::{Capability, LogLevel};
+
+  fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|s| s.to_string()).collect()
+  }
+
+  #[test]
+  fn run_with_lang() {
+    let cli = parse_args(&args(&["--lang", "ja", "main.tr"])).unwrap();
+    assert_eq!(
+      cli.command,
+      Command::Run {
+        file: "main.tr".to_owned()
+      }
+    );
+    assert_eq!(cli.options.lang, Some(Lang::Ja));
+    assert_eq!(cli.options.color, ColorChoice::Auto);
+  }
+
+  #[test]
+  fn explain_with_options() {
+    let cli = parse_args(&args(&["explain", "T0001", "--lang=en", "--color", "never"])).unwrap();
+    assert_eq!(
+      cli.command,
+      Command::Explain {
+        code: Some("T0001".to_owned())
+      }
+    );
+    assert_eq!(cli.options.lang, Some(Lang::En));
+    assert_eq!(cli.options.color, ColorChoice::Never);
+  }
+
+  #[test]
+  fn graph() {
+    let cli = parse_args(&args(&["graph", "main.tr"])).unwrap();
+    assert_eq!(
+      cli.command,
+      Command::Graph {
+        file: "main.tr".to_owned()
+      }
+    );
+    assert!(parse_args(&args(&["graph"])).is_err());
+  }
+
+  #[test]
+  fn render() {
+    let cli = parse_args(&args(&["render", "main.tr", "-o", "main.svg"])).unwrap();
+    assert_eq!(
+      cli.command,
+      Command::Render {
+        file: "main.tr".to_owned()
+      }
+    );
+    assert_eq!(cli.options.output, Some(PathBuf::from("main.svg")));
+    assert!(parse_args(&args(&["render", "main.tr", "-o"])).is_err());
+
+    let cli = parse_args(&args(&["edit", "main.tr"])).unwrap();
+    assert_eq!(
+      cli.command,
+      Command::Edit {
+        file: "main.tr".to_owned()
+      }
+    );
+  }
+
+  #[test]
+  fn build() {
+    assert_eq!(
+      parse_args(&args(&["build"])).unwrap().command,
+      Command::Build { dir: ".".to_owned() }
+    );
+    assert_eq!(
+      parse_args(&args(&["build", "project"])).unwrap().command,
+      Command::Build {
+        dir: "project".to_owned()
+      }
+    );
+  }
+
+  #[test]
+  fn program_args() {
+    let cli = parse_args(&args(&["run", "main.tr", "--trace", "--", "1", "--lang", "-I"])).unwrap();
+    assert_eq!(
+      cli.command,
+      Command::Run {
+        file: "main.tr".to_owned()
+      }
+    );
+    assert!(cli.options.execute.trace);
+    assert_eq!(cli.options.execute.program_args, args(&["1", "--lang", "-I"]));
+    assert_eq!(cli.options.lang, None);
+  }
+
+  #[test]
+  fn stdin_source() {
+    assert_eq!(
+      parse_args(&args(&["--check", "-"])).unwrap().command,
+      Command::Run { file: "-".to_owned() }
+    );
+  }
+
+  #[test]
+  fn limits() {
+    let cli = parse_args(&args(&["--max-depth", "10", "--max-steps=500", "main.tr"])).unwrap();
+    assert_eq!(cli.options.execute.max_depth, Some(10));
+    assert_eq!(cli.options.execute.max_steps, Some(500));
+  }
+
+  #[test]
+  fn log_level() {
+    assert_eq!(
+      parse_args(&args(&["main.tr"])).unwrap().options.execute.log_level,
+      LogLevel::Warn
+    );
+    let cli = parse_args(&args(&["--log-level", "debug", "main.tr"])).unwrap();
+    assert_eq!(cli.options.execute.log_level, LogLevel::Debug);
+    assert!(parse_args(&args(&["--log-level=loud", "main.tr"])).is_err());
+  }
+
+  #[test]
+  fn seed() {
+    assert_eq!(parse_args(&args(&["main.tr"])).unwrap().options.execute.seed, None);
+    assert_eq!(
+      parse_args(&args(&["--seed", "42", "main.tr"])).unwrap().options.execute.seed,
+      Some(42)
+    );
+    assert!(parse_args(&args(&["--seed=-1", "main.tr"])).is_err());
+  }
+
+  #[test]
+  fn tab_width() {
+    assert_eq!(parse_args(&args(&["main.tr"])).unwrap().options.compile.tab_width, 4);
+    let cli = parse_args(&args(&["--tab-width=8", "main.tr"])).unwrap();
+    assert_eq!(cli.options.compile.tab_width, 8);
+  }
+
+  #[test]
+  fn charset() {
+    let cli = parse_args(&args(&["--charset", "rounded", "main.tr"])).unwrap();
+    assert_eq!(cli.options.compile.charset, Charset::rounded());
+    assert!(parse_args(&args(&["--charset", "curly", "main.tr"])).is_err());
+  }
+
+  #[test]
+  fn sandbox() {
+    let cli = parse_args(&args(&["--deny", "cmd,net", "main.tr"])).unwrap();
+    assert_eq!(
+      cli.options.execute.denied,
+      HashSet::from([Capability::Cmd, Capability::Net])
+    );
+
+    let cli = parse_args(&args(&["--sandbox", "main.tr"])).unwrap();
+    assert_eq!(cli.options.execute.denied, HashSet::from(Capability::ALL));
+  }
+
+  #[test]
+  fn include_paths() {
+    let cli = parse_args(&args(&["-I", "lib", "main.tr", "-I/usr/share/trees"])).unwrap();
+    assert_eq!(
+      cli.options.include_paths,
+      vec![PathBuf::from("lib"), PathBuf::from("/usr/share/trees")]
+    );
+  }
+
+  #[test]
+  fn trace() {
+    assert!(!parse_args(&args(&["main.tr"])).unwrap().options.execute.trace);
+    assert!(parse_args(&args(&["--trace", "main.tr"])).unwrap().options.execute.trace);
+  }
+
+  #[test]
+  fn check() {
+    assert!(!parse_args(&args(&["main.tr"])).unwrap().options.check);
+    assert!(parse_args(&args(&["--check", "main.tr"])).unwrap().options.check);
+  }
+
+  #[test]
+  fn plugins() {
+    let cli = parse_args(&args(&["--plugin", "libfoo.so", "--plugin=libbar.so", "main.tr"])).unwrap();
+    assert_eq!(
+      cli.options.plugins,
+      vec![PathBuf::from("libfoo.so"), PathBuf::from("libbar.so")]
+    );
+  }
+
+  #[test]
+  fn module_separator() {
+    let cli = parse_args(&args(&["main.tr"])).unwrap();
+    assert_eq!(cli.options.execute.module_separator, "::");
+
+    let cli = parse_args(&args(&["--module-separator", ".", "main.tr"])).unwrap();
+    assert_eq!(cli.options.execute.module_separator, ".");
+  }
+
+  #[test]
+  fn qualified_only() {
+    assert!(!parse_args(&args(&["main.tr"])).unwrap().options.execute.qualified_only);
+    assert!(parse_args(&args(&["--qualified-only", "main.tr"])).unwrap().options.execute.qualified_only);
+  }
+
+  #[test]
+  fn invalid_args() {
+    assert!(parse_args(&args(&["--lang", "fr", "main.tr"])).is_err());
+    assert!(parse_args(&args(&["--unknown", "main.tr"])).is_err());
+    assert!(parse_args(&args(&["--color", "sometimes", "main.tr"])).is_err());
+    assert!(parse_args(&args(&["--max-depth", "-1", "main.tr"])).is_err());
+    assert!(parse_args(&args(&["--deny", "cmd,time", "main.tr"])).is_err());
+    assert!(parse_args(&args(&["--module-separator=", "main.tr"])).is_err());
+    assert!(parse_args(&args(&[])).is_err());
+  }
+}
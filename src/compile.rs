@@ -1,6 +1,113 @@
-use std::cmp::Ordering;
+use std::{
+  collections::{HashMap, HashSet},
+  fmt::{Debug, Display},
+  iter,
+  sync::Arc,
+};
 
-use crate::structs::{Block, QuoteStyle};
+use unicode_width::UnicodeWidthChar;
+
+use crate::{
+  errors::ErrorCode,
+  messages,
+  structs::{Block, QuoteStyle},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompileError {
+  NonUniqueStartBlock {
+    found: usize,
+  },
+  /// `edge` は引数プラグから辿った辺の座標 (引数プラグ自身を含み、途切れた位置 (x, y) は含まない)。
+  /// 辺が輪になっていてブロックに辿り着かないときは、(x, y) は輪に戻ってきた位置
+  DanglingArgEdge {
+    x: usize,
+    y: usize,
+    edge: Vec<(usize, usize)>,
+  },
+  /// 分岐でつないだ辺がブロック自身の祖先に戻っている。(x, y) は循環に入ったブロックの左上
+  CyclicBlocks {
+    x: usize,
+    y: usize,
+  },
+  /// (x, y) から始まるブロックの枠が閉じていない。`found` は (at_x, at_y) の文字 (行の外なら空)
+  MalformedBlock {
+    x: usize,
+    y: usize,
+    at_x: usize,
+    at_y: usize,
+    found: String,
+  },
+  /// (x, y) のブロックに対するマクロ `proc_name` の展開が失敗した
+  MacroFailed {
+    x: usize,
+    y: usize,
+    proc_name: String,
+    msg: String,
+  },
+}
+
+impl CompileError {
+  pub fn code(&self) -> ErrorCode {
+    match self {
+      CompileError::NonUniqueStartBlock { .. } => ErrorCode::NonUniqueStartBlock,
+      CompileError::DanglingArgEdge { .. } => ErrorCode::DanglingArgEdge,
+      CompileError::CyclicBlocks { .. } => ErrorCode::CyclicBlocks,
+      CompileError::MalformedBlock { .. } => ErrorCode::MalformedBlock,
+      CompileError::MacroFailed { .. } => ErrorCode::MacroFailed,
+    }
+  }
+}
+
+impl Display for CompileError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      CompileError::NonUniqueStartBlock { found } => write!(f, "{}", messages::non_unique_start_block(*found)),
+      CompileError::DanglingArgEdge { x, y, .. } => write!(f, "{}", messages::dangling_arg_edge(*x, *y)),
+      CompileError::CyclicBlocks { x, y } => write!(f, "{}", messages::cyclic_blocks(*x, *y)),
+      CompileError::MalformedBlock {
+        x,
+        y,
+        at_x,
+        at_y,
+        found,
+      } => write!(f, "{}", messages::malformed_block(*x, *y, *at_x, *at_y, found)),
+      CompileError::MacroFailed { x, y, proc_name, msg } => {
+        write!(f, "{}", messages::macro_failed(*x, *y, proc_name, msg))
+      }
+    }
+  }
+}
+
+/// コンパイルは続けられるが、おそらく書き間違いであるもの
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompileWarning {
+  /// 根から辿れず、実行されないブロック。(x, y) は左上の角
+  OrphanBlock { x: usize, y: usize, proc_name: String },
+  /// ブロックの枠に似ているが閉じていない。辺がたまたま枠の形に曲がっているだけのこともある
+  MalformedBlock {
+    x: usize,
+    y: usize,
+    at_x: usize,
+    at_y: usize,
+    found: String,
+  },
+}
+
+impl Display for CompileWarning {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      CompileWarning::OrphanBlock { x, y, proc_name } => write!(f, "{}", messages::orphan_block(*x, *y, proc_name)),
+      CompileWarning::MalformedBlock {
+        x,
+        y,
+        at_x,
+        at_y,
+        found,
+      } => write!(f, "{}", messages::malformed_block(*x, *y, *at_x, *at_y, found)),
+    }
+  }
+}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 struct CompilingBlock {
@@ -36,7 +143,7 @@ struct BlockPlug {
   quote: QuoteStyle,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 enum Orientation {
   Up,
   Left,
@@ -45,238 +152,454 @@ enum Orientation {
 }
 
 impl CompilingBlock {
-  fn to_block(&self, blocks: &Vec<CompilingBlock>) -> Block {
-    Block {
-      proc_name: self.proc_name.clone(),
-      args: self
-        .args
-        .clone()
-        .into_iter()
-        .map(|(expand, block_index)| (expand, Box::new(blocks[block_index].to_block(blocks))))
-        .collect(),
+  /// 分岐で複数の引数プラグにつながったブロックは、それぞれの引数として複製する。
+  /// 一度組み立てたブロックは `built` に控えておき、同じ部分木を何度も辿らない。
+  fn to_block(
+    &self,
+    blocks: &[CompilingBlock],
+    macros: &HashMap<String, Macro>,
+    built: &mut HashMap<usize, Block>,
+    visiting: &mut Vec<usize>,
+  ) -> Result<Block, CompileError> {
+    let mut args = vec![];
+    for (expand, block_index) in &self.args {
+      let arg = match built.get(block_index) {
+        Some(block) => block.clone(),
+        None => {
+          let arg_block = &blocks[*block_index];
+          if visiting.contains(block_index) {
+            return Err(CompileError::CyclicBlocks {
+              x: arg_block.x,
+              y: arg_block.y,
+            });
+          }
+          visiting.push(*block_index);
+          let block = arg_block.to_block(blocks, macros, built, visiting)?;
+          visiting.pop();
+          built.insert(*block_index, block.clone());
+          block
+        }
+      };
+      args.push((*expand, Box::new(arg)));
+    }
+
+    let block = Block {
+      proc_name: self.proc_name.as_str().into(),
+      args,
       quote: if let Some(p) = &self.block_plug {
         p.quote.clone()
       } else {
         QuoteStyle::None
       },
+    };
+    expand_macros(block, macros, self.x, self.y, &mut 0)
+  }
+}
+
+/// 1 つのブロックから続けて展開できるマクロの回数。展開結果が同じマクロを含み続けると止まらないため
+pub const MAX_MACRO_EXPANSIONS: usize = 1000;
+
+/// マクロの名前のブロックを、マクロが返すブロックに置き換える。置き換えた結果に含まれるマクロも展開する。
+/// 引数は先に展開済みなので、置き換えなかったブロックの引数は辿らない。
+/// (x, y) はエラーに表示する、ソース上のブロックの左上の角
+fn expand_macros(
+  mut block: Block,
+  macros: &HashMap<String, Macro>,
+  x: usize,
+  y: usize,
+  expansions: &mut usize,
+) -> Result<Block, CompileError> {
+  let mut expanded = false;
+  while let Some(Macro(expand)) = macros.get(&*block.proc_name) {
+    *expansions += 1;
+    if *expansions > MAX_MACRO_EXPANSIONS {
+      return Err(CompileError::MacroFailed {
+        x,
+        y,
+        proc_name: block.proc_name.to_string(),
+        msg: messages::macro_expansion_limit(MAX_MACRO_EXPANSIONS),
+      });
     }
+    // 引用・クロージャかどうかはソース上のブロックプラグで決まる
+    let quote = block.quote.clone();
+    block = expand(&block).map_err(|msg| CompileError::MacroFailed {
+      x,
+      y,
+      proc_name: block.proc_name.to_string(),
+      msg,
+    })?;
+    block.quote = quote;
+    expanded = true;
+  }
+  if expanded {
+    block.args = block
+      .args
+      .into_iter()
+      .map(|(expand, arg)| Ok((expand, Box::new(expand_macros(*arg, macros, x, y, expansions)?))))
+      .collect::<Result<_, CompileError>>()?;
   }
+  Ok(block)
 }
 
-fn find_a_block(code: &Vec<Vec<String>>, x: usize, y: usize) -> Option<CompilingBlock> {
-  macro_rules! char {
-    ($dx:expr, $dy:expr) => {{
-      code.get(y + $dy)?.get(x + $dx)?
-    }};
+/// ブロックの枠の辺に置かれた印
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mark {
+  /// ┴ ├ ┬ ┤
+  Tee,
+  /// ╨ ╞ ╥ ╡ (どの辺にあってもブロックプラグになる)
+  DoubleTee,
+  /// @
+  Expand,
+  /// • (上辺のみ)
+  Quote,
+  /// / (上辺のみ)
+  Closure,
+  /// ~ (上辺のみ)
+  Unquote,
+}
+
+/// `ori` は辺の外側の向き。辺の線なら Some(None)、印なら Some(Some(印))、辺でなければ None
+fn side_mark(charset: &Charset, char: &str, ori: &Orientation) -> Option<Option<Mark>> {
+  if contains(charset.line(ori), char) {
+    Some(None)
+  } else if contains(charset.tee(ori), char) {
+    Some(Some(Mark::Tee))
+  } else if contains(charset.double_tee(ori), char) {
+    Some(Some(Mark::DoubleTee))
+  } else if contains(&charset.expand, char) {
+    Some(Some(Mark::Expand))
+  } else if *ori == Orientation::Up && contains(&charset.quote, char) {
+    Some(Some(Mark::Quote))
+  } else if *ori == Orientation::Up && contains(&charset.closure, char) {
+    Some(Some(Mark::Closure))
+  } else if *ori == Orientation::Up && contains(&charset.unquote, char) {
+    Some(Some(Mark::Unquote))
+  } else {
+    None
   }
+}
 
-  let mut up_plug = None;
-  let mut arg_plugs: Vec<_> = vec![];
+/// 上辺がそろい、両側の列が枠らしい文字を辿って下の角に届いていればブロックを描こうとしたものとみなし、
+/// 枠が閉じていなければ最初に食い違った文字を `MalformedBlock` として返す。
+/// 辺を共有して並んだブロック (┌──┬──┐ など) も、外側の長方形が閉じていれば同じく返す。
+/// 辺の曲がり方がたまたま枠に似ていることもあるので、返したものをどう扱うかは呼び出し側で決める
+fn find_a_block(
+  code: &[Vec<String>],
+  charset: &Charset,
+  x: usize,
+  y: usize,
+) -> Result<Option<CompilingBlock>, CompileError> {
+  // 行の外は空文字列として扱う
+  let char = |dx: usize, dy: usize| code.get(y + dy).and_then(|line| line.get(x + dx)).map_or("", |c| c.as_str());
+  let malformed_error = |at_x: usize, at_y: usize| CompileError::MalformedBlock {
+    x,
+    y,
+    at_x,
+    at_y,
+    found: code.get(at_y).and_then(|line| line.get(at_x)).cloned().unwrap_or_default(),
+  };
+
+  // 枠の上の印を (x, y, 辺の向き, 印) で集める
+  let mut marks: Vec<(usize, usize, Orientation, Mark)> = vec![];
 
-  if char!(0, 0) != "┌" {
-    return None;
+  if !charset.is_top_left(char(0, 0)) {
+    return Ok(None);
+  };
+
+  let border_like = |char: &str| {
+    [
+      Orientation::Up,
+      Orientation::Right,
+      Orientation::Down,
+      Orientation::Left,
+    ]
+    .iter()
+    .any(|ori| side_mark(charset, char, ori).is_some())
+  };
+  // 辺の形に曲がった辺 (┌──┐ など) を誤って報告しないよう、両側の列の下に角があるときだけ報告する
+  let reaches_bottom = |width1: usize| {
+    let mut side_height1 = 1;
+    while border_like(char(0, side_height1)) && border_like(char(width1, side_height1)) {
+      side_height1 += 1;
+    }
+    charset.is_bottom_left(char(0, side_height1)) || charset.is_bottom_right(char(width1, side_height1))
   };
+
   // 右回り
   // 1から始める
   let mut width1 = 1;
-  while char!(width1, 0) == "─" || char!(width1, 0) == "┴" || char!(width1, 0) == "•" || char!(width1, 0) == "/" {
-    if char!(width1, 0) == "┴" {
-      up_plug = Some(BlockPlug {
-        x: x + width1,
-        y,
-        quote: QuoteStyle::None,
-      });
-    } else if char!(width1, 0) == "•" {
-      up_plug = Some(BlockPlug {
-        x: x + width1,
-        y,
-        quote: QuoteStyle::Quote,
-      });
-    } else if char!(width1, 0) == "/" {
-      up_plug = Some(BlockPlug {
-        x: x + width1,
-        y,
-        quote: QuoteStyle::Closure,
-      });
+  while let Some(mark) = side_mark(charset, char(width1, 0), &Orientation::Up) {
+    if let Some(mark) = mark {
+      marks.push((x + width1, y, Orientation::Up, mark));
     }
     width1 += 1;
   }
-  if char!(width1, 0) != "┐" {
-    return None;
+  if !charset.is_top_right(char(width1, 0)) {
+    // 上辺に ┬ などがあっても、その先で上辺が続いて閉じていれば、辺を共有して並んだブロック
+    let mut outer_width1 = width1;
+    while border_like(char(outer_width1, 0)) {
+      outer_width1 += 1;
+    }
+    if outer_width1 > width1 && charset.is_top_right(char(outer_width1, 0)) && reaches_bottom(outer_width1) {
+      return Err(malformed_error(x + width1, y));
+    }
+    return Ok(None);
+  };
+
+  let almost_block = reaches_bottom(width1);
+  let malformed = |at_x: usize, at_y: usize| {
+    if almost_block {
+      Err(malformed_error(at_x, at_y))
+    } else {
+      Ok(None)
+    }
   };
 
   let mut height1 = 1;
-  while char!(width1, height1) == "│" || char!(width1, height1) == "├" || char!(width1, height1) == "@" {
-    if char!(width1, height1) == "├" {
-      arg_plugs.push(ArgPlug {
-        x: x + width1,
-        y: y + height1,
-        expand: false,
-        ori: Orientation::Right,
-      });
-    } else if char!(width1, height1) == "@" {
-      arg_plugs.push(ArgPlug {
-        x: x + width1,
-        y: y + height1,
-        expand: true,
-        ori: Orientation::Right,
-      });
+  while let Some(mark) = side_mark(charset, char(width1, height1), &Orientation::Right) {
+    if let Some(mark) = mark {
+      marks.push((x + width1, y + height1, Orientation::Right, mark));
     }
     height1 += 1;
   }
-  if char!(width1, height1) != "┘" {
-    return None;
+  if !charset.is_bottom_right(char(width1, height1)) {
+    return malformed(x + width1, y + height1);
   };
 
   let mut under_width1 = 1;
-  while char!(under_width1, height1) == "─"
-    || char!(under_width1, height1) == "┬"
-    || char!(under_width1, height1) == "@"
-  {
-    if char!(under_width1, height1) == "┬" {
-      arg_plugs.push(ArgPlug {
-        x: x + under_width1,
-        y: y + height1,
-        expand: false,
-        ori: Orientation::Down,
-      });
-    } else if char!(under_width1, height1) == "@" {
-      arg_plugs.push(ArgPlug {
-        x: x + under_width1,
-        y: y + height1,
-        expand: true,
-        ori: Orientation::Down,
-      });
+  while let Some(mark) = side_mark(charset, char(under_width1, height1), &Orientation::Down) {
+    if let Some(mark) = mark {
+      marks.push((x + under_width1, y + height1, Orientation::Down, mark));
     }
     under_width1 += 1;
   }
-  if char!(0, height1) != "└" || under_width1 != width1 {
-    return None;
+  if under_width1 != width1 {
+    return malformed(x + under_width1, y + height1);
+  }
+  if !charset.is_bottom_left(char(0, height1)) {
+    return malformed(x, y + height1);
   };
 
   let mut under_height1 = 1;
-  while char!(0, under_height1) == "│" || char!(0, under_height1) == "┤" || char!(0, under_height1) == "@" {
-    if char!(0, under_height1) == "┤" {
-      arg_plugs.push(ArgPlug {
-        x,
-        y: y + under_height1,
-        expand: false,
-        ori: Orientation::Left,
-      });
-    } else if char!(0, under_height1) == "@" {
-      arg_plugs.push(ArgPlug {
-        x,
-        y: y + under_height1,
-        expand: true,
-        ori: Orientation::Left,
-      });
+  while let Some(mark) = side_mark(charset, char(0, under_height1), &Orientation::Left) {
+    if let Some(mark) = mark {
+      marks.push((x, y + under_height1, Orientation::Left, mark));
     }
     under_height1 += 1;
   }
   if under_height1 != height1 {
-    return None;
+    return malformed(x, y + under_height1);
+  };
+
+  // 二重線の印があればそれがブロックプラグ (回転したブロック) で、上辺の ┴ も引数プラグになる。
+  // 無ければ上辺の最後の ┴ • / ~ がブロックプラグ
+  let doubles: Vec<_> = marks.iter().filter(|m| m.3 == Mark::DoubleTee).collect();
+  if let Some(second) = doubles.get(1) {
+    return malformed(second.0, second.1);
+  }
+  let rotated = !doubles.is_empty();
+  if let Some(quote) = marks.iter().find(|m| rotated && matches!(m.3, Mark::Quote | Mark::Closure | Mark::Unquote)) {
+    return malformed(quote.0, quote.1);
+  }
+  let block_plug_index = if rotated {
+    marks.iter().position(|m| m.3 == Mark::DoubleTee)
+  } else {
+    marks
+      .iter()
+      .rposition(|m| m.2 == Orientation::Up && matches!(m.3, Mark::Tee | Mark::Quote | Mark::Closure | Mark::Unquote))
   };
+  let block_plug = block_plug_index.map(|i| BlockPlug {
+    x: marks[i].0,
+    y: marks[i].1,
+    quote: match marks[i].3 {
+      Mark::Quote => QuoteStyle::Quote,
+      Mark::Closure => QuoteStyle::Closure,
+      Mark::Unquote => QuoteStyle::Unquote,
+      _ => QuoteStyle::None,
+    },
+  });
+
+  // 引数は、ブロックプラグ (無ければ左上の角) から左回りに枠を辿った順に並べる
+  let perimeter_position = |mx: usize, my: usize, ori: &Orientation| match ori {
+    Orientation::Left => my - y,
+    Orientation::Down => height1 + (mx - x),
+    Orientation::Right => height1 + width1 + (height1 - (my - y)),
+    Orientation::Up => 2 * height1 + width1 + (width1 - (mx - x)),
+  };
+  let perimeter = 2 * (width1 + height1);
+  let start = block_plug_index.map_or(0, |i| perimeter_position(marks[i].0, marks[i].1, &marks[i].2));
+
+  let mut arg_plugs: Vec<(usize, ArgPlug)> = marks
+    .iter()
+    .enumerate()
+    .filter(|(i, (_, _, ori, mark))| {
+      Some(*i) != block_plug_index
+        && (*mark == Mark::Expand || (*mark == Mark::Tee && (rotated || *ori != Orientation::Up)))
+    })
+    .map(|(_, (mx, my, ori, mark))| {
+      (
+        (perimeter_position(*mx, *my, ori) + perimeter - start) % perimeter,
+        ArgPlug {
+          x: *mx,
+          y: *my,
+          expand: *mark == Mark::Expand,
+          ori: ori.clone(),
+        },
+      )
+    })
+    .collect();
+  arg_plugs.sort_by_key(|(position, _)| *position);
+  let arg_plugs = arg_plugs.into_iter().map(|(_, plug)| plug).collect();
 
   let mut proc_name = "".to_owned();
 
   for inside_y in 1..height1 {
-    proc_name += &code[y + inside_y].get(x + 1..x + width1)?.join("").trim();
+    proc_name += code[y + inside_y][x + 1..x + width1].join("").trim();
     proc_name += "\n";
   }
 
-  arg_plugs.sort_by(|a, b| {
-    if a.x != b.x {
-      a.x.cmp(&b.x)
-    } else if a.x == x {
-      a.y.cmp(&b.y)
-    } else if a.x == x + width1 {
-      b.y.cmp(&a.y)
-    } else {
-      Ordering::Equal
-    }
-  });
-
-  Some(CompilingBlock {
+  Ok(Some(CompilingBlock {
     proc_name: proc_name.trim().to_owned(),
     args: vec![],
     x,
     y,
     width: width1 + 1,
     height: height1 + 1,
-    block_plug: up_plug,
+    block_plug,
     arg_plugs,
-  })
+  }))
 }
 
-fn find_blocks(code_splited: &Vec<Vec<String>>) -> Vec<CompilingBlock> {
+/// ブロックと一緒に、ブロックを描こうとして閉じていない枠 (`MalformedBlock`) を返す
+fn find_blocks(code_splited: &[Vec<String>], charset: &Charset) -> (Vec<CompilingBlock>, Vec<CompileError>) {
   let mut blocks: Vec<CompilingBlock> = vec![];
+  let mut malformed = vec![];
 
-  for y in 0..code_splited.len() {
-    for x in 0..code_splited[y].len() {
-      if let Some(b) = find_a_block(code_splited, x, y) {
-        blocks.push(b);
+  // ブロックは必ず左上の角から始まるので、角のある位置だけを調べる
+  for (y, line) in code_splited.iter().enumerate() {
+    for (x, _) in line.iter().enumerate().filter(|(_, char)| charset.is_top_left(char)) {
+      match find_a_block(code_splited, charset, x, y) {
+        Ok(Some(b)) => blocks.push(b),
+        Ok(None) => {}
+        Err(err) => malformed.push(err),
       }
     }
   }
 
-  blocks
+  (blocks, malformed)
 }
 
-fn find_next_edge(code: &Vec<Vec<String>>, x: &usize, y: &usize, ori: &Orientation) -> Result<Edge, Edge> {
-  let update_and_check =
-    |new_x: usize, new_y: usize, up: &str, left: &str, right: &str, down: &str| -> Result<Edge, Edge> {
-      let t = code
-        .get(new_y)
-        .and_then(|l| l.get(new_x))
-        .ok_or(Edge {
-          x: new_x,
-          y: new_y,
-          ori: ori.clone(),
-        })?
-        .as_str();
-      if t == up {
-        Ok(Edge {
-          x: new_x,
-          y: new_y,
-          ori: Orientation::Up,
-        })
-      } else if t == left {
-        Ok(Edge {
-          x: new_x,
-          y: new_y,
-          ori: Orientation::Left,
-        })
-      } else if t == right {
-        Ok(Edge {
-          x: new_x,
-          y: new_y,
-          ori: Orientation::Right,
-        })
-      } else if t == down {
-        Ok(Edge {
-          x: new_x,
-          y: new_y,
-          ori: Orientation::Down,
-        })
-      } else {
-        Err(Edge {
-          x: new_x,
-          y: new_y,
-          ori: ori.clone(),
-        })
-      }
-    };
+fn find_next_edge(
+  code: &[Vec<String>],
+  charset: &Charset,
+  x: &usize,
+  y: &usize,
+  ori: &Orientation,
+) -> Result<Edge, Edge> {
+  let (new_x, new_y) = match ori {
+    Orientation::Up => (*x, y.wrapping_sub(1)),
+    Orientation::Left => (x.wrapping_sub(1), *y),
+    Orientation::Right => (x + 1, *y),
+    Orientation::Down => (*x, y + 1),
+  };
+  let stop = Edge {
+    x: new_x,
+    y: new_y,
+    ori: ori.clone(),
+  };
+  let Some(t) = code.get(new_y).and_then(|l| l.get(new_x)) else {
+    return Err(stop);
+  };
+
+  // 辺は直進するか、角で曲がる。
+  // 分岐 (┬ ┴ ├ ┤) では横棒に沿って来た辺が合流し、縦棒の向きへ進む
+  let next = match ori {
+    Orientation::Left | Orientation::Right if contains(&charset.tee_down, t) => Orientation::Down,
+    Orientation::Left | Orientation::Right if contains(&charset.tee_up, t) => Orientation::Up,
+    Orientation::Up | Orientation::Down if contains(&charset.tee_right, t) => Orientation::Right,
+    Orientation::Up | Orientation::Down if contains(&charset.tee_left, t) => Orientation::Left,
+    Orientation::Up if contains(&charset.vertical, t) => Orientation::Up,
+    Orientation::Up if charset.is_top_right(t) => Orientation::Left,
+    Orientation::Up if charset.is_top_left(t) => Orientation::Right,
+    Orientation::Left if charset.is_bottom_left(t) => Orientation::Up,
+    Orientation::Left if contains(&charset.horizontal, t) => Orientation::Left,
+    Orientation::Left if charset.is_top_left(t) => Orientation::Down,
+    Orientation::Right if charset.is_bottom_right(t) => Orientation::Up,
+    Orientation::Right if contains(&charset.horizontal, t) => Orientation::Right,
+    Orientation::Right if charset.is_top_right(t) => Orientation::Down,
+    Orientation::Down if charset.is_bottom_right(t) => Orientation::Left,
+    Orientation::Down if charset.is_bottom_left(t) => Orientation::Right,
+    Orientation::Down if contains(&charset.vertical, t) => Orientation::Down,
+    _ => return Err(stop),
+  };
+
+  Ok(Edge {
+    x: new_x,
+    y: new_y,
+    ori: next,
+  })
+}
 
-  match ori {
-    Orientation::Up => update_and_check(*x, y - 1, "│", "┐", "┌", ""),
-    Orientation::Left => update_and_check(x - 1, *y, "└", "─", "", "┌"),
-    Orientation::Right => update_and_check(x + 1, *y, "┘", "", "─", "┐"),
-    Orientation::Down => update_and_check(*x, y + 1, "", "┘", "└", "│"),
+/// ブロックプラグの座標からブロックを引く
+fn block_plugs(blocks: &[CompilingBlock]) -> HashMap<(usize, usize), usize> {
+  blocks.iter().enumerate().filter_map(|(i, block)| block.block_plug.as_ref().map(|p| ((p.x, p.y), i))).collect()
+}
+
+/// 引数プラグから辺を辿り、通った座標 (両端のプラグを含む) と辿り着いたブロックを返す
+fn trace_edge(
+  code: &[Vec<String>],
+  charset: &Charset,
+  block_plugs: &HashMap<(usize, usize), usize>,
+  ArgPlug { x, y, ori, .. }: &ArgPlug,
+) -> Result<(Vec<(usize, usize)>, usize), CompileError> {
+  let mut mut_x = *x;
+  let mut mut_y = *y;
+  let mut mut_ori = ori.clone();
+  let mut fragments = vec![(*x, *y)];
+  // 分岐では縦棒の向きへ進むので、辺が輪になっていると同じ位置に同じ向きで戻ってくる
+  let mut visited = HashSet::new();
+
+  loop {
+    match find_next_edge(code, charset, &mut_x, &mut_y, &mut_ori) {
+      Ok(edge) => {
+        if !visited.insert((edge.x, edge.y, edge.ori.clone())) {
+          return Err(CompileError::DanglingArgEdge {
+            x: edge.x,
+            y: edge.y,
+            edge: fragments,
+          });
+        }
+        fragments.push((edge.x, edge.y));
+        mut_x = edge.x;
+        mut_y = edge.y;
+        mut_ori = edge.ori;
+      }
+      Err(edge) => {
+        mut_x = edge.x;
+        mut_y = edge.y;
+        break;
+      }
+    }
   }
+
+  let Some(index) = block_plugs.get(&(mut_x, mut_y)) else {
+    return Err(CompileError::DanglingArgEdge {
+      x: mut_x,
+      y: mut_y,
+      edge: fragments,
+    });
+  };
+  fragments.push((mut_x, mut_y));
+
+  Ok((fragments, *index))
 }
 
-fn connect_blocks(code: &Vec<Vec<String>>, blocks: &Vec<CompilingBlock>) -> Result<Block, String> {
-  let mut blocks_clone = blocks.clone();
+fn connect_blocks(
+  code: &[Vec<String>],
+  config: &CompileConfig,
+  blocks: &[CompilingBlock],
+) -> Result<(Block, Vec<CompileWarning>), CompileError> {
+  let mut blocks_clone = blocks.to_vec();
   let head_candinates: Vec<usize> = blocks
     .iter()
     .enumerate()
@@ -284,76 +607,377 @@ fn connect_blocks(code: &Vec<Vec<String>>, blocks: &Vec<CompilingBlock>) -> Resu
     .collect();
 
   if head_candinates.len() != 1 {
-    return Err(format!(
-      "The code must have exact one block which has no block-plug. Found {}.",
-      head_candinates.len()
-    ));
+    return Err(CompileError::NonUniqueStartBlock {
+      found: head_candinates.len(),
+    });
   }
   let head = head_candinates[0];
 
+  let block_plugs = block_plugs(blocks);
   for block in blocks_clone.iter_mut() {
-    for ArgPlug { x, y, expand, ori } in block.arg_plugs.iter() {
-      let mut mut_x = *x;
-      let mut mut_y = *y;
-      let mut mut_ori = ori.clone();
-
-      loop {
-        match find_next_edge(code, &mut_x, &mut_y, &mut_ori) {
-          Ok(edge) => {
-            mut_x = edge.x;
-            mut_y = edge.y;
-            mut_ori = edge.ori;
-          }
-          Err(edge) => {
-            mut_x = edge.x;
-            mut_y = edge.y;
-            break;
-          }
-        }
-      }
+    for plug in block.arg_plugs.iter() {
+      let (_, index) = trace_edge(code, &config.charset, &block_plugs, plug)?;
+      block.args.push((plug.expand, index));
+    }
+  }
 
-      let (index, _) = blocks
-        .iter()
-        .enumerate()
-        .find(|(_, b)| {
-          if let Some(p) = &b.block_plug {
-            p.x == mut_x && p.y == mut_y
-          } else {
-            false
-          }
-        })
-        .ok_or(format!("No block-plug found at ({}, {})", mut_x, mut_y))?;
+  let mut built = HashMap::new();
+  let root = blocks_clone[head].to_block(&blocks_clone, &config.macros, &mut built, &mut vec![head])?;
+
+  let warnings = blocks
+    .iter()
+    .enumerate()
+    .filter(|(i, _)| *i != head && !built.contains_key(i))
+    .map(|(_, block)| CompileWarning::OrphanBlock {
+      x: block.x,
+      y: block.y,
+      proc_name: block.proc_name.clone(),
+    })
+    .collect();
+
+  Ok((root, warnings))
+}
+
+/// ソース上のブロックと辺の配置。座標は文字単位
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLayout {
+  /// 最も長い行の文字数
+  pub width: usize,
+  pub height: usize,
+  pub blocks: Vec<BlockLayout>,
+  pub edges: Vec<EdgeLayout>,
+}
+
+/// ブロックの枠。(x, y) は左上の角で、幅と高さは枠を含む
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockLayout {
+  pub x: usize,
+  pub y: usize,
+  pub width: usize,
+  pub height: usize,
+  pub proc_name: String,
+  pub quote: QuoteStyle,
+}
+
+/// 引数プラグからブロックプラグまでの辺が通る座標
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EdgeLayout {
+  pub points: Vec<(usize, usize)>,
+  pub expand: bool,
+}
+
+/// 描画のために、ブロックと辺の配置を返す。根から辿れないブロックも含む
+pub fn layout_with_config(code: Vec<String>, config: &CompileConfig) -> Result<SourceLayout, CompileError> {
+  let code_splited: Vec<Vec<String>> = split_code(&normalize_source(&code, config));
+  let (blocks, _) = find_blocks(&code_splited, &config.charset);
+
+  let block_plugs = block_plugs(&blocks);
+  let mut edges = vec![];
+  for block in &blocks {
+    for plug in &block.arg_plugs {
+      let (points, _) = trace_edge(&code_splited, &config.charset, &block_plugs, plug)?;
+      edges.push(EdgeLayout {
+        points,
+        expand: plug.expand,
+      });
+    }
+  }
+
+  Ok(SourceLayout {
+    width: code_splited.iter().map(|line| line.len()).max().unwrap_or(0),
+    height: code_splited.len(),
+    blocks: blocks
+      .iter()
+      .map(|block| BlockLayout {
+        x: block.x,
+        y: block.y,
+        width: block.width,
+        height: block.height,
+        proc_name: block.proc_name.clone(),
+        quote: block.block_plug.as_ref().map_or(QuoteStyle::None, |plug| plug.quote.clone()),
+      })
+      .collect(),
+    edges,
+  })
+}
+
+/// ブロックの枠と辺に使える文字。どれも複数の候補を持てる
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Charset {
+  pub top_left: Vec<String>,
+  pub top_right: Vec<String>,
+  pub bottom_left: Vec<String>,
+  pub bottom_right: Vec<String>,
+  /// 横の線 (─)
+  pub horizontal: Vec<String>,
+  /// 縦の線 (│)
+  pub vertical: Vec<String>,
+  /// 上・右・下・左に伸びる分岐 (┴ ├ ┬ ┤)。枠の上ではプラグ、辺の上では合流になる
+  pub tee_up: Vec<String>,
+  pub tee_right: Vec<String>,
+  pub tee_down: Vec<String>,
+  pub tee_left: Vec<String>,
+  /// 二重線の分岐 (╨ ╞ ╥ ╡)。どの辺にあってもブロックプラグになる
+  pub double_tee_up: Vec<String>,
+  pub double_tee_right: Vec<String>,
+  pub double_tee_down: Vec<String>,
+  pub double_tee_left: Vec<String>,
+  /// 引数を展開する引数プラグ (@)
+  pub expand: Vec<String>,
+  /// 引用のブロックプラグ (•)
+  pub quote: Vec<String>,
+  /// クロージャのブロックプラグ (/)
+  pub closure: Vec<String>,
+  /// 引用の中で値に置き換えるブロックプラグ (~)
+  pub unquote: Vec<String>,
+}
+
+fn chars(chars: &[&str]) -> Vec<String> {
+  chars.iter().map(|c| c.to_string()).collect()
+}
+
+fn contains(chars: &[String], char: &str) -> bool {
+  chars.iter().any(|c| c == char)
+}
+
+impl Charset {
+  /// 角は ┌ ┐ └ ┘ のみ
+  pub fn standard() -> Charset {
+    Charset {
+      top_left: chars(&["┌"]),
+      top_right: chars(&["┐"]),
+      bottom_left: chars(&["└"]),
+      bottom_right: chars(&["┘"]),
+      horizontal: chars(&["─"]),
+      vertical: chars(&["│"]),
+      tee_up: chars(&["┴"]),
+      tee_right: chars(&["├"]),
+      tee_down: chars(&["┬"]),
+      tee_left: chars(&["┤"]),
+      double_tee_up: chars(&["╨"]),
+      double_tee_right: chars(&["╞"]),
+      double_tee_down: chars(&["╥"]),
+      double_tee_left: chars(&["╡"]),
+      expand: chars(&["@"]),
+      quote: chars(&["•"]),
+      closure: chars(&["/"]),
+      unquote: chars(&["~"]),
+    }
+  }
+
+  /// standard に加えて丸い角 ╭ ╮ ╰ ╯ も使える
+  pub fn rounded() -> Charset {
+    let mut charset = Charset::standard();
+    charset.top_left.push("╭".to_owned());
+    charset.top_right.push("╮".to_owned());
+    charset.bottom_left.push("╰".to_owned());
+    charset.bottom_right.push("╯".to_owned());
+    charset
+  }
+
+  /// 名前から文字の組を選ぶ
+  pub fn from_profile(name: &str) -> Option<Charset> {
+    match name {
+      "standard" => Some(Charset::standard()),
+      "rounded" => Some(Charset::rounded()),
+      _ => None,
+    }
+  }
+
+  fn is_top_left(&self, char: &str) -> bool {
+    contains(&self.top_left, char)
+  }
+  fn is_top_right(&self, char: &str) -> bool {
+    contains(&self.top_right, char)
+  }
+  fn is_bottom_left(&self, char: &str) -> bool {
+    contains(&self.bottom_left, char)
+  }
+  fn is_bottom_right(&self, char: &str) -> bool {
+    contains(&self.bottom_right, char)
+  }
 
-      block.args.push((*expand, index));
+  /// `ori` 側の辺の線
+  fn line(&self, ori: &Orientation) -> &[String] {
+    match ori {
+      Orientation::Up | Orientation::Down => &self.horizontal,
+      Orientation::Left | Orientation::Right => &self.vertical,
+    }
+  }
+  fn tee(&self, ori: &Orientation) -> &[String] {
+    match ori {
+      Orientation::Up => &self.tee_up,
+      Orientation::Right => &self.tee_right,
+      Orientation::Down => &self.tee_down,
+      Orientation::Left => &self.tee_left,
     }
   }
+  fn double_tee(&self, ori: &Orientation) -> &[String] {
+    match ori {
+      Orientation::Up => &self.double_tee_up,
+      Orientation::Right => &self.double_tee_right,
+      Orientation::Down => &self.double_tee_down,
+      Orientation::Left => &self.double_tee_left,
+    }
+  }
+}
+
+/// コンパイル時にブロックを書き換える関数。マクロの名前のブロックを受け取り、置き換えるブロックを返す
+#[derive(Clone)]
+pub struct Macro(pub Arc<MacroFn>);
+
+pub type MacroFn = dyn Fn(&Block) -> Result<Block, String> + Send + Sync;
+
+impl PartialEq for Macro {
+  fn eq(&self, other: &Self) -> bool {
+    Arc::ptr_eq(&self.0, &other.0)
+  }
+}
 
-  Ok(blocks_clone[head].to_block(&blocks_clone.clone()))
+impl Eq for Macro {}
+
+impl Debug for Macro {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "Macro")
+  }
 }
 
-fn split_code(code: &Vec<String>) -> Vec<Vec<String>> {
+/// コンパイルの設定
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompileConfig {
+  /// タブを何文字幅の桁区切りまで空白で埋めるか
+  pub tab_width: usize,
+  pub charset: Charset,
+  /// 名前をキーにしたマクロ。ブロックを組み立てるときに、子から順に展開する
+  pub macros: HashMap<String, Macro>,
+}
+
+pub const DEFAULT_TAB_WIDTH: usize = 4;
+
+impl Default for CompileConfig {
+  fn default() -> Self {
+    CompileConfig {
+      tab_width: DEFAULT_TAB_WIDTH,
+      charset: Charset::standard(),
+      macros: HashMap::new(),
+    }
+  }
+}
+
+/// 先頭の BOM を取り除き、タブを空白に展開する。
+/// コンパイルエラーの座標はこの結果の上での位置 (全角文字は 2 マスと数える) になる。
+pub fn normalize_source(code: &[String], config: &CompileConfig) -> Vec<String> {
   code
     .iter()
-    .map(|s| s.split("").filter_map(|s| if s.is_empty() { None } else { Some(s.to_owned()) }).collect())
+    .enumerate()
+    .map(|(y, line)| {
+      let line = if y == 0 {
+        line.strip_prefix('\u{feff}').unwrap_or(line)
+      } else {
+        line
+      };
+      let mut expanded = String::new();
+      let mut column = 0;
+      for char in line.chars() {
+        if char == '\t' && config.tab_width > 0 {
+          let spaces = config.tab_width - column % config.tab_width;
+          expanded.extend(std::iter::repeat_n(' ', spaces));
+          column += spaces;
+        } else {
+          expanded.push(char);
+          column += char.width().unwrap_or(0).max(1);
+        }
+      }
+      expanded
+    })
     .collect()
 }
 
-pub fn compile(code: Vec<String>) -> Result<Block, String> {
-  let code_splited: Vec<Vec<String>> = split_code(&code);
+/// 1 文字を 1 マスに分ける。全角文字は表示幅に合わせて 2 マスを占め、2 マス目は空のマスにする
+fn split_code(code: &[String]) -> Vec<Vec<String>> {
+  code
+    .iter()
+    .map(|s| {
+      s.chars()
+        .flat_map(|char| {
+          let padding = char.width().unwrap_or(0).saturating_sub(1);
+          iter::once(char.to_string()).chain(iter::repeat_n(String::new(), padding))
+        })
+        .collect()
+    })
+    .collect()
+}
 
-  let blocks = find_blocks(&code_splited);
+pub fn compile(code: Vec<String>) -> Result<Block, CompileError> {
+  compile_with_config(code, &CompileConfig::default())
+}
+
+pub fn compile_with_config(code: Vec<String>, config: &CompileConfig) -> Result<Block, CompileError> {
+  compile_with_warnings(code, config).map(|(block, _)| block)
+}
+
+/// 根のブロックと一緒に、根から辿れないブロックなどの警告を返す
+pub fn compile_with_warnings(
+  code: Vec<String>,
+  config: &CompileConfig,
+) -> Result<(Block, Vec<CompileWarning>), CompileError> {
+  let code_splited: Vec<Vec<String>> = split_code(&normalize_source(&code, config));
+
+  let (blocks, mut malformed) = find_blocks(&code_splited, &config.charset);
 
-  connect_blocks(&code_splited, &blocks)
+  // 閉じていない枠は、そのせいで消えたブロックが辺の途切れや根の数の食い違いを起こしたときだけエラーにし、
+  // コンパイルできたなら警告にとどめる
+  match connect_blocks(&code_splited, config, &blocks) {
+    Ok((root, mut warnings)) => {
+      warnings.extend(malformed.into_iter().filter_map(|err| match err {
+        CompileError::MalformedBlock {
+          x,
+          y,
+          at_x,
+          at_y,
+          found,
+        } => Some(CompileWarning::MalformedBlock {
+          x,
+          y,
+          at_x,
+          at_y,
+          found,
+        }),
+        _ => None,
+      }));
+      Ok((root, warnings))
+    }
+    Err(CompileError::DanglingArgEdge { x, y, .. }) if !malformed.is_empty() => {
+      // 途切れた位置にいちばん近い枠が原因とみなす
+      Err(
+        malformed
+          .into_iter()
+          .min_by_key(|err| match err {
+            CompileError::MalformedBlock { at_x, at_y, .. } => at_x.abs_diff(x) + at_y.abs_diff(y),
+            _ => usize::MAX,
+          })
+          .unwrap(),
+      )
+    }
+    Err(CompileError::NonUniqueStartBlock { .. }) if !malformed.is_empty() => Err(malformed.remove(0)),
+    Err(err) => Err(err),
+  }
 }
 
 #[cfg(test)]
 mod tests {
+  use std::{collections::HashMap, sync::Arc};
+
   use crate::{
-    compile::{find_blocks, ArgPlug, BlockPlug, CompilingBlock, Orientation},
+    compile::{find_blocks, ArgPlug, BlockPlug, CompileError, CompilingBlock, Macro, Orientation},
+    errors::ErrorCode,
     structs::{Block, QuoteStyle},
   };
 
-  use super::{compile, split_code};
+  use super::{
+    compile, compile_with_config, compile_with_warnings, normalize_source, split_code, Charset, CompileConfig,
+    CompileWarning,
+  };
 
   #[test]
   fn test_split_code() {
@@ -375,7 +999,7 @@ mod tests {
 
     assert_eq!(
       Ok(Block {
-        proc_name: "abc".to_owned(),
+        proc_name: "abc".into(),
         args: vec![],
         quote: QuoteStyle::None
       }),
@@ -396,7 +1020,7 @@ mod tests {
 
     assert_eq!(
       Ok(Block {
-        proc_name: "abc\ndef g".to_owned(),
+        proc_name: "abc\ndef g".into(),
         args: vec![],
         quote: QuoteStyle::None
       }),
@@ -405,20 +1029,24 @@ mod tests {
   }
   #[test]
   fn check_find_blocks() {
-    let blocks = find_blocks(&split_code(&vec![
-      "    ".to_owned(),
-      "    ┌───────┐".to_owned(),
-      "    │ abc   │    ".to_owned(),
-      "    └───┬───┘   ".to_owned(),
-      "    ┌───┴──┐".to_owned(),
-      "    │ def  │    ".to_owned(),
-      "    └──────┘   ".to_owned(),
-    ]));
+    let blocks = find_blocks(
+      &split_code(&[
+        "    ".to_owned(),
+        "    ┌───────┐".to_owned(),
+        "    │ abc   │    ".to_owned(),
+        "    └───┬───┘   ".to_owned(),
+        "    ┌───┴──┐".to_owned(),
+        "    │ def  │    ".to_owned(),
+        "    └──────┘   ".to_owned(),
+      ]),
+      &Charset::standard(),
+    )
+    .0;
 
     assert_eq!(
       vec![
         CompilingBlock {
-          proc_name: "abc".to_owned(),
+          proc_name: "abc".into(),
           x: 4,
           y: 1,
           width: 9,
@@ -433,7 +1061,7 @@ mod tests {
           args: vec![]
         },
         CompilingBlock {
-          proc_name: "def".to_owned(),
+          proc_name: "def".into(),
           x: 4,
           y: 4,
           width: 8,
@@ -465,11 +1093,11 @@ mod tests {
 
     assert_eq!(
       Ok(Block {
-        proc_name: "abc".to_owned(),
+        proc_name: "abc".into(),
         args: vec![(
           false,
           Box::new(Block {
-            proc_name: "def".to_owned(),
+            proc_name: "def".into(),
             args: vec![],
             quote: QuoteStyle::None
           })
@@ -479,4 +1107,418 @@ mod tests {
       block
     );
   }
+
+  #[test]
+  fn no_start_block() {
+    let result = compile(vec![
+      "┌───┐┌───┐".to_owned(),
+      "│ a ││ b │".to_owned(),
+      "└───┘└───┘".to_owned(),
+    ]);
+
+    assert_eq!(Err(CompileError::NonUniqueStartBlock { found: 2 }), result);
+  }
+
+  #[test]
+  fn dangling_arg_edge() {
+    let result = compile(vec![
+      "┌───┐".to_owned(),
+      "│ a │".to_owned(),
+      "└─┬─┘".to_owned(),
+      "  │  ".to_owned(),
+      "     ".to_owned(),
+    ]);
+
+    assert_eq!(
+      Err(CompileError::DanglingArgEdge {
+        x: 2,
+        y: 4,
+        edge: vec![(2, 2), (2, 3)]
+      }),
+      result
+    );
+  }
+
+  #[test]
+  fn looped_junction() {
+    let result = compile(vec![
+      "┌───┐     ".to_owned(),
+      "│ a ├──┬─┐".to_owned(),
+      "└───┘  │ │".to_owned(),
+      "       └─┘".to_owned(),
+    ]);
+
+    assert_eq!(
+      Err(CompileError::DanglingArgEdge {
+        x: 7,
+        y: 1,
+        edge: vec![
+          (4, 1),
+          (5, 1),
+          (6, 1),
+          (7, 1),
+          (7, 2),
+          (7, 3),
+          (8, 3),
+          (9, 3),
+          (9, 2),
+          (9, 1),
+          (8, 1)
+        ]
+      }),
+      result
+    );
+  }
+
+  #[test]
+  fn long_chain() {
+    let mut code = vec!["┌───┐".to_owned(), "│ a │".to_owned(), "└─┬─┘".to_owned()];
+    for _ in 0..300 {
+      code.extend(["┌─┴─┐".to_owned(), "│ a │".to_owned(), "└─┬─┘".to_owned()]);
+    }
+    code.extend(["┌─┴─┐".to_owned(), "│ b │".to_owned(), "└───┘".to_owned()]);
+
+    let mut block = compile(code).unwrap();
+    let mut depth = 0;
+    while let Some((_, arg)) = block.args.pop() {
+      block = *arg;
+      depth += 1;
+    }
+    assert_eq!(depth, 301);
+    assert_eq!(&*block.proc_name, "b");
+  }
+
+  #[test]
+  fn macros() {
+    let b = |proc_name: &str, args: Vec<Block>| Block {
+      proc_name: proc_name.into(),
+      args: args.into_iter().map(|arg| (false, Box::new(arg))).collect(),
+      quote: QuoteStyle::None,
+    };
+    let mut macros = HashMap::new();
+    macros.insert(
+      "inc".to_owned(),
+      Macro(Arc::new(move |block: &Block| match &block.args[..] {
+        [(_, arg)] => Ok(b("+", vec![(**arg).clone(), b("1", vec![])])),
+        _ => Err("inc needs 1 arg".to_owned()),
+      })),
+    );
+    macros.insert(
+      "twice".to_owned(),
+      Macro(Arc::new(move |block: &Block| {
+        Ok(b(
+          "inc",
+          vec![b("inc", block.args.iter().map(|(_, arg)| (**arg).clone()).collect())],
+        ))
+      })),
+    );
+    macros.insert("loop".to_owned(), Macro(Arc::new(|block: &Block| Ok(block.clone()))));
+    let config = CompileConfig {
+      macros,
+      ..CompileConfig::default()
+    };
+    let source = |proc_name: &str| {
+      vec![
+        "┌─────┐".to_owned(),
+        format!("│{:^5}│", proc_name),
+        "└──┬──┘".to_owned(),
+        "┌──┴──┐".to_owned(),
+        "│  2  │".to_owned(),
+        "└─────┘".to_owned(),
+      ]
+    };
+
+    let inc = |arg: Block| b("+", vec![arg, b("1", vec![])]);
+    assert_eq!(compile_with_config(source("inc"), &config), Ok(inc(b("2", vec![]))));
+    assert_eq!(
+      compile_with_config(source("twice"), &config),
+      Ok(inc(inc(b("2", vec![]))))
+    );
+    assert_eq!(
+      compile_with_config(
+        vec!["  ┌───┐".to_owned(), "  │inc│".to_owned(), "  └───┘".to_owned()],
+        &config
+      ),
+      Err(CompileError::MacroFailed {
+        x: 2,
+        y: 0,
+        proc_name: "inc".to_owned(),
+        msg: "inc needs 1 arg".to_owned()
+      })
+    );
+    assert_eq!(
+      compile_with_config(source("loop"), &config).map_err(|err| err.code()),
+      Err(ErrorCode::MacroFailed)
+    );
+  }
+
+  #[test]
+  fn tabs_and_bom() {
+    let config = CompileConfig {
+      tab_width: 4,
+      ..CompileConfig::default()
+    };
+    assert_eq!(
+      normalize_source(&["\u{feff}a\tb".to_owned(), "ab\t\tc".to_owned()], &config),
+      vec!["a   b".to_owned(), "ab      c".to_owned()]
+    );
+
+    let block = compile_with_config(
+      vec![
+        "\u{feff}┌─────┐".to_owned(),
+        "│  a  │".to_owned(),
+        "└───┬─┘".to_owned(),
+        "\t┌─┴─┐".to_owned(),
+        "\t│ b │".to_owned(),
+        "\t└───┘".to_owned(),
+      ],
+      &CompileConfig {
+        tab_width: 2,
+        ..CompileConfig::default()
+      },
+    );
+    assert_eq!(block.map(|block| block.args.len()), Ok(1));
+  }
+
+  #[test]
+  fn rounded_corners() {
+    let code = vec![
+      "╭───╮".to_owned(),
+      "│ a │".to_owned(),
+      "╰─┬─╯".to_owned(),
+      "  ╰──╮".to_owned(),
+      "   ╭─┴─╮".to_owned(),
+      "   │ b │".to_owned(),
+      "   ╰───╯".to_owned(),
+    ];
+
+    assert!(compile(code.clone()).is_err());
+    let config = CompileConfig {
+      charset: Charset::rounded(),
+      ..CompileConfig::default()
+    };
+    assert_eq!(
+      compile_with_config(code, &config).map(|block| block.args[0].1.proc_name.to_string()),
+      Ok("b".to_owned())
+    );
+  }
+
+  #[test]
+  fn shared_block() {
+    let block = compile(vec![
+      "┌─────────┐".to_owned(),
+      "│    a    │".to_owned(),
+      "└─┬─────┬─┘".to_owned(),
+      "  │     │".to_owned(),
+      "  └──┬──┘".to_owned(),
+      "   ┌─┴─┐".to_owned(),
+      "   │ b │".to_owned(),
+      "   └───┘".to_owned(),
+    ])
+    .unwrap();
+
+    let names: Vec<_> = block.args.iter().map(|(_, arg)| arg.proc_name.to_string()).collect();
+    assert_eq!(names, vec!["b", "b"]);
+  }
+
+  #[test]
+  fn cyclic_blocks() {
+    let result = compile(vec![
+      "┌─────┐".to_owned(),
+      "│  a  │".to_owned(),
+      "└──┬──┘".to_owned(),
+      "   │   ┌───┐".to_owned(),
+      "   └─┬─┘   │".to_owned(),
+      "   ┌─┴─┐   │".to_owned(),
+      "   │ b ├───┘".to_owned(),
+      "   └───┘".to_owned(),
+    ]);
+
+    assert_eq!(Err(CompileError::CyclicBlocks { x: 3, y: 5 }), result);
+  }
+
+  #[test]
+  fn rotated_blocks() {
+    let block = compile(vec![
+      "       ┌───┐".to_owned(),
+      "       │ c │".to_owned(),
+      "       └─╥─┘".to_owned(),
+      "┌───┐  ┌─┴─┐".to_owned(),
+      "│ a ├──╡ b ├─┐".to_owned(),
+      "└───┘  └───┘ │".to_owned(),
+      "           ┌─╨─┐".to_owned(),
+      "           │ d │".to_owned(),
+      "           └───┘".to_owned(),
+    ])
+    .unwrap();
+
+    let b = &block.args[0].1;
+    assert_eq!(&*b.proc_name, "b");
+    // 左のブロックプラグから左回りに、右辺、上辺の順
+    let names: Vec<_> = b.args.iter().map(|(_, arg)| arg.proc_name.to_string()).collect();
+    assert_eq!(names, vec!["d", "c"]);
+  }
+
+  #[test]
+  fn custom_charset() {
+    let code = vec![
+      "+---+".to_owned(),
+      "| a |".to_owned(),
+      "+-v-+".to_owned(),
+      "+-*-+".to_owned(),
+      "| b |".to_owned(),
+      "+---+".to_owned(),
+    ];
+    assert!(compile(code.clone()).is_err());
+
+    let mut charset = Charset::standard();
+    charset.top_left.push("+".to_owned());
+    charset.top_right.push("+".to_owned());
+    charset.bottom_left.push("+".to_owned());
+    charset.bottom_right.push("+".to_owned());
+    charset.horizontal.push("-".to_owned());
+    charset.vertical.push("|".to_owned());
+    charset.tee_down.push("v".to_owned());
+    charset.quote.push("*".to_owned());
+    let block = compile_with_config(
+      code,
+      &CompileConfig {
+        charset,
+        ..CompileConfig::default()
+      },
+    )
+    .unwrap();
+    assert_eq!(&*block.args[0].1.proc_name, "b");
+    assert_eq!(block.args[0].1.quote, QuoteStyle::Quote);
+  }
+
+  #[test]
+  fn orphan_blocks() {
+    let result = compile_with_warnings(
+      vec![
+        "┌───┐  ┌─┴─┐".to_owned(),
+        "│ a │  │ b │".to_owned(),
+        "└───┘  └───┘".to_owned(),
+      ],
+      &CompileConfig::default(),
+    );
+
+    assert_eq!(
+      result.map(|(_, warnings)| warnings),
+      Ok(vec![CompileWarning::OrphanBlock {
+        x: 7,
+        y: 0,
+        proc_name: "b".to_owned()
+      }])
+    );
+  }
+
+  #[test]
+  fn malformed_blocks() {
+    // 2 つのブロックが辺を共有している
+    let result = compile(vec![
+      "┌───┐".to_owned(),
+      "│ a │".to_owned(),
+      "├───┤".to_owned(),
+      "│ b │".to_owned(),
+      "└───┘".to_owned(),
+    ]);
+    assert_eq!(
+      Err(CompileError::MalformedBlock {
+        x: 0,
+        y: 0,
+        at_x: 4,
+        at_y: 2,
+        found: "┤".to_owned()
+      }),
+      result
+    );
+
+    // 下辺が短い
+    let result = compile(vec!["┌───┐".to_owned(), "│ a │".to_owned(), "└──┘".to_owned()]);
+    assert_eq!(
+      Err(CompileError::MalformedBlock {
+        x: 0,
+        y: 0,
+        at_x: 4,
+        at_y: 2,
+        found: "".to_owned()
+      }),
+      result
+    );
+
+    // 辺を共有して横に並んでいる
+    let result = compile(vec![
+      "┌───┬───┐".to_owned(),
+      "│ a │ b │".to_owned(),
+      "└───┴───┘".to_owned(),
+    ]);
+    assert_eq!(
+      Err(CompileError::MalformedBlock {
+        x: 0,
+        y: 0,
+        at_x: 4,
+        at_y: 0,
+        found: "┬".to_owned()
+      }),
+      result
+    );
+
+    // 閉じていないブロックにつながる辺が途切れる
+    let result = compile(vec![
+      "┌─────┐".to_owned(),
+      "│print│".to_owned(),
+      "└──┬──┘".to_owned(),
+      "┌──┴─┐".to_owned(),
+      "│ 1  │".to_owned(),
+      "└───┘".to_owned(),
+    ]);
+    assert_eq!(
+      Err(CompileError::MalformedBlock {
+        x: 0,
+        y: 3,
+        at_x: 5,
+        at_y: 5,
+        found: "".to_owned()
+      }),
+      result
+    );
+
+    // 枠の形に曲がった辺は、コンパイルできれば警告にとどめる
+    let result = compile_with_warnings(
+      vec![
+        " ┌──────────┐".to_owned(),
+        " │ ┌─────┐  │".to_owned(),
+        " │ │print├──┘".to_owned(),
+        "┌┴┐└─────┘".to_owned(),
+        "│1│".to_owned(),
+        "└─┘".to_owned(),
+      ],
+      &CompileConfig::default(),
+    );
+    assert_eq!(
+      Ok((
+        Block {
+          proc_name: "print".into(),
+          args: vec![(
+            false,
+            Box::new(Block {
+              proc_name: "1".into(),
+              args: vec![],
+              quote: QuoteStyle::None
+            })
+          )],
+          quote: QuoteStyle::None
+        },
+        vec![CompileWarning::MalformedBlock {
+          x: 1,
+          y: 0,
+          at_x: 2,
+          at_y: 2,
+          found: " ".to_owned()
+        }]
+      )),
+      result
+    );
+  }
 }
@@ -0,0 +1,68 @@
+//! プログラムの木を Graphviz の DOT 形式で書き出す。
+
+use crate::structs::{Block, QuoteStyle};
+
+/// ノードには手続き名を、辺には引数の番号 (`@` で展開するものは `@番号`) を付ける。
+/// 引用のブロックは破線、クロージャは点線で囲む。
+pub fn to_dot(block: &Block) -> String {
+  let mut dot = "digraph trees {\n  node [shape=box];\n".to_owned();
+  let mut next_id = 1;
+  // (ノードの番号, ブロック)
+  let mut stack = vec![(0, block)];
+  while let Some((id, block)) = stack.pop() {
+    let style = match block.quote {
+      QuoteStyle::Quote => ", style=dashed",
+      QuoteStyle::Closure => ", style=dotted",
+      QuoteStyle::Unquote => ", style=bold",
+      QuoteStyle::None => "",
+    };
+    dot += &format!("  n{} [label=\"{}\"{}];\n", id, escape(&block.proc_name), style);
+
+    let mut children = vec![];
+    for (index, (expand, arg)) in block.args.iter().enumerate() {
+      let label = if *expand {
+        format!("@{}", index)
+      } else {
+        index.to_string()
+      };
+      dot += &format!("  n{} -> n{} [label=\"{}\"];\n", id, next_id, label);
+      children.push((next_id, arg.as_ref()));
+      next_id += 1;
+    }
+    stack.extend(children.into_iter().rev());
+  }
+  dot += "}\n";
+  dot
+}
+
+fn escape(label: &str) -> String {
+  label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::to_dot;
+  use crate::structs::BlockBuilder;
+
+  #[test]
+  fn dot() {
+    let block = BlockBuilder::proc("print")
+      .arg(BlockBuilder::string("a").closure())
+      .expand_arg(BlockBuilder::proc("list\nof"))
+      .quote()
+      .build();
+
+    assert_eq!(
+      to_dot(&block),
+      r#"digraph trees {
+  node [shape=box];
+  n0 [label="print", style=dashed];
+  n0 -> n1 [label="0"];
+  n0 -> n2 [label="@1"];
+  n1 [label="\"a\"", style=dotted];
+  n2 [label="list\nof"];
+}
+"#
+    );
+  }
+}
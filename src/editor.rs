@@ -0,0 +1,389 @@
+use std::{
+  fs,
+  io::{self, stdout, Write},
+  path::Path,
+};
+
+use crossterm::{
+  cursor,
+  event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+  queue,
+  style::{Attribute, Print, SetAttribute},
+  terminal::{self, ClearType},
+};
+use trees::{
+  layout::layout,
+  messages,
+  structs::{Block, BlockBuilder, QuoteStyle},
+};
+
+/// キーボードで木を編集する。選択中のブロックは根からの引数の番号の列で表す。
+#[derive(Debug, Clone)]
+pub struct Editor {
+  root: Block,
+  /// 切り離したブロック。最後に切り離したものから順につなぎ直せる
+  detached: Vec<Block>,
+  cursor: Vec<usize>,
+}
+
+impl Editor {
+  pub fn new(root: Block) -> Editor {
+    Editor {
+      root,
+      detached: vec![],
+      cursor: vec![],
+    }
+  }
+
+  pub fn root(&self) -> &Block {
+    &self.root
+  }
+
+  fn block_at<'a>(root: &'a Block, path: &[usize]) -> &'a Block {
+    path.iter().fold(root, |block, index| &block.args[*index].1)
+  }
+
+  fn selected_mut(&mut self) -> &mut Block {
+    self.cursor.iter().fold(&mut self.root, |block, index| &mut block.args[*index].1)
+  }
+
+  /// 木を上から順に辿ったときの、各ブロックへの道筋
+  fn paths(&self) -> Vec<Vec<usize>> {
+    let mut paths = vec![];
+    let mut stack = vec![vec![]];
+    while let Some(path) = stack.pop() {
+      let block = Editor::block_at(&self.root, &path);
+      for index in (0..block.args.len()).rev() {
+        let mut child = path.clone();
+        child.push(index);
+        stack.push(child);
+      }
+      paths.push(path);
+    }
+    paths
+  }
+
+  /// 表示順で前後のブロックを選ぶ
+  pub fn step(&mut self, delta: isize) {
+    let paths = self.paths();
+    let current = paths.iter().position(|path| *path == self.cursor).unwrap_or(0);
+    let next = current.saturating_add_signed(delta).min(paths.len() - 1);
+    self.cursor = paths[next].clone();
+  }
+
+  pub fn select_parent(&mut self) {
+    self.cursor.pop();
+  }
+
+  pub fn select_first_child(&mut self) {
+    if !Editor::block_at(&self.root, &self.cursor).args.is_empty() {
+      self.cursor.push(0);
+    }
+  }
+
+  pub fn selected_name(&self) -> String {
+    Editor::block_at(&self.root, &self.cursor).proc_name.to_string()
+  }
+
+  /// 選択中のブロックの最後の引数として新しいブロックを作り、それを選ぶ
+  pub fn add_child(&mut self, name: &str) {
+    let selected = self.selected_mut();
+    selected.args.push((false, Box::new(BlockBuilder::proc(name).build())));
+    let index = selected.args.len() - 1;
+    self.cursor.push(index);
+  }
+
+  pub fn rename(&mut self, name: &str) {
+    self.selected_mut().proc_name = name.into();
+  }
+
+  /// 選択中のブロックを部分木ごと切り離す。根は切り離せない
+  pub fn detach(&mut self) -> bool {
+    let Some(index) = self.cursor.pop() else {
+      return false;
+    };
+    let (_, block) = self.selected_mut().args.remove(index);
+    self.detached.push(*block);
+    true
+  }
+
+  /// 最後に切り離したブロックを、選択中のブロックの最後の引数としてつなぐ
+  pub fn attach(&mut self) -> bool {
+    let Some(block) = self.detached.pop() else {
+      return false;
+    };
+    let selected = self.selected_mut();
+    selected.args.push((false, Box::new(block)));
+    let index = selected.args.len() - 1;
+    self.cursor.push(index);
+    true
+  }
+
+  pub fn detached_count(&self) -> usize {
+    self.detached.len()
+  }
+
+  /// 兄弟の中で選択中のブロックの順番を動かす
+  pub fn move_by(&mut self, delta: isize) {
+    let Some(index) = self.cursor.pop() else {
+      return;
+    };
+    let parent = self.selected_mut();
+    let target = index.saturating_add_signed(delta).min(parent.args.len() - 1);
+    let arg = parent.args.remove(index);
+    parent.args.insert(target, arg);
+    self.cursor.push(target);
+  }
+
+  /// 選択中のブロックを `@` で展開して渡すかどうかを切り替える
+  pub fn toggle_expand(&mut self) {
+    let Some(index) = self.cursor.pop() else {
+      return;
+    };
+    let arg = &mut self.selected_mut().args[index];
+    arg.0 = !arg.0;
+    self.cursor.push(index);
+  }
+
+  /// 通常 → 引用 (•) → クロージャ (/) の順にブロックプラグを切り替える
+  pub fn cycle_quote(&mut self) {
+    if self.cursor.is_empty() {
+      return;
+    }
+    let selected = self.selected_mut();
+    selected.quote = match selected.quote {
+      QuoteStyle::None => QuoteStyle::Quote,
+      QuoteStyle::Quote => QuoteStyle::Closure,
+      QuoteStyle::Closure => QuoteStyle::Unquote,
+      QuoteStyle::Unquote => QuoteStyle::None,
+    };
+  }
+
+  /// 字下げした木の各行と、それが選択中かどうか
+  pub fn outline(&self) -> Vec<(String, bool)> {
+    self
+      .paths()
+      .into_iter()
+      .map(|path| {
+        let block = Editor::block_at(&self.root, &path);
+        let expand = match path.split_last() {
+          Some((index, parent)) if Editor::block_at(&self.root, parent).args[*index].0 => "@",
+          _ => "",
+        };
+        let quote = match block.quote {
+          QuoteStyle::Quote => "•",
+          QuoteStyle::Closure => "/",
+          QuoteStyle::Unquote => "~",
+          QuoteStyle::None => "",
+        };
+        let line = format!(
+          "{}{}{}{}",
+          "  ".repeat(path.len()),
+          expand,
+          quote,
+          block.proc_name.replace('\n', "⏎")
+        );
+        (line, path == self.cursor)
+      })
+      .collect()
+  }
+}
+
+enum Mode {
+  Normal,
+  /// 名前の入力中。`rename` が真なら選択中のブロックの名前を変え、偽なら子を作る
+  Prompt {
+    input: String,
+    rename: bool,
+  },
+}
+
+/// 端末を raw モードにして編集し、終了したら元に戻す
+pub fn edit(path: &Path, root: Block) -> io::Result<()> {
+  terminal::enable_raw_mode()?;
+  queue!(stdout(), terminal::EnterAlternateScreen, cursor::Hide)?;
+  let result = edit_loop(path, Editor::new(root));
+  queue!(stdout(), cursor::Show, terminal::LeaveAlternateScreen)?;
+  stdout().flush()?;
+  terminal::disable_raw_mode()?;
+  result
+}
+
+fn edit_loop(path: &Path, mut editor: Editor) -> io::Result<()> {
+  let mut mode = Mode::Normal;
+  let mut status = String::new();
+  let mut modified = false;
+
+  loop {
+    draw(&editor, &mode, &status)?;
+    let Event::Key(KeyEvent {
+      code, modifiers, kind, ..
+    }) = event::read()?
+    else {
+      continue;
+    };
+    if kind != KeyEventKind::Press {
+      continue;
+    }
+
+    match &mut mode {
+      Mode::Prompt { input, rename } => match code {
+        KeyCode::Enter => {
+          if *rename {
+            editor.rename(input);
+          } else {
+            editor.add_child(input);
+          }
+          modified = true;
+          mode = Mode::Normal;
+        }
+        KeyCode::Esc => mode = Mode::Normal,
+        KeyCode::Backspace => {
+          input.pop();
+        }
+        KeyCode::Char(char) => input.push(char),
+        _ => {}
+      },
+      Mode::Normal => {
+        status.clear();
+        match code {
+          KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => return Ok(()),
+          KeyCode::Esc => {
+            if !modified {
+              return Ok(());
+            }
+            // 保存していなければ、もう一度押したときに終了する
+            modified = false;
+            status = messages::editor_unsaved();
+          }
+          KeyCode::Up | KeyCode::Char('k') => editor.step(-1),
+          KeyCode::Down | KeyCode::Char('j') => editor.step(1),
+          KeyCode::Left | KeyCode::Char('h') => editor.select_parent(),
+          KeyCode::Right | KeyCode::Char('l') => editor.select_first_child(),
+          KeyCode::Char('K') => {
+            editor.move_by(-1);
+            modified = true;
+          }
+          KeyCode::Char('J') => {
+            editor.move_by(1);
+            modified = true;
+          }
+          KeyCode::Char('a') => {
+            mode = Mode::Prompt {
+              input: String::new(),
+              rename: false,
+            }
+          }
+          KeyCode::Char('r') => {
+            mode = Mode::Prompt {
+              input: editor.selected_name(),
+              rename: true,
+            }
+          }
+          KeyCode::Char('d') => modified |= editor.detach(),
+          KeyCode::Char('p') => modified |= editor.attach(),
+          KeyCode::Char('e') => {
+            editor.toggle_expand();
+            modified = true;
+          }
+          KeyCode::Char('q') => {
+            editor.cycle_quote();
+            modified = true;
+          }
+          KeyCode::Char('s') => {
+            fs::write(path, layout(editor.root()).join("\n") + "\n")?;
+            modified = false;
+            status = messages::editor_saved(&path.to_string_lossy());
+          }
+          _ => {}
+        }
+      }
+    }
+  }
+}
+
+fn draw(editor: &Editor, mode: &Mode, status: &str) -> io::Result<()> {
+  let mut out = stdout();
+  let (width, height) = terminal::size()?;
+  let rows = (height as usize).saturating_sub(2);
+  let outline = editor.outline();
+
+  // 選択中の行が見えるようにずらす
+  let selected = outline.iter().position(|(_, selected)| *selected).unwrap_or(0);
+  let first = selected.saturating_sub(rows.saturating_sub(1));
+
+  queue!(out, terminal::Clear(ClearType::All))?;
+  for (row, (line, selected)) in outline.iter().skip(first).take(rows).enumerate() {
+    let line: String = line.chars().take(width as usize).collect();
+    queue!(out, cursor::MoveTo(0, row as u16))?;
+    if *selected {
+      queue!(
+        out,
+        SetAttribute(Attribute::Reverse),
+        Print(line),
+        SetAttribute(Attribute::Reset)
+      )?;
+    } else {
+      queue!(out, Print(line))?;
+    }
+  }
+
+  let status = match mode {
+    Mode::Prompt { input, .. } => format!("{}{}", messages::editor_prompt_name(), input),
+    Mode::Normal if status.is_empty() => messages::editor_detached(editor.detached_count()),
+    Mode::Normal => status.to_owned(),
+  };
+  queue!(
+    out,
+    cursor::MoveTo(0, height.saturating_sub(2)),
+    Print(status),
+    cursor::MoveTo(0, height.saturating_sub(1)),
+    SetAttribute(Attribute::Dim),
+    Print(messages::editor_help().chars().take(width as usize).collect::<String>()),
+    SetAttribute(Attribute::Reset)
+  )?;
+  out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Editor;
+  use trees::structs::BlockBuilder;
+
+  #[test]
+  fn edit_tree() {
+    let mut editor = Editor::new(BlockBuilder::proc("seq").build());
+    editor.add_child("print");
+    editor.add_child("\"a\"");
+    editor.select_parent();
+    editor.select_parent();
+    editor.add_child("print");
+    editor.add_child("\"b\"");
+    editor.rename("\"c\"");
+    editor.cycle_quote();
+
+    // "c" を最初の print につなぎ直す
+    assert!(editor.detach());
+    editor.step(-2);
+    assert_eq!(editor.selected_name(), "print");
+    assert!(editor.attach());
+    editor.move_by(-1);
+    editor.toggle_expand();
+
+    let expected = BlockBuilder::proc("seq")
+      .arg(BlockBuilder::proc("print").expand_arg(BlockBuilder::proc("\"c\"").quote()).arg(BlockBuilder::string("a")))
+      .arg(BlockBuilder::proc("print"))
+      .build();
+    assert_eq!(editor.root(), &expected);
+    assert_eq!(
+      editor.outline(),
+      vec![
+        ("seq".to_owned(), false),
+        ("  print".to_owned(), false),
+        ("    @•\"c\"".to_owned(), true),
+        ("    \"a\"".to_owned(), false),
+        ("  print".to_owned(), false),
+      ]
+    );
+  }
+}
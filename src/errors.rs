@@ -0,0 +1,756 @@
+use std::fmt::Display;
+
+use crate::messages::{lang, Lang};
+
+/// コンパイル時・実行時のエラーに割り当てる固定のコード。
+/// T0xxx はコンパイル時、T1xxx は実行時のエラーを表す。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+  NonUniqueStartBlock,
+  DanglingArgEdge,
+  SourceUnreadable,
+  CyclicBlocks,
+  MalformedBlock,
+  MacroFailed,
+  RuntimeError,
+  UndefinedName,
+  UndefinedVariable,
+  ArgCountMismatch,
+  TypeMismatch,
+  IndexOutOfRange,
+  ExpandNotList,
+  CommandFailed,
+  LimitExceeded,
+  CapabilityDenied,
+  IncludeCycle,
+  ConstReassigned,
+  FileError,
+  Interrupted,
+  NetworkError,
+}
+
+impl ErrorCode {
+  pub const ALL: [ErrorCode; 21] = [
+    ErrorCode::NonUniqueStartBlock,
+    ErrorCode::DanglingArgEdge,
+    ErrorCode::SourceUnreadable,
+    ErrorCode::CyclicBlocks,
+    ErrorCode::MalformedBlock,
+    ErrorCode::MacroFailed,
+    ErrorCode::RuntimeError,
+    ErrorCode::UndefinedName,
+    ErrorCode::UndefinedVariable,
+    ErrorCode::ArgCountMismatch,
+    ErrorCode::TypeMismatch,
+    ErrorCode::IndexOutOfRange,
+    ErrorCode::ExpandNotList,
+    ErrorCode::CommandFailed,
+    ErrorCode::LimitExceeded,
+    ErrorCode::CapabilityDenied,
+    ErrorCode::IncludeCycle,
+    ErrorCode::ConstReassigned,
+    ErrorCode::FileError,
+    ErrorCode::Interrupted,
+    ErrorCode::NetworkError,
+  ];
+
+  pub fn code(&self) -> &'static str {
+    match self {
+      ErrorCode::NonUniqueStartBlock => "T0001",
+      ErrorCode::DanglingArgEdge => "T0002",
+      ErrorCode::SourceUnreadable => "T0003",
+      ErrorCode::CyclicBlocks => "T0004",
+      ErrorCode::MalformedBlock => "T0005",
+      ErrorCode::MacroFailed => "T0006",
+      ErrorCode::RuntimeError => "T1000",
+      ErrorCode::UndefinedName => "T1001",
+      ErrorCode::UndefinedVariable => "T1002",
+      ErrorCode::ArgCountMismatch => "T1003",
+      ErrorCode::TypeMismatch => "T1004",
+      ErrorCode::IndexOutOfRange => "T1005",
+      ErrorCode::ExpandNotList => "T1006",
+      ErrorCode::CommandFailed => "T1007",
+      ErrorCode::LimitExceeded => "T1008",
+      ErrorCode::CapabilityDenied => "T1009",
+      ErrorCode::IncludeCycle => "T1010",
+      ErrorCode::ConstReassigned => "T1011",
+      ErrorCode::FileError => "T1012",
+      ErrorCode::Interrupted => "T1013",
+      ErrorCode::NetworkError => "T1014",
+    }
+  }
+
+  pub fn from_code(code: &str) -> Option<ErrorCode> {
+    ErrorCode::ALL.into_iter().find(|c| c.code().eq_ignore_ascii_case(code))
+  }
+
+  pub fn explain(&self) -> &'static str {
+    match lang() {
+      Lang::En => self.explain_en(),
+      Lang::Ja => self.explain_ja(),
+    }
+  }
+
+  fn explain_en(&self) -> &'static str {
+    match self {
+      ErrorCode::NonUniqueStartBlock => {
+        r#"The program must contain exactly one block which has no block-plug
+(the "┴", "•", "/" or "~" on its upper edge). That block is where execution starts.
+
+Two blocks without a block-plug:
+
+┌─────┐  ┌─────┐
+│print│  │print│
+└──┬──┘  └──┬──┘
+┌──┴──┐  ┌──┴──┐
+│  1  │  │  2  │
+└─────┘  └─────┘
+
+Join them under a single root, e.g. "seq":
+
+      ┌───┐
+      │seq├────┐
+      └─┬─┘    │
+   ┌────┴┐  ┌──┴──┐
+   │print│  │print│
+   └──┬──┘  └──┬──┘
+   ┌──┴──┐  ┌──┴──┐
+   │  1  │  │  2  │
+   └─────┘  └─────┘"#
+      }
+      ErrorCode::DanglingArgEdge => {
+        r#"An edge leaving an arg-plug ("┬", "├", "┤" or "@") does not end at the
+block-plug of another block. The reported coordinates are where the edge stops.
+
+The edge is broken by a space:
+
+┌─────┐
+│print│
+└──┬──┘
+
+┌──┴──┐
+│  1  │
+└─────┘
+
+Draw the edge all the way to the block-plug:
+
+┌─────┐
+│print│
+└──┬──┘
+┌──┴──┐
+│  1  │
+└─────┘"#
+      }
+      ErrorCode::SourceUnreadable => {
+        r#"The source file (or an included file) could not be opened or is not valid UTF-8.
+Check the path given on the command line or to "include". Paths of "include" are
+resolved relative to the file which includes them.
+
+┌───────┐
+│include│
+└───┬───┘
+┌───┴─────┐
+│"iter.tr"│
+└─────────┘"#
+      }
+      ErrorCode::CyclicBlocks => {
+        r#"An edge joined at a junction ("┬", "┴", "├" or "┤" on an edge) leads back to
+a block which is already an ancestor of the arg, so the tree would never end.
+The reported coordinates are the upper-left corner of that block.
+
+The arg of "b" is joined into the edge which feeds "b" itself:
+
+┌─────┐
+│  a  │
+└──┬──┘
+   │   ┌───┐
+   └─┬─┘   │
+   ┌─┴─┐   │
+   │ b ├───┘
+   └───┘
+
+A junction may share a block between several args, but the shared block must
+not depend on any of them."#
+      }
+      ErrorCode::MalformedBlock => {
+        r#"A box starts with a complete upper edge but its other sides do not close into
+a rectangle. The error shows where the box starts and the first character which
+does not fit. Boxes must not share a border.
+
+Since an edge may happen to bend into the shape of a box, this is an error only
+when the missing block leaves an edge dangling or no single root block remains.
+Otherwise the program compiles with a warning.
+
+Two blocks sharing a border:
+
+┌─────┐
+│print│
+├─────┤
+│  1  │
+└─────┘
+
+Draw each block as its own rectangle:
+
+┌─────┐
+│print│
+└──┬──┘
+┌──┴──┐
+│  1  │
+└─────┘"#
+      }
+      ErrorCode::MacroFailed => {
+        r#"A macro registered in the compile config (CompileConfig::macros) failed to
+rewrite a block with its name, or kept producing macros and did not finish
+within 1000 expansions. The reported coordinates are the upper-left corner of
+the block in the source, and the message is the one returned by the macro.
+
+Macros only exist for programs compiled through the library; a block with the
+name of a macro is replaced before the program runs:
+
+┌───────┐
+│fordown│
+└───┬───┘
+  ┌─┴─┐
+  │"i"│
+  └───┘
+
+Check the args the macro expects, or make sure its result does not expand back
+into itself."#
+      }
+      ErrorCode::RuntimeError => {
+        r#"A procedure failed while running. The message describes the cause, and the
+tree printed below it shows which block failed ("<-") and the values of the
+blocks which were already evaluated.
+
+┌──────────┐
+│str to int│
+└────┬─────┘
+┌────┴┐
+│"abc"│
+└─────┘"#
+      }
+      ErrorCode::UndefinedName => {
+        r#"The text of a block is neither a defined procedure or variable nor a literal
+(an integer, a "quoted string", true/false, or an empty block).
+
+┌─────┐
+│prnt │
+└──┬──┘
+┌──┴──┐
+│  1  │
+└─────┘
+
+Check the spelling, or define the procedure with "defproc" before using it."#
+      }
+      ErrorCode::UndefinedVariable => {
+        r#"A variable was read, set or exported, but no visible scope defines it.
+Define it with "defset" first. Note that variables defined inside a block are
+not visible from outside that block unless they are exported.
+
+┌───┐
+│set├──┐
+└─┬─┘  │
+┌─┴─┐┌─┴┐
+│"x"││42│
+└───┘└──┘"#
+      }
+      ErrorCode::ArgCountMismatch => {
+        r#"A predefined procedure was given the wrong number of arguments.
+
+    ┌─────┐
+ ┌──┤  +  ├──┐
+ │  └──┬──┘  │
+┌┴┐  ┌─┴┐  ┌─┴┐
+│1│  │2 │  │3 │
+└─┘  └──┘  └──┘
+
+"+" takes exactly two arguments. Nest the blocks instead."#
+      }
+      ErrorCode::TypeMismatch => {
+        r#"An argument has a different type than the procedure expects. The message
+shows which argument ($arg[n]) was wrong and the value it got.
+
+┌─────┐
+│  +  ├──┐
+└──┬──┘  │
+┌──┴┐  ┌─┴─┐
+│ 1 │  │"2"│
+└───┘  └───┘
+
+"+" needs two ints. Convert with "str to int" or "to str" as needed."#
+      }
+      ErrorCode::IndexOutOfRange => {
+        r#"A list was indexed with a position outside 0..len.
+
+┌──┐
+│[]├─────┐
+└┬─┘     │
+┌┴──────┐┌┴┐
+│listing││5│
+└───────┘└─┘
+
+Check the index with "len" before reading."#
+      }
+      ErrorCode::ExpandNotList => {
+        r#"The "@" arg-plug expands a list into several arguments, so the block connected
+to it must evaluate to a list.
+
+┌─────┐
+│  +  │
+└──@──┘
+┌──┴──┐
+│  1  │
+└─────┘
+
+Use a normal arg-plug, or connect a block which returns a list."#
+      }
+      ErrorCode::CommandFailed => {
+        r#"An external command started by "cmd" could not be run, or its output was not
+valid UTF-8, or it did not finish within the time given to "cmd timeout".
+This also occurs when "wait" or "kill" is given a handle of a process
+which was not started by "spawn cmd" or has already been waited.
+
+┌─────┐
+│ cmd ├────┐
+└───┬─┘    │
+┌───┴──┐┌──┴──┐
+│"echo"││"out"│
+└──────┘└─────┘"#
+      }
+      ErrorCode::LimitExceeded => {
+        r#"The program exceeded the limit of nested procedure calls (--max-depth) or of
+the total number of procedure calls (--max-steps). This usually means a
+recursion which never ends:
+
+┌───┐
+│seq├──────────┐
+└─┬─┘          │
+┌─┴─────┐    ┌─┴─┐
+│defproc├─┐  │ f │
+└┬──────┘ │  └───┘
+┌┴──┐    ┌•┐
+│"f"│    │f│
+└───┘    └─┘
+
+Add a base case to the recursion, or raise the limits if the program really
+needs them."#
+      }
+      ErrorCode::CapabilityDenied => {
+        r#"The program tried to use a capability which was denied by --sandbox or
+--deny (cmd: external commands, fs: files, net: network).
+
+┌─────┐
+│ cmd ├────┐
+└───┬─┘    │
+┌───┴──┐┌──┴──┐
+│"echo"││"out"│
+└──────┘└─────┘
+
+Run the program without the restriction only if you trust it."#
+      }
+      ErrorCode::IncludeCycle => {
+        r#"A module includes itself, directly or through other modules. The error
+lists the chain of includes which forms the cycle.
+
+a.tr:            b.tr:
+┌───────┐        ┌───────┐
+│include│        │include│
+└───┬───┘        └───┬───┘
+┌───┴──┐         ┌───┴──┐
+│"b.tr"│         │"a.tr"│
+└──────┘         └──────┘
+
+Move the definitions both modules need into a third module and include that
+one from each instead."#
+      }
+      ErrorCode::ConstReassigned => {
+        r#"The program tried to change a constant defined by "defconst" with "set",
+"undef" or another "defconst" in the same scope.
+
+┌────────┐
+│defconst├──┐
+└───┬────┘  │
+┌───┴─┐  ┌──┴──┐
+│"max"│  │ 10  │
+└─────┘  └─────┘
+
+Define a variable with "defset" instead if the value needs to change."#
+      }
+      ErrorCode::FileError => {
+        r#"A file or directory given to "list dir", "mkdir", "remove file",
+"copy file" or "open lines", or created by "temp file" or "temp dir", could
+not be accessed. It may not exist, or the permission may be missing.
+This also occurs when "cd" is given a path which is not a directory, when
+"next line" or "close" is given a handle of a file which is not open, when a
+"store ..." procedure is given a handle of a store which is not open, or when
+"store open" reads a store file with a broken line.
+
+┌───────────┐
+│remove file├──┐
+└───────────┘  │
+         ┌─────┴─────┐
+         │"notes.txt"│
+         └───────────┘"#
+      }
+      ErrorCode::Interrupted => {
+        r#"The program was stopped by Ctrl-C. The tree shows the procedure which was
+about to be called ("<-"); the blocks shown without a value were not run.
+Press Ctrl-C again to stop immediately when the program is waiting for input
+or a command.
+
+┌─────┐
+│while├────┐
+└───┬─┘    │
+┌───┴─┐ ┌──┴──┐
+│true │ │ ... │
+└─────┘ └─────┘"#
+      }
+      ErrorCode::NetworkError => {
+        r#""http serve" could not listen on the port, or stopped accepting connections.
+The port may already be used by another program, or a port below 1024 may
+need extra permission. The server only listens on 127.0.0.1.
+
+┌──────────┐
+│http serve├──┐
+└────┬─────┘  │
+  ┌──┴─┐   ┌──┴──┐
+  │8080│   │ ... │
+  └────┘   └─────┘"#
+      }
+    }
+  }
+
+  fn explain_ja(&self) -> &'static str {
+    match self {
+      ErrorCode::NonUniqueStartBlock => {
+        r#"プログラムには、ブロックプラグ (上辺の "┴", "•", "/", "~") を持たないブロックが
+ちょうど 1 つ必要です。そのブロックから実行が始まります。
+
+ブロックプラグを持たないブロックが 2 つある例:
+
+┌─────┐  ┌─────┐
+│print│  │print│
+└──┬──┘  └──┬──┘
+┌──┴──┐  ┌──┴──┐
+│  1  │  │  2  │
+└─────┘  └─────┘
+
+"seq" などで 1 つの根にまとめてください:
+
+      ┌───┐
+      │seq├────┐
+      └─┬─┘    │
+   ┌────┴┐  ┌──┴──┐
+   │print│  │print│
+   └──┬──┘  └──┬──┘
+   ┌──┴──┐  ┌──┴──┐
+   │  1  │  │  2  │
+   └─────┘  └─────┘"#
+      }
+      ErrorCode::DanglingArgEdge => {
+        r#"引数プラグ ("┬", "├", "┤", "@") から伸びる辺が、他のブロックのブロックプラグに
+届いていません。表示される座標は辺が途切れた位置です。
+
+空白で辺が途切れている例:
+
+┌─────┐
+│print│
+└──┬──┘
+
+┌──┴──┐
+│  1  │
+└─────┘
+
+ブロックプラグまで辺をつなげてください:
+
+┌─────┐
+│print│
+└──┬──┘
+┌──┴──┐
+│  1  │
+└─────┘"#
+      }
+      ErrorCode::SourceUnreadable => {
+        r#"ソースファイル (または include されたファイル) を開けないか、UTF-8 として読めません。
+コマンドラインや "include" に渡したパスを確認してください。"include" のパスは
+include するファイルからの相対パスとして解決されます。
+
+┌───────┐
+│include│
+└───┬───┘
+┌───┴─────┐
+│"iter.tr"│
+└─────────┘"#
+      }
+      ErrorCode::CyclicBlocks => {
+        r#"分岐 (辺の上の "┬", "┴", "├", "┤") で合流させた辺が、その引数の祖先にあたる
+ブロックに戻っているため、木が終わりません。表示される座標はそのブロックの左上の角です。
+
+"b" の引数が、"b" 自身につながる辺に合流している例:
+
+┌─────┐
+│  a  │
+└──┬──┘
+   │   ┌───┐
+   └─┬─┘   │
+   ┌─┴─┐   │
+   │ b ├───┘
+   └───┘
+
+分岐で 1 つのブロックを複数の引数に共有できますが、共有するブロックがそれらの引数に
+依存してはいけません。"#
+      }
+      ErrorCode::MalformedBlock => {
+        r#"上辺まではそろっているのに、残りの辺が長方形として閉じていない枠があります。
+エラーには枠の始まる位置と、最初に合わなかった文字の位置が表示されます。
+ブロック同士で辺を共有することはできません。
+
+辺がたまたま枠の形に曲がっていることもあるので、ブロックが消えたせいで辺が途切れたり、
+根のブロックが 1 つに定まらなかったりしたときだけエラーになります。
+それ以外のときは警告を表示してコンパイルを続けます。
+
+2 つのブロックが辺を共有している例:
+
+┌─────┐
+│print│
+├─────┤
+│  1  │
+└─────┘
+
+ブロックはそれぞれ独立した長方形で描いてください:
+
+┌─────┐
+│print│
+└──┬──┘
+┌──┴──┐
+│  1  │
+└─────┘"#
+      }
+      ErrorCode::MacroFailed => {
+        r#"コンパイルの設定 (CompileConfig::macros) に登録したマクロが、その名前のブロックの
+書き換えに失敗したか、マクロを作り続けて 1000 回の展開で終わりませんでした。
+表示される座標はソース上のブロックの左上の角で、メッセージはマクロが返したものです。
+
+マクロはライブラリからコンパイルしたプログラムにだけあり、マクロの名前のブロックは
+実行の前に置き換えられます:
+
+┌───────┐
+│fordown│
+└───┬───┘
+  ┌─┴─┐
+  │"i"│
+  └───┘
+
+マクロが受け取る引数を確かめるか、展開した結果が自分自身に戻らないようにしてください。"#
+      }
+      ErrorCode::RuntimeError => {
+        r#"実行中に手続きが失敗しました。メッセージが原因を示し、その下の木は失敗した
+ブロック ("<-") と、評価済みのブロックの値を示します。
+
+┌──────────┐
+│str to int│
+└────┬─────┘
+┌────┴┐
+│"abc"│
+└─────┘"#
+      }
+      ErrorCode::UndefinedName => {
+        r#"ブロックの文字列が、定義済みの手続き・変数でも、リテラル (整数、"文字列"、
+true/false、空のブロック) でもありません。
+
+┌─────┐
+│prnt │
+└──┬──┘
+┌──┴──┐
+│  1  │
+└─────┘
+
+綴りを確認するか、使う前に "defproc" で手続きを定義してください。"#
+      }
+      ErrorCode::UndefinedVariable => {
+        r#"変数を読み書き・export しようとしましたが、見えるスコープに定義がありません。
+先に "defset" で定義してください。ブロックの内側で定義した変数は、export しない限り
+外側からは見えません。
+
+┌───┐
+│set├──┐
+└─┬─┘  │
+┌─┴─┐┌─┴┐
+│"x"││42│
+└───┘└──┘"#
+      }
+      ErrorCode::ArgCountMismatch => {
+        r#"組み込み手続きに渡した引数の数が違います。
+
+    ┌─────┐
+ ┌──┤  +  ├──┐
+ │  └──┬──┘  │
+┌┴┐  ┌─┴┐  ┌─┴┐
+│1│  │2 │  │3 │
+└─┘  └──┘  └──┘
+
+"+" の引数はちょうど 2 つです。ブロックを入れ子にしてください。"#
+      }
+      ErrorCode::TypeMismatch => {
+        r#"引数の型が手続きの期待する型と違います。メッセージには誤っている引数 ($arg[n])
+と実際の値が表示されます。
+
+┌─────┐
+│  +  ├──┐
+└──┬──┘  │
+┌──┴┐  ┌─┴─┐
+│ 1 │  │"2"│
+└───┘  └───┘
+
+"+" には int が 2 つ必要です。必要に応じて "str to int" や "to str" で変換してください。"#
+      }
+      ErrorCode::IndexOutOfRange => {
+        r#"リストの範囲 (0..len) の外を参照しました。
+
+┌──┐
+│[]├─────┐
+└┬─┘     │
+┌┴──────┐┌┴┐
+│listing││5│
+└───────┘└─┘
+
+参照する前に "len" で長さを確認してください。"#
+      }
+      ErrorCode::ExpandNotList => {
+        r#"引数プラグ "@" はリストを複数の引数に展開するため、つながるブロックは
+リストを返さなければなりません。
+
+┌─────┐
+│  +  │
+└──@──┘
+┌──┴──┐
+│  1  │
+└─────┘
+
+通常の引数プラグを使うか、リストを返すブロックをつないでください。"#
+      }
+      ErrorCode::CommandFailed => {
+        r#""cmd" で起動した外部コマンドを実行できないか、出力が UTF-8 ではないか、"cmd timeout" で指定した時間内に終わりませんでした。
+"wait" や "kill" に、"spawn cmd" で起動していないか既に待ち終えたプロセスのハンドルを渡したときにも起こります。
+
+┌─────┐
+│ cmd ├────┐
+└───┬─┘    │
+┌───┴──┐┌──┴──┐
+│"echo"││"out"│
+└──────┘└─────┘"#
+      }
+      ErrorCode::LimitExceeded => {
+        r#"手続き呼び出しの入れ子の深さ (--max-depth) または呼び出しの総数 (--max-steps) の
+上限を超えました。多くの場合、終わらない再帰が原因です:
+
+┌───┐
+│seq├──────────┐
+└─┬─┘          │
+┌─┴─────┐    ┌─┴─┐
+│defproc├─┐  │ f │
+└┬──────┘ │  └───┘
+┌┴──┐    ┌•┐
+│"f"│    │f│
+└───┘    └─┘
+
+再帰に終了条件を加えるか、本当に必要なら上限を引き上げてください。"#
+      }
+      ErrorCode::CapabilityDenied => {
+        r#"--sandbox または --deny で拒否された権限 (cmd: 外部コマンド、fs: ファイル、
+net: ネットワーク) を使おうとしました。
+
+┌─────┐
+│ cmd ├────┐
+└───┬─┘    │
+┌───┴──┐┌──┴──┐
+│"echo"││"out"│
+└──────┘└─────┘
+
+信頼できるプログラムの場合に限り、制限を外して実行してください。"#
+      }
+      ErrorCode::IncludeCycle => {
+        r#"モジュールが直接、または他のモジュールを経由して自分自身を include しています。
+エラーには循環を作っている include の連なりが表示されます。
+
+a.tr:            b.tr:
+┌───────┐        ┌───────┐
+│include│        │include│
+└───┬───┘        └───┬───┘
+┌───┴──┐         ┌───┴──┐
+│"b.tr"│         │"a.tr"│
+└──────┘         └──────┘
+
+両方のモジュールが必要とする定義を 3 つ目のモジュールに移し、それぞれからそれを include してください。"#
+      }
+      ErrorCode::ConstReassigned => {
+        r#""defconst" で定義した定数を "set" や "undef"、同じスコープでの "defconst" で変えようとしました。
+
+┌────────┐
+│defconst├──┐
+└───┬────┘  │
+┌───┴─┐  ┌──┴──┐
+│"max"│  │ 10  │
+└─────┘  └─────┘
+
+値を変える必要があれば、代わりに "defset" で変数を定義してください。"#
+      }
+      ErrorCode::FileError => {
+        r#""list dir"、"mkdir"、"remove file"、"copy file"、"open lines" に渡したか、"temp file"、"temp dir" で作ろうとしたファイルやディレクトリにアクセスできませんでした。
+存在しないか、権限が無い可能性があります。
+"cd" にディレクトリでないパスを渡したときや、"next line" や "close" に開いていないファイルのハンドルを渡したとき、"store ..." の手続きに開いていないストアのハンドルを渡したとき、"store open" で読んだストアのファイルに壊れた行があったときにも起こります。
+
+┌───────────┐
+│remove file├──┐
+└───────────┘  │
+         ┌─────┴─────┐
+         │"notes.txt"│
+         └───────────┘"#
+      }
+      ErrorCode::Interrupted => {
+        r#"Ctrl-C でプログラムを止めました。木には呼び出そうとしていた手続き ("<-") が表示され、値の無いブロックは実行されていません。
+入力やコマンドを待っている間は、もう一度 Ctrl-C を押すとすぐに止まります。
+
+┌─────┐
+│while├────┐
+└───┬─┘    │
+┌───┴─┐ ┌──┴──┐
+│true │ │ ... │
+└─────┘ └─────┘"#
+      }
+      ErrorCode::NetworkError => {
+        r#""http serve" がポートで待ち受けられなかったか、接続を受け付けられなくなりました。
+ポートを他のプログラムが使っているか、1024 より小さいポートには権限が必要な可能性があります。
+サーバは 127.0.0.1 でだけ待ち受けます。
+
+┌──────────┐
+│http serve├──┐
+└────┬─────┘  │
+  ┌──┴─┐   ┌──┴──┐
+  │8080│   │ ... │
+  └────┘   └─────┘"#
+      }
+    }
+  }
+}
+
+impl Display for ErrorCode {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.code())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::ErrorCode;
+
+  #[test]
+  fn codes_are_unique_and_resolvable() {
+    for code in ErrorCode::ALL {
+      assert_eq!(ErrorCode::from_code(code.code()), Some(code));
+      assert_eq!(ErrorCode::ALL.iter().filter(|c| c.code() == code.code()).count(), 1);
+    }
+    assert_eq!(ErrorCode::from_code("t0001"), Some(ErrorCode::NonUniqueStartBlock));
+    assert_eq!(ErrorCode::from_code("T9999"), None);
+  }
+}
@@ -1,118 +1,2563 @@
+mod interpreter;
 mod predefined;
 
-use crate::structs::{Block, BlockError, ExecuteEnv, Includer, Literal};
-use std::process::Command;
-
-use predefined::predefined_procs;
-
-pub fn execute(tree: Block, includer: Includer) -> Result<Literal, BlockError> {
-  execute_with_mock(
-    tree,
-    Box::new(|| {
-      let mut str = String::new();
-      std::io::stdin().read_line(&mut str).unwrap();
-      str.trim().to_string()
-    }),
-    Box::new(|msg| print!("{}", msg)),
-    Box::new(|cmd, args| {
-      let acutual_cmd = format!("{} {}", cmd, args.join(" "));
-      if cfg!(target_os = "windows") {
-        Command::new("cmd").args(["/C", &acutual_cmd]).output()
-      } else {
-        Command::new("sh").arg("-c").arg(acutual_cmd).output()
-      }
-      .map_err(|err| err.to_string())
-      .and_then(|out| String::from_utf8(out.stdout).map_err(|e| e.to_string()))
-    }),
-    includer,
-  )
+use crate::structs::{Block, BlockError, CmdExecutor, ExecuteOptions, Includer, Literal, ProcedureOrVar};
+use std::collections::HashMap;
+
+pub use interpreter::{Interpreter, InterpreterBuilder};
+
+/// プラグインが公開する登録関数のシンボル名
+pub const PLUGIN_REGISTER_SYMBOL: &str = "trees_register";
+
+/// プラグインの登録関数の型。
+/// プラグインは `#[no_mangle] pub fn trees_register(procs: &mut HashMap<String, ProcedureOrVar>)` を公開する。
+/// Rust の ABI をそのまま使うので、プラグインは同じコンパイラと同じ版の trees でビルドしなければならない。
+pub type PluginRegister = fn(&mut HashMap<String, ProcedureOrVar>);
+
+/// procs には組み込みの手続きに加えて登録する手続き (プラグインが登録したものなど) を渡す。
+pub fn execute(
+  tree: Block,
+  procs: HashMap<String, ProcedureOrVar>,
+  includer: Includer,
+  options: ExecuteOptions,
+) -> Result<Literal, BlockError> {
+  Interpreter::builder().procs(procs).includer(includer).options(options).build().run(&tree)
+}
+
+pub fn execute_with_mock(
+  tree: Block,
+  input_stream: Box<dyn FnMut() -> String>,
+  out_stream: Box<dyn FnMut(String)>,
+  cmd_executor: CmdExecutor,
+  includer: Includer,
+  options: ExecuteOptions,
+) -> Result<Literal, BlockError> {
+  Interpreter::builder()
+    .input(input_stream)
+    .output(out_stream)
+    .cmd_executor(cmd_executor)
+    .includer(includer)
+    .options(options)
+    .build()
+    .run(&tree)
 }
 
-pub fn execute_with_mock(
-  tree: Block,
-  input_stream: Box<dyn FnMut() -> String>,
-  out_stream: Box<dyn FnMut(String)>,
-  cmd_executor: Box<dyn FnMut(String, Vec<String>) -> Result<String, String>>,
-  includer: Includer,
-) -> Result<Literal, BlockError> {
-  let procs = predefined_procs();
-  let mut exec_env = ExecuteEnv::new(procs, input_stream, out_stream, cmd_executor, includer);
+#[cfg(test)]
+mod tests {
+  use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashSet},
+    io::{BufRead, Cursor},
+    rc::Rc,
+    sync::{
+      atomic::{AtomicBool, Ordering},
+      Arc,
+    },
+  };
+
+  use crate::{
+    errors::ErrorCode,
+    structs::{
+      Block, BlockResult, Capability, ChildProcess, CmdOutput, CmdRequest, ExecuteOptions, FileSystem, HttpListener,
+      HttpRequest, HttpResponse, Literal, LogLevel, QuoteStyle,
+    },
+  };
+
+  use super::{execute_with_mock, Interpreter};
+
+  /// パスから中身への表だけを持つファイルシステム。ディレクトリは "/" で終わる
+  struct MockFs(Rc<RefCell<BTreeMap<String, String>>>);
+
+  impl FileSystem for MockFs {
+    fn list_dir(&mut self, path: &str) -> Result<Vec<String>, String> {
+      let prefix = format!("{path}/");
+      let files = self.0.borrow();
+      Ok(files.keys().filter_map(|p| p.strip_prefix(&prefix)).filter(|p| !p.is_empty()).map(|p| p.to_owned()).collect())
+    }
+    fn create_dir(&mut self, path: &str) -> Result<(), String> {
+      self.0.borrow_mut().insert(format!("{path}/"), String::new());
+      Ok(())
+    }
+    fn exists(&mut self, path: &str) -> bool {
+      let files = self.0.borrow();
+      files.contains_key(path) || files.contains_key(&format!("{path}/"))
+    }
+    fn is_dir(&mut self, path: &str) -> bool {
+      path == "." || self.0.borrow().contains_key(&format!("{path}/"))
+    }
+    fn remove_file(&mut self, path: &str) -> Result<(), String> {
+      self.0.borrow_mut().remove(path).map(|_| ()).ok_or("not found".to_owned())
+    }
+    fn copy_file(&mut self, from: &str, to: &str) -> Result<(), String> {
+      let content = self.0.borrow().get(from).cloned().ok_or("not found".to_owned())?;
+      self.0.borrow_mut().insert(to.to_owned(), content);
+      Ok(())
+    }
+    fn open_read(&mut self, path: &str) -> Result<Box<dyn BufRead>, String> {
+      let content = self.0.borrow().get(path).cloned().ok_or("not found".to_owned())?;
+      Ok(Box::new(Cursor::new(content)))
+    }
+    fn create_temp(&mut self, dir: bool) -> Result<String, String> {
+      let path = format!("tmp/{}", self.0.borrow().len());
+      self.0.borrow_mut().insert(if dir { format!("{path}/") } else { path.clone() }, String::new());
+      Ok(path)
+    }
+    fn remove_all(&mut self, path: &str) -> Result<(), String> {
+      let prefix = format!("{path}/");
+      self.0.borrow_mut().retain(|p, _| p != path && !p.starts_with(&prefix));
+      Ok(())
+    }
+    fn append(&mut self, path: &str, text: &str) -> Result<(), String> {
+      self.0.borrow_mut().entry(path.to_owned()).or_default().push_str(text);
+      Ok(())
+    }
+  }
+
+  macro_rules! b {
+    ($name:expr) => {
+      Box::new(Block {
+        proc_name: $name.into(),
+        args: vec![],
+        quote: QuoteStyle::None,
+      })
+    };
+    ($name:expr, $args:expr) => {
+      Box::new(Block {
+        proc_name: $name.into(),
+        args: $args.into_iter().map(|a| (false, a)).collect(),
+        quote: QuoteStyle::None,
+      })
+    };
+  }
+
+  macro_rules! bq {
+    ($name:expr) => {
+      Box::new(Block {
+        proc_name: $name.into(),
+        args: vec![],
+        quote: QuoteStyle::Quote,
+      })
+    };
+    ($name:expr, $args:expr) => {
+      Box::new(Block {
+        proc_name: $name.into(),
+        args: $args.into_iter().map(|a| (false, a)).collect(),
+        quote: QuoteStyle::Quote,
+      })
+    };
+  }
+
+  macro_rules! str {
+    ($str:expr) => {
+      format!("\"{}\"", $str)
+    };
+  }
+
+  fn execute(tree: Block) -> Result<Literal, String> {
+    execute_with_mock(
+      tree,
+      Box::new(|| panic!()),
+      Box::new(|_| panic!()),
+      Box::new(|_| panic!()),
+      Box::new(|_| panic!()),
+      ExecuteOptions::default(),
+    )
+    .map_err(|err| err.msg)
+  }
+
+  #[test]
+  fn simple_summing() {
+    let result = execute(*b!("+", vec![b!("3"), b!("4")]));
+
+    assert_eq!(result, Ok(Literal::Int(7)))
+  }
+  #[test]
+  fn too_much_args() {
+    let result = execute(*b!("+", vec![b!("3"), b!("4"), b!("5")]));
+
+    assert!(result.is_err())
+  }
+
+  #[test]
+  fn embedding() {
+    let out = Rc::new(RefCell::new(String::new()));
+    let out_ref = out.clone();
+    let mut interpreter = Interpreter::builder()
+      .proc("double", |_exec_env, args| match args {
+        [Literal::Int(i)] => Ok(Literal::Int(i * 2)),
+        _ => Err("double needs an int".to_owned().into()),
+      })
+      .output(move |msg| *out.borrow_mut() += &msg)
+      .build();
+    interpreter.set_var("x", Literal::Int(20)).unwrap();
+
+    let result = interpreter.run(&b!(
+      "seq",
+      vec![
+        b!("defset", vec![b!(str!("y")), b!("double", vec![b!("x")])]),
+        b!("print", vec![b!("y")]),
+        b!("set", vec![b!(str!("x")), b!("y")])
+      ]
+    ));
+
+    assert_eq!(result.map_err(|err| err.msg), Ok(Literal::Void));
+    assert_eq!(*out_ref.borrow(), "40");
+    assert_eq!(interpreter.get_var("x"), Some(Literal::Int(40)));
+    // seq の中で定義した名前は実行が終わると消える
+    assert_eq!(interpreter.get_var("y"), None);
+  }
+
+  #[test]
+  fn consecutive_runs() {
+    let out = Rc::new(RefCell::new(String::new()));
+    let out_ref = out.clone();
+    let mut interpreter = Interpreter::builder().output(move |msg| *out.borrow_mut() += &msg).build();
+    let mut run = |tree: Box<Block>| interpreter.run(&tree).map_err(|err| err.code);
+
+    assert_eq!(
+      run(b!(
+        "seq",
+        vec![
+          b!("defset", vec![b!(str!("y")), b!("1")]),
+          b!("defset", vec![b!(str!("print")), b!("2")]),
+          b!("y")
+        ]
+      )),
+      Ok(Literal::Int(1))
+    );
+    // 前の実行の中で定義した名前は次の実行に持ち越さず、組み込みの手続きも隠したままにならない
+    assert_eq!(run(b!("y")), Err(ErrorCode::UndefinedName));
+    assert_eq!(run(b!("print", vec![b!(str!("a"))])), Ok(Literal::Void));
+    assert_eq!(*out_ref.borrow(), "a");
+    // トップレベルに直接置いた定義は共有する
+    assert_eq!(run(b!("defset", vec![b!(str!("z")), b!("3")])), Ok(Literal::Void));
+    assert_eq!(run(b!("z")), Ok(Literal::Int(3)));
+  }
+
+  #[test]
+  fn error_output() {
+    let out = Rc::new(RefCell::new(String::new()));
+    let err = Rc::new(RefCell::new(String::new()));
+    let (out_ref, err_ref) = (out.clone(), err.clone());
+    let mut interpreter = Interpreter::builder()
+      .output(move |msg| *out.borrow_mut() += &msg)
+      .error_output(move |msg| *err.borrow_mut() += &msg)
+      .build();
+
+    let result = interpreter.run(&b!(
+      "seq",
+      vec![
+        b!("println", vec![b!(str!("data"))]),
+        b!("eprintln", vec![b!(str!("warn"))]),
+        b!("eprint", vec![b!("1")])
+      ]
+    ));
+
+    assert_eq!(result.ok(), Some(Literal::Void));
+    assert_eq!(*out_ref.borrow(), "data\n");
+    assert_eq!(*err_ref.borrow(), "warn\n1");
+  }
+
+  #[test]
+  fn prompt_and_read_key() {
+    let out = Rc::new(RefCell::new(String::new()));
+    let out_ref = out.clone();
+    let mut keys = vec!["Enter".to_owned(), "y".to_owned()];
+    let mut interpreter = Interpreter::builder()
+      .input(|| "Alice".to_owned())
+      .key_input(move || keys.pop().unwrap())
+      .output(move |msg| *out.borrow_mut() += &msg)
+      .build();
+
+    let result = interpreter.run(&b!(
+      "listing",
+      vec![b!("prompt", vec![b!(str!("Name? "))]), b!("read key"), b!("read key")]
+    ));
+
+    assert_eq!(
+      result.ok(),
+      Some(Literal::List(Rc::new(vec![
+        Literal::String("Alice".to_owned()),
+        Literal::String("y".to_owned()),
+        Literal::String("Enter".to_owned())
+      ])))
+    );
+    assert_eq!(*out_ref.borrow(), "Name? ");
+  }
+
+  #[test]
+  fn read_key_interrupted() {
+    // 端末の Ctrl-C と同じく、キーを待つ間に中断の印が立ったらプロセスを終えずにエラーを返す
+    let interrupt = Arc::new(AtomicBool::new(false));
+    let flag = interrupt.clone();
+    let mut interpreter = Interpreter::builder()
+      .interrupt(interrupt)
+      .key_input(move || {
+        flag.store(true, Ordering::Relaxed);
+        "".to_owned()
+      })
+      .build();
+
+    let result = interpreter.run(&b!("read key"));
+
+    assert_eq!(result.map_err(|err| err.code), Err(ErrorCode::Interrupted));
+  }
+
+  #[test]
+  fn cmd_full() {
+    let run = |name: &str| {
+      execute_with_mock(
+        *b!(name, vec![b!(str!("ls")), b!(str!("-l"))]),
+        Box::new(|| panic!()),
+        Box::new(|_| panic!()),
+        Box::new(|request: CmdRequest| {
+          assert_eq!((request.program.as_str(), request.args), ("ls", vec!["-l".to_owned()]));
+          assert!(request.shell);
+          Ok(CmdOutput {
+            status: 2,
+            stdout: "out".to_owned(),
+            stderr: "err".to_owned(),
+          })
+        }),
+        Box::new(|_| panic!()),
+        ExecuteOptions::default(),
+      )
+      .ok()
+    };
+
+    assert_eq!(run("cmd"), Some(Literal::String("out".to_owned())));
+    assert_eq!(
+      run("cmd full"),
+      Some(Literal::List(Rc::new(vec![
+        Literal::Int(2),
+        Literal::String("out".to_owned()),
+        Literal::String("err".to_owned())
+      ])))
+    );
+  }
+
+  #[test]
+  fn cmd_options() {
+    let requests = Rc::new(RefCell::new(vec![]));
+    let requests_ref = requests.clone();
+    let result = execute_with_mock(
+      *b!(
+        "seq",
+        vec![
+          b!("cmd argv", vec![b!(str!("echo")), b!(str!("a b"))]),
+          b!("cmd in", vec![b!(str!("/tmp")), b!(str!("ls"))]),
+          b!(
+            "cmd with env",
+            vec![b!("listing", vec![b!(str!("A=1=2"))]), b!(str!("env")), b!(str!("-0"))]
+          )
+        ]
+      ),
+      Box::new(|| panic!()),
+      Box::new(|_| panic!()),
+      Box::new(move |request| {
+        requests.borrow_mut().push(request);
+        Ok(CmdOutput::default())
+      }),
+      Box::new(|_| panic!()),
+      ExecuteOptions::default(),
+    );
+
+    assert_eq!(result.ok(), Some(Literal::String("".to_owned())));
+    assert_eq!(
+      *requests_ref.borrow(),
+      vec![
+        CmdRequest {
+          program: "echo".to_owned(),
+          args: vec!["a b".to_owned()],
+          ..CmdRequest::default()
+        },
+        CmdRequest {
+          program: "ls".to_owned(),
+          dir: Some("/tmp".to_owned()),
+          ..CmdRequest::default()
+        },
+        CmdRequest {
+          program: "env".to_owned(),
+          args: vec!["-0".to_owned()],
+          env: vec![("A".to_owned(), "1=2".to_owned())],
+          ..CmdRequest::default()
+        }
+      ]
+    );
+  }
+
+  #[test]
+  fn mock_processes() {
+    /// 起動した名前と終了コードだけを持つ子プロセス。kill されたら名前を記録する
+    struct MockChild(String, i64, Rc<RefCell<Vec<String>>>);
+
+    impl ChildProcess for MockChild {
+      fn wait(&mut self) -> Result<i64, String> {
+        Ok(self.1)
+      }
+      fn try_wait(&mut self) -> Result<Option<i64>, String> {
+        Ok(None)
+      }
+      fn kill(&mut self) {
+        self.2.borrow_mut().push(self.0.clone());
+      }
+    }
+
+    let killed = Rc::new(RefCell::new(vec![]));
+    let killed_ref = killed.clone();
+    let spawn = |args: Vec<Box<Block>>| b!("spawn cmd", args);
+    let result = Interpreter::builder()
+      .process_spawner(move |request| {
+        let code = request.args.len() as i64;
+        Ok(Box::new(MockChild(request.program, code, killed.clone())) as Box<dyn ChildProcess>)
+      })
+      .build()
+      .run(&b!(
+        "seq",
+        vec![
+          b!(
+            "defset",
+            vec![b!(str!("a")), spawn(vec![b!(str!("a")), b!(str!("x")), b!(str!("y"))])]
+          ),
+          b!("defset", vec![b!(str!("b")), spawn(vec![b!(str!("b"))])]),
+          b!(
+            "listing",
+            vec![
+              b!("try wait", vec![b!("a")]),
+              b!("wait", vec![b!("a")]),
+              b!("kill", vec![b!("b")])
+            ]
+          )
+        ]
+      ))
+      .map_err(|err| err.msg);
+
+    assert_eq!(
+      result,
+      Ok(Literal::List(Rc::new(vec![
+        Literal::Void,
+        Literal::Int(2),
+        Literal::Void
+      ])))
+    );
+    assert_eq!(*killed_ref.borrow(), vec!["b".to_owned()]);
+
+    let missing = Interpreter::builder()
+      .process_spawner(|_| Err("not found".to_owned()))
+      .build()
+      .run(&spawn(vec![b!(str!("missing"))]));
+    assert_eq!(missing.map_err(|err| err.code), Err(ErrorCode::CommandFailed));
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn spawn_processes() {
+    let sh = |script: &str| b!("spawn cmd", vec![b!(str!("sh")), b!(str!("-c")), b!(str!(script))]);
+    let result = execute(*b!(
+      "seq",
+      vec![
+        b!("defset", vec![b!(str!("exit")), sh("exit 3")]),
+        b!("defset", vec![b!(str!("sleep")), sh("sleep 10")]),
+        b!(
+          "listing",
+          vec![
+            b!("try wait", vec![b!("sleep")]),
+            b!("kill", vec![b!("sleep")]),
+            b!("wait", vec![b!("exit")])
+          ]
+        )
+      ]
+    ));
+    assert_eq!(
+      result,
+      Ok(Literal::List(Rc::new(vec![
+        Literal::Void,
+        Literal::Void,
+        Literal::Int(3)
+      ])))
+    );
+
+    let waited_twice = execute(*b!(
+      "seq",
+      vec![
+        b!("defset", vec![b!(str!("p")), sh("exit 0")]),
+        b!("wait", vec![b!("p")]),
+        b!("wait", vec![b!("p")])
+      ]
+    ));
+    assert!(waited_twice.is_err());
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn cmd_timeout() {
+    let run = |script: &str| {
+      Interpreter::builder().build().run(&b!("cmd timeout", vec![b!("200"), b!(str!(script))])).map_err(|err| err.code)
+    };
+
+    assert_eq!(run("echo ok").ok(), Some(Literal::String("ok\n".to_owned())));
+    assert_eq!(run("sleep 5").unwrap_err(), ErrorCode::CommandFailed);
+  }
+
+  #[test]
+  fn named_params() {
+    let run = |call: Box<Block>| {
+      execute_with_mock(
+        *b!(
+          "seq",
+          vec![
+            b!(
+              "defproc named",
+              vec![
+                b!(str!("minus")),
+                b!("listing", vec![b!(str!("x")), b!(str!("y"))]),
+                bq!("-", vec![b!("x"), b!("$1")])
+              ]
+            ),
+            call
+          ]
+        ),
+        Box::new(|| panic!()),
+        Box::new(|_| panic!()),
+        Box::new(|_| panic!()),
+        Box::new(|_| panic!()),
+        ExecuteOptions::default(),
+      )
+      .map_err(|err| err.code)
+    };
+
+    assert_eq!(run(b!("minus", vec![b!("5"), b!("2")])), Ok(Literal::Int(3)));
+    assert_eq!(run(b!("minus", vec![b!("5")])), Err(ErrorCode::ArgCountMismatch));
+  }
+
+  #[test]
+  fn default_params() {
+    let run = |call: Box<Block>| {
+      execute_with_mock(
+        *b!(
+          "seq",
+          vec![
+            b!(
+              "defproc defaults",
+              vec![
+                b!(str!("line")),
+                b!("listing", vec![b!(str!("text")), b!(str!("sep")), b!(str!("end"))]),
+                b!("listing", vec![b!(str!(",")), b!(str!("."))]),
+                bq!("strcat", vec![b!("text"), b!("strcat", vec![b!("sep"), b!("$2")])])
+              ]
+            ),
+            call
+          ]
+        ),
+        Box::new(|| panic!()),
+        Box::new(|_| panic!()),
+        Box::new(|_| panic!()),
+        Box::new(|_| panic!()),
+        ExecuteOptions::default(),
+      )
+      .map_err(|err| err.code)
+    };
+
+    assert_eq!(
+      run(b!("line", vec![b!(str!("a"))])),
+      Ok(Literal::String("a,.".to_owned()))
+    );
+    assert_eq!(
+      run(b!("line", vec![b!(str!("a")), b!(str!("-"))])),
+      Ok(Literal::String("a-.".to_owned()))
+    );
+    assert_eq!(
+      run(b!("line", vec![b!(str!("a")), b!(str!("-")), b!(str!("!"))])),
+      Ok(Literal::String("a-!".to_owned()))
+    );
+    assert_eq!(run(b!("line")), Err(ErrorCode::ArgCountMismatch));
+  }
+
+  #[test]
+  fn rest_params() {
+    let run = |call: Box<Block>| {
+      execute_with_mock(
+        *b!(
+          "seq",
+          vec![
+            b!(
+              "defproc rest",
+              vec![
+                b!(str!("tail")),
+                b!("listing", vec![b!(str!("head"))]),
+                b!(str!("items")),
+                bq!("listing", vec![b!("head"), b!("items")])
+              ]
+            ),
+            call
+          ]
+        ),
+        Box::new(|| panic!()),
+        Box::new(|_| panic!()),
+        Box::new(|_| panic!()),
+        Box::new(|_| panic!()),
+        ExecuteOptions::default(),
+      )
+      .map_err(|err| err.code)
+    };
+
+    assert_eq!(
+      run(b!("tail", vec![b!("1"), b!("2"), b!("3")])),
+      Ok(Literal::List(Rc::new(vec![
+        Literal::Int(1),
+        Literal::List(Rc::new(vec![Literal::Int(2), Literal::Int(3)]))
+      ])))
+    );
+    assert_eq!(
+      run(b!("tail", vec![b!("1")])),
+      Ok(Literal::List(Rc::new(vec![
+        Literal::Int(1),
+        Literal::List(Rc::new(vec![]))
+      ])))
+    );
+    assert_eq!(run(b!("tail")), Err(ErrorCode::ArgCountMismatch));
+  }
+
+  #[test]
+  fn bind_args() {
+    let result = execute(*b!(
+      "seq",
+      vec![
+        b!(
+          "defset",
+          vec![
+            b!(str!("sub")),
+            b!("bind args", vec![bq!("-", vec![b!("$0"), b!("$1")]), b!("10")])
+          ]
+        ),
+        b!(
+          "listing",
+          vec![
+            b!("exec", vec![b!("sub"), b!("3")]),
+            b!("exec", vec![b!("bind args", vec![b!("sub"), b!("4")])]),
+            b!(
+              "len",
+              vec![b!("exec", vec![b!("bind args", vec![bq!("$args"), b!("1")]), b!("2")])]
+            )
+          ]
+        )
+      ]
+    ));
+
+    assert_eq!(
+      result,
+      Ok(Literal::List(Rc::new(vec![
+        Literal::Int(7),
+        Literal::Int(6),
+        Literal::Int(2)
+      ])))
+    );
+  }
+
+  #[test]
+  fn compose() {
+    let result = execute(*b!(
+      "seq",
+      vec![
+        b!(
+          "defset",
+          vec![
+            b!(str!("f")),
+            b!(
+              "compose",
+              vec![
+                bq!("+", vec![b!("$0"), b!("$1")]),
+                bq!("*", vec![b!("$0"), b!("10")]),
+                bq!("to str", vec![b!("$0")])
+              ]
+            )
+          ]
+        ),
+        b!("exec", vec![b!("f"), b!("1"), b!("2")])
+      ]
+    ));
+
+    assert_eq!(result, Ok(Literal::String("30".to_owned())));
+  }
+
+  #[test]
+  fn self_recursion() {
+    // 分岐のブロックの中では $self はそのブロックになるので、先に名前を付けておく
+    let factorial = bq!(
+      "seq",
+      vec![
+        b!("defset", vec![b!(str!("fact")), b!("$self")]),
+        b!(
+          "exec",
+          vec![b!(
+            "if0",
+            vec![
+              b!("$0"),
+              bq!("1"),
+              bq!(
+                "*",
+                vec![b!("$0"), b!("exec", vec![b!("fact"), b!("-", vec![b!("$0"), b!("1")])])]
+              )
+            ]
+          )]
+        )
+      ]
+    );
+    let result = execute(*b!("exec", vec![factorial, b!("5")]));
+
+    assert_eq!(result, Ok(Literal::Int(120)));
+  }
+
+  #[test]
+  fn memo() {
+    let fib = bq!(
+      "seq",
+      vec![b!(
+        "exec",
+        vec![b!(
+          "if",
+          vec![
+            b!("<", vec![b!("$0"), b!("2")]),
+            bq!("$0"),
+            bq!(
+              "+",
+              vec![
+                b!("fib", vec![b!("-", vec![b!("$0"), b!("1")])]),
+                b!("fib", vec![b!("-", vec![b!("$0"), b!("2")])])
+              ]
+            )
+          ]
+        )]
+      )]
+    );
+    let result = execute_with_mock(
+      *b!(
+        "seq",
+        vec![
+          b!("defproc", vec![b!(str!("fib")), b!("memo", vec![fib])]),
+          b!("fib", vec![b!("80")])
+        ]
+      ),
+      Box::new(|| panic!()),
+      Box::new(|_| panic!()),
+      Box::new(|_| panic!()),
+      Box::new(|_| panic!()),
+      ExecuteOptions {
+        max_steps: Some(10000),
+        ..ExecuteOptions::default()
+      },
+    );
+
+    assert_eq!(result.map_err(|e| e.msg), Ok(Literal::Int(23416728348467685)));
+  }
+
+  #[test]
+  fn let_bindings() {
+    let run = |tree: Box<Block>| {
+      execute_with_mock(
+        *tree,
+        Box::new(|| panic!()),
+        Box::new(|_| panic!()),
+        Box::new(|_| panic!()),
+        Box::new(|_| panic!()),
+        ExecuteOptions::default(),
+      )
+      .map_err(|err| err.code)
+    };
+
+    assert_eq!(
+      run(b!(
+        "let",
+        vec![
+          b!(str!("x")),
+          b!("3"),
+          b!(str!("y")),
+          b!("4"),
+          bq!("+", vec![b!("x"), b!("y")])
+        ]
+      )),
+      Ok(Literal::Int(7))
+    );
+    assert_eq!(
+      run(b!(
+        "seq",
+        vec![b!("let", vec![b!(str!("x")), b!("3"), bq!("x")]), b!("x")]
+      )),
+      Err(ErrorCode::UndefinedName)
+    );
+    assert_eq!(
+      run(b!("let", vec![b!(str!("x")), bq!("x")])),
+      Err(ErrorCode::ArgCountMismatch)
+    );
+  }
+
+  #[test]
+  fn constants() {
+    let run = |tree: Box<Block>| {
+      execute_with_mock(
+        *tree,
+        Box::new(|| panic!()),
+        Box::new(|_| panic!()),
+        Box::new(|_| panic!()),
+        Box::new(|_| panic!()),
+        ExecuteOptions::default(),
+      )
+      .map_err(|err| (err.code, err.msg))
+    };
+    let define = || b!("defconst", vec![b!(str!("max")), b!("10")]);
+
+    assert_eq!(run(b!("seq", vec![define(), b!("max")])), Ok(Literal::Int(10)));
+    let (code, msg) = run(b!("seq", vec![define(), b!("set", vec![b!(str!("max")), b!("20")])])).unwrap_err();
+    assert_eq!(code, ErrorCode::ConstReassigned);
+    assert!(msg.contains("max"));
+    // set 以外の定義し直す手続きでも上書きできない
+    for redefine in [
+      b!("defset", vec![b!(str!("max")), b!("20")]),
+      b!("defproc", vec![b!(str!("max")), bq!("20")]),
+      b!(
+        "unpack",
+        vec![b!("listing", vec![b!("20")]), b!("listing", vec![b!(str!("max"))])]
+      ),
+      define(),
+    ] {
+      assert_eq!(
+        run(b!("seq", vec![define(), redefine])).map_err(|(code, _)| code),
+        Err(ErrorCode::ConstReassigned)
+      );
+    }
+
+    let mut interpreter = Interpreter::builder().build();
+    interpreter.run(&define()).unwrap();
+    assert!(interpreter.set_var("max", Literal::Int(20)).is_err());
+    assert_eq!(interpreter.get_var("max"), Some(Literal::Int(10)));
+  }
+
+  #[test]
+  fn globals() {
+    let mut interpreter = Interpreter::builder().build();
+    let result = interpreter.run(&b!(
+      "seq",
+      vec![
+        b!("defset", vec![b!(str!("count")), b!("1")]),
+        b!(
+          "exec",
+          vec![bq!(
+            "seq",
+            vec![b!(
+              "exec",
+              vec![bq!(
+                "seq",
+                vec![
+                  b!("defset", vec![b!(str!("count")), b!("100")]),
+                  b!("defglobal", vec![b!(str!("count")), b!("2")]),
+                  b!("defglobal", vec![b!(str!("total")), b!("3")]),
+                  b!("+", vec![b!("count"), b!("getglobal", vec![b!(str!("count"))])])
+                ]
+              )]
+            )]
+          )]
+        )
+      ]
+    ));
+
+    assert_eq!(result.ok(), Some(Literal::Int(102)));
+    assert_eq!(interpreter.get_var("count"), Some(Literal::Int(2)));
+    assert_eq!(interpreter.get_var("total"), Some(Literal::Int(3)));
+  }
+
+  #[test]
+  fn undef() {
+    let run = |tree: Box<Block>| {
+      execute_with_mock(
+        *tree,
+        Box::new(|| panic!()),
+        Box::new(|_| panic!()),
+        Box::new(|_| panic!()),
+        Box::new(|_| panic!()),
+        ExecuteOptions::default(),
+      )
+      .map_err(|err| err.code)
+    };
+    let define = || b!("defset", vec![b!(str!("x")), b!("1")]);
+    let undef = || b!("undef", vec![b!(str!("x"))]);
+
+    assert_eq!(
+      run(b!("seq", vec![define(), undef(), b!("x")])),
+      Err(ErrorCode::UndefinedName)
+    );
+    assert_eq!(
+      run(b!("seq", vec![define(), undef(), undef()])),
+      Err(ErrorCode::UndefinedVariable)
+    );
+    assert_eq!(
+      run(b!("seq", vec![define(), undef(), define(), b!("x")])),
+      Ok(Literal::Int(1))
+    );
+    assert_eq!(
+      run(b!("seq", vec![b!("defconst", vec![b!(str!("x")), b!("1")]), undef()])),
+      Err(ErrorCode::ConstReassigned)
+    );
+  }
+
+  #[test]
+  fn name_predicates() {
+    let result = execute(*b!(
+      "seq",
+      vec![
+        b!("defset", vec![b!(str!("x")), b!("1")]),
+        b!("defproc", vec![b!(str!("f")), bq!("1")]),
+        b!(
+          "listing",
+          vec![
+            b!("defined?", vec![b!(str!("x"))]),
+            b!("defined?", vec![b!(str!("f"))]),
+            b!("defined?", vec![b!(str!("y"))]),
+            b!("proc exists", vec![b!(str!("f"))]),
+            b!("proc exists", vec![b!(str!("print"))]),
+            b!("proc exists", vec![b!(str!("x"))]),
+            b!("proc exists", vec![b!(str!("json parse"))])
+          ]
+        )
+      ]
+    ));
+
+    assert_eq!(
+      result,
+      Ok(Literal::List(Rc::new(
+        [true, false, false, true, true, false, false].map(Literal::Boolean).to_vec()
+      )))
+    );
+  }
+
+  #[test]
+  fn types() {
+    let type_of = |arg: Box<Block>| execute(*b!("type of", vec![arg]));
+
+    assert_eq!(type_of(b!("1")), Ok(Literal::String("int".to_owned())));
+    assert_eq!(type_of(b!(str!("1"))), Ok(Literal::String("str".to_owned())));
+    assert_eq!(type_of(b!("true")), Ok(Literal::String("boolean".to_owned())));
+    assert_eq!(type_of(bq!("1")), Ok(Literal::String("block".to_owned())));
+    assert_eq!(type_of(b!("listing")), Ok(Literal::String("list".to_owned())));
+    assert_eq!(type_of(b!("")), Ok(Literal::String("void".to_owned())));
+    assert_eq!(execute(*b!("is int", vec![b!("1")])), Ok(Literal::Boolean(true)));
+    assert_eq!(execute(*b!("is list", vec![b!("1")])), Ok(Literal::Boolean(false)));
+  }
+
+  #[test]
+  fn conversions() {
+    let call = |name: &str, args: &[&str]| execute(*b!(name, args.iter().map(|arg| b!(*arg)).collect::<Vec<_>>()));
+    let str = |s: &str| Literal::String(s.to_owned());
+
+    assert_eq!(call("int to str base", &["255", "16"]), Ok(str("ff")));
+    assert_eq!(call("int to str base", &["-10", "2"]), Ok(str("-1010")));
+    assert_eq!(call("int to str base", &["0", "8"]), Ok(str("0")));
+    assert_eq!(
+      call("int to str base", &["-9223372036854775808", "16"]),
+      Ok(str("-8000000000000000"))
+    );
+    assert_eq!(call("str to int base", &["\"FF\"", "16"]), Ok(Literal::Int(255)));
+    assert_eq!(call("str to int base", &["\"-755\"", "8"]), Ok(Literal::Int(-493)));
+    assert!(call("str to int base", &["\"12\"", "2"]).is_err());
+    assert!(call("int to str base", &["1", "37"]).is_err());
+    assert_eq!(call("bool to int", &["true"]), Ok(Literal::Int(1)));
+    assert_eq!(call("int to bool", &["0"]), Ok(Literal::Boolean(false)));
+  }
+
+  #[test]
+  fn int_literals() {
+    let literal = |name: &str| execute(*b!(name)).ok();
+
+    assert_eq!(literal("0xFF"), Some(Literal::Int(255)));
+    assert_eq!(literal("-0b1010"), Some(Literal::Int(-10)));
+    assert_eq!(literal("0o755"), Some(Literal::Int(493)));
+    assert_eq!(literal("1_000_000"), Some(Literal::Int(1_000_000)));
+    assert_eq!(literal("-0x8000_0000_0000_0000"), Some(Literal::Int(i64::MIN)));
+    assert_eq!(literal("0x8000_0000_0000_0000"), None);
+    assert_eq!(literal("1__0"), None);
+    assert_eq!(literal("_1"), None);
+    assert_eq!(literal("0b102"), None);
+  }
+
+  #[test]
+  fn characters() {
+    let str = |s: &str| Literal::String(s.to_owned());
+
+    assert_eq!(execute(*b!("chr", vec![b!("0x6728")])), Ok(str("木")));
+    assert!(execute(*b!("chr", vec![b!("0xD800")])).is_err());
+    assert_eq!(execute(*b!("ord", vec![b!(str!("木々"))])), Ok(Literal::Int(0x6728)));
+    assert!(execute(*b!("ord", vec![b!(str!(""))])).is_err());
+    assert_eq!(
+      execute(*b!("chars", vec![b!(str!("a木"))])),
+      Ok(Literal::List(Rc::new(vec![str("a"), str("木")])))
+    );
+  }
+
+  #[test]
+  fn unicode_strings() {
+    let call = |name: &str, arg: &str| execute(*b!(name, vec![b!(str!(arg))]));
+
+    assert_eq!(call("display width", "木a"), Ok(Literal::Int(3)));
+    assert_eq!(
+      call("nfc normalize", "e\u{301}"),
+      Ok(Literal::String("\u{e9}".to_owned()))
+    );
+    assert_eq!(call("grapheme count", "e\u{301}👍🏽"), Ok(Literal::Int(2)));
+  }
+
+  #[test]
+  fn string_compare() {
+    let call = |name: &str, a: &str, b: &str| execute(*b!(name, vec![b!(str!(a)), b!(str!(b))]));
+
+    assert_eq!(call("str cmp", "apple", "banana"), Ok(Literal::Int(-1)));
+    assert_eq!(call("str cmp", "b", "b"), Ok(Literal::Int(0)));
+    assert_eq!(call("str cmp", "ab", "a"), Ok(Literal::Int(1)));
+    assert_eq!(call("str less", "Z", "a"), Ok(Literal::Boolean(true)));
+    assert_eq!(call("str eq ignorecase", "Trees", "tREES"), Ok(Literal::Boolean(true)));
+    assert_eq!(call("str eq ignorecase", "Trees", "Tree"), Ok(Literal::Boolean(false)));
+  }
+
+  #[test]
+  fn list_search() {
+    let list = |items: &[&str]| b!("listing", items.iter().map(|item| b!(*item)).collect::<Vec<_>>());
+    let ints = |items: &[i64]| Literal::List(Rc::new(items.iter().map(|i| Literal::Int(*i)).collect()));
+
+    assert_eq!(
+      execute(*b!("contains", vec![list(&["1", "2"]), b!("2")])),
+      Ok(Literal::Boolean(true))
+    );
+    assert_eq!(
+      execute(*b!("index of item", vec![list(&["5", "6", "6"]), b!("6")])),
+      Ok(Literal::Int(1))
+    );
+    assert_eq!(
+      execute(*b!("index of item", vec![list(&["5"]), b!("6")])),
+      Ok(Literal::Int(-1))
+    );
+    assert_eq!(
+      execute(*b!("unique", vec![list(&["3", "1", "3", "2", "1"])])),
+      Ok(ints(&[3, 1, 2]))
+    );
+    assert_eq!(
+      execute(*b!("zip", vec![list(&["1", "2", "3"]), list(&["4", "5"])])),
+      Ok(Literal::List(Rc::new(vec![ints(&[1, 4]), ints(&[2, 5])])))
+    );
+    assert_eq!(
+      execute(*b!(
+        "flatten",
+        vec![b!("listing", vec![list(&["1", "2"]), b!("3"), list(&[])])]
+      )),
+      Ok(ints(&[1, 2, 3]))
+    );
+  }
+
+  #[test]
+  fn array() {
+    let ints = |items: &[i64]| Literal::List(Rc::new(items.iter().map(|i| Literal::Int(*i)).collect()));
+
+    assert_eq!(
+      execute(*b!(
+        "seq",
+        vec![
+          b!("defset", vec![b!(str!("a")), b!("array new", vec![b!("2"), b!("0")])]),
+          b!("defset", vec![b!(str!("alias")), b!("a")]),
+          b!("array set", vec![b!("alias"), b!("1"), b!("5")]),
+          b!("array push", vec![b!("a"), b!("7")]),
+          b!(
+            "listing",
+            vec![
+              b!("array get", vec![b!("a"), b!("1")]),
+              b!("array len", vec![b!("alias")]),
+              b!("array to list", vec![b!("a")]),
+            ]
+          ),
+        ]
+      )),
+      Ok(Literal::List(Rc::new(vec![
+        Literal::Int(5),
+        Literal::Int(3),
+        ints(&[0, 5, 7])
+      ])))
+    );
+    assert_eq!(
+      execute(*b!(
+        "array get",
+        vec![b!("list to array", vec![b!("listing", vec![b!("1")])]), b!("1")]
+      ))
+      .map_err(|err| err.contains("Index (1) out of range")),
+      Err(true)
+    );
+  }
+
+  #[test]
+  fn string_builder() {
+    assert_eq!(
+      execute(*b!(
+        "seq",
+        vec![
+          b!("defset", vec![b!(str!("sb")), b!("sb new")]),
+          b!(
+            "for",
+            vec![b!("3"), b!(str!("i")), bq!("sb push", vec![b!("sb"), b!(str!("木"))])]
+          ),
+          b!("sb push", vec![b!("sb"), b!(str!("!"))]),
+          b!("sb to str", vec![b!("sb")]),
+        ]
+      )),
+      Ok(Literal::String("木木木!".to_owned()))
+    );
+  }
+
+  #[test]
+  fn foreach() {
+    assert_eq!(
+      execute(*b!(
+        "seq",
+        vec![
+          b!("defset", vec![b!(str!("sb")), b!("sb new")]),
+          b!(
+            "foreach",
+            vec![
+              b!("listing", vec![b!(str!("a")), b!(str!("b"))]),
+              bq!(
+                "seq",
+                vec![
+                  b!("sb push", vec![b!("sb"), b!("to str", vec![b!("$1")])]),
+                  b!("sb push", vec![b!("sb"), b!("$0")]),
+                ]
+              ),
+            ]
+          ),
+          b!("sb to str", vec![b!("sb")]),
+        ]
+      )),
+      Ok(Literal::String("0a1b".to_owned()))
+    );
+  }
+
+  #[test]
+  fn break_continue() {
+    // i が at なら flow を実行する
+    let when = |at: &str, flow: &str| {
+      b!(
+        "exec",
+        vec![b!("if", vec![b!("=", vec![b!("i"), b!(at)]), bq!(flow), bq!("seq")])]
+      )
+    };
+
+    assert_eq!(
+      execute(*b!(
+        "seq",
+        vec![
+          b!("defset", vec![b!(str!("sb")), b!("sb new")]),
+          b!(
+            "for",
+            vec![
+              b!("10"),
+              b!(str!("i")),
+              bq!(
+                "seq",
+                vec![
+                  when("5", "break"),
+                  when("2", "continue"),
+                  b!("sb push", vec![b!("sb"), b!("to str", vec![b!("i")])]),
+                ]
+              ),
+            ]
+          ),
+          b!("sb to str", vec![b!("sb")]),
+        ]
+      )),
+      Ok(Literal::String("0134".to_owned()))
+    );
+    assert_eq!(
+      execute(*b!(
+        "seq",
+        vec![
+          b!("defset", vec![b!(str!("i")), b!("0")]),
+          b!(
+            "while",
+            vec![
+              bq!("true"),
+              bq!(
+                "seq",
+                vec![
+                  b!("set", vec![b!(str!("i")), b!("+", vec![b!("i"), b!("1")])]),
+                  when("3", "break"),
+                ]
+              ),
+            ]
+          ),
+          b!("i"),
+        ]
+      )),
+      Ok(Literal::Int(3))
+    );
+    assert!(execute(*b!("break")).unwrap_err().contains("break"));
+    // 手続きの本体の break は、呼び出し元のループを終えずにエラーになる
+    assert!(execute(*b!(
+      "seq",
+      vec![
+        b!("defproc", vec![b!(str!("f")), bq!("break")]),
+        b!("defset", vec![b!(str!("n")), b!("0")]),
+        b!(
+          "while",
+          vec![
+            bq!("<", vec![b!("n"), b!("3")]),
+            bq!(
+              "seq",
+              vec![b!("set", vec![b!(str!("n")), b!("+", vec![b!("n"), b!("1")])]), b!("f")]
+            ),
+          ]
+        ),
+        b!("n"),
+      ]
+    ))
+    .unwrap_err()
+    .contains("break"));
+  }
+
+  #[test]
+  fn early_return() {
+    // リストの中で最初に 2 より大きい値を返し、無ければ -1 を返す
+    let first_big = bq!(
+      "seq",
+      vec![
+        b!(
+          "foreach",
+          vec![
+            b!("$0"),
+            bq!(
+              "seq",
+              vec![b!(
+                "exec",
+                vec![b!(
+                  "if",
+                  vec![
+                    b!(">", vec![b!("$0"), b!("2")]),
+                    bq!("return", vec![b!("$0")]),
+                    bq!("seq")
+                  ]
+                )]
+              )]
+            ),
+          ]
+        ),
+        b!("-1"),
+      ]
+    );
+    let call = |items: &[&str]| {
+      execute(*b!(
+        "seq",
+        vec![
+          b!("defproc", vec![b!(str!("first big")), first_big.clone()]),
+          b!(
+            "first big",
+            vec![b!("listing", items.iter().map(|i| b!(*i)).collect::<Vec<_>>())]
+          ),
+        ]
+      ))
+    };
+
+    assert_eq!(call(&["1", "5", "3"]), Ok(Literal::Int(5)));
+    assert_eq!(call(&["1", "2"]), Ok(Literal::Int(-1)));
+
+    // while の本体の return も、ループではなく手続きの呼び出しを終える
+    let out = Rc::new(RefCell::new(String::new()));
+    let out_clone = out.clone();
+    let result = Interpreter::builder().output(move |msg| out_clone.borrow_mut().push_str(&msg)).build().run(&b!(
+      "seq",
+      vec![
+        b!(
+          "defproc",
+          vec![
+            b!(str!("f")),
+            bq!(
+              "seq",
+              vec![
+                b!("defset", vec![b!(str!("i")), b!("0")]),
+                b!(
+                  "while",
+                  vec![
+                    bq!("<", vec![b!("i"), b!("5")]),
+                    bq!(
+                      "seq",
+                      vec![
+                        b!("set", vec![b!(str!("i")), b!("+", vec![b!("i"), b!("1")])]),
+                        b!("print", vec![b!("i")]),
+                        b!("return", vec![b!(str!("early"))]),
+                      ]
+                    ),
+                  ]
+                ),
+                b!(str!("late")),
+              ]
+            ),
+          ]
+        ),
+        b!("f"),
+      ]
+    ));
+    assert_eq!(result.unwrap(), Literal::String("early".to_owned()));
+    assert_eq!(*out.borrow(), "1");
+
+    assert!(execute(*b!("return", vec![b!("1")])).unwrap_err().contains("defproc"));
+    assert!(execute(*b!("exec", vec![bq!("return", vec![b!("1")])])).unwrap_err().contains("defproc"));
+  }
+
+  #[test]
+  fn unpack() {
+    let names = || b!("listing", vec![b!(str!("x")), b!(str!("y"))]);
+
+    assert_eq!(
+      execute(*b!(
+        "seq",
+        vec![
+          b!("unpack", vec![b!("listing", vec![b!("3"), b!("4")]), names()]),
+          b!("-", vec![b!("x"), b!("y")]),
+        ]
+      )),
+      Ok(Literal::Int(-1))
+    );
+    assert!(execute(*b!("unpack", vec![b!("listing", vec![b!("3")]), names()])).unwrap_err().contains("unpack"));
+  }
+
+  #[test]
+  fn or_default() {
+    assert_eq!(
+      execute(*b!("or default", vec![b!("seq"), b!("1")])),
+      Ok(Literal::Int(1))
+    );
+    assert_eq!(execute(*b!("or default", vec![b!("0"), b!("1")])), Ok(Literal::Int(0)));
+  }
+
+  #[test]
+  fn hashing() {
+    let hash = |arg: Box<Block>| execute(*b!("hash", vec![arg]));
+
+    assert_eq!(
+      execute(*b!("sha256", vec![b!(str!("abc"))])),
+      Ok(Literal::String(
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".to_owned()
+      ))
+    );
+    assert_eq!(
+      execute(*b!("md5", vec![b!("listing", vec![b!("97"), b!("98"), b!("99")])])),
+      Ok(Literal::String("900150983cd24fb0d6963f7d28e17f72".to_owned()))
+    );
+    assert_eq!(hash(b!(str!("1"))), hash(b!(str!("1"))));
+    assert_ne!(hash(b!(str!("1"))), hash(b!("1")));
+    assert_ne!(
+      hash(b!("listing", vec![b!(str!("ab")), b!(str!(""))])),
+      hash(b!("listing", vec![b!(str!("a")), b!(str!("b"))]))
+    );
+  }
+
+  #[test]
+  fn encoding() {
+    let call = |name: &str, arg: &str| execute(*b!(name, vec![b!(str!(arg))]));
+    let str = |s: &str| Ok(Literal::String(s.to_owned()));
+
+    assert_eq!(call("base64 encode", "木?"), str("5pyoPw=="));
+    assert_eq!(call("base64 decode", "5pyoPw=="), str("木?"));
+    assert!(call("base64 decode", "!").unwrap_err().contains("Base64"));
+    // UTF-8 でないバイト列は文字列にせず、バイトのリストとして取り出す
+    assert!(call("base64 decode", "/wA=").unwrap_err().contains("UTF-8"));
+    assert_eq!(
+      call("base64 decode bytes", "/wA="),
+      Ok(Literal::List(Rc::new(vec![Literal::Int(255), Literal::Int(0)])))
+    );
+    assert_eq!(call("url encode", "a b&c=木~"), str("a%20b%26c%3D%E6%9C%A8~"));
+    assert_eq!(call("url decode", "a%20b%26c%3D%E6%9C%A8~"), str("a b&c=木~"));
+  }
+
+  #[test]
+  fn random() {
+    let run = |tree: Box<Block>, seed: Option<u64>| {
+      execute_with_mock(
+        *tree,
+        Box::new(|| panic!()),
+        Box::new(|_| {}),
+        Box::new(|_| panic!()),
+        Box::new(|_| panic!()),
+        ExecuteOptions {
+          seed,
+          ..Default::default()
+        },
+      )
+      .map_err(|err| err.msg)
+    };
+    let rolls = || {
+      b!(
+        "listing",
+        (0..8).map(|_| b!("random int", vec![b!("1"), b!("6")])).collect::<Vec<_>>()
+      )
+    };
+
+    let first = run(rolls(), Some(7)).unwrap();
+    assert_eq!(run(rolls(), Some(7)), Ok(first.clone()));
+    let Literal::List(list) = &first else { panic!() };
+    assert!(list.iter().all(|l| matches!(l, Literal::Int(1..=6))));
+    assert_eq!(
+      run(b!("seq", vec![b!("random seed", vec![b!("7")]), rolls()]), None),
+      Ok(first)
+    );
+    assert!(run(b!("random int", vec![b!("2"), b!("1")]), None).is_err());
+  }
+
+  #[test]
+  fn uuid() {
+    let Ok(Literal::String(uuid)) = execute(*b!("uuid v4")) else {
+      panic!()
+    };
+    let regex = regex::Regex::new("^[0-9a-f]{8}-[0-9a-f]{4}-4[0-9a-f]{3}-[89ab][0-9a-f]{3}-[0-9a-f]{12}$").unwrap();
+    assert!(regex.is_match(&uuid), "{uuid}");
+    assert_ne!(execute(*b!("uuid v4")), Ok(Literal::String(uuid)));
+  }
+
+  #[test]
+  fn file_system() {
+    let files = Rc::new(RefCell::new(BTreeMap::from([("a.txt".to_owned(), String::new())])));
+    let run = |tree: Box<Block>, denied: HashSet<Capability>| {
+      Interpreter::builder()
+        .file_system(MockFs(files.clone()))
+        .options(ExecuteOptions {
+          denied,
+          ..Default::default()
+        })
+        .build()
+        .run(&tree)
+        .map_err(|err| err.code)
+    };
+    let path = |p: &str| b!(str!(p));
+
+    assert_eq!(
+      run(
+        b!(
+          "seq",
+          vec![
+            b!("mkdir", vec![path("out")]),
+            b!("copy file", vec![path("a.txt"), path("out/a.txt")]),
+            b!("remove file", vec![path("a.txt")]),
+            b!(
+              "listing",
+              vec![
+                b!("exists", vec![path("a.txt")]),
+                b!("exists", vec![path("out")]),
+                b!("list dir", vec![path("out")]),
+              ]
+            ),
+          ]
+        ),
+        HashSet::new()
+      ),
+      Ok(Literal::List(Rc::new(vec![
+        Literal::Boolean(false),
+        Literal::Boolean(true),
+        Literal::List(Rc::new(vec![Literal::String("a.txt".to_owned())])),
+      ])))
+    );
+    assert_eq!(
+      run(b!("remove file", vec![path("a.txt")]), HashSet::new()),
+      Err(ErrorCode::FileError)
+    );
+    assert_eq!(
+      run(b!("exists", vec![path("out")]), HashSet::from([Capability::Fs])),
+      Err(ErrorCode::CapabilityDenied)
+    );
+  }
+
+  #[test]
+  fn read_lines() {
+    let files = Rc::new(RefCell::new(BTreeMap::from([(
+      "log.txt".to_owned(),
+      "first\r\n\nlast".to_owned(),
+    )])));
+    let next = || b!("next line", vec![b!("h")]);
+    let result = Interpreter::builder()
+      .file_system(MockFs(files))
+      .build()
+      .run(&b!(
+        "seq",
+        vec![
+          b!(
+            "defset",
+            vec![b!(str!("h")), b!("open lines", vec![b!(str!("log.txt"))])]
+          ),
+          b!(
+            "defset",
+            vec![b!(str!("lines")), b!("listing", vec![next(), next(), next(), next()])]
+          ),
+          b!("close", vec![b!("h")]),
+          b!("lines"),
+        ]
+      ))
+      .map_err(|err| err.code);
+
+    assert_eq!(
+      result,
+      Ok(Literal::List(Rc::new(vec![
+        Literal::String("first".to_owned()),
+        Literal::String("".to_owned()),
+        Literal::String("last".to_owned()),
+        Literal::Void,
+      ])))
+    );
+    assert_eq!(
+      Interpreter::builder().build().run(&b!("next line", vec![b!("0")])).map_err(|err| err.code),
+      Err(ErrorCode::FileError)
+    );
+  }
+
+  #[test]
+  fn store() {
+    let files = Rc::new(RefCell::new(BTreeMap::from([(
+      "broken.db".to_owned(),
+      "!!!\n".to_owned(),
+    )])));
+    let run = |block: Box<Block>| {
+      Interpreter::builder()
+        .file_system(MockFs(files.clone()))
+        .build()
+        .run(&b!(
+          "seq",
+          vec![
+            b!(
+              "defset",
+              vec![b!(str!("s")), b!("store open", vec![b!(str!("data.db"))])]
+            ),
+            block
+          ]
+        ))
+        .map_err(|err| err.code)
+    };
+    let set = |key: &str, value: Box<Block>| b!("store set", vec![b!("s"), b!(str!(key)), value]);
+    let get = |key: &str| b!("store get", vec![b!("s"), b!(str!(key))]);
+
+    assert_eq!(
+      run(b!(
+        "seq",
+        vec![
+          set("b", b!("listing", vec![b!("1"), b!(str!("x"))])),
+          set("a", b!("true")),
+          set("b", b!("2")),
+          b!("store keys", vec![b!("s")]),
+        ]
+      )),
+      Ok(Literal::List(Rc::new(vec![
+        Literal::String("a".to_owned()),
+        Literal::String("b".to_owned())
+      ])))
+    );
+    assert_eq!(files.borrow()["data.db"].lines().count(), 3);
+    // 開き直すと、ファイルに書き足した記録から読み込む
+    assert_eq!(
+      run(b!("listing", vec![get("b"), get("a"), get("c")])),
+      Ok(Literal::List(Rc::new(vec![
+        Literal::Int(2),
+        Literal::Boolean(true),
+        Literal::Void
+      ])))
+    );
+    assert_eq!(run(set("f", bq!("x"))), Err(ErrorCode::TypeMismatch));
+    assert_eq!(
+      run(b!("store get", vec![b!("1"), b!(str!("a"))])),
+      Err(ErrorCode::FileError)
+    );
+    assert_eq!(
+      run(b!("store open", vec![b!(str!("broken.db"))])),
+      Err(ErrorCode::FileError)
+    );
+  }
+
+  #[test]
+  fn http_serve() {
+    struct MockListener(Vec<HttpRequest>, Rc<RefCell<Vec<HttpResponse>>>);
+
+    impl HttpListener for MockListener {
+      fn next_request(&mut self) -> Result<Option<HttpRequest>, String> {
+        Ok(Some(self.0.remove(0)))
+      }
+      fn respond(&mut self, response: HttpResponse) {
+        self.1.borrow_mut().push(response);
+      }
+    }
+
+    let serve = |paths: &[&str], handler: Box<Block>, options: ExecuteOptions| {
+      let requests: Vec<_> = paths
+        .iter()
+        .map(|path| HttpRequest {
+          method: "POST".to_owned(),
+          path: path.to_string(),
+          body: "!".to_owned(),
+        })
+        .collect();
+      let responses = Rc::new(RefCell::new(vec![]));
+      let sent = responses.clone();
+      let result = Interpreter::builder()
+        .http_binder(move |port| {
+          assert_eq!(port, 8080);
+          Ok(Box::new(MockListener(requests.clone(), sent.clone())) as Box<dyn HttpListener>)
+        })
+        .options(options)
+        .build()
+        .run(&b!("http serve", vec![b!("8080"), handler]))
+        .map_err(|err| err.code);
+      let responses = responses.borrow().iter().map(|r| (r.status, r.body.clone())).collect::<Vec<_>>();
+      (result, responses)
+    };
+    let handler = || {
+      bq!(
+        "seq",
+        vec![
+          b!(
+            "exec",
+            vec![b!(
+              "if",
+              vec![b!("=", vec![b!("$1"), b!(str!("/stop"))]), bq!("break"), bq!("seq")]
+            )]
+          ),
+          b!(
+            "if",
+            vec![
+              b!("=", vec![b!("$1"), b!(str!("/missing"))]),
+              b!("listing", vec![b!("404"), b!(str!("not found"))]),
+              b!("strcat", vec![b!("$1"), b!("$2")]),
+            ]
+          ),
+        ]
+      )
+    };
+
+    assert_eq!(
+      serve(&["/a", "/missing", "/stop"], handler(), ExecuteOptions::default()),
+      (
+        Ok(Literal::Void),
+        vec![
+          (200, "/a!".to_owned()),
+          (404, "not found".to_owned()),
+          (200, "".to_owned())
+        ]
+      )
+    );
+    assert_eq!(
+      serve(&["/a"], bq!("1"), ExecuteOptions::default()),
+      (Err(ErrorCode::TypeMismatch), vec![(500, "".to_owned())])
+    );
+    let sandbox = ExecuteOptions {
+      denied: HashSet::from([Capability::Net]),
+      ..ExecuteOptions::default()
+    };
+    assert_eq!(
+      serve(&[], handler(), sandbox),
+      (Err(ErrorCode::CapabilityDenied), vec![])
+    );
+  }
+
+  #[test]
+  fn temp_paths() {
+    let files = Rc::new(RefCell::new(BTreeMap::new()));
+    let result = Interpreter::builder().file_system(MockFs(files.clone())).build().run(&b!(
+      "seq",
+      vec![
+        b!("defset", vec![b!(str!("dir")), b!("temp dir")]),
+        b!(
+          "copy file",
+          vec![b!("temp file"), b!("strcat", vec![b!("dir"), b!(str!("/copied"))])]
+        ),
+        b!("list dir", vec![b!("dir")]),
+      ]
+    ));
 
-  exec_env.new_scope();
-  let result = tree.execute(&mut exec_env);
-  exec_env.back_scope();
+    assert_eq!(
+      result.map_err(|err| err.msg),
+      Ok(Literal::List(Rc::new(vec![Literal::String("copied".to_owned())])))
+    );
+    // 実行環境を捨てると、作った一時ファイルも中身ごと消える
+    assert_eq!(*files.borrow(), BTreeMap::new());
+  }
 
-  result
-}
+  #[test]
+  fn temp_file_on_disk() {
+    let mut interpreter = Interpreter::builder().build();
+    let Ok(Literal::String(path)) = interpreter.run(&b!("temp file")) else {
+      panic!()
+    };
+    assert!(std::path::Path::new(&path).is_file());
+    drop(interpreter);
+    assert!(!std::path::Path::new(&path).exists());
+  }
 
-#[cfg(test)]
-mod tests {
-  use crate::structs::{Block, Literal, QuoteStyle};
+  #[test]
+  fn working_directory() {
+    let files = Rc::new(RefCell::new(BTreeMap::from([
+      ("out/".to_owned(), String::new()),
+      ("out/a.txt".to_owned(), String::new()),
+    ])));
+    let dirs = Rc::new(RefCell::new(vec![]));
+    let cmd_dirs = dirs.clone();
+    let mut interpreter = Interpreter::builder()
+      .file_system(MockFs(files))
+      .cmd_executor(move |request| {
+        cmd_dirs.borrow_mut().push(request.dir);
+        Ok(CmdOutput::default())
+      })
+      .build();
+    let mut run = |tree: Box<Block>| interpreter.run(&tree).map_err(|err| err.code);
+    let exists = |path: &str| b!("exists", vec![b!(str!(path))]);
 
-  use super::execute_with_mock;
+    assert_eq!(run(b!("cd", vec![b!(str!("out"))])), Ok(Literal::Void));
+    assert_eq!(run(exists("a.txt")), Ok(Literal::Boolean(true)));
+    assert_eq!(run(b!("cmd", vec![b!(str!("ls"))])).map(|_| ()), Ok(()));
+    assert_eq!(run(b!("cd", vec![b!(str!("a.txt"))])), Err(ErrorCode::FileError));
+    assert_eq!(run(b!("cd", vec![b!(str!("./.."))])), Ok(Literal::Void));
+    assert_eq!(run(exists("out/a.txt")), Ok(Literal::Boolean(true)));
+    assert_eq!(run(b!("cmd", vec![b!(str!("ls"))])).map(|_| ()), Ok(()));
+    assert_eq!(
+      run(b!("cwd")),
+      Ok(Literal::String(
+        std::env::current_dir().unwrap().to_string_lossy().into_owned()
+      ))
+    );
+    assert_eq!(*dirs.borrow(), vec![Some("out".to_owned()), None]);
+  }
 
-  macro_rules! b {
-    ($name:expr) => {
-      Box::new(Block {
-        proc_name: $name.to_owned(),
-        args: vec![],
-        quote: QuoteStyle::None,
+  #[test]
+  fn interrupt() {
+    let interrupt = Arc::new(AtomicBool::new(false));
+    let flag = interrupt.clone();
+    let out = Rc::new(RefCell::new(vec![]));
+    let out_clone = out.clone();
+    let result = Interpreter::builder()
+      .proc("press ctrl c", move |_, _| {
+        flag.store(true, Ordering::Relaxed);
+        Ok(Literal::Void)
       })
+      .output(move |msg| out_clone.borrow_mut().push(msg))
+      .interrupt(interrupt)
+      .build()
+      .run(&b!(
+        "seq",
+        vec![b!("press ctrl c"), b!("print", vec![b!(str!("after"))]), b!("1")]
+      ));
+
+    let err = result.unwrap_err();
+    assert_eq!(err.code, ErrorCode::Interrupted);
+    assert!(out.borrow().is_empty());
+    assert!(matches!(
+      err.root.children.iter().map(|child| &child.result).collect::<Vec<_>>()[..],
+      [
+        BlockResult::Success(Literal::Void),
+        BlockResult::Error,
+        BlockResult::Unreached
+      ]
+    ));
+  }
+
+  #[test]
+  fn log() {
+    let run = |log_level: LogLevel| {
+      let err = Rc::new(RefCell::new(String::new()));
+      let err_clone = err.clone();
+      Interpreter::builder()
+        .error_output(move |msg| err_clone.borrow_mut().push_str(&msg))
+        .options(ExecuteOptions {
+          log_level,
+          ..Default::default()
+        })
+        .build()
+        .run(&b!(
+          "seq",
+          vec![
+            b!("log info", vec![b!(str!("started"))]),
+            b!("log error", vec![b!(str!("said \"no\""))]),
+          ]
+        ))
+        .unwrap();
+      let err = err.borrow().clone();
+      err
     };
-    ($name:expr, $args:expr) => {
-      Box::new(Block {
-        proc_name: $name.to_owned(),
-        args: $args.into_iter().map(|a| (false, a)).collect(),
-        quote: QuoteStyle::None,
+
+    let lines = run(LogLevel::Warn);
+    let regex =
+      regex::Regex::new(r#"^time=\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}\.\d{3}Z level=error msg="said \\"no\\""\n$"#)
+        .unwrap();
+    assert!(regex.is_match(&lines), "{lines}");
+    let lines = run(LogLevel::Debug);
+    assert!(lines.contains("level=info msg=\"started\""), "{lines}");
+    assert!(lines.contains("level=debug msg=\"new scope"), "{lines}");
+    assert_eq!(run(LogLevel::Off), "");
+  }
+
+  #[test]
+  fn loop_cost() {
+    // while の 1 回分で積むスコープの数。増えるとループがそのぶん遅くなる
+    let scopes = |count: i64| {
+      let err = Rc::new(RefCell::new(String::new()));
+      let err_clone = err.clone();
+      Interpreter::builder()
+        .error_output(move |msg| err_clone.borrow_mut().push_str(&msg))
+        .options(ExecuteOptions {
+          log_level: LogLevel::Debug,
+          ..Default::default()
+        })
+        .build()
+        .run(&b!(
+          "seq",
+          vec![
+            b!("defset", vec![b!(str!("i")), b!("0")]),
+            b!(
+              "while",
+              vec![
+                bq!("<", vec![b!("i"), b!(count.to_string())]),
+                bq!("set", vec![b!(str!("i")), b!("+", vec![b!("i"), b!("1")])]),
+              ]
+            ),
+          ]
+        ))
+        .unwrap();
+      let count = err.borrow().matches("new scope").count();
+      count
+    };
+
+    assert_eq!(scopes(11) - scopes(10), 8);
+  }
+
+  #[test]
+  fn trace() {
+    let err = Rc::new(RefCell::new(String::new()));
+    let err_clone = err.clone();
+    Interpreter::builder()
+      .error_output(move |msg| err_clone.borrow_mut().push_str(&msg))
+      .options(ExecuteOptions {
+        trace: true,
+        ..Default::default()
       })
+      .build()
+      .run(&b!("+", vec![b!("1"), b!("*", vec![b!("2"), b!("3")])]))
+      .unwrap();
+    assert_eq!(*err.borrow(), "-> * [2, 3]\n<- * = 6\n-> + [1, 6]\n<- + = 7\n");
+  }
+
+  #[test]
+  fn inspect() {
+    let out = Rc::new(RefCell::new(String::new()));
+    let out_clone = out.clone();
+    let result = Interpreter::builder().output(move |msg| out_clone.borrow_mut().push_str(&msg)).build().run(&b!(
+      "seq",
+      vec![
+        b!(
+          "inspect",
+          vec![b!(
+            "listing",
+            vec![b!("1"), b!(str!("a")), b!("listing", vec![b!("true")])]
+          )]
+        ),
+        b!("inspect", vec![bq!("print", vec![b!("+", vec![b!("x"), b!("1")])])]),
+      ]
+    ));
+
+    assert!(matches!(result, Ok(Literal::Block(_))));
+    assert_eq!(
+      *out.borrow(),
+      [
+        "list (3)",
+        "  int 1",
+        "  str \"a\"",
+        "  list (1)",
+        "    boolean true",
+        "block",
+        "  print",
+        "    +",
+        "      x",
+        "      1",
+        "",
+      ]
+      .join("\n")
+    );
+  }
+
+  #[test]
+  fn scope_names() {
+    let names = |result: Result<Literal, String>| match result {
+      Ok(Literal::List(list)) => list.iter().map(|l| l.to_string()).collect::<Vec<_>>(),
+      other => panic!("{other:?}"),
+    };
+    let define = |name: &str| b!("defset", vec![b!(str!(name)), b!("1")]);
+
+    let visible = names(execute(*b!(
+      "seq",
+      vec![
+        define("outer"),
+        b!("let", vec![b!(str!("inner")), b!("2"), bq!("scope names")])
+      ]
+    )));
+    assert!(["outer", "inner", "print", "scope names"].iter().all(|name| visible.contains(&name.to_string())));
+    assert!(visible.windows(2).all(|pair| pair[0] < pair[1]));
+
+    let Ok(Literal::List(levels)) = execute(*b!("seq", vec![define("outer"), b!("scope names by level")])) else {
+      panic!()
     };
+    assert!(names(Ok(levels[0].clone())).contains(&"print".to_owned()));
+    assert!(levels.iter().skip(1).any(|level| names(Ok(level.clone())) == ["outer"]));
   }
 
-  macro_rules! bq {
-    ($name:expr) => {
+  #[test]
+  fn eval() {
+    let source = ["  ┌─┐", " ┌┤+├┐", " │└─┘│", "┌┴┐ ┌┴┐", "│x│ │1│", "└─┘ └─┘"].join("\n");
+
+    assert_eq!(
+      execute(*b!(
+        "seq",
+        vec![
+          b!("defset", vec![b!(str!("x")), b!("41")]),
+          b!("eval", vec![b!(str!(source.as_str()))]),
+        ]
+      )),
+      Ok(Literal::Int(42))
+    );
+    assert!(execute(*b!("eval", vec![b!(str!("┌─┐"))])).is_err());
+  }
+
+  #[test]
+  fn block_construction() {
+    let sum = || bq!("+", vec![b!("3"), b!("4")]);
+
+    assert_eq!(
+      execute(*b!(
+        "exec",
+        vec![b!(
+          "block new",
+          vec![
+            b!(str!("+")),
+            b!("listing", vec![b!("1"), bq!("*", vec![b!("2"), b!("3")])])
+          ]
+        )]
+      )),
+      Ok(Literal::Int(7))
+    );
+    assert_eq!(
+      execute(*b!("block name", vec![sum()])),
+      Ok(Literal::String("+".to_owned()))
+    );
+    assert_eq!(
+      execute(*b!(
+        "exec",
+        vec![b!("block new", vec![b!(str!("*")), b!("block args", vec![sum()])])]
+      )),
+      Ok(Literal::Int(12))
+    );
+    assert_eq!(
+      execute(*b!(
+        "exec",
+        vec![b!(
+          "block with args",
+          vec![sum(), b!("listing", vec![b!("10"), b!("20")])]
+        )]
+      )),
+      Ok(Literal::Int(30))
+    );
+    assert_eq!(
+      execute(*b!(
+        "exec",
+        vec![b!(
+          "block new",
+          vec![b!(str!("listing")), b!("listing", vec![b!("true"), b!("")])]
+        )]
+      )),
+      Ok(Literal::List(Rc::new(vec![Literal::Boolean(true), Literal::Void])))
+    );
+    assert!(execute(*b!(
+      "block new",
+      vec![b!(str!("+")), b!("listing", vec![b!("listing")])]
+    ))
+    .is_err());
+  }
+
+  #[test]
+  fn unquote() {
+    let bu = |name: &str| {
       Box::new(Block {
-        proc_name: $name.to_owned(),
+        proc_name: name.into(),
         args: vec![],
-        quote: QuoteStyle::Quote,
+        quote: QuoteStyle::Unquote,
       })
     };
-    ($name:expr, $args:expr) => {
-      Box::new(Block {
-        proc_name: $name.to_owned(),
-        args: $args.into_iter().map(|a| (false, a)).collect(),
-        quote: QuoteStyle::Quote,
-      })
+
+    // ~ は引用を作った時点の値になる
+    assert_eq!(
+      execute(*b!(
+        "seq",
+        vec![
+          b!("defset", vec![b!(str!("n")), b!("5")]),
+          b!("defset", vec![b!(str!("f")), bq!("+", vec![bu("n"), b!("1")])]),
+          b!("set", vec![b!(str!("n")), b!("100")]),
+          b!("exec", vec![b!("f")]),
+        ]
+      )),
+      Ok(Literal::Int(6))
+    );
+    // ブロックの値は木ごと埋め込む
+    assert_eq!(
+      execute(*b!(
+        "seq",
+        vec![
+          b!("defset", vec![b!(str!("g")), bq!("+", vec![b!("1"), b!("2")])]),
+          b!("exec", vec![bq!("*", vec![bu("g"), b!("$0")]), b!("10")]),
+        ]
+      )),
+      Ok(Literal::Int(30))
+    );
+    // 中の引用の ~ は、その引用を作るまで評価しない
+    assert_eq!(
+      execute(*b!(
+        "block name",
+        vec![bq!("seq", vec![bq!("+", vec![bu("undefined")])])]
+      )),
+      Ok(Literal::String("seq".to_owned()))
+    );
+    assert!(execute(*b!("exec", vec![bq!("print", vec![bu("listing")])])).is_err());
+  }
+
+  #[test]
+  fn literal_bytes() {
+    let value = || {
+      b!(
+        "listing",
+        vec![
+          b!("-2"),
+          b!(str!("あ")),
+          b!("true"),
+          b!(""),
+          b!("listing", vec![b!("listing")])
+        ]
+      )
     };
+    let ints = |bytes: &[i64]| Literal::List(Rc::new(bytes.iter().map(|b| Literal::Int(*b)).collect()));
+
+    assert_eq!(
+      execute(*b!("bytes to literal", vec![b!("literal to bytes", vec![value()])])),
+      execute(*value())
+    );
+    // 形式を変えないよう、書き出したバイト列そのものも確かめる
+    assert_eq!(
+      execute(*b!("literal to bytes", vec![b!("1")])),
+      Ok(ints(&[1, 0, 1, 0, 0, 0, 0, 0, 0, 0]))
+    );
+    assert_eq!(
+      execute(*b!("literal to bytes", vec![b!(str!("a"))])),
+      Ok(ints(&[1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 97]))
+    );
+    assert!(execute(*b!("literal to bytes", vec![b!("listing", vec![bq!("x")])])).is_err());
+    assert!(execute(*b!(
+      "bytes to literal",
+      vec![b!("listing", vec![b!("1"), b!("2"), b!("2")])]
+    ))
+    .unwrap_err()
+    .contains('2'));
+    assert!(execute(*b!(
+      "bytes to literal",
+      vec![b!("listing", vec![b!("1"), b!("7"), b!("7")])]
+    ))
+    .is_err());
   }
 
-  macro_rules! str {
-    ($str:expr) => {
-      format!("\"{}\"", $str)
+  #[test]
+  fn matrix() {
+    let vector = |v: &[i64]| b!("listing", v.iter().map(|x| b!(x.to_string())).collect::<Vec<_>>());
+    let matrix = |m: &[&[i64]]| b!("listing", m.iter().map(|row| vector(row)).collect::<Vec<_>>());
+    let literal = |m: &[&[i64]]| execute(*matrix(m));
+
+    assert_eq!(
+      execute(*b!("mat mul", vec![matrix(&[&[1, 2], &[3, 4]]), matrix(&[&[5], &[6]])])),
+      literal(&[&[17], &[39]])
+    );
+    assert_eq!(
+      execute(*b!("mat transpose", vec![matrix(&[&[1, 2, 3]])])),
+      literal(&[&[1], &[2], &[3]])
+    );
+    assert_eq!(execute(*b!("mat new", vec![b!("2"), b!("1")])), literal(&[&[0], &[0]]));
+    assert_eq!(
+      execute(*b!("vec dot", vec![vector(&[1, 2, 3]), vector(&[4, 5, 6])])),
+      Ok(Literal::Int(32))
+    );
+    assert_eq!(
+      execute(*b!("vec scale", vec![vector(&[1, -2]), b!("3")])),
+      execute(*vector(&[3, -6]))
+    );
+
+    assert!(execute(*b!("mat mul", vec![matrix(&[&[1, 2], &[3, 4]]), matrix(&[&[5]])])).unwrap_err().contains("2x2"));
+    assert!(execute(*b!("mat transpose", vec![matrix(&[&[1, 2], &[3]])])).is_err());
+    assert!(execute(*b!("vec dot", vec![vector(&[1]), vector(&[1, 2])])).is_err());
+    assert!(execute(*b!("vec scale", vec![vector(&[i64::MAX]), b!("2")])).is_err());
+  }
+
+  #[test]
+  fn collect() {
+    assert_eq!(
+      execute(*b!(
+        "collect",
+        vec![b!("1"), b!("+", vec![b!("1"), b!("2")]), b!(str!("a"))]
+      )),
+      Ok(Literal::List(Rc::new(vec![
+        Literal::Int(1),
+        Literal::Int(3),
+        Literal::String("a".to_owned())
+      ])))
+    );
+    assert_eq!(execute(*b!("collect")), Ok(Literal::List(Rc::new(vec![]))));
+  }
+
+  #[test]
+  fn decimal() {
+    let dec = |s: &str| b!("dec", vec![b!(format!("\"{s}\""))]);
+    let show = |block: Box<Block>| execute(*b!("to str", vec![block]));
+    let text = |s: &str| Ok(Literal::String(s.to_owned()));
+
+    assert_eq!(show(b!("dec add", vec![dec("0.1"), dec("0.2")])), text("0.3"));
+    assert_eq!(
+      show(b!("dec sub", vec![dec("1.10"), b!("dec", vec![b!("2")])])),
+      text("-0.90")
+    );
+    assert_eq!(show(b!("dec mul", vec![dec("1.5"), dec("-0.2")])), text("-0.30"));
+    assert_eq!(show(b!("dec div", vec![dec("1"), dec("8")])), text("0.125"));
+    assert_eq!(
+      execute(*b!("=", vec![dec("1.10"), dec("1.1")])),
+      Ok(Literal::Boolean(true))
+    );
+    assert_eq!(
+      execute(*b!("dec <", vec![dec("0.3"), dec("0.25")])),
+      Ok(Literal::Boolean(false))
+    );
+
+    let round = |s: &str, mode: &str| show(b!("dec round", vec![dec(s), b!("0"), b!(format!("\"{mode}\""))]));
+    assert_eq!(round("2.5", "half even"), text("2"));
+    assert_eq!(round("2.5", "half up"), text("3"));
+    assert_eq!(round("-2.5", "floor"), text("-3"));
+    assert_eq!(round("-2.5", "down"), text("-2"));
+
+    assert!(execute(*b!("dec div", vec![dec("1"), dec("0")])).is_err());
+    assert!(execute(*dec("1.2.3")).is_err());
+    assert!(round("1", "nearest").unwrap_err().contains("nearest"));
+  }
+
+  #[test]
+  fn math() {
+    let call = |name: &str, args: &[&str]| execute(*b!(name, args.iter().map(|arg| b!(*arg)).collect::<Vec<_>>()));
+
+    assert_eq!(call("abs", &["-5"]), Ok(Literal::Int(5)));
+    assert_eq!(call("min", &["3", "-2"]), Ok(Literal::Int(-2)));
+    assert_eq!(call("max", &["3", "-2"]), Ok(Literal::Int(3)));
+    assert_eq!(call("pow", &["2", "10"]), Ok(Literal::Int(1024)));
+    assert_eq!(call("isqrt", &["17"]), Ok(Literal::Int(4)));
+    assert_eq!(call("gcd", &["12", "-18"]), Ok(Literal::Int(6)));
+    assert!(call("pow", &["2", "-1"]).is_err());
+    assert!(call("pow", &["2", "64"]).is_err());
+    assert!(call("sqrt", &["-1"]).is_err());
+    assert_eq!(call("sqrt", &["17"]), call("isqrt", &["17"]));
+  }
+
+  #[test]
+  fn program_args() {
+    let result = execute_with_mock(
+      *b!("program args"),
+      Box::new(|| panic!()),
+      Box::new(|_| panic!()),
+      Box::new(|_| panic!()),
+      Box::new(|_| panic!()),
+      ExecuteOptions {
+        program_args: vec!["1".to_owned(), "a b".to_owned()],
+        ..ExecuteOptions::default()
+      },
+    );
+    assert_eq!(
+      result.ok(),
+      Some(Literal::List(Rc::new(vec![
+        Literal::String("1".to_owned()),
+        Literal::String("a b".to_owned())
+      ])))
+    );
+  }
+
+  #[test]
+  fn terminal_control() {
+    let tree = b!(
+      "seq",
+      vec![
+        b!("term clear"),
+        b!("term goto", vec![b!("4"), b!("0")]),
+        b!("term color", vec![b!(str!("red"))]),
+        b!("print", vec![b!(str!("x"))])
+      ]
+    );
+    let run = |terminal: bool| {
+      let out = Rc::new(RefCell::new(String::new()));
+      let out_ref = out.clone();
+      let result = execute_with_mock(
+        *tree.clone(),
+        Box::new(|| panic!()),
+        Box::new(move |msg| *out.borrow_mut() += &msg),
+        Box::new(|_| panic!()),
+        Box::new(|_| panic!()),
+        ExecuteOptions {
+          terminal,
+          ..ExecuteOptions::default()
+        },
+      );
+      assert_eq!(result.ok(), Some(Literal::Void));
+      let out = out_ref.borrow().clone();
+      out
     };
+
+    assert_eq!(run(true), "\x1b[2J\x1b[H\x1b[1;5H\x1b[31mx");
+    assert_eq!(run(false), "x");
   }
 
-  fn execute(tree: Block) -> Result<Literal, String> {
-    execute_with_mock(
-      tree,
+  #[test]
+  fn error_codes() {
+    let code = |tree: Block| {
+      execute_with_mock(
+        tree,
+        Box::new(|| panic!()),
+        Box::new(|_| panic!()),
+        Box::new(|_| panic!()),
+        Box::new(|_| panic!()),
+        ExecuteOptions::default(),
+      )
+      .map_err(|err| err.code)
+    };
+
+    assert_eq!(
+      code(*b!("+", vec![b!("3"), b!(str!("4"))])),
+      Err(ErrorCode::TypeMismatch)
+    );
+    assert_eq!(code(*b!("+", vec![b!("3")])), Err(ErrorCode::ArgCountMismatch));
+    assert_eq!(code(*b!("undefined proc")), Err(ErrorCode::UndefinedName));
+    assert_eq!(
+      code(*b!(
+        "seq",
+        vec![b!("print", vec![b!("set", vec![b!(str!("x")), b!("1")])])]
+      )),
+      Err(ErrorCode::UndefinedVariable)
+    );
+  }
+
+  #[test]
+  fn limits() {
+    let run = |max_depth, max_steps| {
+      execute_with_mock(
+        *b!("seq", vec![b!("defproc", vec![b!(str!("f")), bq!("f")]), b!("f")]),
+        Box::new(|| panic!()),
+        Box::new(|_| panic!()),
+        Box::new(|_| panic!()),
+        Box::new(|_| panic!()),
+        ExecuteOptions {
+          max_depth,
+          max_steps,
+          ..Default::default()
+        },
+      )
+      .map_err(|err| {
+        // 上限を超えたブロックは起因の一番奥にある
+        let mut innermost = &err;
+        while let Some(caused_by) = &innermost.caused_by {
+          innermost = caused_by;
+        }
+        (err.code, innermost.root.proc_name.clone())
+      })
+    };
+
+    assert_eq!(run(Some(50), None), Err((ErrorCode::LimitExceeded, "f".to_owned())));
+    assert_eq!(run(None, Some(30)), Err((ErrorCode::LimitExceeded, "f".to_owned())));
+  }
+
+  #[test]
+  fn depth_limit_on_small_stack() {
+    // スタックは足りなくなると継ぎ足すので、小さなスタックのスレッドでも溢れずに上限のエラーになる
+    let code = std::thread::Builder::new()
+      .stack_size(512 * 1024)
+      .spawn(|| {
+        Interpreter::builder()
+          .options(ExecuteOptions {
+            max_depth: Some(2000),
+            ..Default::default()
+          })
+          .build()
+          .run(&b!(
+            "seq",
+            vec![
+              b!("defproc", vec![b!(str!("f")), bq!("+", vec![b!("1"), b!("f")])]),
+              b!("f"),
+            ]
+          ))
+          .map(|_| ())
+          .map_err(|err| err.code)
+      })
+      .unwrap()
+      .join()
+      .unwrap();
+
+    assert_eq!(code, Err(ErrorCode::LimitExceeded));
+  }
+
+  #[test]
+  fn sandbox() {
+    let result = execute_with_mock(
+      *b!("cmd", vec![b!(str!("echo")), b!(str!("out"))]),
       Box::new(|| panic!()),
       Box::new(|_| panic!()),
-      Box::new(|_, _| panic!()),
       Box::new(|_| panic!()),
-    )
-    .map_err(|err| err.msg)
+      Box::new(|_| panic!()),
+      ExecuteOptions {
+        denied: HashSet::from([Capability::Cmd]),
+        ..Default::default()
+      },
+    );
+
+    assert_eq!(result.map_err(|err| err.code), Err(ErrorCode::CapabilityDenied));
   }
 
   #[test]
-  fn simple_summing() {
-    let result = execute(*b!("+", vec![b!("3"), b!("4")]));
+  fn include_evaluates_once() {
+    let out = Rc::new(RefCell::new(String::new()));
+    let out_ref = out.clone();
+    let compiled = Rc::new(RefCell::new(0));
+    let compiled_ref = compiled.clone();
 
-    assert_eq!(result, Ok(Literal::Int(7)))
+    let result = execute_with_mock(
+      *b!(
+        "seq",
+        vec![
+          b!("include", vec![b!(str!("module.tr"))]),
+          b!("include", vec![b!(str!("module.tr"))]),
+          b!("print", vec![b!("module::x")])
+        ]
+      ),
+      Box::new(|| panic!()),
+      Box::new(move |msg| *out.borrow_mut() += &msg),
+      Box::new(|_| panic!()),
+      Box::new(move |paths| {
+        *compiled.borrow_mut() += 1;
+        Ok((
+          paths.join("/"),
+          *b!(
+            "seq",
+            vec![
+              b!("print", vec![b!(str!("loaded "))]),
+              b!("defset", vec![b!(str!("x")), b!("42")]),
+              b!("export", vec![b!(str!("x"))])
+            ]
+          ),
+        ))
+      }),
+      ExecuteOptions::default(),
+    );
+
+    assert_eq!(result.map_err(|err| err.msg), Ok(Literal::Void));
+    assert_eq!(*out_ref.borrow(), "loaded 42");
+    assert_eq!(*compiled_ref.borrow(), 2);
   }
+
   #[test]
-  fn too_much_args() {
-    let result = execute(*b!("+", vec![b!("3"), b!("4"), b!("5")]));
+  fn qualified_module_names() {
+    let run = |call: Box<Block>, qualified_only: bool| {
+      execute_with_mock(
+        *b!("seq", vec![b!("include", vec![b!(str!("lib/math.tr"))]), call]),
+        Box::new(|| panic!()),
+        Box::new(|_| panic!()),
+        Box::new(|_| panic!()),
+        Box::new(|paths| {
+          Ok((
+            paths.join("/"),
+            *b!(
+              "seq",
+              vec![
+                b!("defproc", vec![b!(str!("double")), bq!("*", vec![b!("2"), b!("$0")])]),
+                b!("export", vec![b!(str!("double"))])
+              ]
+            ),
+          ))
+        }),
+        ExecuteOptions {
+          qualified_only,
+          ..Default::default()
+        },
+      )
+      .map_err(|err| err.code)
+    };
 
-    assert!(result.is_err())
+    assert_eq!(run(b!("math::double", vec![b!("3")]), false), Ok(Literal::Int(6)));
+    assert_eq!(run(b!("double", vec![b!("3")]), false), Ok(Literal::Int(6)));
+    // qualified_only なら、そのままの名前では束縛しない
+    assert_eq!(run(b!("math::double", vec![b!("3")]), true), Ok(Literal::Int(6)));
+    assert_eq!(run(b!("double", vec![b!("3")]), true), Err(ErrorCode::UndefinedName));
+  }
+
+  #[test]
+  fn include_only() {
+    let run = |tree: Block| {
+      execute_with_mock(
+        tree,
+        Box::new(|| panic!()),
+        Box::new(|_| panic!()),
+        Box::new(|_| panic!()),
+        Box::new(|paths| {
+          Ok((
+            paths.join("/"),
+            *b!(
+              "seq",
+              vec![
+                b!("defset", vec![b!(str!("x")), b!("1")]),
+                b!("defset", vec![b!(str!("y")), b!("2")]),
+                b!("export", vec![b!(str!("x"))]),
+                b!("export", vec![b!(str!("y"))])
+              ]
+            ),
+          ))
+        }),
+        ExecuteOptions::default(),
+      )
+      .map_err(|err| err.code)
+    };
+
+    assert_eq!(
+      run(*b!(
+        "seq",
+        vec![
+          b!("include only", vec![b!(str!("m.tr")), b!(str!("x"))]),
+          b!("+", vec![b!("x"), b!("m::x")])
+        ]
+      )),
+      Ok(Literal::Int(2))
+    );
+    assert_eq!(
+      run(*b!(
+        "seq",
+        vec![b!("include only", vec![b!(str!("m.tr")), b!(str!("x"))]), b!("y")]
+      )),
+      Err(ErrorCode::UndefinedName)
+    );
+    assert_eq!(
+      run(*b!("include only", vec![b!(str!("m.tr")), b!(str!("z"))])),
+      Err(ErrorCode::UndefinedName)
+    );
+    // include した名前で定数を上書きしない
+    assert_eq!(
+      run(*b!(
+        "seq",
+        vec![
+          b!("defconst", vec![b!(str!("x")), b!("5")]),
+          b!("include only", vec![b!(str!("m.tr")), b!(str!("x"))])
+        ]
+      )),
+      Err(ErrorCode::ConstReassigned)
+    );
+  }
+
+  #[test]
+  fn module_visibility() {
+    let run = |export: Box<Block>| {
+      execute_with_mock(
+        *b!(
+          "seq",
+          vec![
+            b!("include", vec![b!(str!("m.tr"))]),
+            b!("listing", vec![b!("m::public"), b!("m::helper")])
+          ]
+        ),
+        Box::new(|| panic!()),
+        Box::new(|_| panic!()),
+        Box::new(|_| panic!()),
+        Box::new(move |paths| {
+          Ok((
+            paths.join("/"),
+            *b!(
+              "seq",
+              vec![
+                b!("defset", vec![b!(str!("helper")), b!("1")]),
+                b!("defset", vec![b!(str!("public")), b!("2")]),
+                export.clone()
+              ]
+            ),
+          ))
+        }),
+        ExecuteOptions::default(),
+      )
+      .map_err(|err| err.code)
+    };
+
+    assert_eq!(
+      run(b!("export", vec![b!(str!("public"))])),
+      Err(ErrorCode::UndefinedName)
+    );
+    assert_eq!(
+      run(b!("export all")),
+      Ok(Literal::List(Rc::new(vec![Literal::Int(2), Literal::Int(1)])))
+    );
+  }
+
+  #[test]
+  fn reexport_hides_module_helpers() {
+    let run = |name: &str| {
+      execute_with_mock(
+        *b!("seq", vec![b!("include", vec![b!(str!("a.tr"))]), b!(name)]),
+        Box::new(|| panic!()),
+        Box::new(|_| panic!()),
+        Box::new(|_| panic!()),
+        Box::new(|paths| {
+          let name = paths.last().unwrap().clone();
+          let module = if name == "a.tr" {
+            b!(
+              "seq",
+              vec![
+                b!("reexport", vec![b!("include", vec![b!(str!("b.tr"))])]),
+                b!("reexport", vec![b!("defset", vec![b!(str!("own")), b!("3")])])
+              ]
+            )
+          } else {
+            b!(
+              "seq",
+              vec![
+                b!("defset", vec![b!(str!("helper")), b!("1")]),
+                b!("defset", vec![b!(str!("public")), b!("2")]),
+                b!("export", vec![b!(str!("public"))])
+              ]
+            )
+          };
+          Ok((name, *module))
+        }),
+        ExecuteOptions::default(),
+      )
+      .map_err(|err| err.code)
+    };
+
+    assert_eq!(run("a::b::public"), Ok(Literal::Int(2)));
+    assert_eq!(run("a::own"), Ok(Literal::Int(3)));
+    // reexport を通しても、b が公開していない補助の名前は見えない
+    assert_eq!(run("a::b::helper"), Err(ErrorCode::UndefinedName));
+  }
+
+  #[test]
+  fn include_cycle() {
+    let result = execute_with_mock(
+      *b!("include", vec![b!(str!("a.tr"))]),
+      Box::new(|| panic!()),
+      Box::new(|_| panic!()),
+      Box::new(|_| panic!()),
+      Box::new(|paths| {
+        let name = paths.last().unwrap().clone();
+        let next = if name == "a.tr" { "b.tr" } else { "a.tr" };
+        Ok((name, *b!("include", vec![b!(str!(next))])))
+      }),
+      ExecuteOptions::default(),
+    );
+
+    let err = result.unwrap_err();
+    assert_eq!(err.code, ErrorCode::IncludeCycle);
+    let mut innermost = &err;
+    while let Some(caused_by) = innermost.caused_by.as_deref() {
+      innermost = caused_by;
+    }
+    assert!(innermost.msg.contains("a.tr -> b.tr -> a.tr"));
   }
 
   #[test]
@@ -310,12 +2755,12 @@ mod tests {
     let result = execute(*b!("split str", vec![b!(str!("abc def ghi")), b!(str!(" "))]));
 
     assert_eq!(
-      result,
-      Ok(Literal::List(vec![
+      result.ok(),
+      Some(Literal::List(Rc::new(vec![
         Literal::String("abc".to_string()),
         Literal::String("def".to_string()),
         Literal::String("ghi".to_string())
-      ]))
+      ])))
     )
   }
 
@@ -324,12 +2769,12 @@ mod tests {
     let result = execute(*b!("split str", vec![b!(str!("abc")), b!(str!(""))]));
 
     assert_eq!(
-      result,
-      Ok(Literal::List(vec![
+      result.ok(),
+      Some(Literal::List(Rc::new(vec![
         Literal::String("a".to_string()),
         Literal::String("b".to_string()),
         Literal::String("c".to_string())
-      ]))
+      ])))
     )
   }
 
@@ -0,0 +1,549 @@
+use std::{
+  collections::HashMap,
+  env, fs,
+  io::{self, BufRead, BufReader, IsTerminal, Read, Write},
+  net::{TcpListener, TcpStream},
+  path::Path,
+  process::{self, Child, Command, Output, Stdio},
+  rc::Rc,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+  thread,
+  time::{Duration, Instant},
+};
+
+use crossterm::{
+  event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+  terminal,
+};
+
+use crate::{
+  errors::ErrorCode,
+  messages,
+  structs::{
+    exit_code, Block, BlockError, ChildProcess, ClosureProcedure, CmdExecutor, CmdOutput, CmdRequest, ExecuteEnv,
+    ExecuteOptions, FileSystem, HttpBinder, HttpListener, HttpRequest, HttpResponse, Includer, Literal, ProcedureError,
+    ProcedureOrVar, ProcessSpawner,
+  },
+};
+
+use super::predefined::predefined_procs;
+
+/// Trees のプログラムを実行する環境。
+/// 同じ Interpreter で続けて実行したプログラムは、トップレベルで定義された変数や手続きを共有する。
+/// seq などの中で定義した名前は、その実行が終わると消える。
+pub struct Interpreter {
+  exec_env: ExecuteEnv,
+}
+
+impl Interpreter {
+  pub fn builder() -> InterpreterBuilder {
+    InterpreterBuilder::new()
+  }
+
+  /// プログラムを実行する。コマンドラインで実行するときと同じく、プログラムは実行ごとに作るスコープで動く。
+  pub fn run(&mut self, tree: &Block) -> Result<Literal, BlockError> {
+    let result = tree.execute(&mut self.exec_env);
+    self.exec_env.forget_returning();
+    result
+  }
+
+  /// トップレベルの変数の値を読む。
+  pub fn get_var(&mut self, name: &str) -> Option<Literal> {
+    self.exec_env.get_var(name).ok()
+  }
+
+  /// トップレベルに変数を定義する。プログラムのトップレベルの defset と同じスコープに定義するので、
+  /// 同じ名前の定数があれば上書きせずにエラーを返す。
+  pub fn set_var(&mut self, name: &str, value: Literal) -> Result<(), ProcedureError> {
+    self.exec_env.defset_var_into_last_scope(name, &value)
+  }
+}
+
+/// 端末を raw モードにして、押されたキーを 1 つ読む。
+/// 文字はその文字を、それ以外のキーは "Enter" や "Up" などの名前を返す。
+/// 標準入力が端末でなければ 1 文字読む (入力の終わりなら空文字列)。
+/// Ctrl-C が押されたら interrupt を立てて空文字列を返す。
+fn read_key(interrupt: &AtomicBool) -> String {
+  let _ = io::stdout().flush();
+  if !io::stdin().is_terminal() {
+    let mut bytes = vec![];
+    for byte in io::stdin().lock().bytes() {
+      let Ok(byte) = byte else {
+        break;
+      };
+      bytes.push(byte);
+      if let Ok(str) = std::str::from_utf8(&bytes) {
+        return str.to_owned();
+      }
+    }
+    return String::from_utf8_lossy(&bytes).to_string();
+  }
+
+  let _ = terminal::enable_raw_mode();
+  let key = loop {
+    match event::read() {
+      Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => break key,
+      Ok(_) => continue,
+      Err(_) => break KeyEvent::from(KeyCode::Null),
+    }
+  };
+  let _ = terminal::disable_raw_mode();
+
+  match key.code {
+    // raw モードでは Ctrl-C がシグナルにならないので、シグナルと同じく中断の印を立てる
+    KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+      interrupt.store(true, Ordering::Relaxed);
+      "".to_owned()
+    }
+    KeyCode::Char(c) => c.to_string(),
+    KeyCode::Enter => "Enter".to_owned(),
+    KeyCode::Tab => "Tab".to_owned(),
+    KeyCode::Backspace => "Backspace".to_owned(),
+    KeyCode::Esc => "Esc".to_owned(),
+    KeyCode::Up => "Up".to_owned(),
+    KeyCode::Down => "Down".to_owned(),
+    KeyCode::Left => "Left".to_owned(),
+    KeyCode::Right => "Right".to_owned(),
+    _ => "".to_owned(),
+  }
+}
+
+/// spawn cmd のプロセスを起動する。標準入力は空にし、標準出力と標準エラー出力は親のものを使う。
+fn spawn_process(request: CmdRequest) -> Result<Box<dyn ChildProcess>, String> {
+  let mut command = Command::new(&request.program);
+  command.args(&request.args).envs(request.env).stdin(Stdio::null());
+  if let Some(dir) = &request.dir {
+    command.current_dir(dir);
+  }
+  Ok(Box::new(command.spawn().map_err(|err| err.to_string())?))
+}
+
+impl ChildProcess for Child {
+  fn wait(&mut self) -> Result<i64, String> {
+    Child::wait(self).map(exit_code).map_err(|err| err.to_string())
+  }
+
+  fn try_wait(&mut self) -> Result<Option<i64>, String> {
+    Child::try_wait(self).map(|status| status.map(exit_code)).map_err(|err| err.to_string())
+  }
+
+  fn kill(&mut self) {
+    // 既に終了していたときの kill の失敗は無視する
+    let _ = Child::kill(self);
+    let _ = Child::wait(self);
+  }
+}
+
+/// プロセスを起動して出力を集める。timeout を過ぎたらプロセスを止めてエラーにする。
+fn output_with_timeout(mut command: Command, program: &str, timeout: Duration) -> Result<Output, String> {
+  let mut child = command
+    .stdin(Stdio::null())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .map_err(|err| err.to_string())?;
+  // パイプが一杯になって子プロセスが止まらないよう、待つ間も別のスレッドで読み続ける
+  let read_all = |mut pipe: Box<dyn Read + Send>| {
+    thread::spawn(move || {
+      let mut buf = vec![];
+      pipe.read_to_end(&mut buf).map(|_| buf)
+    })
+  };
+  let stdout = read_all(Box::new(child.stdout.take().unwrap()));
+  let stderr = read_all(Box::new(child.stderr.take().unwrap()));
+
+  let deadline = Instant::now() + timeout;
+  let status = loop {
+    if let Some(status) = child.try_wait().map_err(|err| err.to_string())? {
+      break status;
+    }
+    if Instant::now() >= deadline {
+      let _ = child.kill();
+      let _ = child.wait();
+      // 孫プロセスがパイプを開いたままのことがあるので、読み取りのスレッドは待たない
+      return Err(messages::command_timed_out(program, timeout.as_millis()));
+    }
+    thread::sleep(Duration::from_millis(10));
+  };
+  let join = |handle: thread::JoinHandle<io::Result<Vec<u8>>>| {
+    handle.join().unwrap_or_else(|_| Ok(vec![])).map_err(|err| err.to_string())
+  };
+  Ok(Output {
+    status,
+    stdout: join(stdout)?,
+    stderr: join(stderr)?,
+  })
+}
+
+/// std::fs をそのまま使うファイルシステム
+struct StdFileSystem;
+
+impl FileSystem for StdFileSystem {
+  fn list_dir(&mut self, path: &str) -> Result<Vec<String>, String> {
+    let mut names = fs::read_dir(path)
+      .and_then(|entries| {
+        entries
+          .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().into_owned()))
+          .collect::<io::Result<Vec<_>>>()
+      })
+      .map_err(|err| messages::file_error(path, &err.to_string()))?;
+    names.sort();
+    Ok(names)
+  }
+
+  fn create_dir(&mut self, path: &str) -> Result<(), String> {
+    fs::create_dir_all(path).map_err(|err| messages::file_error(path, &err.to_string()))
+  }
+
+  fn exists(&mut self, path: &str) -> bool {
+    Path::new(path).exists()
+  }
+
+  fn is_dir(&mut self, path: &str) -> bool {
+    Path::new(path).is_dir()
+  }
+
+  fn remove_file(&mut self, path: &str) -> Result<(), String> {
+    fs::remove_file(path).map_err(|err| messages::file_error(path, &err.to_string()))
+  }
+
+  fn copy_file(&mut self, from: &str, to: &str) -> Result<(), String> {
+    fs::copy(from, to).map(|_| ()).map_err(|err| messages::file_error(from, &err.to_string()))
+  }
+
+  fn open_read(&mut self, path: &str) -> Result<Box<dyn BufRead>, String> {
+    let file = fs::File::open(path).map_err(|err| messages::file_error(path, &err.to_string()))?;
+    Ok(Box::new(BufReader::new(file)))
+  }
+
+  fn create_temp(&mut self, dir: bool) -> Result<String, String> {
+    let temp_dir = env::temp_dir();
+    loop {
+      // --seed で乱数の列を固定しても名前がぶつからないよう、実行環境の乱数は使わない
+      let path = temp_dir.join(format!("trees-{}-{:016x}", process::id(), fastrand::u64(..)));
+      let created = if dir {
+        fs::create_dir(&path)
+      } else {
+        fs::OpenOptions::new().write(true).create_new(true).open(&path).map(|_| ())
+      };
+      match created {
+        Ok(()) => return Ok(path.to_string_lossy().into_owned()),
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+        Err(err) => return Err(messages::file_error(&temp_dir.to_string_lossy(), &err.to_string())),
+      }
+    }
+  }
+
+  fn remove_all(&mut self, path: &str) -> Result<(), String> {
+    if Path::new(path).is_dir() {
+      fs::remove_dir_all(path)
+    } else {
+      fs::remove_file(path)
+    }
+    .map_err(|err| messages::file_error(path, &err.to_string()))
+  }
+
+  fn append(&mut self, path: &str, text: &str) -> Result<(), String> {
+    fs::OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(path)
+      .and_then(|mut file| file.write_all(text.as_bytes()))
+      .map_err(|err| messages::file_error(path, &err.to_string()))
+  }
+}
+
+/// 127.0.0.1 で待ち受ける HTTP/1.1 のサーバ。1 つの接続では 1 つのリクエストだけを扱い、応答したら閉じる
+struct StdHttpListener {
+  listener: TcpListener,
+  /// 応答を待っている接続
+  stream: Option<TcpStream>,
+}
+
+fn bind_http(port: u16) -> Result<Box<dyn HttpListener>, String> {
+  let listener = TcpListener::bind(("127.0.0.1", port))
+    .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+    .map_err(|err| messages::network_error(port, &err.to_string()))?;
+  Ok(Box::new(StdHttpListener { listener, stream: None }))
+}
+
+/// リクエスト行とヘッダを読み、Content-Length の分だけ本文を読む。読めなければ None
+fn read_http_request(stream: &TcpStream) -> Option<HttpRequest> {
+  let mut reader = BufReader::new(stream);
+  let mut line = String::new();
+  reader.read_line(&mut line).ok()?;
+  let mut parts = line.split_whitespace();
+  let (method, path) = (parts.next()?.to_owned(), parts.next()?.to_owned());
+
+  let mut length = 0;
+  loop {
+    let mut header = String::new();
+    reader.read_line(&mut header).ok()?;
+    let header = header.trim_end();
+    if header.is_empty() {
+      break;
+    }
+    if let Some((name, value)) = header.split_once(':') {
+      if name.eq_ignore_ascii_case("content-length") {
+        length = value.trim().parse().ok()?;
+      }
+    }
+  }
+  let mut body = vec![];
+  reader.take(length).read_to_end(&mut body).ok()?;
+  if body.len() as u64 != length {
+    return None;
+  }
+  Some(HttpRequest {
+    method,
+    path,
+    body: String::from_utf8(body).ok()?,
+  })
+}
+
+fn write_http_response(mut stream: &TcpStream, response: &HttpResponse) -> io::Result<()> {
+  let reason = match response.status {
+    200 => "OK",
+    201 => "Created",
+    204 => "No Content",
+    400 => "Bad Request",
+    404 => "Not Found",
+    500 => "Internal Server Error",
+    _ => "",
+  };
+  write!(
+    stream,
+    "HTTP/1.1 {} {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+    response.status,
+    reason,
+    response.body.len(),
+    response.body
+  )
+}
+
+impl HttpListener for StdHttpListener {
+  fn next_request(&mut self) -> Result<Option<HttpRequest>, String> {
+    let stream = match self.listener.accept() {
+      Ok((stream, _)) => stream,
+      Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+        thread::sleep(Duration::from_millis(50));
+        return Ok(None);
+      }
+      Err(err) => return Err(err.to_string()),
+    };
+    // 送ってこないクライアントで止まらないよう、読み込みには期限をつける
+    let _ = stream.set_nonblocking(false);
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+    match read_http_request(&stream) {
+      Some(request) => {
+        self.stream = Some(stream);
+        Ok(Some(request))
+      }
+      None => {
+        let _ = write_http_response(
+          &stream,
+          &HttpResponse {
+            status: 400,
+            body: String::new(),
+          },
+        );
+        Ok(None)
+      }
+    }
+  }
+
+  fn respond(&mut self, response: HttpResponse) {
+    if let Some(stream) = self.stream.take() {
+      let _ = write_http_response(&stream, &response);
+    }
+  }
+}
+
+pub struct InterpreterBuilder {
+  procs: HashMap<String, ProcedureOrVar>,
+  input_stream: Box<dyn FnMut() -> String>,
+  /// None なら端末から読む (build のときに interrupt を渡す)
+  key_input: Option<Box<dyn FnMut() -> String>>,
+  out_stream: Box<dyn FnMut(String)>,
+  err_stream: Box<dyn FnMut(String)>,
+  cmd_executor: CmdExecutor,
+  file_system: Box<dyn FileSystem>,
+  http_binder: HttpBinder,
+  process_spawner: ProcessSpawner,
+  includer: Includer,
+  interrupt: Arc<AtomicBool>,
+  options: ExecuteOptions,
+}
+
+impl InterpreterBuilder {
+  /// 標準入出力と sh (Windows では cmd) を使う設定で始める。include は使えない。
+  fn new() -> InterpreterBuilder {
+    InterpreterBuilder {
+      procs: predefined_procs(),
+      input_stream: Box::new(|| {
+        let _ = io::stdout().flush();
+        let mut str = String::new();
+        std::io::stdin().read_line(&mut str).unwrap();
+        str.trim().to_string()
+      }),
+      key_input: None,
+      out_stream: Box::new(|msg| print!("{}", msg)),
+      err_stream: Box::new(|msg| eprint!("{}", msg)),
+      cmd_executor: Box::new(|request| {
+        let mut command = if !request.shell {
+          let mut command = Command::new(&request.program);
+          command.args(&request.args);
+          command
+        } else {
+          let acutual_cmd = format!("{} {}", request.program, request.args.join(" "));
+          if cfg!(target_os = "windows") {
+            let mut command = Command::new("cmd");
+            command.args(["/C", &acutual_cmd]);
+            command
+          } else {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(acutual_cmd);
+            command
+          }
+        };
+        if let Some(dir) = &request.dir {
+          command.current_dir(dir);
+        }
+        command.envs(request.env);
+        match request.timeout {
+          Some(timeout) => output_with_timeout(command, &request.program, timeout),
+          None => command.output().map_err(|err| err.to_string()),
+        }
+        .and_then(|out| {
+          Ok(CmdOutput {
+            status: exit_code(out.status),
+            stdout: String::from_utf8(out.stdout).map_err(|e| e.to_string())?,
+            stderr: String::from_utf8(out.stderr).map_err(|e| e.to_string())?,
+          })
+        })
+      }),
+      file_system: Box::new(StdFileSystem),
+      http_binder: Box::new(bind_http),
+      process_spawner: Box::new(spawn_process),
+      includer: Box::new(|paths| {
+        Err((
+          ErrorCode::SourceUnreadable,
+          messages::include_unavailable(&paths.join("/")),
+        ))
+      }),
+      interrupt: Arc::default(),
+      options: ExecuteOptions::default(),
+    }
+  }
+
+  /// クロージャを手続きとして登録する。同じ名前の組み込みの手続きは上書きされる。
+  pub fn proc<F>(mut self, name: &str, procedure: F) -> Self
+  where
+    F: Fn(&mut ExecuteEnv, &[Literal]) -> Result<Literal, ProcedureError> + 'static,
+  {
+    self.procs.insert(
+      name.to_owned(),
+      ProcedureOrVar::ClosureProcedure(ClosureProcedure(Rc::new(procedure))),
+    );
+    self
+  }
+
+  /// 手続きや変数をまとめて登録する。
+  pub fn procs(mut self, procs: HashMap<String, ProcedureOrVar>) -> Self {
+    self.procs.extend(procs);
+    self
+  }
+
+  pub fn input(mut self, input_stream: impl FnMut() -> String + 'static) -> Self {
+    self.input_stream = Box::new(input_stream);
+    self
+  }
+
+  /// read key で使う、キーを 1 つ読む関数を差し替える。
+  pub fn key_input(mut self, key_input: impl FnMut() -> String + 'static) -> Self {
+    self.key_input = Some(Box::new(key_input));
+    self
+  }
+
+  pub fn output(mut self, out_stream: impl FnMut(String) + 'static) -> Self {
+    self.out_stream = Box::new(out_stream);
+    self
+  }
+
+  /// eprint などの診断用の出力先を差し替える。
+  pub fn error_output(mut self, err_stream: impl FnMut(String) + 'static) -> Self {
+    self.err_stream = Box::new(err_stream);
+    self
+  }
+
+  pub fn cmd_executor(mut self, cmd_executor: impl FnMut(CmdRequest) -> Result<CmdOutput, String> + 'static) -> Self {
+    self.cmd_executor = Box::new(cmd_executor);
+    self
+  }
+
+  /// list dir などで使うファイルシステムを差し替える。
+  pub fn file_system(mut self, file_system: impl FileSystem + 'static) -> Self {
+    self.file_system = Box::new(file_system);
+    self
+  }
+
+  /// http serve で待ち受けを始める関数を差し替える。
+  pub fn http_binder(
+    mut self,
+    http_binder: impl FnMut(u16) -> Result<Box<dyn HttpListener>, String> + 'static,
+  ) -> Self {
+    self.http_binder = Box::new(http_binder);
+    self
+  }
+
+  /// spawn cmd でプロセスを起動する関数を差し替える。
+  pub fn process_spawner(
+    mut self,
+    process_spawner: impl FnMut(CmdRequest) -> Result<Box<dyn ChildProcess>, String> + 'static,
+  ) -> Self {
+    self.process_spawner = Box::new(process_spawner);
+    self
+  }
+
+  pub fn includer(mut self, includer: Includer) -> Self {
+    self.includer = includer;
+    self
+  }
+
+  /// 立てると実行を止めるフラグを渡す。Ctrl-C のシグナルハンドラなど、別のスレッドから立ててよい。
+  pub fn interrupt(mut self, interrupt: Arc<AtomicBool>) -> Self {
+    self.interrupt = interrupt;
+    self
+  }
+
+  pub fn options(mut self, options: ExecuteOptions) -> Self {
+    self.options = options;
+    self
+  }
+
+  pub fn build(self) -> Interpreter {
+    let interrupt = self.interrupt.clone();
+    let key_input = self.key_input.unwrap_or_else(|| Box::new(move || read_key(&interrupt)));
+    let mut exec_env = ExecuteEnv::new(
+      self.procs,
+      self.input_stream,
+      key_input,
+      self.out_stream,
+      self.err_stream,
+      self.cmd_executor,
+      self.file_system,
+      self.http_binder,
+      self.process_spawner,
+      self.includer,
+      self.interrupt,
+      self.options,
+    );
+    // トップレベルのブロックを実行するスコープ
+    exec_env.new_scope();
+
+    Interpreter { exec_env }
+  }
+}
@@ -1,42 +1,246 @@
-use std::collections::HashMap;
-
-use crate::structs::{Literal, ProcedureOrVar};
-
-fn type_error_msg(proc_name: &str, index: usize, actually: &Literal, expected: &str) -> String {
-  format!(
-    "Procedure {}: $arg[{}] must be {}. (Got {})",
-    proc_name,
-    index,
-    expected,
-    actually.to_string()
-  )
+use std::{cell::RefCell, collections::HashMap, rc::Rc, str::FromStr, time::Duration};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bigdecimal::{BigDecimal, RoundingMode, Zero};
+use md5::Md5;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use sha2::{Digest, Sha256};
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+use crate::{
+  errors::ErrorCode,
+  messages,
+  structs::{
+    Block, BlockLiteral, CmdRequest, ControlFlow, HttpResponse, Literal, LogLevel, MemoTable, Params, ProcedureError,
+    ProcedureOrVar, QuoteStyle,
+  },
+};
+
+fn type_error(proc_name: &str, index: usize, actually: &Literal, expected: &str) -> ProcedureError {
+  let msg = messages::type_error(proc_name, index, expected, &actually.to_string());
+  ProcedureError::OtherError(ErrorCode::TypeMismatch, msg)
 }
 
-fn block_type_error_msg(proc_name: &str, index: usize, actually: &Literal, expected: &str) -> String {
-  format!(
-    "Procedure {}: Executed result of $arg[{}] must be {}. (Got {})",
-    proc_name,
-    index,
-    expected,
-    actually.to_string()
-  )
+fn block_type_error(proc_name: &str, index: usize, actually: &Literal, expected: &str) -> ProcedureError {
+  let msg = messages::block_type_error(proc_name, index, expected, &actually.to_string());
+  ProcedureError::OtherError(ErrorCode::TypeMismatch, msg)
 }
 
-fn list_type_error_msg(
+fn list_type_error(
   proc_name: &str,
   arg_index: usize,
   list_index: usize,
   actually: &Literal,
   expected: &str,
-) -> String {
-  format!(
-    "Procedure {}: [{}] of $arg[{}] must be {}. (Got {})",
-    proc_name,
-    list_index,
-    arg_index,
-    expected,
-    actually.to_string()
-  )
+) -> ProcedureError {
+  let msg = messages::list_type_error(proc_name, arg_index, list_index, expected, &actually.to_string());
+  ProcedureError::OtherError(ErrorCode::TypeMismatch, msg)
+}
+
+/// 残りの引数 (first_index 番目から) をすべて文字列として取り出す。
+fn str_args(proc_name: &str, first_index: usize, list: &[Literal]) -> Result<Vec<String>, ProcedureError> {
+  list
+    .iter()
+    .enumerate()
+    .map(|(index, l)| match l {
+      Literal::String(s) => Ok(s.to_owned()),
+      _ => Err(type_error(proc_name, first_index + index, l, "str")),
+    })
+    .collect()
+}
+
+/// $arg[arg_index] に渡されたリストの要素を子のブロックにする。値は、その値を返すブロックにする。
+fn child_blocks(
+  proc_name: &str,
+  arg_index: usize,
+  list: &[Literal],
+) -> Result<Vec<(bool, Box<Block>)>, ProcedureError> {
+  list
+    .iter()
+    .enumerate()
+    .map(|(index, l)| match l.to_block() {
+      Some(block) => Ok((false, Box::new(block))),
+      None => Err(list_type_error(proc_name, arg_index, index, l, "block")),
+    })
+    .collect()
+}
+
+/// $arg[1] に渡された引数名のリストを取り出す。
+fn param_names(proc_name: &str, list: &[Literal]) -> Result<Vec<String>, ProcedureError> {
+  list
+    .iter()
+    .enumerate()
+    .map(|(index, l)| match l {
+      Literal::String(s) => Ok(s.to_owned()),
+      _ => Err(list_type_error(proc_name, 1, index, l, "str")),
+    })
+    .collect()
+}
+
+/// $arg[1] に渡された基数を確かめる。
+fn radix(proc_name: &str, base: i64) -> Result<u32, ProcedureError> {
+  match u32::try_from(base) {
+    Ok(radix @ 2..=36) => Ok(radix),
+    _ => Err(type_error(proc_name, 1, &Literal::Int(base), "int from 2 to 36")),
+  }
+}
+
+/// 配列の添字を確かめる。
+fn array_index(index: i64, len: usize) -> Result<usize, ProcedureError> {
+  usize::try_from(index).ok().filter(|i| *i < len).ok_or(ProcedureError::OtherError(
+    ErrorCode::IndexOutOfRange,
+    messages::index_out_of_range(index, len),
+  ))
+}
+
+/// url encode でそのまま残す文字 (RFC 3986 の非予約文字) 以外
+const URL_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.').remove(b'~');
+
+/// 文字列か、バイト (0 から 255 の整数) のリストとして渡された $arg[index] をバイト列にする。
+fn bytes_arg(proc_name: &str, index: usize, literal: &Literal) -> Result<Vec<u8>, ProcedureError> {
+  match literal {
+    Literal::String(s) => Ok(s.as_bytes().to_vec()),
+    Literal::List(list) => list
+      .iter()
+      .enumerate()
+      .map(|(list_index, l)| match l {
+        Literal::Int(b) => u8::try_from(*b).map_err(|_| list_type_error(proc_name, index, list_index, l, "byte")),
+        _ => Err(list_type_error(proc_name, index, list_index, l, "byte")),
+      })
+      .collect(),
+    _ => Err(type_error(proc_name, index, literal, "str or list")),
+  }
+}
+
+/// hash のために、型と中身が同じ値だけが同じになるバイト列に書き出す。
+/// 実行ごとに変わらないよう、std の Hasher は使わない
+fn hash_encode(literal: &Literal, out: &mut Vec<u8>) {
+  fn encode_str(s: &str, out: &mut Vec<u8>) {
+    out.extend_from_slice(&(s.len() as u64).to_le_bytes());
+    out.extend_from_slice(s.as_bytes());
+  }
+  fn encode_items(items: &[Literal], out: &mut Vec<u8>) {
+    out.extend_from_slice(&(items.len() as u64).to_le_bytes());
+    items.iter().for_each(|item| hash_encode(item, out));
+  }
+  fn encode_block(block: &Block, out: &mut Vec<u8>) {
+    encode_str(&block.proc_name, out);
+    out.push(block.quote.clone() as u8);
+    out.extend_from_slice(&(block.args.len() as u64).to_le_bytes());
+    for (expand, arg) in &block.args {
+      out.push(u8::from(*expand));
+      encode_block(arg, out);
+    }
+  }
+
+  out.push(match literal {
+    Literal::Int(_) => 0,
+    Literal::String(_) => 1,
+    Literal::Boolean(_) => 2,
+    Literal::Block(_) => 3,
+    Literal::List(_) => 4,
+    Literal::Array(_) => 5,
+    Literal::StringBuilder(_) => 6,
+    Literal::Void => 7,
+    Literal::Decimal(_) => 8,
+  });
+  match literal {
+    Literal::Int(i) => out.extend_from_slice(&i.to_le_bytes()),
+    Literal::String(s) => encode_str(s, out),
+    Literal::Boolean(b) => out.push(u8::from(*b)),
+    Literal::Block(b) => encode_block(&b.block, out),
+    Literal::List(list) => encode_items(list, out),
+    Literal::Array(array) => encode_items(&array.borrow(), out),
+    Literal::StringBuilder(sb) => encode_str(&sb.borrow(), out),
+    Literal::Decimal(d) => encode_str(&d.normalized().to_string(), out),
+    Literal::Void => {}
+  }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// $arg[index] に渡された整数のリストを取り出す。
+fn int_vector(proc_name: &str, index: usize, list: &[Literal]) -> Result<Vec<i64>, ProcedureError> {
+  list
+    .iter()
+    .enumerate()
+    .map(|(list_index, l)| match l {
+      Literal::Int(n) => Ok(*n),
+      _ => Err(list_type_error(proc_name, index, list_index, l, "int")),
+    })
+    .collect()
+}
+
+/// $arg[index] に渡された整数のリストのリストを、行の並んだ行列として取り出す。行の長さがそろっていなければエラー
+fn int_matrix(proc_name: &str, index: usize, list: &[Literal]) -> Result<Vec<Vec<i64>>, ProcedureError> {
+  let rows = list
+    .iter()
+    .enumerate()
+    .map(|(list_index, row)| match row {
+      Literal::List(row) => int_vector(proc_name, index, row),
+      _ => Err(list_type_error(proc_name, index, list_index, row, "list of int")),
+    })
+    .collect::<Result<Vec<_>, _>>()?;
+  if let Some(row) = rows.iter().position(|row| row.len() != rows[0].len()) {
+    return Err(messages::ragged_matrix(proc_name, index, row).into());
+  }
+  Ok(rows)
+}
+
+fn matrix_literal(rows: Vec<Vec<i64>>) -> Literal {
+  Literal::List(Rc::new(
+    rows.into_iter().map(|row| Literal::List(Rc::new(row.into_iter().map(Literal::Int).collect()))).collect(),
+  ))
+}
+
+/// 行列の大きさを 行数x列数 で表す
+fn matrix_size(rows: &[Vec<i64>]) -> String {
+  format!("{}x{}", rows.len(), rows.first().map_or(0, Vec::len))
+}
+
+fn checked_dot(
+  proc_name: &str,
+  a: impl Iterator<Item = i64>,
+  b: impl Iterator<Item = i64>,
+) -> Result<i64, ProcedureError> {
+  a.zip(b)
+    .try_fold(0i64, |sum, (x, y)| {
+      x.checked_mul(y).and_then(|product| sum.checked_add(product))
+    })
+    .ok_or_else(|| messages::overflow(proc_name).into())
+}
+
+/// http serve のハンドラの結果を応答にする。文字列なら 200 で、[状態コード, 文字列] ならその状態コードで返す
+fn http_response(result: &Literal) -> Option<HttpResponse> {
+  match result {
+    Literal::String(body) => Some(HttpResponse {
+      status: 200,
+      body: body.clone(),
+    }),
+    Literal::List(list) => match &list[..] {
+      [Literal::Int(status), Literal::String(body)] => Some(HttpResponse {
+        status: u16::try_from(*status).ok().filter(|status| (100..600).contains(status))?,
+        body: body.clone(),
+      }),
+      _ => None,
+    },
+    _ => None,
+  }
+}
+
+/// ループの本体の結果から break と continue を取り出す。return を含むそれ以外のエラーはそのまま返す
+fn loop_control(result: Result<Literal, impl Into<ProcedureError>>) -> Result<Option<ControlFlow>, ProcedureError> {
+  match result.map_err(Into::into) {
+    Ok(_) => Ok(None),
+    Err(err) => match err.control() {
+      Some(flow @ (ControlFlow::Break | ControlFlow::Continue)) => Ok(Some(flow.clone())),
+      _ => Err(err),
+    },
+  }
 }
 
 #[allow(unused_variables, unused_mut)]
@@ -67,7 +271,10 @@ pub fn predefined_procs() -> HashMap<String, ProcedureOrVar> {
   macro_rules! initialize_vars {
     ($name: expr, $vec:expr, $($tail:ident:$type:tt),*) => {
       if $vec.len() != count_idents!($($tail)*) {
-        return Err(format!("Procedure {}: Length of args must be {}. (Got {})", $name, count_idents!($($tail)*), $vec.len()).into());
+        return Err(ProcedureError::OtherError(
+          ErrorCode::ArgCountMismatch,
+          messages::arg_count($name, count_idents!($($tail)*), $vec.len()),
+        ));
       }
       let mut iter = $vec.into_iter().enumerate();
       $(
@@ -97,31 +304,49 @@ pub fn predefined_procs() -> HashMap<String, ProcedureOrVar> {
     };
     ($index: expr, $name: expr, $literal:expr, $tail:ident:int) => {
       let Literal::Int($tail) = $literal else {
-        return Err(type_error_msg($name, $index, $literal, "int").into());
+        return Err(type_error($name, $index, $literal, "int"));
       };
       let $tail = $tail.clone();
     };
     ($index: expr, $name: expr, $literal:expr, $tail:ident:str) => {
       let Literal::String($tail) = $literal else {
-        return Err(type_error_msg($name, $index, $literal, "str").into());
+        return Err(type_error($name, $index, $literal, "str"));
       };
       let $tail = $tail.clone();
     };
     ($index: expr, $name: expr, $literal:expr, $tail:ident:boolean) => {
       let Literal::Boolean($tail) = $literal else {
-        return Err(type_error_msg($name, $index, $literal, "boolean").into());
+        return Err(type_error($name, $index, $literal, "boolean"));
       };
       let $tail = $tail.clone();
     };
     ($index: expr, $name: expr, $literal:expr, $tail:ident:block) => {
       let Literal::Block($tail) = $literal else {
-        return Err(type_error_msg($name, $index, $literal, "block").into());
+        return Err(type_error($name, $index, $literal, "block"));
       };
       let $tail = $tail.clone();
     };
     ($index: expr, $name: expr, $literal:expr, $tail:ident:list) => {
       let Literal::List($tail) = $literal else {
-        return Err(type_error_msg($name, $index, $literal, "list").into());
+        return Err(type_error($name, $index, $literal, "list"));
+      };
+      let $tail = $tail.clone();
+    };
+    ($index: expr, $name: expr, $literal:expr, $tail:ident:array) => {
+      let Literal::Array($tail) = $literal else {
+        return Err(type_error($name, $index, $literal, "array"));
+      };
+      let $tail = $tail.clone();
+    };
+    ($index: expr, $name: expr, $literal:expr, $tail:ident:sb) => {
+      let Literal::StringBuilder($tail) = $literal else {
+        return Err(type_error($name, $index, $literal, "sb"));
+      };
+      let $tail = $tail.clone();
+    };
+    ($index: expr, $name: expr, $literal:expr, $tail:ident:dec) => {
+      let Literal::Decimal($tail) = $literal else {
+        return Err(type_error($name, $index, $literal, "dec"));
       };
       let $tail = $tail.clone();
     };
@@ -145,16 +370,184 @@ pub fn predefined_procs() -> HashMap<String, ProcedureOrVar> {
   add_map!(">", {Ok(Literal::Boolean(a > b))}; a:int, b:int);
   add_map!("<=", {Ok(Literal::Boolean(a <= b))}; a:int, b:int);
   add_map!(">=", {Ok(Literal::Boolean(a >= b))}; a:int, b:int);
+  add_map!("abs", {
+    Ok(Literal::Int(a.checked_abs().ok_or(messages::overflow("abs"))?))
+  }; a:int);
+  add_map!("min", {Ok(Literal::Int(a.min(b)))}; a:int, b:int);
+  add_map!("max", {Ok(Literal::Int(a.max(b)))}; a:int, b:int);
+  add_map!("pow", {
+    let Ok(exp) = u32::try_from(exp) else {
+      return Err(type_error("pow", 1, &Literal::Int(exp), "non-negative int"));
+    };
+    Ok(Literal::Int(base.checked_pow(exp).ok_or(messages::overflow("pow"))?))
+  }; base:int, exp:int);
+  add_map!("isqrt", {
+    if a < 0 {
+      return Err(type_error("isqrt", 0, &Literal::Int(a), "non-negative int"));
+    }
+    Ok(Literal::Int(a.isqrt()))
+  }; a:int);
+  // sqrt は isqrt の別名
+  map.insert("sqrt".to_string(), map["isqrt"].clone());
+  add_map!("gcd", {
+    let (mut a, mut b) = (a.unsigned_abs(), b.unsigned_abs());
+    while b != 0 {
+      (a, b) = (b, a % b);
+    }
+    Ok(Literal::Int(i64::try_from(a).map_err(|_| messages::overflow("gcd"))?))
+  }; a:int, b:int);
+  add_map!("mat new", {
+    let Ok(rows) = usize::try_from(rows) else {
+      return Err(type_error("mat new", 0, &Literal::Int(rows), "non-negative int"));
+    };
+    let Ok(cols) = usize::try_from(cols) else {
+      return Err(type_error("mat new", 1, &Literal::Int(cols), "non-negative int"));
+    };
+    Ok(matrix_literal(vec![vec![0; cols]; rows]))
+  }; rows:int, cols:int);
+  add_map!("mat mul", {
+    let (a, b) = (int_matrix("mat mul", 0, &a)?, int_matrix("mat mul", 1, &b)?);
+    if a.first().map_or(0, Vec::len) != b.len() {
+      return Err(messages::size_mismatch("mat mul", &matrix_size(&a), &matrix_size(&b)).into());
+    }
+    let cols = b.first().map_or(0, Vec::len);
+    let product = a
+      .iter()
+      .map(|row| (0..cols).map(|j| checked_dot("mat mul", row.iter().copied(), b.iter().map(|r| r[j]))).collect())
+      .collect::<Result<_, _>>()?;
+    Ok(matrix_literal(product))
+  }; a:list, b:list);
+  add_map!("mat transpose", {
+    let a = int_matrix("mat transpose", 0, &a)?;
+    let cols = a.first().map_or(0, Vec::len);
+    Ok(matrix_literal((0..cols).map(|j| a.iter().map(|row| row[j]).collect()).collect()))
+  }; a:list);
+  add_map!("vec dot", {
+    let (a, b) = (int_vector("vec dot", 0, &a)?, int_vector("vec dot", 1, &b)?);
+    if a.len() != b.len() {
+      return Err(messages::size_mismatch("vec dot", &a.len().to_string(), &b.len().to_string()).into());
+    }
+    Ok(Literal::Int(checked_dot("vec dot", a.into_iter(), b.into_iter())?))
+  }; a:list, b:list);
+  add_map!("vec scale", {
+    let scaled = int_vector("vec scale", 0, &v)?
+      .into_iter()
+      .map(|x| x.checked_mul(k).map(Literal::Int).ok_or(messages::overflow("vec scale")))
+      .collect::<Result<_, _>>()?;
+    Ok(Literal::List(Rc::new(scaled)))
+  }; v:list, k:int);
+  add_map!("dec", {
+    match &a {
+      Literal::Int(i) => Ok(Literal::Decimal(BigDecimal::from(*i))),
+      Literal::String(s) => Ok(Literal::Decimal(
+        BigDecimal::from_str(s.trim()).map_err(|_| messages::invalid_decimal("dec", s))?,
+      )),
+      Literal::Decimal(_) => Ok(a),
+      _ => Err(type_error("dec", 0, &a, "int, str or dec")),
+    }
+  }; a:any);
+  add_map!("dec add", {Ok(Literal::Decimal(a + b))}; a:dec, b:dec);
+  add_map!("dec sub", {Ok(Literal::Decimal(a - b))}; a:dec, b:dec);
+  add_map!("dec mul", {Ok(Literal::Decimal(a * b))}; a:dec, b:dec);
+  add_map!("dec div", {
+    if b.is_zero() {
+      return Err(messages::division_by_zero("dec div").into());
+    }
+    Ok(Literal::Decimal(a / b))
+  }; a:dec, b:dec);
+  add_map!("dec <", {Ok(Literal::Boolean(a < b))}; a:dec, b:dec);
+  add_map!("dec round", {
+    let mode = match mode.as_str() {
+      "up" => RoundingMode::Up,
+      "down" => RoundingMode::Down,
+      "ceiling" => RoundingMode::Ceiling,
+      "floor" => RoundingMode::Floor,
+      "half up" => RoundingMode::HalfUp,
+      "half down" => RoundingMode::HalfDown,
+      "half even" => RoundingMode::HalfEven,
+      _ => return Err(messages::unknown_rounding_mode("dec round", &mode).into()),
+    };
+    Ok(Literal::Decimal(a.with_scale_round(scale, mode)))
+  }; a:dec, scale:int, mode:str);
   add_map!("strcat", {Ok(Literal::String(format!("{}{}", a, b)))}; a:str, b:str);
   add_map!("to str", {Ok(Literal::String(a.to_string()))}; a:any);
   add_map!("str to int", {
     Ok(Literal::Int(a.parse::<i64>().map_err(|e|e.to_string())?))
   }; a:str);
+  add_map!("int to str base", {
+    let radix = radix("int to str base", base)?;
+    let mut n = a.unsigned_abs();
+    let mut digits = vec![];
+    loop {
+      digits.push(std::char::from_digit((n % radix as u64) as u32, radix).unwrap());
+      n /= radix as u64;
+      if n == 0 {
+        break;
+      }
+    }
+    if a < 0 {
+      digits.push('-');
+    }
+    Ok(Literal::String(digits.into_iter().rev().collect()))
+  }; a:int, base:int);
+  add_map!("str to int base", {
+    let radix = radix("str to int base", base)?;
+    Ok(Literal::Int(i64::from_str_radix(&a, radix).map_err(|e|e.to_string())?))
+  }; a:str, base:int);
+  add_map!("bool to int", { Ok(Literal::Int(i64::from(a))) }; a:boolean);
+  add_map!("int to bool", { Ok(Literal::Boolean(a != 0)) }; a:int);
   add_map!("get", {exec_env.get_var(&name)}, exec_env, _args; name:str);
   add_map!("defset", {
-    exec_env.defset_var(&name, &from);
+    exec_env.defset_var(&name, &from)?;
     Ok(Literal::Void)
   }, exec_env, _args; name:str, from:any);
+  add_map!("defconst", {
+    exec_env.def_const(&name, &from)?;
+    Ok(Literal::Void)
+  }, exec_env, _args; name:str, from:any);
+  add_map!("defglobal", {
+    exec_env.defset_global(&name, &from)?;
+    Ok(Literal::Void)
+  }, exec_env, _args; name:str, from:any);
+  add_map!("unpack", {
+    let names = param_names("unpack", &names)?;
+    if names.len() != list.len() {
+      return Err(messages::unpack_length(names.len(), list.len()).into());
+    }
+    for (name, value) in names.iter().zip(list.iter()) {
+      exec_env.defset_var(name, value)?;
+    }
+    Ok(Literal::Void)
+  }, exec_env, _args; list:list, names:list);
+  add_map!("getglobal", { exec_env.get_global(&name) }, exec_env, _args; name:str);
+  add_map!("type of", { Ok(Literal::String(a.type_name().to_owned())) }; a:any);
+  add_map!("is int", { Ok(Literal::Boolean(matches!(a, Literal::Int(_)))) }; a:any);
+  add_map!("is str", { Ok(Literal::Boolean(matches!(a, Literal::String(_)))) }; a:any);
+  add_map!("is boolean", { Ok(Literal::Boolean(matches!(a, Literal::Boolean(_)))) }; a:any);
+  add_map!("is block", { Ok(Literal::Boolean(matches!(a, Literal::Block(_)))) }; a:any);
+  add_map!("is list", { Ok(Literal::Boolean(matches!(a, Literal::List(_)))) }; a:any);
+  add_map!("is array", { Ok(Literal::Boolean(matches!(a, Literal::Array(_)))) }; a:any);
+  add_map!("is sb", { Ok(Literal::Boolean(matches!(a, Literal::StringBuilder(_)))) }; a:any);
+  add_map!("is dec", { Ok(Literal::Boolean(matches!(a, Literal::Decimal(_)))) }; a:any);
+  add_map!("is void", { Ok(Literal::Boolean(matches!(a, Literal::Void))) }; a:any);
+  add_map!("or default", {
+    Ok(if let Literal::Void = a { default } else { a })
+  }; a:any, default:any);
+  add_map!("defined?", {
+    let defined = matches!(exec_env.lookup(&name), Some(ProcedureOrVar::Var(_) | ProcedureOrVar::Const(_)));
+    Ok(Literal::Boolean(defined))
+  }, exec_env, _args; name:str);
+  add_map!("proc exists", {
+    let exists = matches!(
+      exec_env.lookup(&name),
+      Some(ProcedureOrVar::FnProcedure(_) | ProcedureOrVar::ClosureProcedure(_) | ProcedureOrVar::BlockProcedure(_))
+    );
+    Ok(Literal::Boolean(exists))
+  }, exec_env, _args; name:str);
+  add_map!("undef", {
+    exec_env.undef(&name)?;
+    Ok(Literal::Void)
+  }, exec_env, _args; name:str);
   add_map!("set", {
     exec_env.set_var(&name, &from)?;
     Ok(Literal::Void)
@@ -167,13 +560,92 @@ pub fn predefined_procs() -> HashMap<String, ProcedureOrVar> {
     exec_env.print(a.to_string() + "\n");
     Ok(Literal::Void)
   }, exec_env, args; a:any);
+  add_map!("term clear", {
+    exec_env.print_control("\x1b[2J\x1b[H");
+    Ok(Literal::Void)
+  }, exec_env, args;);
+  add_map!("term goto", {
+    if x < 0 {
+      return Err(type_error("term goto", 0, &Literal::Int(x), "non-negative int"));
+    }
+    if y < 0 {
+      return Err(type_error("term goto", 1, &Literal::Int(y), "non-negative int"));
+    }
+    exec_env.print_control(&format!("\x1b[{};{}H", y + 1, x + 1));
+    Ok(Literal::Void)
+  }, exec_env, args; x:int, y:int);
+  add_map!("term color", {
+    let code = match color.as_str() {
+      "reset" => 0,
+      "black" => 30,
+      "red" => 31,
+      "green" => 32,
+      "yellow" => 33,
+      "blue" => 34,
+      "magenta" => 35,
+      "cyan" => 36,
+      "white" => 37,
+      _ => return Err(type_error("term color", 0, &Literal::String(color), "a color name")),
+    };
+    exec_env.print_control(&format!("\x1b[{}m", code));
+    Ok(Literal::Void)
+  }, exec_env, args; color:str);
+  add_map!("eprint", {
+    exec_env.eprint(a.to_string());
+    Ok(Literal::Void)
+  }, exec_env, args; a:any);
+  add_map!("eval", { exec_env.eval(&source) }, exec_env, args; source:str);
+  add_map!("scope names", {
+    let mut names: Vec<String> = exec_env.scope_names().concat();
+    names.sort();
+    names.dedup();
+    Ok(Literal::List(Rc::new(names.into_iter().map(Literal::String).collect())))
+  }, exec_env, args;);
+  add_map!("scope names by level", {
+    let levels = exec_env.scope_names().into_iter().map(|names| {
+      Literal::List(Rc::new(names.into_iter().map(Literal::String).collect()))
+    });
+    Ok(Literal::List(Rc::new(levels.collect())))
+  }, exec_env, args;);
+  add_map!("inspect", {
+    exec_env.print(a.inspect());
+    Ok(a)
+  }, exec_env, args; a:any);
+  add_map!("log debug", {
+    exec_env.log(LogLevel::Debug, || a.to_string());
+    Ok(Literal::Void)
+  }, exec_env, args; a:any);
+  add_map!("log info", {
+    exec_env.log(LogLevel::Info, || a.to_string());
+    Ok(Literal::Void)
+  }, exec_env, args; a:any);
+  add_map!("log warn", {
+    exec_env.log(LogLevel::Warn, || a.to_string());
+    Ok(Literal::Void)
+  }, exec_env, args; a:any);
+  add_map!("log error", {
+    exec_env.log(LogLevel::Error, || a.to_string());
+    Ok(Literal::Void)
+  }, exec_env, args; a:any);
+  add_map!("eprintln", {
+    exec_env.eprint(a.to_string() + "\n");
+    Ok(Literal::Void)
+  }, exec_env, args; a:any);
   add_map!("read line", { Ok(Literal::String(exec_env.read_line())) }, exec_env, args;);
+  add_map!("prompt", {
+    exec_env.print(message.to_string());
+    Ok(Literal::String(exec_env.read_line()))
+  }, exec_env, args; message:any);
+  add_map!("read key", { Ok(Literal::String(exec_env.read_key()?)) }, exec_env, args;);
+  add_map!("program args", {
+    Ok(Literal::List(Rc::new(exec_env.program_args().iter().map(|arg| Literal::String(arg.clone())).collect())))
+  }, exec_env, args;);
 
   add_map!("split str", {
-    Ok(Literal::List(origin.split(&spliter).filter(|str| !str.is_empty()).map(|str|Literal::String(str.to_owned())).collect()))
+    Ok(Literal::List(Rc::new(origin.split(&spliter).filter(|str| !str.is_empty()).map(|str|Literal::String(str.to_owned())).collect())))
   }; origin: str, spliter: str);
   add_map!("str to bytes", {
-    Ok(Literal::List(string.as_bytes().iter().map(|b|Literal::Int((*b).into())).collect()))
+    Ok(Literal::List(Rc::new(string.as_bytes().iter().map(|b|Literal::Int((*b).into())).collect())))
   }; string:str);
   add_map!("bytes to str", {
     let mut data = vec![];
@@ -181,35 +653,296 @@ pub fn predefined_procs() -> HashMap<String, ProcedureOrVar> {
       if let Literal::Int(b) = byte {
         data.push(u8::try_from(b.to_owned()).map_err(|e| e.to_string())?); 
       } else {
-        return Err(list_type_error_msg("bytes to str", index, 0, byte, "int").into());
+        return Err(list_type_error("bytes to str", index, 0, byte, "int"));
       }
     }
     Ok(Literal::String(String::from_utf8_lossy(&data).to_string()))
   }; bytes:list);
+  add_map!("chr", {
+    let c = u32::try_from(code).ok().and_then(char::from_u32);
+    let c = c.ok_or_else(|| type_error("chr", 0, &Literal::Int(code), "a Unicode code point"))?;
+    Ok(Literal::String(c.to_string()))
+  }; code:int);
+  add_map!("ord", {
+    let c = string.chars().next().ok_or_else(|| type_error("ord", 0, &Literal::String(string.clone()), "non-empty str"))?;
+    Ok(Literal::Int(i64::from(u32::from(c))))
+  }; string:str);
+  add_map!("chars", {
+    Ok(Literal::List(Rc::new(string.chars().map(|c| Literal::String(c.to_string())).collect())))
+  }; string:str);
+  add_map!("str cmp", { Ok(Literal::Int(a.cmp(&b) as i64)) }; a:str, b:str);
+  add_map!("str less", { Ok(Literal::Boolean(a < b)) }; a:str, b:str);
+  add_map!("str eq ignorecase", { Ok(Literal::Boolean(a.to_lowercase() == b.to_lowercase())) }; a:str, b:str);
+  add_map!("list dir", {
+    Ok(Literal::List(Rc::new(exec_env.list_dir(&path)?.into_iter().map(Literal::String).collect())))
+  }, exec_env, args; path:str);
+  add_map!("mkdir", {
+    exec_env.create_dir(&path)?;
+    Ok(Literal::Void)
+  }, exec_env, args; path:str);
+  add_map!("exists", { Ok(Literal::Boolean(exec_env.exists(&path)?)) }, exec_env, args; path:str);
+  add_map!("remove file", {
+    exec_env.remove_file(&path)?;
+    Ok(Literal::Void)
+  }, exec_env, args; path:str);
+  add_map!("copy file", {
+    exec_env.copy_file(&from, &to)?;
+    Ok(Literal::Void)
+  }, exec_env, args; from:str, to:str);
+  add_map!("cwd", { Ok(Literal::String(exec_env.cwd())) }, exec_env, args;);
+  add_map!("cd", {
+    exec_env.cd(&path)?;
+    Ok(Literal::Void)
+  }, exec_env, args; path:str);
+  add_map!("temp file", { Ok(Literal::String(exec_env.create_temp(false)?)) }, exec_env, args;);
+  add_map!("temp dir", { Ok(Literal::String(exec_env.create_temp(true)?)) }, exec_env, args;);
+  add_map!("open lines", { Ok(Literal::Int(exec_env.open_lines(&path)?)) }, exec_env, args; path:str);
+  add_map!("next line", {
+    Ok(exec_env.next_line(handle)?.map_or(Literal::Void, Literal::String))
+  }, exec_env, args; handle:int);
+  add_map!("close", {
+    exec_env.close_lines(handle)?;
+    Ok(Literal::Void)
+  }, exec_env, args; handle:int);
+  add_map!("http serve", {
+    let port = u16::try_from(port).map_err(|_| messages::port_range(port))?;
+    let mut listener = exec_env.http_listen(port)?;
+    let empty = |status| HttpResponse { status, body: String::new() };
+    loop {
+      let request = exec_env.next_http_request(listener.as_mut())?;
+      let args = [request.method, request.path, request.body].map(Literal::String);
+      match handler.call(exec_env, "http serve", &args) {
+        Ok(result) => {
+          let Some(response) = http_response(&result) else {
+            listener.respond(empty(500));
+            return Err(block_type_error("http serve", 1, &result, "str or list of status and str"));
+          };
+          listener.respond(response);
+        }
+        // break・continue・return で抜けたリクエストには空の本文を返す。return はそのまま外へ伝える
+        Err(err) => match err.control() {
+          Some(ControlFlow::Return) => {
+            listener.respond(empty(200));
+            return Err(err);
+          }
+          Some(flow) => {
+            listener.respond(empty(200));
+            if *flow == ControlFlow::Break {
+              break;
+            }
+          }
+          None => {
+            listener.respond(empty(500));
+            return Err(err);
+          }
+        },
+      }
+    }
+    Ok(Literal::Void)
+  }, exec_env, args; port:int, handler:block);
+  add_map!("store open", { Ok(Literal::Int(exec_env.store_open(&path)?)) }, exec_env, args; path:str);
+  add_map!("store get", { exec_env.store_get(handle, &key) }, exec_env, args; handle:int, key:str);
+  add_map!("store set", {
+    exec_env.store_set(handle, &key, value)?;
+    Ok(Literal::Void)
+  }, exec_env, args; handle:int, key:str, value:any);
+  add_map!("store keys", {
+    let keys = exec_env.store_keys(handle)?;
+    Ok(Literal::List(Rc::new(keys.into_iter().map(Literal::String).collect())))
+  }, exec_env, args; handle:int);
+  add_map!("random int", {
+    if min > max {
+      return Err(messages::random_range(min, max).into());
+    }
+    Ok(Literal::Int(exec_env.random_int(min, max)))
+  }, exec_env, _args; min:int, max:int);
+  add_map!("random seed", {
+    // 負の種も使えるよう、ビット列をそのまま u64 として使う
+    exec_env.seed_random(seed as u64);
+    Ok(Literal::Void)
+  }, exec_env, _args; seed:int);
+  add_map!("uuid v4", {
+    // 版 (4) と variant (10) のビットを立てる
+    let bits = exec_env.random_u128() & !(0xf << 76) & !(0b11 << 62) | (0x4 << 76) | (0b10 << 62);
+    let hex = format!("{bits:032x}");
+    Ok(Literal::String(format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])))
+  }, exec_env, args;);
+  add_map!("hash", {
+    let mut encoded = vec![];
+    hash_encode(&a, &mut encoded);
+    let mut head = [0; 8];
+    head.copy_from_slice(&Sha256::digest(&encoded)[..8]);
+    Ok(Literal::Int(i64::from_le_bytes(head)))
+  }; a:any);
+  add_map!("sha256", { Ok(Literal::String(to_hex(&Sha256::digest(bytes_arg("sha256", 0, &data)?)))) }; data:any);
+  add_map!("md5", { Ok(Literal::String(to_hex(&Md5::digest(bytes_arg("md5", 0, &data)?)))) }; data:any);
+  add_map!("literal to bytes", {
+    let bytes = a.to_bytes().map_err(|value| type_error("literal to bytes", 0, &value, "int, str, boolean, list or void"))?;
+    Ok(Literal::List(Rc::new(bytes.into_iter().map(|b| Literal::Int(b.into())).collect())))
+  }; a:any);
+  add_map!("bytes to literal", {
+    Ok(Literal::from_bytes(&bytes_arg("bytes to literal", 0, &bytes)?).map_err(messages::invalid_literal_bytes)?)
+  }; bytes:any);
+  add_map!("base64 encode", { Ok(Literal::String(BASE64.encode(bytes_arg("base64 encode", 0, &data)?))) }; data:any);
+  add_map!("base64 decode", {
+    let bytes = BASE64.decode(&string).map_err(|_| messages::invalid_base64("base64 decode", &string))?;
+    Ok(Literal::String(String::from_utf8(bytes).map_err(|_| messages::not_utf8("base64 decode"))?))
+  }; string:str);
+  add_map!("base64 decode bytes", {
+    let bytes = BASE64.decode(&string).map_err(|_| messages::invalid_base64("base64 decode bytes", &string))?;
+    Ok(Literal::List(Rc::new(bytes.into_iter().map(|b| Literal::Int(b.into())).collect())))
+  }; string:str);
+  add_map!("url encode", { Ok(Literal::String(utf8_percent_encode(&string, URL_ENCODE_SET).to_string())) }; string:str);
+  add_map!("url decode", {
+    Ok(Literal::String(percent_decode_str(&string).decode_utf8().map_err(|err| err.to_string())?.into_owned()))
+  }; string:str);
+  add_map!("display width", {
+    Ok(Literal::Int(i64::try_from(string.width()).map_err(|err| err.to_string())?))
+  }; string:str);
+  add_map!("nfc normalize", { Ok(Literal::String(string.nfc().collect())) }; string:str);
+  add_map!("grapheme count", {
+    Ok(Literal::Int(i64::try_from(string.graphemes(true).count()).map_err(|err| err.to_string())?))
+  }; string:str);
+  add_map!("sb new", { Ok(Literal::StringBuilder(Rc::new(RefCell::new(String::new())))) };);
+  add_map!("sb push", {
+    sb.borrow_mut().push_str(&string);
+    Ok(Literal::Void)
+  }; sb:sb, string:str);
+  add_map!("sb to str", {
+    let string = sb.borrow().clone();
+    Ok(Literal::String(string))
+  }; sb:sb);
   add_map!(r"\n", {Ok(Literal::String("\n".to_owned()))};);
   add_map!(r"\r", {Ok(Literal::String("\r".to_owned()))};);
   add_map!(r"\t", {Ok(Literal::String("\t".to_owned()))};);
   add_map!(r"\0", {Ok(Literal::String("\0".to_owned()))};);
   add_map!("listing", {
-    Ok(Literal::List(list))
+    Ok(Literal::List(Rc::new(list)))
   }, _exec_env, args;;list:list);
   add_map!("[]", {
     let index_usize:usize = usize::try_from( index).map_err(|e|e.to_string())?;
-    list.get(index_usize).cloned().ok_or(format!("Index ({}) out of range. (Length = {})", index, list.len()).into())
+    list.get(index_usize).cloned().ok_or(ProcedureError::OtherError(
+      ErrorCode::IndexOutOfRange,
+      messages::index_out_of_range(index, list.len()),
+    ))
   };list:list, index:int);
+  add_map!("contains", { Ok(Literal::Boolean(list.contains(&item))) }; list:list, item:any);
+  add_map!("index of item", {
+    match list.iter().position(|l| *l == item) {
+      Some(index) => Ok(Literal::Int(i64::try_from(index).map_err(|err| err.to_string())?)),
+      None => Ok(Literal::Int(-1)),
+    }
+  }; list:list, item:any);
+  add_map!("unique", {
+    let mut unique: Vec<Literal> = vec![];
+    for l in list.iter() {
+      if !unique.contains(l) {
+        unique.push(l.clone());
+      }
+    }
+    Ok(Literal::List(Rc::new(unique)))
+  }; list:list);
+  add_map!("zip", {
+    Ok(Literal::List(Rc::new(
+      a.iter().zip(b.iter()).map(|(x, y)| Literal::List(Rc::new(vec![x.clone(), y.clone()]))).collect(),
+    )))
+  }; a:list, b:list);
+  add_map!("flatten", {
+    let mut flat = vec![];
+    for l in list.iter() {
+      match l {
+        Literal::List(inner) => flat.extend(inner.iter().cloned()),
+        _ => flat.push(l.clone()),
+      }
+    }
+    Ok(Literal::List(Rc::new(flat)))
+  }; list:list);
   add_map!("len", {
     Ok(Literal::Int(i64::try_from(list.len()).map_err(|err|err.to_string())?))
   };list:list);
+  add_map!("array new", {
+    let size = usize::try_from(size).map_err(|_| type_error("array new", 0, &Literal::Int(size), "non-negative int"))?;
+    Ok(Literal::Array(Rc::new(RefCell::new(vec![init; size]))))
+  }; size:int, init:any);
+  add_map!("array get", {
+    let array = array.borrow();
+    array_index(index, array.len()).map(|i| array[i].clone())
+  }; array:array, index:int);
+  add_map!("array set", {
+    let mut array = array.borrow_mut();
+    let i = array_index(index, array.len())?;
+    array[i] = value;
+    Ok(Literal::Void)
+  }; array:array, index:int, value:any);
+  add_map!("array push", {
+    array.borrow_mut().push(value);
+    Ok(Literal::Void)
+  }; array:array, value:any);
+  add_map!("array len", {
+    let len = array.borrow().len();
+    Ok(Literal::Int(i64::try_from(len).map_err(|err|err.to_string())?))
+  }; array:array);
+  add_map!("array to list", {
+    let list = array.borrow().clone();
+    Ok(Literal::List(Rc::new(list)))
+  }; array:array);
+  add_map!("list to array", { Ok(Literal::Array(Rc::new(RefCell::new(list.to_vec())))) }; list:list);
 
   add_map!("seq", {
     Ok(list.last().unwrap_or(&Literal::Void).clone())
   }, _exec_env, args;;list:list);
+  add_map!("collect", {
+    Ok(Literal::List(Rc::new(list)))
+  }, _exec_env, args;;list:list);
   add_map!("for", {
     for i in 0..times {
-      child.execute_without_scope(exec_env, |exec_env|{exec_env.defset_var_into_last_scope(&var, &Literal::Int(i))})?;
+      // 本体を実行するために作ったばかりのスコープなので、定数とぶつかることは無い
+      let result = child.execute_without_scope(exec_env, |exec_env|{let _ = exec_env.defset_var_into_last_scope(&var, &Literal::Int(i));});
+      if loop_control(result)? == Some(ControlFlow::Break) {
+        break;
+      }
     }
     Ok(Literal::Void)
   }, exec_env, args; times:int, var:str, child:block);
+  add_map!("foreach", {
+    let items = match &items {
+      Literal::List(list) => list.to_vec(),
+      Literal::Array(array) => array.borrow().clone(),
+      _ => return Err(type_error("foreach", 0, &items, "list or array")),
+    };
+    for (index, item) in items.into_iter().enumerate() {
+      let index = Literal::Int(i64::try_from(index).map_err(|err| err.to_string())?);
+      if loop_control(child.call(exec_env, "foreach", &[item, index]))? == Some(ControlFlow::Break) {
+        break;
+      }
+    }
+    Ok(Literal::Void)
+  }, exec_env, args; items:any, child:block);
+  add_map!("let", {
+    // 名前と値の組が続き、最後に本体のブロックが来る
+    let Some((body, pairs)) = list.split_last() else {
+      return Err(ProcedureError::OtherError(ErrorCode::ArgCountMismatch, messages::let_args(0)));
+    };
+    if pairs.len() % 2 != 0 {
+      return Err(ProcedureError::OtherError(ErrorCode::ArgCountMismatch, messages::let_args(list.len())));
+    }
+    let Literal::Block(body) = body else {
+      return Err(type_error("let", pairs.len(), body, "block"));
+    };
+    let mut bindings = vec![];
+    for (index, pair) in pairs.chunks(2).enumerate() {
+      let Literal::String(name) = &pair[0] else {
+        return Err(type_error("let", index * 2, &pair[0], "str"));
+      };
+      bindings.push((name, &pair[1]));
+    }
+    body.execute_without_scope(exec_env, |exec_env| {
+      // 本体を実行するために作ったばかりのスコープなので、定数とぶつかることは無い
+      for (name, value) in bindings {
+        let _ = exec_env.defset_var_into_last_scope(name, value);
+      }
+    }).map_err(|err| err.into())
+  }, exec_env, args;; list:list);
   add_map!("while", {
     loop {
       let cond_res = {
@@ -218,17 +951,25 @@ pub fn predefined_procs() -> HashMap<String, ProcedureOrVar> {
             if let Literal::Boolean(res_bool) = res {
               res_bool
             } else {
-              return Err(block_type_error_msg("while", 0, &res, "boolean").into());
+              return Err(block_type_error("while", 0, &res, "boolean"));
             }
           },
           Err(err) => {return Err(err.into());}
         }
       };
       if !cond_res {break;} 
-      child.execute_without_scope(exec_env, |_|{})?;
+      if loop_control(child.execute_without_scope(exec_env, |_|{}))? == Some(ControlFlow::Break) {
+        break;
+      }
     }
     Ok(Literal::Void)
   }, exec_env, args; cond:block, child:block);
+  add_map!("break", { Err(ProcedureError::Control(ControlFlow::Break)) };);
+  add_map!("continue", { Err(ProcedureError::Control(ControlFlow::Continue)) };);
+  add_map!("return", {
+    exec_env.set_returning(value);
+    Err(ProcedureError::Control(ControlFlow::Return))
+  }, exec_env, args; value:any);
   add_map!("if0", {
     Ok(if let Literal::Int(0) = cond {
       then
@@ -252,18 +993,96 @@ pub fn predefined_procs() -> HashMap<String, ProcedureOrVar> {
     )
   }; cond:boolean, then:any, els:any);
   add_map!("defproc", {
-    exec_env.def_proc(&name, &block);
+    exec_env.def_proc(&name, &block)?;
     Ok(Literal::Void)
   }, exec_env, args; name: str, block:block);
+  add_map!("defproc named", {
+    let params = Params { names: param_names("defproc named", &params)?, ..Params::default() };
+    exec_env.def_proc(&name, &BlockLiteral { params: Rc::new(params), ..block })?;
+    Ok(Literal::Void)
+  }, exec_env, args; name:str, params:list, block:block);
+  add_map!("defproc defaults", {
+    let names = param_names("defproc defaults", &params)?;
+    if defaults.len() > names.len() {
+      let expected = "a list no longer than $arg[1]";
+      return Err(type_error("defproc defaults", 2, &Literal::List(defaults), expected));
+    }
+    let params = Params { names, defaults: defaults.to_vec(), ..Params::default() };
+    exec_env.def_proc(&name, &BlockLiteral { params: Rc::new(params), ..block })?;
+    Ok(Literal::Void)
+  }, exec_env, args; name:str, params:list, defaults:list, block:block);
+  add_map!("defproc rest", {
+    let params = Params { names: param_names("defproc rest", &params)?, rest: Some(rest), ..Params::default() };
+    exec_env.def_proc(&name, &BlockLiteral { params: Rc::new(params), ..block })?;
+    Ok(Literal::Void)
+  }, exec_env, args; name:str, params:list, rest:str, block:block);
+  add_map!("bind args", {
+    let mut params = (*block.params).clone();
+    params.bound.extend(list);
+    Ok(Literal::Block(BlockLiteral { params: Rc::new(params), ..block }))
+  }, exec_env, args; block:block; list:list);
+  add_map!("compose", {
+    let mut then = block.then.to_vec();
+    for (index, l) in list.iter().enumerate() {
+      let Literal::Block(next) = l else {
+        return Err(type_error("compose", index + 1, l, "block"));
+      };
+      then.push(next.clone());
+    }
+    Ok(Literal::Block(BlockLiteral { then: Rc::new(then), ..block }))
+  }, exec_env, args; block:block; list:list);
+  add_map!("block new", {
+    let args = child_blocks("block new", 1, &list)?;
+    let block = Block { proc_name: name.into(), args, quote: QuoteStyle::None };
+    Ok(Literal::Block(BlockLiteral {
+      scopes: vec![],
+      block: Rc::new(block),
+      params: Rc::default(),
+      then: Rc::default(),
+      memo: None,
+    }))
+  }; name:str, list:list);
+  add_map!("block name", {
+    Ok(Literal::String(block.block.proc_name.to_string()))
+  }; block:block);
+  add_map!("block args", {
+    let args = block
+      .block
+      .args
+      .iter()
+      .map(|(_, arg)| {
+        Literal::Block(BlockLiteral {
+          scopes: block.scopes.clone(),
+          block: Rc::new((**arg).clone()),
+          params: Rc::default(),
+          then: Rc::default(),
+          memo: None,
+        })
+      })
+      .collect();
+    Ok(Literal::List(Rc::new(args)))
+  }; block:block);
+  add_map!("block with args", {
+    let args = child_blocks("block with args", 1, &list)?;
+    let replaced = Block { args, ..(*block.block).clone() };
+    Ok(Literal::Block(BlockLiteral { block: Rc::new(replaced), ..block }))
+  }; block:block, list:list);
+  add_map!("memo", {
+    Ok(Literal::Block(BlockLiteral { memo: Some(MemoTable::default()), ..block }))
+  }; block:block);
   add_map!("exec", {
-    block.execute_without_scope(exec_env, |exec_env| exec_env.defset_args(&list)).map_err(|err|err.into())
+    block.call(exec_env, "exec", &list)
   }, exec_env, args; block:block; list:list);
   add_map!("export", {
     exec_env.export(&name)?;
     Ok(Literal::Void)
   }, exec_env, args; name:str );
+  add_map!("export all", {
+    exec_env.export_all();
+    Ok(Literal::Void)
+  }, exec_env, args;);
   add_map!("reexport", {
-    exec_env.reexport();
+    exec_env.reexport()?;
 
     Ok(child)
   }, exec_env, args; child: any);
@@ -274,15 +1093,98 @@ pub fn predefined_procs() -> HashMap<String, ProcedureOrVar> {
       if let Literal::String(s) = l {
         args.push( s.to_owned()); 
       } else {
-        return Err(list_type_error_msg("cmd", index, 1, l, "str").into());
+        return Err(list_type_error("cmd", index, 1, l, "str"));
       }
     }
-    exec_env.cmd(cmd, args).map(Literal::String).map_err(|err|err.into())
+    let request = CmdRequest { program: cmd, args, shell: true, ..CmdRequest::default() };
+    exec_env.cmd(request).map(|out| Literal::String(out.stdout))
+  }, exec_env, args; cmd:str; list:list );
+  add_map!("cmd argv", {
+    let request = CmdRequest { program: cmd, args: str_args("cmd argv", 1, &list)?, ..CmdRequest::default() };
+    exec_env.cmd(request).map(|out| Literal::String(out.stdout))
+  }, exec_env, args; cmd:str; list:list );
+  add_map!("cmd in", {
+    let request = CmdRequest {
+      program: cmd,
+      args: str_args("cmd in", 2, &list)?,
+      dir: Some(dir),
+      ..CmdRequest::default()
+    };
+    exec_env.cmd(request).map(|out| Literal::String(out.stdout))
+  }, exec_env, args; dir:str, cmd:str; list:list );
+  add_map!("cmd with env", {
+    let mut vars = vec![];
+    for (index, l) in env.iter().enumerate() {
+      let Some((key, value)) = (match l {
+        Literal::String(s) => s.split_once('='),
+        _ => None,
+      }) else {
+        return Err(list_type_error("cmd with env", 0, index, l, "\"KEY=VALUE\" str"));
+      };
+      vars.push((key.to_owned(), value.to_owned()));
+    }
+    let request = CmdRequest {
+      program: cmd,
+      args: str_args("cmd with env", 2, &list)?,
+      env: vars,
+      ..CmdRequest::default()
+    };
+    exec_env.cmd(request).map(|out| Literal::String(out.stdout))
+  }, exec_env, args; env:list, cmd:str; list:list );
+  add_map!("cmd timeout", {
+    let Ok(millis) = u64::try_from(millis) else {
+      return Err(type_error("cmd timeout", 0, &Literal::Int(millis), "non-negative int"));
+    };
+    let request = CmdRequest {
+      program: cmd,
+      args: str_args("cmd timeout", 2, &list)?,
+      shell: true,
+      timeout: Some(Duration::from_millis(millis)),
+      ..CmdRequest::default()
+    };
+    exec_env.cmd(request).map(|out| Literal::String(out.stdout))
+  }, exec_env, args; millis:int, cmd:str; list:list );
+  add_map!("spawn cmd", {
+    let request = CmdRequest { program: cmd, args: str_args("spawn cmd", 1, &list)?, ..CmdRequest::default() };
+    exec_env.spawn(request).map(Literal::Int)
+  }, exec_env, args; cmd:str; list:list );
+  add_map!("wait", { exec_env.wait(handle).map(Literal::Int) }, exec_env, args; handle:int);
+  add_map!("try wait", {
+    Ok(exec_env.try_wait(handle)?.map_or(Literal::Void, Literal::Int))
+  }, exec_env, args; handle:int);
+  add_map!("kill", {
+    exec_env.kill(handle)?;
+    Ok(Literal::Void)
+  }, exec_env, args; handle:int);
+  add_map!("cmd full", {
+    let request = CmdRequest {
+      program: cmd,
+      args: str_args("cmd full", 1, &list)?,
+      shell: true,
+      ..CmdRequest::default()
+    };
+    let out = exec_env.cmd(request)?;
+    Ok(Literal::List(Rc::new(vec![
+      Literal::Int(out.status),
+      Literal::String(out.stdout),
+      Literal::String(out.stderr),
+    ])))
   }, exec_env, args; cmd:str; list:list );
 
   add_map!("include", {
     exec_env.include(path)
   }, exec_env, args; path:str);
+  add_map!("include only", {
+    let mut names = vec![];
+    for (index, l) in list.iter().enumerate() {
+      if let Literal::String(s) = l {
+        names.push(s.to_owned());
+      } else {
+        return Err(type_error("include only", index + 1, l, "str"));
+      }
+    }
+    exec_env.include_only(path, &names)
+  }, exec_env, args; path:str; list:list);
 
   map
 }
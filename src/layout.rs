@@ -0,0 +1,150 @@
+//! [`Block`] からボックスの図のソースを組み立てる。
+
+use unicode_width::UnicodeWidthChar;
+
+use crate::structs::{Block, QuoteStyle};
+
+/// 全角文字の 2 マス目。前のマスの文字が 2 マス分を占めるので、行にするときには取り除く
+const WIDE_PADDING: char = '\0';
+
+/// 引数を左から順に下に並べ、親の下辺のプラグから子の上辺のプラグへまっすぐ辺を下ろす。
+/// 子の部分木は列が重ならないように並べるので、辺同士や枠とぶつかることはない。
+/// 結果を [`crate::compile::compile`] に渡すと元のブロックに戻る。
+pub fn layout(block: &Block) -> Vec<String> {
+  draw(block, true)
+    .cells
+    .into_iter()
+    .map(|line| line.into_iter().filter(|char| *char != WIDE_PADDING).collect::<String>().trim_end().to_owned())
+    .collect()
+}
+
+struct Drawing {
+  cells: Vec<Vec<char>>,
+  width: usize,
+  /// 上辺のブロックプラグの列
+  top_plug: usize,
+}
+
+fn draw(block: &Block, root: bool) -> Drawing {
+  let children: Vec<Drawing> = block.args.iter().map(|(_, arg)| draw(arg, false)).collect();
+
+  // 名前は表示幅で並べ、枠の幅も表示幅で決める
+  let mut name_lines: Vec<Vec<char>> = block
+    .proc_name
+    .lines()
+    .map(|line| {
+      line
+        .chars()
+        .flat_map(|char| {
+          let padding = char.width().unwrap_or(0).saturating_sub(1);
+          std::iter::once(char).chain(std::iter::repeat_n(WIDE_PADDING, padding))
+        })
+        .collect()
+    })
+    .collect();
+  if name_lines.is_empty() {
+    name_lines.push(vec![]);
+  }
+  let text_width = name_lines.iter().map(|line| line.len()).max().unwrap_or(0);
+
+  // 子の部分木を 1 列空けて並べる
+  let mut offsets = vec![];
+  let mut children_width = 0;
+  for child in &children {
+    if !offsets.is_empty() {
+      children_width += 1;
+    }
+    offsets.push(children_width);
+    children_width += child.width;
+  }
+  let plugs: Vec<usize> = children.iter().zip(&offsets).map(|(child, offset)| offset + child.top_plug).collect();
+
+  // 枠は子のプラグをすべて下辺に含み、名前の左右に空白を 1 つずつ置ける幅にする
+  let box_left = plugs.first().map_or(0, |plug| plug - 1);
+  let box_right = plugs.last().map_or(0, |plug| plug + 1).max(box_left + text_width + 3);
+  let box_height = name_lines.len() + 2;
+  let width = children_width.max(box_right + 1);
+  let height = box_height + children.iter().map(|child| child.cells.len()).max().unwrap_or(0);
+  let mut cells = vec![vec![' '; width]; height];
+
+  cells[0][box_left + 1..box_right].fill('─');
+  cells[box_height - 1][box_left + 1..box_right].fill('─');
+  cells[0][box_left] = '┌';
+  cells[0][box_right] = '┐';
+  cells[box_height - 1][box_left] = '└';
+  cells[box_height - 1][box_right] = '┘';
+  for (i, line) in name_lines.iter().enumerate() {
+    cells[i + 1][box_left] = '│';
+    cells[i + 1][box_right] = '│';
+    for (x, char) in line.iter().enumerate() {
+      cells[i + 1][box_left + 2 + x] = *char;
+    }
+  }
+
+  let top_plug = (box_left + box_right) / 2;
+  if !root {
+    cells[0][top_plug] = match block.quote {
+      QuoteStyle::Quote => '•',
+      QuoteStyle::Closure => '/',
+      QuoteStyle::Unquote => '~',
+      QuoteStyle::None => '┴',
+    };
+  }
+  for ((expand, _), plug) in block.args.iter().zip(&plugs) {
+    cells[box_height - 1][*plug] = if *expand { '@' } else { '┬' };
+  }
+
+  for (child, offset) in children.iter().zip(&offsets) {
+    for (y, line) in child.cells.iter().enumerate() {
+      cells[box_height + y][*offset..*offset + child.width].copy_from_slice(line);
+    }
+  }
+
+  Drawing { cells, width, top_plug }
+}
+
+#[cfg(test)]
+mod tests {
+  use unicode_width::UnicodeWidthStr;
+
+  use super::layout;
+  use crate::{compile::compile, structs::BlockBuilder};
+
+  #[test]
+  fn round_trip() {
+    let block = BlockBuilder::proc("seq")
+      .arg(
+        BlockBuilder::proc("defproc")
+          .arg(BlockBuilder::string("f"))
+          .arg(BlockBuilder::proc("+").arg(BlockBuilder::proc("$0")).arg(BlockBuilder::int(1)).quote()),
+      )
+      .arg(BlockBuilder::proc("print").expand_arg(BlockBuilder::proc("a long\nname").closure()))
+      .arg(BlockBuilder::proc(""))
+      .arg(BlockBuilder::proc("*").arg(BlockBuilder::proc("n").unquote()).arg(BlockBuilder::int(2)).quote())
+      .build();
+
+    let lines = layout(&block);
+    assert_eq!(compile(lines.clone()), Ok(block), "{}", lines.join("\n"));
+  }
+
+  #[test]
+  fn wide_names() {
+    let block = BlockBuilder::proc("表示")
+      .arg(BlockBuilder::proc("足す").arg(BlockBuilder::int(1)).arg(BlockBuilder::proc("値\nふたつめ")))
+      .build();
+
+    let lines = layout(&block);
+    assert_eq!(compile(lines.clone()), Ok(block), "{}", lines.join("\n"));
+    // 枠の右端が表示上そろっている
+    assert_eq!(lines[0].width(), lines[1].width());
+    assert_eq!(lines[1].width(), lines[2].width());
+  }
+
+  #[test]
+  fn one_block() {
+    assert_eq!(
+      layout(&BlockBuilder::proc("abc").build()),
+      vec!["┌─────┐", "│ abc │", "└─────┘"]
+    );
+  }
+}
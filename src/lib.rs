@@ -0,0 +1,35 @@
+//! Trees のコンパイラとインタプリタ。
+//! `trees` コマンドはこのライブラリの薄いフロントエンドになっている。
+//!
+//! [`compile::compile`] でソースを [`structs::Block`] にし、[`executor::Interpreter`] で実行する。
+//!
+//! ```
+//! use trees::{compile::compile, executor::Interpreter, structs::Literal};
+//!
+//! let source = "
+//! ┌──────┐
+//! │defset├─┐
+//! └┬─────┘ │
+//! ┌┴──┐ ┌──┴──┐
+//! │\"x\"│ │  *  ├─┐
+//! └───┘ └──┬──┘ │
+//!         ┌┴┐  ┌┴┐
+//!         │6│  │7│
+//!         └─┘  └─┘";
+//! let block = compile(source.lines().map(|line| line.to_owned()).collect()).unwrap();
+//!
+//! let mut interpreter = Interpreter::builder().build();
+//! interpreter.run(&block).unwrap();
+//!
+//! assert_eq!(interpreter.get_var("x"), Some(Literal::Int(42)));
+//! ```
+#![allow(clippy::result_large_err)]
+
+pub mod compile;
+pub mod dot;
+pub mod errors;
+pub mod executor;
+pub mod layout;
+pub mod messages;
+pub mod structs;
+pub mod svg;
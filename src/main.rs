@@ -1,49 +1,475 @@
-use compile::compile;
-use executor::execute;
-use std::{env, fs::File, io::Read, path::PathBuf, rc::Rc};
-use structs::{Block, BlockError, BlockErrorTree};
-
-use crate::structs::BlockResult;
-
-mod compile;
-mod executor;
-mod structs;
+use cli::{parse_args, Command, Options};
+use signal_hook::consts::SIGINT;
+use std::{
+  collections::{HashMap, HashSet},
+  env,
+  fs::{self, File},
+  io::{self, IsTerminal, Read},
+  iter,
+  path::{Path, PathBuf},
+  process::exit,
+  rc::Rc,
+  sync::{atomic::AtomicBool, Arc},
+  thread,
+};
+use trees::{
+  compile::{compile_with_warnings, layout_with_config, normalize_source, CompileConfig, CompileError, CompileWarning},
+  dot::to_dot,
+  errors::ErrorCode,
+  executor::Interpreter,
+  messages::{self, Lang},
+  structs::{Block, BlockBuilder, BlockError, BlockErrorTree, BlockResult, ProcedureOrVar, QuoteStyle},
+  svg::render_svg,
+};
+use unicode_width::UnicodeWidthChar;
+
+mod cli;
+mod editor;
+mod plugin;
+mod project;
 
 fn main() {
-  let args: Vec<String> = env::args().collect();
-  let code_file = &args[1];
+  let args: Vec<String> = env::args().skip(1).collect();
+  let cli = match parse_args(&args) {
+    Ok(cli) => cli,
+    Err(msg) => {
+      messages::set_lang(Lang::from_env());
+      eprintln!("{}", msg);
+      exit(2);
+    }
+  };
+  messages::set_lang(cli.options.lang.unwrap_or_else(Lang::from_env));
+
+  match cli.command {
+    Command::Explain { code } => explain(code.as_deref()),
+    Command::Graph { file } => graph(&file, &cli.options),
+    Command::Render { file } => render(&file, &cli.options),
+    Command::Edit { file } => edit(&file, &cli.options),
+    Command::Build { dir } => build(&dir, cli.options.clone()),
+    Command::Run { file } => {
+      // 実行するファイルの祖先に trees.toml があれば、その設定を使う
+      let mut options = cli.options.clone();
+      let path = env::current_dir().unwrap().join(&file);
+      if let Some(dir) = path.parent().and_then(project::find_project_dir) {
+        if let Err(msg) = project::load_manifest(&dir, &mut options) {
+          eprintln!("{}", msg);
+          exit(1);
+        }
+      }
+      if options.check {
+        check(&file, &options);
+        return;
+      }
+      let handle =
+        thread::Builder::new().stack_size(INTERPRETER_STACK_SIZE).spawn(move || run(&file, &options)).unwrap();
+      if let Err(panic) = handle.join() {
+        std::panic::resume_unwind(panic);
+      }
+    }
+  }
+}
 
+/// 深い再帰でもネイティブのスタックが溢れないよう、インタプリタは大きなスタックを持つスレッドで動かす
+const INTERPRETER_STACK_SIZE: usize = 512 * 1024 * 1024;
+
+fn run(code_file: &str, options: &Options) {
   let path = Rc::new(env::current_dir().unwrap().join(code_file));
-  let block = compile_file(path.to_path_buf()).unwrap();
-  match execute(
-    block,
-    Box::new(move |name| compile_file(name.iter().fold(path.parent().unwrap().to_path_buf(), |a, b| a.join(b)))),
-  ) {
-    Ok(_) => {}
-    Err(err) => print_error(&err),
+  let Some(block) = compile_reporting(&path, options) else {
+    exit(1);
+  };
+  let include_bases = include_bases(&path, options);
+  let mut procs = HashMap::new();
+  for plugin in &options.plugins {
+    if let Err(err) = plugin::load_plugin(plugin, &mut procs) {
+      eprintln!("{}", messages::failed_to_load_plugin(&plugin.to_string_lossy(), &err));
+      exit(1);
+    }
+  }
+  let mut compiled: HashMap<PathBuf, Block> = HashMap::new();
+  let compile_config = options.compile.clone();
+  let mut execute_options = options.execute.clone();
+  execute_options.terminal = io::stdout().is_terminal();
+  execute_options.compile = options.compile.clone();
+  // 1 回目の Ctrl-C では実行中の場所を表示して止まり、入力などを待っていて止まらなければ 2 回目ですぐ終わる
+  let interrupt = Arc::new(AtomicBool::new(false));
+  let _ = signal_hook::flag::register_conditional_shutdown(SIGINT, 130, interrupt.clone());
+  let _ = signal_hook::flag::register(SIGINT, interrupt.clone());
+  let result = Interpreter::builder()
+    .procs(procs)
+    .includer(Box::new(move |name| {
+      let path = resolve_include(&include_bases, name);
+      let key = path.canonicalize().unwrap_or(path);
+      if !compiled.contains_key(&key) {
+        compiled.insert(key.clone(), compile_file(key.clone(), &compile_config)?);
+      }
+      Ok((key.to_string_lossy().to_string(), compiled[&key].clone()))
+    }))
+    .interrupt(interrupt)
+    .options(execute_options)
+    .build()
+    .run(&block);
+  if let Err(err) = result {
+    print_error(&err);
+    if err.code == ErrorCode::Interrupted {
+      exit(130);
+    }
+  }
+}
+
+fn graph(code_file: &str, options: &Options) {
+  let Some(block) = compile_reporting(&env::current_dir().unwrap().join(code_file), options) else {
+    exit(1);
+  };
+  print!("{}", to_dot(&block));
+}
+
+/// trees.toml の設定で、プロジェクトの .tr ファイルをすべてコンパイルして診断を表示する。
+/// 前回エラーが無く、内容も設定も変わっていないファイルは飛ばす。
+fn build(dir: &str, mut options: Options) {
+  let dir = env::current_dir().unwrap().join(dir);
+  let dir = dir.canonicalize().unwrap_or(dir);
+  let manifest = match project::load_manifest(&dir, &mut options) {
+    Ok(manifest) => manifest,
+    Err(msg) => {
+      eprintln!("{}", msg);
+      exit(1);
+    }
+  };
+  let out_dir = dir.join(&manifest.out_dir);
+
+  let mut failed = false;
+  let entry = dir.join(&manifest.entry);
+  if let Err(err) = fs::metadata(&entry) {
+    eprintln!(
+      "{}",
+      messages::failed_to_read(&entry.to_string_lossy(), &err.to_string())
+    );
+    failed = true;
+  }
+
+  let cache = project::read_build_cache(&out_dir);
+  let mut new_cache = HashMap::new();
+  let (mut checked, mut unchanged) = (0, 0);
+  for path in project::source_files(&dir, &out_dir) {
+    let relative = path.strip_prefix(&dir).unwrap_or(&path).to_path_buf();
+    let hash = project::source_hash(&fs::read(&path).unwrap_or_default(), &options);
+    if cache.get(&relative) == Some(&hash) {
+      unchanged += 1;
+      new_cache.insert(relative, hash);
+      continue;
+    }
+    checked += 1;
+    if compile_reporting(&path, &options).is_some() {
+      new_cache.insert(relative, hash);
+    } else {
+      failed = true;
+    }
+  }
+
+  if let Err(err) = project::write_build_cache(&out_dir, &new_cache) {
+    eprintln!(
+      "{}",
+      messages::failed_to_write(&out_dir.to_string_lossy(), &err.to_string())
+    );
+  }
+  eprintln!("{}", messages::build_finished(checked, unchanged));
+  if failed {
+    exit(1);
+  }
+}
+
+/// ファイルが無ければ seq だけの木から始める
+fn edit(code_file: &str, options: &Options) {
+  let path = env::current_dir().unwrap().join(code_file);
+  let root = if path.exists() {
+    let Some(block) = compile_reporting(&path, options) else {
+      exit(1);
+    };
+    block
+  } else {
+    BlockBuilder::proc("seq").build()
+  };
+
+  if let Err(err) = editor::edit(&path, root) {
+    eprintln!(
+      "{}",
+      messages::failed_to_write(&path.to_string_lossy(), &err.to_string())
+    );
+    exit(1);
+  }
+}
+
+fn render(code_file: &str, options: &Options) {
+  let path = env::current_dir().unwrap().join(code_file);
+  let layout = match read_source(&path).map(|lines| layout_with_config(lines, &options.compile)) {
+    Ok(Ok(layout)) => layout,
+    Ok(Err(err)) => {
+      eprintln!(
+        "\n\n{}",
+        messages::compile_error_occurred(err.code().code(), &err.to_string())
+      );
+      exit(1);
+    }
+    Err((code, msg)) => {
+      eprintln!("\n\n{}", messages::compile_error_occurred(code.code(), &msg));
+      exit(1);
+    }
+  };
+
+  let svg = render_svg(&layout);
+  match &options.output {
+    Some(output) => {
+      if let Err(err) = fs::write(output, svg) {
+        eprintln!(
+          "{}",
+          messages::failed_to_write(&output.to_string_lossy(), &err.to_string())
+        );
+        exit(1);
+      }
+    }
+    None => print!("{}", svg),
+  }
+}
+
+/// 実行はせず、ソースと、文字列で直接指定された include 先を辿ってコンパイルし、診断だけを表示する。
+/// 実行時に組み立てるパスの include は辿れない。
+fn check(code_file: &str, options: &Options) {
+  let path = env::current_dir().unwrap().join(code_file);
+  let include_bases = include_bases(&path, options);
+
+  let mut checked = HashSet::new();
+  // (ファイル, include したときのパスの連なり)
+  let mut pending = vec![(path, vec![])];
+  let mut failed = false;
+  while let Some((path, parents)) = pending.pop() {
+    if !checked.insert(path.canonicalize().unwrap_or(path.clone())) {
+      continue;
+    }
+    let Some(block) = compile_reporting(&path, options) else {
+      failed = true;
+      continue;
+    };
+    for include in static_includes(&block) {
+      let mut paths: Vec<String> = parents.clone();
+      paths.push(include.clone());
+      let included = resolve_include(&include_bases, &paths);
+      paths.pop();
+      paths.push(include.rfind('/').map(|index| include[..index].to_owned()).unwrap_or_default());
+      pending.push((included, paths));
+    }
+  }
+
+  if failed {
+    exit(1);
+  }
+}
+
+/// `include` と `include only` の引数のうち、文字列のブロックで直接書かれたパス
+fn static_includes(block: &Block) -> Vec<String> {
+  let mut includes = vec![];
+  let mut stack = vec![block];
+  while let Some(block) = stack.pop() {
+    if &*block.proc_name == "include" || &*block.proc_name == "include only" {
+      if let Some((false, path)) = block.args.first() {
+        let name = &*path.proc_name;
+        if path.quote == QuoteStyle::None && name.len() >= 2 && name.starts_with('"') && name.ends_with('"') {
+          includes.push(name[1..name.len() - 1].to_owned());
+        }
+      }
+    }
+    stack.extend(block.args.iter().rev().map(|(_, arg)| arg.as_ref()));
+  }
+  includes
+}
+
+/// ファイルをコンパイルし、警告とエラーを表示する。エラーなら None
+fn compile_reporting(path: &Path, options: &Options) -> Option<Block> {
+  match read_source(path)
+    .map(|lines| normalize_source(&lines, &options.compile))
+    .map(|lines| (compile_with_warnings(lines.clone(), &options.compile), lines))
+  {
+    Ok((Ok((block, warnings)), _)) => {
+      print_warnings(path, &warnings);
+      Some(block)
+    }
+    Ok((Err(err), lines)) => {
+      eprintln!(
+        "\n\n{}",
+        messages::compile_error_occurred(err.code().code(), &err.to_string())
+      );
+      eprintln!("--> {}", path.to_string_lossy());
+      print_source_annotation(&lines, &err, options.color.enabled());
+      None
+    }
+    Err((code, msg)) => {
+      eprintln!("\n\n{}", messages::compile_error_occurred(code.code(), &msg));
+      None
+    }
+  }
+}
+
+/// include を探すディレクトリ。読み込み元のディレクトリ、-I、TREES_PATH の順
+fn include_bases(path: &Path, options: &Options) -> Vec<PathBuf> {
+  let mut include_bases = vec![path.parent().unwrap().to_path_buf()];
+  include_bases.extend(options.include_paths.iter().cloned());
+  if let Some(trees_path) = env::var_os(TREES_PATH) {
+    include_bases.extend(env::split_paths(&trees_path));
+  }
+  include_bases
+}
+
+/// include の検索パスを追加する環境変数。-I で指定したパスの後に検索する。
+const TREES_PATH: &str = "TREES_PATH";
+
+/// include されたファイルを探す。`bases` を順に調べ、最初に存在したパスを返す。
+/// どこにも無ければ先頭の候補を返す (読み込みエラーはそのパスで報告される)。
+fn resolve_include(bases: &[PathBuf], paths: &[String]) -> PathBuf {
+  let candidates: Vec<PathBuf> = bases.iter().map(|base| paths.iter().fold(base.clone(), |a, b| a.join(b))).collect();
+  candidates.iter().find(|candidate| candidate.is_file()).unwrap_or(&candidates[0]).clone()
+}
+
+fn explain(code: Option<&str>) {
+  let Some(code) = code else {
+    for code in ErrorCode::ALL {
+      println!("{}", code);
+    }
+    return;
   };
+  match ErrorCode::from_code(code) {
+    Some(code) => println!("{}\n\n{}", code, code.explain()),
+    None => {
+      eprintln!("{}", messages::unknown_error_code(code));
+      exit(2);
+    }
+  }
 }
 
-fn compile_file(file_path: PathBuf) -> Result<Block, String> {
-  let mut codes = File::open(&file_path).map_err(|err| format!("failed to read {:?}: {}", &file_path.to_str(), err))?;
+/// このファイル名を指定すると標準入力からソースを読む。include は作業ディレクトリから探す
+const STDIN_FILE: &str = "-";
+
+fn read_source(file_path: &Path) -> Result<Vec<String>, (ErrorCode, String)> {
+  let read_error = |err: std::io::Error| {
+    (
+      ErrorCode::SourceUnreadable,
+      messages::failed_to_read(&file_path.to_string_lossy(), &err.to_string()),
+    )
+  };
   let mut buf: String = String::new();
-  codes.read_to_string(&mut buf).map_err(|err| format!("failed to read {:?}: {}", &file_path.to_str(), err))?;
+  if file_path.file_name().is_some_and(|name| name == STDIN_FILE) {
+    io::stdin().read_to_string(&mut buf).map_err(read_error)?;
+  } else {
+    File::open(file_path).map_err(read_error)?.read_to_string(&mut buf).map_err(read_error)?;
+  }
 
-  compile(buf.split('\n').map(|t| t.to_owned()).collect())
+  Ok(buf.split('\n').map(|t| t.to_owned()).collect())
+}
+
+fn compile_file(file_path: PathBuf, config: &CompileConfig) -> Result<Block, (ErrorCode, String)> {
+  let (block, warnings) =
+    compile_with_warnings(read_source(&file_path)?, config).map_err(|err| (err.code(), err.to_string()))?;
+  print_warnings(&file_path, &warnings);
+  Ok(block)
+}
+
+fn print_warnings(file_path: &Path, warnings: &[CompileWarning]) {
+  for warning in warnings {
+    eprintln!(
+      "{}",
+      messages::compile_warning(&file_path.to_string_lossy(), &warning.to_string())
+    );
+  }
+}
+
+/// 途切れた辺や閉じていない枠の周辺のソースを表示する。辺 (枠なら左上の角) は黄色、
+/// 途切れた位置は赤で強調し、色を使わない場合は下の行に印をつける。
+fn print_source_annotation(lines: &[String], err: &CompileError, color: bool) {
+  let (x, y, edge) = match err {
+    CompileError::DanglingArgEdge { x, y, edge } => (x, y, edge.clone()),
+    CompileError::MalformedBlock {
+      x: corner_x,
+      y: corner_y,
+      at_x,
+      at_y,
+      ..
+    } => (at_x, at_y, vec![(*corner_x, *corner_y)]),
+    _ => return,
+  };
+
+  let min_y = edge.iter().map(|(_, y)| *y).chain([*y]).min().unwrap().saturating_sub(1);
+  let max_y = edge.iter().map(|(_, y)| *y).chain([*y]).max().unwrap() + 1;
+  let gutter = (max_y + 1).to_string().len();
+
+  eprintln!();
+  for line_y in min_y..=max_y {
+    // コンパイラと同じく全角文字は 2 マスと数え、2 マス目は '\0' で埋めて表示しない
+    let line: Vec<char> = lines
+      .get(line_y)
+      .map(|l| {
+        l.chars()
+          .flat_map(|char| {
+            let padding = char.width().unwrap_or(0).saturating_sub(1);
+            iter::once(char).chain(iter::repeat_n('\0', padding))
+          })
+          .collect()
+      })
+      .unwrap_or_default();
+    let width = line.len().max(if line_y == *y { x + 1 } else { 0 });
+
+    let mut rendered = String::new();
+    let mut marker = String::new();
+    for line_x in 0..width {
+      let char = line.get(line_x).copied().unwrap_or(' ');
+      let (style, mark) = if (line_x, line_y) == (*x, *y) {
+        ("\x1b[1;31m", '^')
+      } else if edge.contains(&(line_x, line_y)) {
+        ("\x1b[33m", '~')
+      } else {
+        ("", ' ')
+      };
+      if char == '\0' {
+        // 全角文字の 2 マス目は、前のマスの文字が表示されている
+      } else if color && !style.is_empty() {
+        rendered += &format!("{}{}\x1b[0m", style, if char == ' ' { '·' } else { char });
+      } else {
+        rendered.push(char);
+      }
+      marker.push(mark);
+    }
+
+    eprintln!("{:>gutter$} │ {}", line_y + 1, rendered.trim_end());
+    if !color && !marker.trim().is_empty() {
+      eprintln!("{:>gutter$} │ {}", "", marker.trim_end());
+    }
+  }
 }
 
 fn print_error(error: &BlockError) {
-  eprintln!("\n\nエラーが発生しました：{}\n◦", error.msg);
-  print_error_rec(&error.root, &mut vec![false]);
+  eprintln!("\n\n{}\n◦", messages::error_occurred(error.code.code(), &error.msg));
+  eprint!("{}", render_error_tree(&error.root));
 
+  // 再帰が深いと同じ起因が延々と続くので、連続する同じ木はまとめて表示する
   let mut before_error = error;
+  let mut last_rendered = String::new();
+  let mut repeated = 0;
   while let Some(now_error) = &before_error.caused_by {
-    eprintln!("\n\n起因：\n◦");
-    print_error_rec(&now_error.root, &mut vec![false]);
+    let rendered = render_error_tree(&now_error.root);
+    if rendered == last_rendered {
+      repeated += 1;
+    } else {
+      if repeated > 0 {
+        eprintln!("\n{}", messages::cause_repeated(repeated));
+        repeated = 0;
+      }
+      eprint!("\n\n{}\n◦\n{}", messages::caused_by(), rendered);
+      last_rendered = rendered;
+    }
     before_error = now_error;
   }
+  if repeated > 0 {
+    eprintln!("\n{}", messages::cause_repeated(repeated));
+  }
 
-  eprintln!("\n名前空間：");
+  eprintln!("\n{}", messages::namespace());
   for scope in &error.scopes {
     let keys: Vec<String> = scope
       .borrow()
@@ -54,7 +480,7 @@ fn print_error(error: &BlockError) {
           "{}{}",
           k,
           match v {
-            structs::ProcedureOrVar::Var(var) => format!("={}", var.to_string()),
+            ProcedureOrVar::Var(var) | ProcedureOrVar::Const(var) => format!("={}", var),
             _ => "".to_owned(),
           }
         )
@@ -64,19 +490,25 @@ fn print_error(error: &BlockError) {
   }
 }
 
-fn print_error_rec(tree: &BlockErrorTree, after_exists: &mut Vec<bool>) {
+fn render_error_tree(tree: &BlockErrorTree) -> String {
+  let mut out = String::new();
+  render_error_tree_rec(tree, &mut vec![false], &mut out);
+  out
+}
+
+fn render_error_tree_rec(tree: &BlockErrorTree, after_exists: &mut Vec<bool>, out: &mut String) {
   // 上位の線を表示
   for a in after_exists[..after_exists.len() - 1].iter() {
     if *a {
-      eprint!("│");
+      out.push('│');
     } else {
-      eprint!(" ");
+      out.push(' ');
     }
   }
 
   // 自身の線を表示
-  eprintln!(
-    "{}{} {}",
+  *out += &format!(
+    "{}{} {}\n",
     if tree.expand {
       "@"
     } else if *after_exists.last().unwrap() {
@@ -86,7 +518,7 @@ fn print_error_rec(tree: &BlockErrorTree, after_exists: &mut Vec<bool>) {
     },
     tree.proc_name,
     match &tree.result {
-      BlockResult::Success(literal) => format!("= {}", literal.to_string()),
+      BlockResult::Success(literal) => format!("= {}", literal),
       BlockResult::Error => "<-".to_owned(),
       BlockResult::Unreached => "".to_owned(),
     }
@@ -100,7 +532,7 @@ fn print_error_rec(tree: &BlockErrorTree, after_exists: &mut Vec<bool>) {
     if i == child_len - 1 {
       after_exists[last_index] = false;
     }
-    print_error_rec(child, after_exists);
+    render_error_tree_rec(child, after_exists, out);
   }
 
   after_exists.pop();
@@ -110,10 +542,10 @@ fn print_error_rec(tree: &BlockErrorTree, after_exists: &mut Vec<bool>) {
 mod tests {
   use std::{cell::RefCell, rc::Rc};
 
-  use crate::{
-    compile,
+  use trees::{
+    compile::compile,
     executor::execute_with_mock,
-    structs::{BlockError, Literal},
+    structs::{BlockError, CmdOutput, CmdRequest, ExecuteOptions, Literal},
   };
 
   #[test]
@@ -123,7 +555,7 @@ mod tests {
     let out_stream = Box::new(move |msg| {
       *out.borrow_mut() = msg;
     });
-    let cmd_executor = Box::new(|_, _| panic!());
+    let cmd_executor = Box::new(|_| panic!());
 
     let result = compile(vec![
       "        ┌─────┐      ".to_owned(),
@@ -136,6 +568,7 @@ mod tests {
       "│  3  │      │  4  │ ".to_owned(),
       "└─────┘      └─────┘ ".to_owned(),
     ])
+    .map_err(|e| e.to_string())
     .and_then(|b| {
       execute_with_mock(
         b,
@@ -143,6 +576,7 @@ mod tests {
         out_stream,
         cmd_executor,
         Box::new(|_| panic!()),
+        ExecuteOptions::default(),
       )
       .map_err(|e: BlockError| e.msg)
     });
@@ -151,27 +585,30 @@ mod tests {
     assert_eq!("7", *out_ref.borrow());
   }
 
-  fn exec_file(code: &str) -> (Result<Literal, String>, String, Vec<(String, Vec<String>)>) {
+  type CmdLog = Vec<(String, Vec<String>)>;
+
+  fn exec_file(code: &str) -> (Result<Literal, String>, String, CmdLog) {
     let out = Rc::new(RefCell::new("".to_owned()));
     let out_ref = out.clone();
     let out_stream = Box::new(move |msg| {
       (*out.borrow_mut()).extend([msg]);
     });
-    let cmd_log: Rc<RefCell<Vec<(String, Vec<String>)>>> = Rc::new(RefCell::new(vec![]));
+    let cmd_log: Rc<RefCell<CmdLog>> = Rc::new(RefCell::new(vec![]));
     let cmd_log_ref = cmd_log.clone();
-    let cmd_executor = Box::new(move |cmd, args| {
-      (*cmd_log.borrow_mut()).push((cmd, args));
-      Ok("".to_string())
+    let cmd_executor = Box::new(move |request: CmdRequest| {
+      (*cmd_log.borrow_mut()).push((request.program, request.args));
+      Ok(CmdOutput::default())
     });
 
     let code_lines: Vec<String> = code.split('\n').map(|c| c.to_owned()).collect();
-    let result = compile(code_lines).and_then(|b| {
+    let result = compile(code_lines).map_err(|e| e.to_string()).and_then(|b| {
       execute_with_mock(
         b,
         Box::new(|| panic!()),
         out_stream,
         cmd_executor,
         Box::new(|_| panic!()),
+        ExecuteOptions::default(),
       )
       .map_err(|e: BlockError| e.msg)
     });
@@ -295,7 +732,33 @@ mod tests {
   }
 
   mod modules {
-    use crate::{structs::Literal, tests::exec_file};
+    use std::{env, fs};
+
+    use trees::structs::Literal;
+
+    use crate::{resolve_include, tests::exec_file};
+
+    #[test]
+    fn include_search_paths() {
+      let root = env::temp_dir().join(format!("trees_include_search_{}", std::process::id()));
+      let (project, lib1, lib2) = (root.join("project"), root.join("lib1"), root.join("lib2"));
+      for dir in [&project, &lib1, &lib2.join("sub")] {
+        fs::create_dir_all(dir).unwrap();
+      }
+      fs::write(project.join("local.tr"), "").unwrap();
+      fs::write(lib1.join("shared.tr"), "").unwrap();
+      fs::write(lib2.join("shared.tr"), "").unwrap();
+      fs::write(lib2.join("sub").join("deep.tr"), "").unwrap();
+
+      let bases = vec![project.clone(), lib1.clone(), lib2.clone()];
+      let resolve = |paths: &[&str]| resolve_include(&bases, &paths.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+      assert_eq!(resolve(&["local.tr"]), project.join("local.tr"));
+      assert_eq!(resolve(&["shared.tr"]), lib1.join("shared.tr"));
+      assert_eq!(resolve(&["sub", "deep.tr"]), lib2.join("sub").join("deep.tr"));
+      assert_eq!(resolve(&["missing.tr"]), project.join("missing.tr"));
+
+      fs::remove_dir_all(root).unwrap();
+    }
 
     #[test]
     fn modules() {
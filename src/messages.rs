@@ -0,0 +1,634 @@
+use std::{env, sync::OnceLock};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lang {
+  En,
+  Ja,
+}
+
+impl Lang {
+  pub fn parse(str: &str) -> Option<Lang> {
+    match str {
+      "en" => Some(Lang::En),
+      "ja" => Some(Lang::Ja),
+      _ => None,
+    }
+  }
+
+  /// LC_ALL, LC_MESSAGES, LANG の順にロケールを調べる。
+  pub fn from_env() -> Lang {
+    ["LC_ALL", "LC_MESSAGES", "LANG"]
+      .iter()
+      .find_map(|key| env::var(key).ok().filter(|v| !v.is_empty()))
+      .map(|locale| if locale.starts_with("ja") { Lang::Ja } else { Lang::En })
+      .unwrap_or(Lang::En)
+  }
+}
+
+static LANG: OnceLock<Lang> = OnceLock::new();
+
+pub fn set_lang(lang: Lang) {
+  let _ = LANG.set(lang);
+}
+
+pub fn lang() -> Lang {
+  *LANG.get().unwrap_or(&Lang::En)
+}
+
+macro_rules! message {
+  ($en:expr, $ja:expr) => {
+    match lang() {
+      Lang::En => $en,
+      Lang::Ja => $ja,
+    }
+  };
+}
+
+pub fn usage() -> String {
+  message!(
+    "usage: trees [--lang en|ja] [--color auto|always|never] [--max-depth N] [--max-steps N]\n             [--sandbox] [--deny cmd,fs,net] [-I dir]... [--module-separator SEP]\n             [--qualified-only] [--plugin lib]... [--trace] [--log-level LEVEL] [--tab-width N] [--seed N]\n             [--charset standard|rounded] [--check] <file|-> [-- args...]\n       trees run <file> [-- args...]\n       trees graph <file>\n       trees render <file> [-o out.svg]\n       trees edit <file>\n       trees build [dir]\n       trees explain [code]".to_owned(),
+    "使い方: trees [--lang en|ja] [--color auto|always|never] [--max-depth N] [--max-steps N]\n              [--sandbox] [--deny cmd,fs,net] [-I ディレクトリ]... [--module-separator 区切り]\n              [--qualified-only] [--plugin ライブラリ]... [--trace] [--log-level レベル] [--tab-width N] [--seed N]\n              [--charset standard|rounded] [--check] <ファイル|-> [-- 引数...]\n        trees run <ファイル> [-- 引数...]\n        trees graph <ファイル>\n        trees render <ファイル> [-o 出力.svg]\n        trees edit <ファイル>\n        trees build [ディレクトリ]\n        trees explain [コード]".to_owned()
+  )
+}
+
+pub fn editor_help() -> &'static str {
+  message!(
+    "↑↓←→ select  a add  r rename  d detach  p attach  J/K move  e toggle @  q toggle •,/  s save  Esc quit",
+    "↑↓←→ 選択  a 追加  r 名前変更  d 切り離す  p つなぐ  J/K 移動  e @ 切替  q •,/ 切替  s 保存  Esc 終了"
+  )
+}
+
+pub fn editor_prompt_name() -> &'static str {
+  message!("Name: ", "名前：")
+}
+
+pub fn editor_saved(path: &str) -> String {
+  message!(format!("Saved {}", path), format!("{} に保存しました", path))
+}
+
+pub fn editor_unsaved() -> String {
+  message!(
+    "There are unsaved changes. Press Esc again to quit.".to_owned(),
+    "保存していない変更があります。終了するにはもう一度 Esc を押してください。".to_owned()
+  )
+}
+
+pub fn editor_detached(count: usize) -> String {
+  message!(
+    format!("Detached blocks: {}", count),
+    format!("切り離したブロック：{} 個", count)
+  )
+}
+
+pub fn error_occurred(code: &str, msg: &str) -> String {
+  message!(
+    format!("An error occurred [{}]: {}", code, msg),
+    format!("エラーが発生しました[{}]：{}", code, msg)
+  )
+}
+
+pub fn compile_error_occurred(code: &str, msg: &str) -> String {
+  message!(
+    format!("A compile error occurred [{}]: {}", code, msg),
+    format!("コンパイルエラーが発生しました[{}]：{}", code, msg)
+  )
+}
+
+pub fn compile_warning(path: &str, msg: &str) -> String {
+  message!(
+    format!("Warning in {}: {}", path, msg),
+    format!("{} の警告：{}", path, msg)
+  )
+}
+
+pub fn caused_by() -> &'static str {
+  message!("Caused by:", "起因：")
+}
+
+pub fn cause_repeated(times: usize) -> String {
+  message!(
+    format!("(the same cause repeats {} more times)", times),
+    format!("(同じ起因がさらに {} 回続きます)", times)
+  )
+}
+
+pub fn namespace() -> &'static str {
+  message!("Namespace:", "名前空間：")
+}
+
+pub fn unknown_error_code(code: &str) -> String {
+  message!(
+    format!("Unknown error code {}", code),
+    format!("不明なエラーコード {}", code)
+  )
+}
+
+pub fn unknown_option(option: &str) -> String {
+  message!(
+    format!("Unknown option {}", option),
+    format!("不明なオプション {}", option)
+  )
+}
+
+pub fn invalid_option_value(option: &str, value: &str) -> String {
+  message!(
+    format!("Invalid value for {}: {}", option, value),
+    format!("{} の値が不正です：{}", option, value)
+  )
+}
+
+pub fn failed_to_read(path: &str, err: &str) -> String {
+  message!(
+    format!("failed to read {}: {}", path, err),
+    format!("{} を読み込めません：{}", path, err)
+  )
+}
+
+pub fn invalid_manifest(path: &str, err: &str) -> String {
+  message!(
+    format!("invalid manifest {}: {}", path, err),
+    format!("マニフェスト {} が不正です：{}", path, err)
+  )
+}
+
+pub fn build_finished(checked: usize, unchanged: usize) -> String {
+  message!(
+    format!("Checked {} files ({} unchanged files skipped)", checked, unchanged),
+    format!(
+      "{} 個のファイルを調べました (変更の無い {} 個は飛ばしました)",
+      checked, unchanged
+    )
+  )
+}
+
+pub fn failed_to_write(path: &str, err: &str) -> String {
+  message!(
+    format!("failed to write {}: {}", path, err),
+    format!("{} に書き込めません：{}", path, err)
+  )
+}
+
+pub fn failed_to_load_plugin(path: &str, err: &str) -> String {
+  message!(
+    format!("failed to load plugin {}: {}", path, err),
+    format!("プラグイン {} を読み込めません：{}", path, err)
+  )
+}
+
+pub fn command_timed_out(program: &str, millis: u128) -> String {
+  message!(
+    format!("{} did not finish within {} ms and was killed", program, millis),
+    format!("{} が {} ミリ秒以内に終わらなかったので止めました", program, millis)
+  )
+}
+
+pub fn unknown_process(handle: i64) -> String {
+  message!(
+    format!("No process with handle {} is running", handle),
+    format!("ハンドル {} のプロセスはありません", handle)
+  )
+}
+
+pub fn not_a_directory(path: &str) -> String {
+  message!(
+    format!("\"{}\" is not a directory", path),
+    format!("\"{}\" はディレクトリではありません", path)
+  )
+}
+
+pub fn network_error(port: u16, reason: &str) -> String {
+  message!(
+    format!("Cannot listen on port {}. ({})", port, reason),
+    format!("ポート {} で待ち受けられません。({})", port, reason)
+  )
+}
+
+pub fn port_range(port: i64) -> String {
+  message!(
+    format!("Port {} is out of range (0 to 65535)", port),
+    format!("ポート {} は範囲 (0 から 65535) の外です", port)
+  )
+}
+
+pub fn unknown_store_handle(handle: i64) -> String {
+  message!(
+    format!("No store with handle {} is open", handle),
+    format!("ハンドル {} の開いているストアはありません", handle)
+  )
+}
+
+pub fn broken_store(path: &str, line: usize) -> String {
+  message!(
+    format!("The store {} has a broken record on line {}", path, line),
+    format!("ストア {} の {} 行目の記録が壊れています", path, line)
+  )
+}
+
+pub fn unknown_file_handle(handle: i64) -> String {
+  message!(
+    format!("No file with handle {} is open", handle),
+    format!("ハンドル {} の開いているファイルはありません", handle)
+  )
+}
+
+pub fn include_unavailable(path: &str) -> String {
+  message!(
+    format!("cannot include {}: no includer is configured", path),
+    format!(
+      "{} を include できません：include の読み込み方法が設定されていません",
+      path
+    )
+  )
+}
+
+pub fn non_unique_start_block(found: usize) -> String {
+  message!(
+    format!(
+      "The code must have exact one block which has no block-plug. Found {}.",
+      found
+    ),
+    format!(
+      "ブロックプラグを持たないブロックはちょうど 1 つでなければなりません。({} 個見つかりました)",
+      found
+    )
+  )
+}
+
+pub fn dangling_arg_edge(x: usize, y: usize) -> String {
+  message!(
+    format!("No block-plug found at ({}, {})", x, y),
+    format!("({}, {}) にブロックプラグが見つかりません", x, y)
+  )
+}
+
+pub fn orphan_block(x: usize, y: usize, proc_name: &str) -> String {
+  message!(
+    format!(
+      "The block {} at ({}, {}) is not connected to the root and is never executed",
+      proc_name, x, y
+    ),
+    format!(
+      "({}, {}) のブロック {} は根につながっておらず、実行されません",
+      x, y, proc_name
+    )
+  )
+}
+
+pub fn malformed_block(x: usize, y: usize, at_x: usize, at_y: usize, found: &str) -> String {
+  if found.is_empty() {
+    message!(
+      format!(
+        "The block at ({}, {}) is not closed: the line ends at ({}, {})",
+        x, y, at_x, at_y
+      ),
+      format!(
+        "({}, {}) のブロックの枠が閉じていません：({}, {}) で行が終わっています",
+        x, y, at_x, at_y
+      )
+    )
+  } else {
+    message!(
+      format!(
+        "The block at ({}, {}) is not closed: unexpected \"{}\" at ({}, {})",
+        x, y, found, at_x, at_y
+      ),
+      format!(
+        "({}, {}) のブロックの枠が閉じていません：({}, {}) の \"{}\" で枠が途切れています",
+        x, y, at_x, at_y, found
+      )
+    )
+  }
+}
+
+pub fn cyclic_blocks(x: usize, y: usize) -> String {
+  message!(
+    format!("The block at ({}, {}) is connected to its own arg", x, y),
+    format!("({}, {}) のブロックが自分自身の引数につながっています", x, y)
+  )
+}
+
+pub fn macro_failed(x: usize, y: usize, proc_name: &str, msg: &str) -> String {
+  message!(
+    format!(
+      "The macro {} failed to expand the block at ({}, {}): {}",
+      proc_name, x, y, msg
+    ),
+    format!(
+      "({}, {}) のブロックでマクロ {} の展開に失敗しました：{}",
+      x, y, proc_name, msg
+    )
+  )
+}
+
+pub fn macro_expansion_limit(max: usize) -> String {
+  message!(
+    format!("Macro expansion did not finish after {} expansions", max),
+    format!("マクロの展開が {} 回で終わりませんでした", max)
+  )
+}
+
+pub fn undefined_proc(name: &str) -> String {
+  message!(
+    format!("Undefined Proc Name {}", name),
+    format!("手続き {} は定義されていません", name)
+  )
+}
+
+pub fn undefined_variable(name: &str) -> String {
+  message!(
+    format!("Variable {} is not defined", name),
+    format!("変数 {} は定義されていません", name)
+  )
+}
+
+pub fn const_reassigned(name: &str) -> String {
+  message!(
+    format!("{} is a constant and cannot be changed", name),
+    format!("{} は定数なので変更できません", name)
+  )
+}
+
+pub fn invalid_literal_bytes(offset: usize) -> String {
+  message!(
+    format!("The bytes are not a valid encoded value at offset {}", offset),
+    format!(
+      "バイト列の {} バイト目から先が、値を書き出したものとして正しくありません",
+      offset
+    )
+  )
+}
+
+pub fn unquote_not_block(got: &str) -> String {
+  message!(
+    format!("Unquoted value {} cannot be embedded in a block", got),
+    format!("~ の値 {} はブロックに埋め込めません", got)
+  )
+}
+
+pub fn expand_not_list(got: &str) -> String {
+  message!(
+    format!("\"@\" needs the arg is a list literal. (Got {})", got),
+    format!("\"@\" の引数はリストでなければなりません。(実際の値: {})", got)
+  )
+}
+
+pub fn arg_count(proc_name: &str, expected: usize, got: usize) -> String {
+  message!(
+    format!(
+      "Procedure {}: Length of args must be {}. (Got {})",
+      proc_name, expected, got
+    ),
+    format!(
+      "手続き {}：引数の数は {} でなければなりません。(実際の数: {})",
+      proc_name, expected, got
+    )
+  )
+}
+
+pub fn arg_count_at_least(proc_name: &str, min: usize, got: usize) -> String {
+  message!(
+    format!(
+      "Procedure {}: Length of args must be at least {}. (Got {})",
+      proc_name, min, got
+    ),
+    format!(
+      "手続き {}：引数の数は {} 以上でなければなりません。(実際の数: {})",
+      proc_name, min, got
+    )
+  )
+}
+
+pub fn arg_count_range(proc_name: &str, min: usize, max: usize, got: usize) -> String {
+  message!(
+    format!(
+      "Procedure {}: Length of args must be {} to {}. (Got {})",
+      proc_name, min, max, got
+    ),
+    format!(
+      "手続き {}：引数の数は {} から {} でなければなりません。(実際の数: {})",
+      proc_name, min, max, got
+    )
+  )
+}
+
+pub fn control_outside(proc_name: &str) -> String {
+  message!(
+    format!("Procedure {}: Can only be used inside a loop.", proc_name),
+    format!("手続き {}: ループの中でしか使えません。", proc_name)
+  )
+}
+
+pub fn return_outside() -> String {
+  message!(
+    "Procedure return: Can only be used inside a procedure defined with defproc.".to_owned(),
+    "手続き return: defproc で定義した手続きの中でしか使えません。".to_owned()
+  )
+}
+
+pub fn unpack_length(names: usize, got: usize) -> String {
+  message!(
+    format!(
+      "Procedure unpack: Length of list must be the same as the number of names ({}). (Got {})",
+      names, got
+    ),
+    format!(
+      "手続き unpack: リストの長さは名前の数 ({}) と同じでなければなりません。(実際は {})",
+      names, got
+    )
+  )
+}
+
+pub fn random_range(min: i64, max: i64) -> String {
+  message!(
+    format!(
+      "Procedure random int: Min ({}) must not be greater than max ({}).",
+      min, max
+    ),
+    format!(
+      "手続き random int: 最小値 ({}) が最大値 ({}) より大きいです。",
+      min, max
+    )
+  )
+}
+
+pub fn file_error(path: &str, reason: &str) -> String {
+  message!(
+    format!("Cannot access \"{}\". ({})", path, reason),
+    format!("\"{}\" にアクセスできません。({})", path, reason)
+  )
+}
+
+pub fn let_args(got: usize) -> String {
+  message!(
+    format!(
+      "Procedure let: Args must be name/value pairs followed by a block. (Got {} args)",
+      got
+    ),
+    format!(
+      "手続き let：引数は名前と値の組の後にブロックを 1 つ並べなければなりません。(実際の数: {})",
+      got
+    )
+  )
+}
+
+pub fn type_error(proc_name: &str, index: usize, expected: &str, got: &str) -> String {
+  message!(
+    format!(
+      "Procedure {}: $arg[{}] must be {}. (Got {})",
+      proc_name, index, expected, got
+    ),
+    format!(
+      "手続き {}：$arg[{}] は {} でなければなりません。(実際の値: {})",
+      proc_name, index, expected, got
+    )
+  )
+}
+
+pub fn block_type_error(proc_name: &str, index: usize, expected: &str, got: &str) -> String {
+  message!(
+    format!(
+      "Procedure {}: Executed result of $arg[{}] must be {}. (Got {})",
+      proc_name, index, expected, got
+    ),
+    format!(
+      "手続き {}：$arg[{}] の実行結果は {} でなければなりません。(実際の値: {})",
+      proc_name, index, expected, got
+    )
+  )
+}
+
+pub fn list_type_error(proc_name: &str, arg_index: usize, list_index: usize, expected: &str, got: &str) -> String {
+  message!(
+    format!(
+      "Procedure {}: [{}] of $arg[{}] must be {}. (Got {})",
+      proc_name, list_index, arg_index, expected, got
+    ),
+    format!(
+      "手続き {}：$arg[{}] の [{}] は {} でなければなりません。(実際の値: {})",
+      proc_name, arg_index, list_index, expected, got
+    )
+  )
+}
+
+pub fn index_out_of_range(index: i64, len: usize) -> String {
+  message!(
+    format!("Index ({}) out of range. (Length = {})", index, len),
+    format!("インデックス ({}) が範囲外です。(長さ = {})", index, len)
+  )
+}
+
+pub fn ragged_matrix(proc_name: &str, index: usize, row: usize) -> String {
+  message!(
+    format!(
+      "Procedure {}: Row {} of $arg[{}] has a different length from row 0",
+      proc_name, row, index
+    ),
+    format!(
+      "手続き {}：$arg[{}] の {} 行目の長さが 0 行目と違います",
+      proc_name, index, row
+    )
+  )
+}
+
+pub fn size_mismatch(proc_name: &str, a: &str, b: &str) -> String {
+  message!(
+    format!("Procedure {}: The sizes {} and {} do not match", proc_name, a, b),
+    format!("手続き {}：大きさ {} と {} が合いません", proc_name, a, b)
+  )
+}
+
+pub fn invalid_base64(proc_name: &str, text: &str) -> String {
+  message!(
+    format!("Procedure {}: \"{}\" is not valid Base64", proc_name, text),
+    format!("手続き {}：\"{}\" は Base64 として読めません", proc_name, text)
+  )
+}
+
+pub fn not_utf8(proc_name: &str) -> String {
+  message!(
+    format!("Procedure {}: The decoded bytes are not valid UTF-8", proc_name),
+    format!("手続き {}：復元したバイト列が UTF-8 の文字列ではありません", proc_name)
+  )
+}
+
+pub fn invalid_decimal(proc_name: &str, text: &str) -> String {
+  message!(
+    format!("Procedure {}: \"{}\" is not a decimal number", proc_name, text),
+    format!("手続き {}：\"{}\" は 10 進数として読めません", proc_name, text)
+  )
+}
+
+pub fn division_by_zero(proc_name: &str) -> String {
+  message!(
+    format!("Procedure {}: Division by zero", proc_name),
+    format!("手続き {}：0 で割ろうとしました", proc_name)
+  )
+}
+
+pub fn unknown_rounding_mode(proc_name: &str, mode: &str) -> String {
+  message!(
+    format!(
+      "Procedure {}: Unknown rounding mode \"{}\" (up, down, ceiling, floor, half up, half down, half even)",
+      proc_name, mode
+    ),
+    format!(
+      "手続き {}：丸め方 \"{}\" はありません (up, down, ceiling, floor, half up, half down, half even)",
+      proc_name, mode
+    )
+  )
+}
+
+pub fn overflow(proc_name: &str) -> String {
+  message!(
+    format!("Procedure {}: The result overflowed", proc_name),
+    format!("手続き {}：計算結果がオーバーフローしました", proc_name)
+  )
+}
+
+pub fn max_depth_exceeded(proc_name: &str, max_depth: usize) -> String {
+  message!(
+    format!("Procedure {}: Call depth exceeded the limit ({})", proc_name, max_depth),
+    format!(
+      "手続き {}：呼び出しの深さが上限 ({}) を超えました",
+      proc_name, max_depth
+    )
+  )
+}
+
+pub fn interrupted() -> String {
+  message!("Interrupted by Ctrl-C".to_owned(), "Ctrl-C で中断しました".to_owned())
+}
+
+pub fn max_steps_exceeded(proc_name: &str, max_steps: u64) -> String {
+  message!(
+    format!(
+      "Procedure {}: Number of procedure calls exceeded the limit ({})",
+      proc_name, max_steps
+    ),
+    format!(
+      "手続き {}：手続き呼び出しの回数が上限 ({}) を超えました",
+      proc_name, max_steps
+    )
+  )
+}
+
+pub fn capability_denied(capability: &str) -> String {
+  message!(
+    format!("Capability denied: {}", capability),
+    format!("権限が拒否されました：{}", capability)
+  )
+}
+
+pub fn not_exported(module_name: &str, name: &str) -> String {
+  message!(
+    format!("Module {} does not export {}", module_name, name),
+    format!("モジュール {} は {} を公開していません", module_name, name)
+  )
+}
+
+pub fn include_cycle(chain: &[String]) -> String {
+  message!(
+    format!("Circular include: {}", chain.join(" -> ")),
+    format!("include が循環しています：{}", chain.join(" -> "))
+  )
+}
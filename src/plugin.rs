@@ -0,0 +1,18 @@
+use std::{collections::HashMap, path::Path};
+
+use libloading::{Library, Symbol};
+use trees::{
+  executor::{PluginRegister, PLUGIN_REGISTER_SYMBOL},
+  structs::ProcedureOrVar,
+};
+
+/// 共有ライブラリを読み込み、登録関数に procs を渡す。
+pub fn load_plugin(path: &Path, procs: &mut HashMap<String, ProcedureOrVar>) -> Result<(), String> {
+  // 登録された手続きはライブラリ内の関数を指すので、ライブラリはプロセスが終わるまで解放しない
+  let library = unsafe { Library::new(path) }.map_err(|err| err.to_string())?;
+  let library = Box::leak(Box::new(library));
+  let register: Symbol<PluginRegister> =
+    unsafe { library.get(PLUGIN_REGISTER_SYMBOL.as_bytes()) }.map_err(|err| err.to_string())?;
+  register(procs);
+  Ok(())
+}
@@ -0,0 +1,149 @@
+use std::{
+  collections::HashMap,
+  fs,
+  hash::{DefaultHasher, Hash, Hasher},
+  path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+use trees::{
+  compile::{Charset, CompileConfig},
+  messages,
+};
+
+use crate::cli::Options;
+
+pub const MANIFEST_FILE: &str = "trees.toml";
+
+/// 前回のビルドでエラーが無かったファイルのハッシュを置くファイル (出力先のディレクトリの中)
+const BUILD_CACHE_FILE: &str = "build-cache";
+
+/// trees.toml の内容。パスはマニフェストのあるディレクトリからの相対パス
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+  /// 実行を始めるファイル
+  pub entry: PathBuf,
+  /// -I と同じく include を探すディレクトリ
+  #[serde(default)]
+  pub include_paths: Vec<PathBuf>,
+  /// ビルドの記録を置くディレクトリ。ここにある .tr は調べない
+  #[serde(default = "default_out_dir")]
+  pub out_dir: PathBuf,
+  pub tab_width: Option<usize>,
+  /// --charset と同じ名前
+  pub charset: Option<String>,
+}
+
+fn default_out_dir() -> PathBuf {
+  PathBuf::from("target")
+}
+
+pub fn parse_manifest(text: &str) -> Result<Manifest, String> {
+  toml::from_str(text).map_err(|err| err.to_string())
+}
+
+/// `start` かその祖先で、trees.toml のある最も近いディレクトリ
+pub fn find_project_dir(start: &Path) -> Option<PathBuf> {
+  start.ancestors().find(|dir| dir.join(MANIFEST_FILE).is_file()).map(|dir| dir.to_path_buf())
+}
+
+/// マニフェストを読み、その設定をコマンドラインの設定に重ねる。
+/// コマンドラインで既定から変えた設定はそちらを優先する
+pub fn load_manifest(dir: &Path, options: &mut Options) -> Result<Manifest, String> {
+  let path = dir.join(MANIFEST_FILE);
+  let text =
+    fs::read_to_string(&path).map_err(|err| messages::failed_to_read(&path.to_string_lossy(), &err.to_string()))?;
+  let manifest =
+    parse_manifest(&text).map_err(|err| messages::invalid_manifest(&path.to_string_lossy(), err.trim_end()))?;
+
+  options.include_paths.extend(manifest.include_paths.iter().map(|path| dir.join(path)));
+  let defaults = CompileConfig::default();
+  if let Some(tab_width) = manifest.tab_width.filter(|_| options.compile.tab_width == defaults.tab_width) {
+    options.compile.tab_width = tab_width;
+  }
+  if let Some(charset) = manifest.charset.as_ref().filter(|_| options.compile.charset == defaults.charset) {
+    options.compile.charset =
+      Charset::from_profile(charset).ok_or(messages::invalid_manifest(&path.to_string_lossy(), charset))?;
+  }
+  Ok(manifest)
+}
+
+/// `dir` 以下の .tr ファイルを、`excluded` の中を除いて名前順に集める
+pub fn source_files(dir: &Path, excluded: &Path) -> Vec<PathBuf> {
+  let mut files = vec![];
+  let mut dirs = vec![dir.to_path_buf()];
+  while let Some(dir) = dirs.pop() {
+    let Ok(entries) = fs::read_dir(&dir) else {
+      continue;
+    };
+    for entry in entries.flatten() {
+      let path = entry.path();
+      if path.is_dir() {
+        if path != excluded {
+          dirs.push(path);
+        }
+      } else if path.extension().is_some_and(|extension| extension == "tr") {
+        files.push(path);
+      }
+    }
+  }
+  files.sort();
+  files
+}
+
+/// ファイルの内容とコンパイルの設定から作るハッシュ。設定が変わればすべて調べ直す。
+/// DefaultHasher は Rust の版によって変わりうるが、そのときは記録が外れて調べ直すだけ
+pub fn source_hash(source: &[u8], options: &Options) -> u64 {
+  let mut hasher = DefaultHasher::new();
+  source.hash(&mut hasher);
+  format!("{:?}", options.compile).hash(&mut hasher);
+  hasher.finish()
+}
+
+pub fn read_build_cache(out_dir: &Path) -> HashMap<PathBuf, u64> {
+  fs::read_to_string(out_dir.join(BUILD_CACHE_FILE))
+    .unwrap_or_default()
+    .lines()
+    .filter_map(|line| {
+      let (hash, path) = line.split_once('\t')?;
+      Some((PathBuf::from(path), u64::from_str_radix(hash, 16).ok()?))
+    })
+    .collect()
+}
+
+pub fn write_build_cache(out_dir: &Path, cache: &HashMap<PathBuf, u64>) -> std::io::Result<()> {
+  fs::create_dir_all(out_dir)?;
+  let mut entries: Vec<_> = cache.iter().collect();
+  entries.sort();
+  let text: String =
+    entries.iter().map(|(path, hash)| format!("{:016x}\t{}\n", hash, path.to_string_lossy())).collect();
+  fs::write(out_dir.join(BUILD_CACHE_FILE), text)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+
+  use super::parse_manifest;
+
+  #[test]
+  fn manifest() {
+    let manifest = parse_manifest(
+      r#"
+entry = "src/main.tr"
+include_paths = ["lib"]
+tab_width = 8
+"#,
+    )
+    .unwrap();
+    assert_eq!(manifest.entry, PathBuf::from("src/main.tr"));
+    assert_eq!(manifest.include_paths, vec![PathBuf::from("lib")]);
+    assert_eq!(manifest.out_dir, PathBuf::from("target"));
+    assert_eq!(manifest.tab_width, Some(8));
+    assert_eq!(manifest.charset, None);
+
+    assert!(parse_manifest("include_paths = []").is_err());
+    assert!(parse_manifest("entry = \"a.tr\"\nunknown = 1").is_err());
+  }
+}
@@ -1,7 +1,14 @@
 mod block;
+mod block_builder;
 mod exec_env;
 mod literal;
 
 pub use block::{Block, BlockError, BlockErrorTree, BlockResult, QuoteStyle};
-pub use exec_env::{ExecuteEnv, Includer, ProcedureError, ProcedureOrVar};
+pub use block_builder::BlockBuilder;
+pub use exec_env::{
+  exit_code, Capability, ChildProcess, ClosureProcedure, CmdExecutor, CmdOutput, CmdRequest, ControlFlow, ExecuteEnv,
+  ExecuteOptions, FileSystem, HttpBinder, HttpListener, HttpRequest, HttpResponse, Includer, LogLevel, ProcedureError,
+  ProcedureOrVar, ProcessSpawner,
+};
 pub use literal::Literal;
+pub(crate) use literal::{BlockLiteral, MemoTable, Params};
@@ -1,8 +1,11 @@
-use super::{exec_env::ExecuteScope, literal::BlockLiteral, ExecuteEnv, Literal};
+use super::{exec_env::ExecuteScope, literal::BlockLiteral, ControlFlow, ExecuteEnv, Literal};
+use crate::{errors::ErrorCode, messages};
+use std::rc::Rc;
 
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub struct Block {
-  pub proc_name: String,
+  /// 同じ名前はブロックを複製しても共有する
+  pub proc_name: Rc<str>,
   pub args: Vec<(bool, Box<Block>)>,
   pub quote: QuoteStyle,
 }
@@ -11,34 +14,46 @@ pub struct Block {
 pub enum QuoteStyle {
   Quote,
   Closure,
+  /// 引用の中で、引用を作るときに評価して値のブロックに置き換える
+  Unquote,
   None,
 }
 
 impl Block {
   pub fn execute(&self, exec_env: &mut ExecuteEnv) -> Result<Literal, BlockError> {
     exec_env.new_scope();
-    let result = self.execute_without_scope(exec_env)?;
+    let result = self.execute_without_scope(exec_env);
     exec_env.back_scope();
 
-    Ok(result)
+    result
   }
 
   pub fn execute_without_scope(&self, exec_env: &mut ExecuteEnv) -> Result<Literal, BlockError> {
-    if self.quote != QuoteStyle::None {
+    if matches!(self.quote, QuoteStyle::Quote | QuoteStyle::Closure) {
       let quote = self.quote.clone();
 
       let mut cloned = self.clone();
       cloned.quote = QuoteStyle::None;
+      if quote == QuoteStyle::Quote {
+        cloned.fill_unquotes(exec_env).map_err(|err| {
+          let msg = err.msg.clone();
+          let code = err.code;
+          self.create_error(exec_env, code, Some(Box::new(err)), msg, vec![])
+        })?;
+      }
 
       let block = match quote {
         QuoteStyle::Quote => Ok(BlockLiteral {
           scopes: vec![],
-          block: cloned,
+          block: Rc::new(cloned),
+          params: Rc::default(),
+          then: Rc::default(),
+          memo: None,
         }),
         QuoteStyle::Closure => exec_env.make_closure(cloned),
-        QuoteStyle::None => unreachable!(),
+        QuoteStyle::Unquote | QuoteStyle::None => unreachable!(),
       }
-      .map_err(|msg| self.create_error(exec_env, None, msg, vec![]))?;
+      .map_err(|msg| self.create_error(exec_env, ErrorCode::RuntimeError, None, msg, vec![]))?;
 
       Ok(Literal::Block(block))
     } else {
@@ -54,8 +69,9 @@ impl Block {
           } else {
             return Err(self.create_error(
               exec_env,
+              ErrorCode::ExpandNotList,
               None,
-              format!("\"@\" needs the arg is a list literal. (Got {})", result.to_string()),
+              messages::expand_not_list(&result.to_string()),
               pure_exec_args,
             ));
           };
@@ -63,27 +79,57 @@ impl Block {
         pure_exec_args.push(result);
       }
 
-      let expanded_args = pure_exec_args
-        .iter()
-        .enumerate()
-        .flat_map(|(i, arg)| {
-          let arg = arg.clone();
-          if self.args[i].0 {
-            let Literal::List(list) = arg else { unreachable!() };
-            list
-          } else {
-            vec![arg]
+      // @ が無ければ評価した引数をそのまま渡し、複製しない
+      let expanded_args: Option<Vec<Literal>> = self.args.iter().any(|(expand, _)| *expand).then(|| {
+        pure_exec_args
+          .iter()
+          .zip(&self.args)
+          .flat_map(|(arg, (expand, _))| match arg {
+            Literal::List(list) if *expand => list.to_vec(),
+            arg => vec![arg.clone()],
+          })
+          .collect()
+      });
+      exec_env.execute_procedure(&self.proc_name, expanded_args.as_deref().unwrap_or(&pure_exec_args)).map_err(
+        |proc_error| match proc_error {
+          super::ProcedureError::CausedByBlockExec(block_error) => {
+            let new_msg = block_error.msg.clone();
+            let code = block_error.code;
+            self.create_error(exec_env, code, Some(block_error), new_msg, pure_exec_args)
           }
-        })
-        .collect();
-      exec_env.execute_procedure(&self.proc_name, &expanded_args).map_err(|proc_error| match proc_error {
-        super::ProcedureError::CausedByBlockExec(block_error) => {
-          let new_msg = block_error.msg.clone();
-          self.create_error(exec_env, Some(block_error), new_msg, pure_exec_args)
+          super::ProcedureError::OtherError(code, msg) => self.create_error(exec_env, code, None, msg, pure_exec_args),
+          super::ProcedureError::Control(flow) => {
+            let msg = match flow {
+              ControlFlow::Return => messages::return_outside(),
+              _ => messages::control_outside(flow.proc_name()),
+            };
+            let mut error = self.create_error(exec_env, ErrorCode::RuntimeError, None, msg, pure_exec_args);
+            error.control = Some(flow);
+            error
+          }
+        },
+      )
+    }
+  }
+
+  /// 子孫の ~ のブロックを評価し、その値のブロックに置き換える。中の引用はその引用を作るときに置き換える
+  fn fill_unquotes(&mut self, exec_env: &mut ExecuteEnv) -> Result<(), BlockError> {
+    for (_, arg) in self.args.iter_mut() {
+      match arg.quote {
+        QuoteStyle::Unquote => {
+          arg.quote = QuoteStyle::None;
+          let value = arg.execute(exec_env)?;
+          let Some(block) = value.to_block() else {
+            let msg = messages::unquote_not_block(&value.to_string());
+            return Err(arg.create_error(exec_env, ErrorCode::TypeMismatch, None, msg, vec![]));
+          };
+          **arg = block;
         }
-        super::ProcedureError::OtherError(msg) => self.create_error(exec_env, None, msg, pure_exec_args),
-      })
+        QuoteStyle::None => arg.fill_unquotes(exec_env)?,
+        QuoteStyle::Quote | QuoteStyle::Closure => {}
+      }
     }
+    Ok(())
   }
 
   fn create_inherite_error(&self, mut err: BlockError, pure_exec_args: Vec<Literal>) -> BlockError {
@@ -117,31 +163,34 @@ impl Block {
         result: BlockResult::Error,
         children,
         expand: false,
-        proc_name: self.proc_name.clone(),
+        proc_name: self.proc_name.to_string(),
       },
       scopes: err.scopes,
       caused_by: err.caused_by,
+      code: err.code,
       msg: err.msg,
+      control: err.control,
     }
   }
 
   fn create_error(
     &self,
     exec_env: &ExecuteEnv,
+    code: ErrorCode,
     caused_by: Option<Box<BlockError>>,
     msg: String,
     pure_exec_args: Vec<Literal>,
   ) -> BlockError {
     let mut children = vec![];
-    for (i, (expand, block)) in self.args.iter().cloned().enumerate() {
-      let proc_name = block.proc_name;
+    for (i, (expand, block)) in self.args.iter().enumerate() {
+      let proc_name = block.proc_name.to_string();
       children.push(BlockErrorTree {
         result: match pure_exec_args.get(i).cloned() {
           Some(arg) => BlockResult::Success(arg),
           None => BlockResult::Unreached,
         },
         children: vec![],
-        expand,
+        expand: *expand,
         proc_name,
       })
     }
@@ -150,10 +199,12 @@ impl Block {
         result: BlockResult::Error,
         children,
         expand: false,
-        proc_name: self.proc_name.clone(),
+        proc_name: self.proc_name.to_string(),
       },
       scopes: exec_env.get_scopes(),
+      control: caused_by.as_ref().and_then(|err| err.control.clone()),
       caused_by,
+      code,
       msg,
     }
   }
@@ -179,5 +230,8 @@ pub struct BlockError {
   pub root: BlockErrorTree,
   pub caused_by: Option<Box<BlockError>>,
   pub scopes: Vec<ExecuteScope>,
+  pub code: ErrorCode,
   pub msg: String,
+  /// break などによる脱出で抜けてきたなら、その種類
+  pub control: Option<ControlFlow>,
 }
@@ -0,0 +1,86 @@
+use super::{Block, QuoteStyle};
+
+/// ボックスの図を書かずに [`Block`] を組み立てる。
+///
+/// ```
+/// use trees::structs::BlockBuilder;
+///
+/// // print (+ 3 4)
+/// let block = BlockBuilder::proc("print")
+///   .arg(BlockBuilder::proc("+").arg(BlockBuilder::int(3)).arg(BlockBuilder::int(4)))
+///   .build();
+/// assert_eq!(&*block.args[0].1.proc_name, "+");
+/// ```
+#[derive(Debug, Clone)]
+pub struct BlockBuilder {
+  block: Block,
+}
+
+impl BlockBuilder {
+  /// 手続き (または変数やリテラル) の名前からブロックを作る。
+  pub fn proc(name: &str) -> BlockBuilder {
+    BlockBuilder {
+      block: Block {
+        proc_name: name.into(),
+        args: vec![],
+        quote: QuoteStyle::None,
+      },
+    }
+  }
+
+  /// 文字列リテラルのブロック。
+  pub fn string(value: &str) -> BlockBuilder {
+    BlockBuilder::proc(&format!("\"{}\"", value))
+  }
+
+  /// 整数リテラルのブロック。
+  pub fn int(value: i64) -> BlockBuilder {
+    BlockBuilder::proc(&value.to_string())
+  }
+
+  /// 引数を追加する。
+  pub fn arg(mut self, arg: impl Into<Block>) -> BlockBuilder {
+    self.block.args.push((false, Box::new(arg.into())));
+    self
+  }
+
+  /// リストを展開して渡す引数 (`@` のプラグ) を追加する。
+  pub fn expand_arg(mut self, arg: impl Into<Block>) -> BlockBuilder {
+    self.block.args.push((true, Box::new(arg.into())));
+    self
+  }
+
+  /// 引数をまとめて追加する。
+  pub fn args<B: Into<Block>>(mut self, args: impl IntoIterator<Item = B>) -> BlockBuilder {
+    self.block.args.extend(args.into_iter().map(|arg| (false, Box::new(arg.into()))));
+    self
+  }
+
+  /// 実行せずにブロックとして渡す (`•` のプラグ)。
+  pub fn quote(mut self) -> BlockBuilder {
+    self.block.quote = QuoteStyle::Quote;
+    self
+  }
+
+  /// 周りのスコープを捕まえたクロージャとして渡す (`/` のプラグ)。
+  pub fn closure(mut self) -> BlockBuilder {
+    self.block.quote = QuoteStyle::Closure;
+    self
+  }
+
+  /// 引用の中で、引用を作るときに評価した値に置き換える (`~` のプラグ)。
+  pub fn unquote(mut self) -> BlockBuilder {
+    self.block.quote = QuoteStyle::Unquote;
+    self
+  }
+
+  pub fn build(self) -> Block {
+    self.block
+  }
+}
+
+impl From<BlockBuilder> for Block {
+  fn from(builder: BlockBuilder) -> Block {
+    builder.build()
+  }
+}
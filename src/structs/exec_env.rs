@@ -1,67 +1,477 @@
 use super::{literal::BlockLiteral, Block, BlockError, Literal};
+use crate::{
+  compile::{compile_with_warnings, CompileConfig},
+  errors::ErrorCode,
+  messages,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use regex::Regex;
-use std::{cell::RefCell, collections::HashMap, rc::Rc, sync::OnceLock};
+use std::{
+  cell::RefCell,
+  collections::{BTreeMap, HashMap, HashSet},
+  env,
+  fmt::Debug,
+  io::BufRead,
+  path::{Component, Path, PathBuf},
+  process::ExitStatus,
+  rc::Rc,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, OnceLock,
+  },
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-pub type FnProcedure = fn(&mut ExecuteEnv, &Vec<Literal>) -> Result<Literal, ProcedureError>;
+pub type FnProcedure = fn(&mut ExecuteEnv, &[Literal]) -> Result<Literal, ProcedureError>;
 
+/// 埋め込み先のアプリケーションが登録したクロージャの手続き
+#[derive(Clone)]
+pub struct ClosureProcedure(pub Rc<ClosureFn>);
+
+pub type ClosureFn = dyn Fn(&mut ExecuteEnv, &[Literal]) -> Result<Literal, ProcedureError>;
+
+impl PartialEq for ClosureProcedure {
+  fn eq(&self, other: &Self) -> bool {
+    Rc::ptr_eq(&self.0, &other.0)
+  }
+}
+
+impl Eq for ClosureProcedure {}
+
+impl Debug for ClosureProcedure {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "ClosureProcedure")
+  }
+}
+
+#[allow(unpredictable_function_pointer_comparisons)]
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum ProcedureOrVar {
   FnProcedure(FnProcedure),
+  ClosureProcedure(ClosureProcedure),
   BlockProcedure(BlockLiteral),
   Var(Literal),
+  /// defconst で定義した、set で変えられない値
+  Const(Literal),
 }
 
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub struct ExecuteScopeBody {
   pub paths: Vec<String>,
   pub namespace: HashMap<String, ProcedureOrVar>,
+  /// include で束縛した (モジュールが公開した) 名前。reexport で公開し直せるのはこれだけ
+  pub imported: HashSet<String>,
 }
 
 pub type ExecuteScope = Rc<RefCell<ExecuteScopeBody>>;
 
-pub type Includer = Box<dyn FnMut(&Vec<String>) -> Result<Block, String>>;
+/// include されたファイルをコンパイルする。
+/// 返り値の文字列は同じファイルなら同じになるキー (正規化したパスなど) で、モジュールの評価結果の再利用に使う。
+pub type Includer = Box<dyn FnMut(&Vec<String>) -> Result<(String, Block), (ErrorCode, String)>>;
+
+/// 外部コマンドの実行結果
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CmdOutput {
+  /// 終了コード (シグナルで終了したときは -1)
+  pub status: i64,
+  pub stdout: String,
+  pub stderr: String,
+}
+
+/// 外部コマンドの実行の指定
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CmdRequest {
+  pub program: String,
+  pub args: Vec<String>,
+  /// 真ならシェル (sh -c、Windows では cmd /C) に空白でつないだコマンド行を渡す。
+  /// 偽なら引数をそのまま渡してプログラムを直接起動する
+  pub shell: bool,
+  /// 作業ディレクトリ (None なら現在のディレクトリ)
+  pub dir: Option<String>,
+  /// 追加する環境変数
+  pub env: Vec<(String, String)>,
+  /// これより長くかかったらプロセスを止めて失敗とする (None なら無制限)
+  pub timeout: Option<Duration>,
+}
+
+/// 外部コマンドを実行する。起動できなかったときはエラーメッセージを返す。
+pub type CmdExecutor = Box<dyn FnMut(CmdRequest) -> Result<CmdOutput, String>>;
+
+/// http serve が受け取ったリクエスト
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HttpRequest {
+  pub method: String,
+  /// クエリを含むパス
+  pub path: String,
+  pub body: String,
+}
+
+/// http serve のハンドラの結果から作った応答
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpResponse {
+  pub status: u16,
+  pub body: String,
+}
+
+/// 1 つのポートで待ち受け、リクエストを 1 つずつ受け取って応答する
+pub trait HttpListener {
+  /// 少し待ってもリクエストが来なければ None を返す。その間に Ctrl-C で止められたかを確かめる
+  fn next_request(&mut self) -> Result<Option<HttpRequest>, String>;
+  /// 最後に受け取ったリクエストに応答する。送れなくても (相手が切断したなど) 待ち受けは続ける
+  fn respond(&mut self, response: HttpResponse);
+}
+
+/// ポートで待ち受けを始める。始められなかったときはエラーメッセージを返す。
+pub type HttpBinder = Box<dyn FnMut(u16) -> Result<Box<dyn HttpListener>, String>>;
+
+/// spawn cmd で起動した子プロセス。失敗したときはエラーメッセージを返す。
+pub trait ChildProcess {
+  /// 終了を待って終了コードを返す
+  fn wait(&mut self) -> Result<i64, String>;
+  /// 終了していれば終了コードを返す。まだ動いていれば None
+  fn try_wait(&mut self) -> Result<Option<i64>, String>;
+  /// 止めて終了を待つ。既に終了していても失敗にはしない
+  fn kill(&mut self);
+}
+
+/// シェルを通さずにプログラムを起動し、待たずに返す。起動できなかったときはエラーメッセージを返す。
+pub type ProcessSpawner = Box<dyn FnMut(CmdRequest) -> Result<Box<dyn ChildProcess>, String>>;
+
+/// ファイル操作の手続きが使うファイルシステム。失敗したときはエラーメッセージを返す。
+pub trait FileSystem {
+  /// ディレクトリの中の名前を並べて返す
+  fn list_dir(&mut self, path: &str) -> Result<Vec<String>, String>;
+  /// 途中のディレクトリも含めて作る。既にあれば何もしない
+  fn create_dir(&mut self, path: &str) -> Result<(), String>;
+  fn exists(&mut self, path: &str) -> bool;
+  fn is_dir(&mut self, path: &str) -> bool;
+  fn remove_file(&mut self, path: &str) -> Result<(), String>;
+  fn copy_file(&mut self, from: &str, to: &str) -> Result<(), String>;
+  /// 少しずつ読むためにファイルを開く
+  fn open_read(&mut self, path: &str) -> Result<Box<dyn BufRead>, String>;
+  /// 一時ディレクトリに、空のファイル (dir なら空のディレクトリ) を新しく作ってパスを返す
+  fn create_temp(&mut self, dir: bool) -> Result<String, String>;
+  /// ファイルか、ディレクトリを中身ごと消す
+  fn remove_all(&mut self, path: &str) -> Result<(), String>;
+  /// ファイルの末尾に書き足す。無ければ作る
+  fn append(&mut self, path: &str, text: &str) -> Result<(), String>;
+}
+
+/// store open で開いた、ファイルに書き残す表。
+/// ファイルは 1 行に 1 つ、[キー, 値] を literal to bytes の形式にして base64 にした記録を書き足していき、
+/// 同じキーは後の行が前の行を上書きする
+struct Store {
+  path: String,
+  entries: BTreeMap<String, Literal>,
+}
+
+/// 評価済みのモジュール。2 回目以降の include では再評価せず、これを再現する。
+#[derive(Debug, Clone)]
+struct EvaluatedModule {
+  result: Literal,
+  /// モジュールが公開した名前
+  exports: Vec<(String, ProcedureOrVar)>,
+}
+
+/// 手続き呼び出しの入れ子の深さの既定の上限。
+/// スタックは足りなくなると継ぎ足すので、呼び出し元のスレッドのスタックの大きさには左右されない。
+pub const DEFAULT_MAX_DEPTH: usize = 10000;
+
+/// 手続きを呼び出す前に、スタックの残りがこれより少なければ継ぎ足す
+const STACK_RED_ZONE: usize = 256 * 1024;
+/// 継ぎ足すスタックの大きさ
+const STACK_SEGMENT_SIZE: usize = 4 * 1024 * 1024;
+
+pub const DEFAULT_MODULE_SEPARATOR: &str = "::";
+
+/// プログラムが外部に作用するための権限。サンドボックスではこれらを拒否する。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Capability {
+  /// 外部コマンドの実行
+  Cmd,
+  /// ファイルシステムへのアクセス (include によるモジュールの読み込みは除く)
+  Fs,
+  /// ネットワークへのアクセス
+  Net,
+}
+
+impl Capability {
+  pub const ALL: [Capability; 3] = [Capability::Cmd, Capability::Fs, Capability::Net];
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Capability::Cmd => "cmd",
+      Capability::Fs => "fs",
+      Capability::Net => "net",
+    }
+  }
+
+  pub fn parse(str: &str) -> Option<Capability> {
+    Capability::ALL.into_iter().find(|c| c.name() == str)
+  }
+}
+
+/// log 手続きと実行環境の診断ログの重要度。後ろほど重い。Off はどのログも書かないときにだけ使う
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LogLevel {
+  Debug,
+  Info,
+  Warn,
+  Error,
+  Off,
+}
+
+impl LogLevel {
+  pub const ALL: [LogLevel; 5] = [
+    LogLevel::Debug,
+    LogLevel::Info,
+    LogLevel::Warn,
+    LogLevel::Error,
+    LogLevel::Off,
+  ];
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      LogLevel::Debug => "debug",
+      LogLevel::Info => "info",
+      LogLevel::Warn => "warn",
+      LogLevel::Error => "error",
+      LogLevel::Off => "off",
+    }
+  }
+
+  pub fn parse(str: &str) -> Option<LogLevel> {
+    LogLevel::ALL.into_iter().find(|l| l.name() == str)
+  }
+}
+
+/// UTC の時刻を 2024-01-02T03:04:05.678Z の形にする
+fn utc_timestamp(time: SystemTime) -> String {
+  let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+  let secs = since_epoch.as_secs();
+  // 1970-01-01 からの日数を年月日にする (グレゴリオ暦を 400 年ごとに区切って数える)
+  let days = (secs / 86400) as i64 + 719468;
+  let era = days.div_euclid(146097);
+  let day_of_era = days - era * 146097;
+  let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+  let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+  let month_from_march = (5 * day_of_year + 2) / 153;
+  let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+  let month = if month_from_march < 10 {
+    month_from_march + 3
+  } else {
+    month_from_march - 9
+  };
+  let year = year_of_era + era * 400 + i64::from(month <= 2);
+  let secs_of_day = secs % 86400;
+  format!(
+    "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+    year,
+    month,
+    day,
+    secs_of_day / 3600,
+    secs_of_day / 60 % 60,
+    secs_of_day % 60,
+    since_epoch.subsec_millis()
+  )
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecuteOptions {
+  /// 手続き呼び出しの入れ子の深さの上限 (None なら無制限)
+  pub max_depth: Option<usize>,
+  /// 手続き呼び出しの総数の上限 (None なら無制限)
+  pub max_steps: Option<u64>,
+  /// 拒否する権限
+  pub denied: HashSet<Capability>,
+  /// include したモジュールの名前を修飾するときの区切り (既定は "::")
+  pub module_separator: String,
+  /// include したモジュールの名前を「モジュール名::名前」だけで束縛し、そのままの名前では束縛しない
+  pub qualified_only: bool,
+  /// 手続きの呼び出しと結果を標準エラー出力に書く
+  pub trace: bool,
+  /// コマンドラインで `--` の後に渡された、プログラムへの引数
+  pub program_args: Vec<String>,
+  /// 出力先が端末か。偽なら term clear などの制御シーケンスは書かない
+  pub terminal: bool,
+  /// 乱数の種。None なら実行ごとに変わる
+  pub seed: Option<u64>,
+  /// これより軽いログは書かない
+  pub log_level: LogLevel,
+  /// eval でソースをコンパイルするときの設定
+  pub compile: CompileConfig,
+}
+
+impl Default for ExecuteOptions {
+  fn default() -> Self {
+    ExecuteOptions {
+      max_depth: Some(DEFAULT_MAX_DEPTH),
+      max_steps: None,
+      denied: HashSet::new(),
+      module_separator: DEFAULT_MODULE_SEPARATOR.to_owned(),
+      qualified_only: false,
+      trace: false,
+      program_args: vec![],
+      terminal: false,
+      seed: None,
+      log_level: LogLevel::Warn,
+      compile: CompileConfig::default(),
+    }
+  }
+}
+
 pub struct ExecuteEnv {
   scopes: Vec<Vec<ExecuteScope>>,
   input_stream: Box<dyn FnMut() -> String>,
+  /// Enter を待たずに押されたキーを 1 つ読む
+  key_input: Box<dyn FnMut() -> String>,
   out_stream: Box<dyn FnMut(String)>,
-  cmd_executor: Box<dyn FnMut(String, Vec<String>) -> Result<String, String>>,
+  /// 診断用の出力先 (既定では標準エラー出力)
+  err_stream: Box<dyn FnMut(String)>,
+  cmd_executor: CmdExecutor,
+  file_system: Box<dyn FileSystem>,
+  http_binder: HttpBinder,
   includer: Includer,
+  /// Ctrl-C などで立てられると、次に手続きを呼び出すところで実行を止める
+  interrupt: Arc<AtomicBool>,
+  /// spawn cmd で起動したまま待っていない子プロセス
+  process_spawner: ProcessSpawner,
+  children: HashMap<i64, Box<dyn ChildProcess>>,
+  next_child: i64,
+  /// open lines で開いたまま閉じていないファイル
+  line_readers: HashMap<i64, Box<dyn BufRead>>,
+  next_line_reader: i64,
+  /// store open で開いた表
+  stores: HashMap<i64, Store>,
+  next_store: i64,
+  /// temp file と temp dir で作ったパス。実行が終わったら消す
+  temp_paths: Vec<String>,
+  /// cd で移った作業ディレクトリ。プロセスの作業ディレクトリからの相対パスで、空ならプロセスのものと同じ
+  cwd: PathBuf,
+  modules: HashMap<String, EvaluatedModule>,
+  /// 評価中のモジュールのキー (外側から順に)
+  including: Vec<String>,
+  options: ExecuteOptions,
+  depth: usize,
+  steps: u64,
+  rng: fastrand::Rng,
+  /// return に渡された値。手続きの呼び出しが受け止めるまで預かる
+  returning: Option<Literal>,
 }
 
+/// . と .. をファイルシステムを見ずに取り除く。先頭の .. は残す
+fn normalize_path(path: &Path) -> PathBuf {
+  let mut normalized = PathBuf::new();
+  for component in path.components() {
+    match component {
+      Component::CurDir => {}
+      Component::ParentDir => match normalized.components().next_back() {
+        Some(Component::Normal(_)) => {
+          normalized.pop();
+        }
+        Some(Component::RootDir | Component::Prefix(_)) => {}
+        _ => normalized.push(".."),
+      },
+      _ => normalized.push(component),
+    }
+  }
+  normalized
+}
+
+/// 整数のリテラルを読む。0x (16 進)・0o (8 進)・0b (2 進) の接頭辞と、桁の間の _ を使える
 fn to_int(str: &str) -> Option<i64> {
   static REGEX: OnceLock<regex::Regex> = OnceLock::<Regex>::new();
-  let regex = REGEX.get_or_init(|| Regex::new(r"^(\+|-)?[0-9]+$").unwrap());
-  if regex.is_match(str) {
-    str.parse::<i64>().ok()
-  } else {
-    None
+  let regex = REGEX.get_or_init(|| {
+    Regex::new(r"^(\+|-)?(0x[0-9a-fA-F](_?[0-9a-fA-F])*|0o[0-7](_?[0-7])*|0b[01](_?[01])*|[0-9](_?[0-9])*)$").unwrap()
+  });
+  if !regex.is_match(str) {
+    return None;
   }
+  let (negative, unsigned) = match str.as_bytes()[0] {
+    b'-' => (true, &str[1..]),
+    b'+' => (false, &str[1..]),
+    _ => (false, str),
+  };
+  let (radix, digits) = match unsigned.get(..2) {
+    Some("0x") => (16, &unsigned[2..]),
+    Some("0o") => (8, &unsigned[2..]),
+    Some("0b") => (2, &unsigned[2..]),
+    _ => (10, unsigned),
+  };
+  let magnitude = i128::from_str_radix(&digits.replace('_', ""), radix).ok()?;
+  i64::try_from(if negative { -magnitude } else { magnitude }).ok()
 }
 
-fn to_bool(str: &str) -> Option<bool> {
-  match str.parse::<bool>() {
-    Ok(arg) => Some(arg),
-    Err(_) => None,
+/// 終了コード (シグナルで終了したときは -1)
+pub fn exit_code(status: ExitStatus) -> i64 {
+  status.code().map_or(-1, i64::from)
+}
+
+fn const_reassigned(name: &str) -> ProcedureError {
+  ProcedureError::OtherError(ErrorCode::ConstReassigned, messages::const_reassigned(name))
+}
+
+/// scope に名前を束縛する。そこに同じ名前の定数があれば上書きせずにエラーにする
+fn bind_unless_const(scope: &ExecuteScope, name: &str, value: ProcedureOrVar) -> Result<(), ProcedureError> {
+  let mut scope = scope.borrow_mut();
+  if let Some(ProcedureOrVar::Const(_)) = scope.namespace.get(name) {
+    return Err(const_reassigned(name));
   }
+  scope.namespace.insert(name.to_string(), value);
+  Ok(())
+}
+
+fn to_bool(str: &str) -> Option<bool> {
+  str.parse::<bool>().ok()
 }
 
 impl ExecuteEnv {
+  #[allow(clippy::too_many_arguments)]
   pub fn new(
     namespace: HashMap<String, ProcedureOrVar>,
     input_stream: Box<dyn FnMut() -> String>,
+    key_input: Box<dyn FnMut() -> String>,
     out_stream: Box<dyn FnMut(String)>,
-    cmd_executor: Box<dyn FnMut(String, Vec<String>) -> Result<String, String>>,
+    err_stream: Box<dyn FnMut(String)>,
+    cmd_executor: CmdExecutor,
+    file_system: Box<dyn FileSystem>,
+    http_binder: HttpBinder,
+    process_spawner: ProcessSpawner,
     includer: Includer,
+    interrupt: Arc<AtomicBool>,
+    options: ExecuteOptions,
   ) -> ExecuteEnv {
     ExecuteEnv {
       scopes: vec![vec![Rc::new(RefCell::new(ExecuteScopeBody {
         paths: vec![],
         namespace,
+        imported: HashSet::new(),
       }))]],
       input_stream,
+      key_input,
       out_stream,
+      err_stream,
       cmd_executor,
+      file_system,
+      http_binder,
       includer,
+      interrupt,
+      process_spawner,
+      children: HashMap::new(),
+      next_child: 0,
+      line_readers: HashMap::new(),
+      next_line_reader: 0,
+      stores: HashMap::new(),
+      next_store: 0,
+      temp_paths: vec![],
+      cwd: PathBuf::new(),
+      modules: HashMap::new(),
+      including: vec![],
+      rng: options.seed.map_or_else(fastrand::Rng::new, fastrand::Rng::with_seed),
+      options,
+      depth: 0,
+      steps: 0,
+      returning: None,
     }
   }
 
@@ -85,10 +495,13 @@ impl ExecuteEnv {
 
   pub fn new_scope(&mut self) {
     let paths = self.get_last_scope().borrow().paths.clone();
+    let depth = self.get_last_scopes().len();
+    self.log(LogLevel::Debug, || format!("new scope (depth {})", depth));
 
     self.get_last_scopes_mut().push(Rc::new(RefCell::new(ExecuteScopeBody {
       paths,
       namespace: HashMap::new(),
+      imported: HashSet::new(),
     })));
   }
   pub fn back_scope(&mut self) {
@@ -121,13 +534,33 @@ impl ExecuteEnv {
     self.get_last_scopes().iter().rev().find_map(|scope| scope.borrow().namespace.get(name).cloned())
   }
 
-  pub fn defset_args(&mut self, args: &Vec<Literal>) {
+  /// 今のスコープから名前を探す。見つからなくてもエラーにしない
+  pub fn lookup(&self, name: &str) -> Option<ProcedureOrVar> {
+    self.find_namespace(name)
+  }
+
+  pub fn defset_args(&mut self, args: &[Literal], block: &BlockLiteral) {
     let binding = self.get_last_scope();
     let namespace = &mut binding.borrow_mut().namespace;
-    namespace.insert("$args".to_string(), ProcedureOrVar::Var(Literal::List(args.clone())));
+    let params = &block.params;
+    namespace.insert("$self".to_string(), ProcedureOrVar::Var(Literal::Block(block.clone())));
+    namespace.insert(
+      "$args".to_string(),
+      ProcedureOrVar::Var(Literal::List(Rc::new(args.to_vec()))),
+    );
     for (i, arg) in args.iter().enumerate() {
       namespace.insert(format!("${}", i), ProcedureOrVar::Var(arg.clone()));
     }
+    for (name, arg) in params.names.iter().zip(args) {
+      namespace.insert(name.clone(), ProcedureOrVar::Var(arg.clone()));
+    }
+    if let Some(rest) = &params.rest {
+      let rest_args = args.get(params.names.len()..).unwrap_or_default();
+      namespace.insert(
+        rest.clone(),
+        ProcedureOrVar::Var(Literal::List(Rc::new(rest_args.to_vec()))),
+      );
+    }
   }
 
   pub fn bind_name(&self, name: &str) -> Option<ProcBind> {
@@ -148,105 +581,605 @@ impl ExecuteEnv {
     }
   }
 
-  pub fn execute_procedure(&mut self, name: &str, exec_args: &Vec<Literal>) -> Result<Literal, ProcedureError> {
+  pub fn execute_procedure(&mut self, name: &str, exec_args: &[Literal]) -> Result<Literal, ProcedureError> {
     self.execute_procedure_with_bind(
       name,
       exec_args,
-      self.bind_name(name).ok_or(format!("Undefined Proc Name {}", name))?,
+      self.bind_name(name).ok_or(ProcedureError::OtherError(
+        ErrorCode::UndefinedName,
+        messages::undefined_proc(name),
+      ))?,
     )
   }
 
   pub fn execute_procedure_with_bind(
     &mut self,
     name: &str,
-    exec_args: &Vec<Literal>,
+    exec_args: &[Literal],
     bind: ProcBind,
   ) -> Result<Literal, ProcedureError> {
-    match bind {
+    if self.interrupt.load(Ordering::Relaxed) {
+      return Err(ProcedureError::OtherError(
+        ErrorCode::Interrupted,
+        messages::interrupted(),
+      ));
+    }
+    self.steps += 1;
+    if let Some(max_steps) = self.options.max_steps {
+      if self.steps > max_steps {
+        return Err(ProcedureError::OtherError(
+          ErrorCode::LimitExceeded,
+          messages::max_steps_exceeded(name, max_steps),
+        ));
+      }
+    }
+    if let Some(max_depth) = self.options.max_depth {
+      if self.depth >= max_depth {
+        return Err(ProcedureError::OtherError(
+          ErrorCode::LimitExceeded,
+          messages::max_depth_exceeded(name, max_depth),
+        ));
+      }
+    }
+
+    self.depth += 1;
+    // 手続きの中から式の評価が再帰するので、残りが少なければ新しいスタックを継ぎ足してから呼び出す
+    let result = stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT_SIZE, || match bind {
       ProcBind::Namespace(namespace) => {
-        if let Some(behavior_or_var) = namespace.borrow().namespace.get(name) {
-          let behavior_or_var = behavior_or_var.clone();
-          match behavior_or_var {
-            ProcedureOrVar::FnProcedure(be) => be(self, exec_args),
-            ProcedureOrVar::BlockProcedure(block) => block
-              .execute_without_scope(self, |exec_env| exec_env.defset_args(exec_args))
-              .map_err(|err| ProcedureError::CausedByBlockExec(Box::new(err))),
-            ProcedureOrVar::Var(var) => Ok(var.clone()),
-          }
-        } else {
-          // 変数が削除できない限り到達不可
-          unreachable!()
+        let behavior_or_var = namespace.borrow().namespace.get(name).cloned();
+        let traced =
+          self.options.trace && !matches!(behavior_or_var, Some(ProcedureOrVar::Var(_) | ProcedureOrVar::Const(_)));
+        if traced {
+          self.trace_call(name, exec_args);
+        }
+        let result = match behavior_or_var {
+          Some(ProcedureOrVar::FnProcedure(be)) => be(self, exec_args),
+          Some(ProcedureOrVar::ClosureProcedure(be)) => (be.0)(self, exec_args),
+          Some(ProcedureOrVar::BlockProcedure(block)) => block.call_proc(self, name, exec_args),
+          Some(ProcedureOrVar::Var(var) | ProcedureOrVar::Const(var)) => Ok(var),
+          // 名前を探してから呼び出すまでに undef で消されたとき
+          None => Err(ProcedureError::OtherError(
+            ErrorCode::UndefinedName,
+            messages::undefined_proc(name),
+          )),
+        };
+        if traced {
+          self.trace_return(name, &result);
         }
+        result
       }
       ProcBind::Literal(literal) => Ok(literal),
-    }
+    });
+    self.depth -= 1;
+
+    result
+  }
+
+  fn trace_call(&mut self, name: &str, args: &[Literal]) {
+    let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+    let line = format!("{}-> {} [{}]\n", "  ".repeat(self.depth - 1), name, args.join(", "));
+    (self.err_stream)(line);
+  }
+
+  fn trace_return(&mut self, name: &str, result: &Result<Literal, ProcedureError>) {
+    let indent = "  ".repeat(self.depth - 1);
+    let line = match result {
+      Ok(literal) => format!("{}<- {} = {}\n", indent, name, literal),
+      Err(ProcedureError::CausedByBlockExec(err)) => format!("{}<- {} ! [{}]\n", indent, name, err.code),
+      Err(ProcedureError::OtherError(code, _)) => format!("{}<- {} ! [{}]\n", indent, name, code),
+      Err(ProcedureError::Control(flow)) => format!("{}<- {} ! {}\n", indent, name, flow.proc_name()),
+    };
+    (self.err_stream)(line);
   }
 
-  pub fn get_var(&mut self, name: &String) -> Result<Literal, ProcedureError> {
-    if let Some(ProcedureOrVar::Var(value)) = self.find_namespace(name) {
+  pub fn get_var(&mut self, name: &str) -> Result<Literal, ProcedureError> {
+    if let Some(ProcedureOrVar::Var(value) | ProcedureOrVar::Const(value)) = self.find_namespace(name) {
       Ok(value.clone())
     } else {
-      Err(ProcedureError::OtherError(format!("Variable {} is not defined", name)))
+      Err(ProcedureError::OtherError(
+        ErrorCode::UndefinedVariable,
+        messages::undefined_variable(name),
+      ))
     }
   }
 
-  pub fn defset_var(&mut self, name: &str, value: &Literal) {
-    self.get_upper_scope().borrow_mut().namespace.insert(name.to_string(), ProcedureOrVar::Var(value.clone()));
+  pub fn defset_var(&mut self, name: &str, value: &Literal) -> Result<(), ProcedureError> {
+    bind_unless_const(&self.get_upper_scope(), name, ProcedureOrVar::Var(value.clone()))
   }
-  pub fn defset_var_into_last_scope(&mut self, name: &str, value: &Literal) {
-    self.get_last_scope().borrow_mut().namespace.insert(name.to_string(), ProcedureOrVar::Var(value.clone()));
+  pub fn defset_var_into_last_scope(&mut self, name: &str, value: &Literal) -> Result<(), ProcedureError> {
+    bind_unless_const(&self.get_last_scope(), name, ProcedureOrVar::Var(value.clone()))
   }
 
-  pub fn set_var(&mut self, name: &String, value: &Literal) -> Result<(), String> {
+  /// トップレベルのスコープ (組み込みの手続きのスコープのすぐ内側)。
+  /// 入れ子の深さやクロージャに関わらず、どこから呼んでも同じスコープになる
+  fn global_scope(&self) -> ExecuteScope {
+    let root = &self.scopes[0];
+    root.get(1).unwrap_or(&root[0]).clone()
+  }
+  pub fn defset_global(&mut self, name: &str, value: &Literal) -> Result<(), ProcedureError> {
+    bind_unless_const(&self.global_scope(), name, ProcedureOrVar::Var(value.clone()))
+  }
+  /// 内側のスコープで隠されていても、トップレベルの変数を読む
+  pub fn get_global(&self, name: &str) -> Result<Literal, ProcedureError> {
+    match self.global_scope().borrow().namespace.get(name) {
+      Some(ProcedureOrVar::Var(value) | ProcedureOrVar::Const(value)) => Ok(value.clone()),
+      _ => Err(ProcedureError::OtherError(
+        ErrorCode::UndefinedVariable,
+        messages::undefined_variable(name),
+      )),
+    }
+  }
+  /// defset が定義するのと同じスコープから名前を取り除く。定数は取り除けない
+  pub fn undef(&mut self, name: &str) -> Result<(), ProcedureError> {
+    let scope = self.get_upper_scope();
+    let mut scope = scope.borrow_mut();
+    match scope.namespace.get(name) {
+      Some(ProcedureOrVar::Const(_)) => Err(const_reassigned(name)),
+      Some(_) => {
+        scope.namespace.remove(name);
+        Ok(())
+      }
+      None => Err(ProcedureError::OtherError(
+        ErrorCode::UndefinedVariable,
+        messages::undefined_variable(name),
+      )),
+    }
+  }
+  /// 値を変えられない定数を定義する。同じスコープの定数は定義し直せない
+  pub fn def_const(&mut self, name: &str, value: &Literal) -> Result<(), ProcedureError> {
+    bind_unless_const(&self.get_upper_scope(), name, ProcedureOrVar::Const(value.clone()))
+  }
+  pub fn set_var(&mut self, name: &str, value: &Literal) -> Result<(), ProcedureError> {
     if let Some(scope) = self.find_scope(name) {
-      scope.borrow_mut().namespace.insert(name.to_string(), ProcedureOrVar::Var(value.clone()));
-      Ok(())
+      bind_unless_const(&scope, name, ProcedureOrVar::Var(value.clone()))
     } else {
-      Err(format!("Variable {} is not defined", name))
+      Err(ProcedureError::OtherError(
+        ErrorCode::UndefinedVariable,
+        messages::undefined_variable(name),
+      ))
     }
   }
 
-  pub fn def_proc(&mut self, name: &String, block: &BlockLiteral) {
+  /// return で手続きを抜ける値を預ける
+  pub fn set_returning(&mut self, value: Literal) {
+    self.returning = Some(value);
+  }
+
+  /// return で預けた値を受け取る
+  pub fn take_returning(&mut self) -> Literal {
+    self.returning.take().unwrap_or(Literal::Void)
+  }
+
+  /// どのブロックにも受け止められずに抜けた return の値を捨てる。後の呼び出しに古い値を見せないため
+  pub fn forget_returning(&mut self) {
+    self.returning = None;
+  }
+
+  pub fn def_proc(&mut self, name: &str, block: &BlockLiteral) -> Result<(), ProcedureError> {
     let behavior = ProcedureOrVar::BlockProcedure(block.clone());
 
-    self.get_upper_scope().borrow_mut().namespace.insert(name.to_string(), behavior);
+    bind_unless_const(&self.get_upper_scope(), name, behavior)
   }
 
-  pub fn export(&mut self, name: &String) -> Result<(), String> {
+  pub fn export(&mut self, name: &str) -> Result<(), ProcedureError> {
     if let Some(value) = self.find_namespace(name) {
       let value = value.clone();
       if let Some(context) = self.get_upper2_scope() {
-        context.borrow_mut().namespace.insert(name.clone(), value.clone());
+        context.borrow_mut().namespace.insert(name.to_string(), value.clone());
       };
       Ok(())
     } else {
-      Err(format!("Variable {} is not defined", name))
+      Err(ProcedureError::OtherError(
+        ErrorCode::UndefinedVariable,
+        messages::undefined_variable(name),
+      ))
+    }
+  }
+
+  /// export を呼んだ階層で定義された名前をすべて公開する。
+  pub fn export_all(&mut self) {
+    let defined = self.get_upper_scope().borrow().namespace.clone();
+    if let Some(context) = self.get_upper2_scope() {
+      context.borrow_mut().namespace.extend(defined);
     }
   }
 
-  pub fn reexport(&mut self) {
-    let scope_len = self.scopes.len();
-    for (key, proc_or_var) in self.get_last_scope().borrow().namespace.clone().iter() {
-      self.get_upper_scope().borrow_mut().namespace.insert(key.clone(), proc_or_var.clone());
-      if let Some(exp_scope) = self.get_upper2_scope() {
-        exp_scope.borrow_mut().namespace.insert(key.clone(), proc_or_var.clone());
+  /// 引数の評価で定義した名前を、この階層で束縛して公開し直す。
+  /// include した名前は、そのモジュールが公開したもの (imported に記録したもの) だけが対象になる
+  pub fn reexport(&mut self) -> Result<(), ProcedureError> {
+    let last = self.get_last_scope();
+    let last = last.borrow();
+    let upper = self.get_upper_scope();
+    let exp_scope = self.get_upper2_scope();
+    for (name, proc_or_var) in &last.namespace {
+      bind_unless_const(&upper, name, proc_or_var.clone())?;
+      if last.imported.contains(name) {
+        upper.borrow_mut().imported.insert(name.clone());
+      }
+      if let Some(exp_scope) = &exp_scope {
+        bind_unless_const(exp_scope, name, proc_or_var.clone())?;
       }
     }
+    Ok(())
   }
 
   pub fn read_line(&mut self) -> String {
     (self.input_stream)()
   }
 
+  /// キーを 1 つ読む。読んでいる間に中断されたら (端末の Ctrl-C など) エラーにする
+  pub fn read_key(&mut self) -> Result<String, ProcedureError> {
+    let key = (self.key_input)();
+    if self.interrupt.load(Ordering::Relaxed) {
+      return Err(ProcedureError::OtherError(
+        ErrorCode::Interrupted,
+        messages::interrupted(),
+      ));
+    }
+    Ok(key)
+  }
+
+  /// min 以上 max 以下の乱数
+  pub fn random_int(&mut self, min: i64, max: i64) -> i64 {
+    self.rng.i64(min..=max)
+  }
+
+  pub fn random_u128(&mut self) -> u128 {
+    self.rng.u128(..)
+  }
+
+  pub fn seed_random(&mut self, seed: u64) {
+    self.rng.seed(seed);
+  }
+
+  pub fn program_args(&self) -> &[String] {
+    &self.options.program_args
+  }
+
   pub fn print(&mut self, msg: String) {
     (self.out_stream)(msg);
   }
 
-  pub fn cmd(&mut self, cmd: String, args: Vec<String>) -> Result<String, String> {
-    (self.cmd_executor)(cmd, args)
+  /// 端末の制御シーケンスを書く。出力先が端末でなければ何もしない。
+  pub fn print_control(&mut self, seq: &str) {
+    if self.options.terminal {
+      (self.out_stream)(seq.to_owned());
+    }
+  }
+
+  pub fn eprint(&mut self, msg: String) {
+    (self.err_stream)(msg);
+  }
+
+  /// 重要度が log_level 以上なら、時刻と重要度を付けた 1 行を診断用の出力先に書く。
+  /// 書かないときは msg を呼ばない
+  pub fn log(&mut self, level: LogLevel, msg: impl FnOnce() -> String) {
+    if level < self.options.log_level {
+      return;
+    }
+    let line = format!(
+      "time={} level={} msg={:?}\n",
+      utc_timestamp(SystemTime::now()),
+      level.name(),
+      msg()
+    );
+    (self.err_stream)(line);
+  }
+
+  pub fn require(&self, capability: Capability) -> Result<(), ProcedureError> {
+    if self.options.denied.contains(&capability) {
+      Err(ProcedureError::OtherError(
+        ErrorCode::CapabilityDenied,
+        messages::capability_denied(capability.name()),
+      ))
+    } else {
+      Ok(())
+    }
+  }
+
+  pub fn cmd(&mut self, mut request: CmdRequest) -> Result<CmdOutput, ProcedureError> {
+    self.require(Capability::Cmd)?;
+    self.apply_cwd(&mut request);
+    (self.cmd_executor)(request).map_err(|msg| ProcedureError::OtherError(ErrorCode::CommandFailed, msg))
+  }
+
+  fn fs<T>(&mut self, operate: impl FnOnce(&mut dyn FileSystem) -> Result<T, String>) -> Result<T, ProcedureError> {
+    self.require(Capability::Fs)?;
+    operate(self.file_system.as_mut()).map_err(|msg| ProcedureError::OtherError(ErrorCode::FileError, msg))
+  }
+
+  /// 相対パスを cd で移った作業ディレクトリから見たパスにする
+  fn resolve(&self, path: &str) -> String {
+    let resolved = normalize_path(&self.cwd.join(path));
+    if resolved.as_os_str().is_empty() {
+      ".".to_owned()
+    } else {
+      resolved.to_string_lossy().into_owned()
+    }
+  }
+
+  /// コマンドを cd で移った作業ディレクトリで実行させる
+  fn apply_cwd(&self, request: &mut CmdRequest) {
+    if !self.cwd.as_os_str().is_empty() || request.dir.is_some() {
+      request.dir = Some(self.resolve(request.dir.as_deref().unwrap_or("")));
+    }
+  }
+
+  /// 作業ディレクトリの絶対パス
+  pub fn cwd(&self) -> String {
+    let current = env::current_dir().unwrap_or_default();
+    normalize_path(&current.join(&self.cwd)).to_string_lossy().into_owned()
+  }
+
+  /// 作業ディレクトリを移る。プロセスの作業ディレクトリは変えない
+  pub fn cd(&mut self, path: &str) -> Result<(), ProcedureError> {
+    let resolved = self.resolve(path);
+    if !self.fs(|fs| Ok(fs.is_dir(&resolved)))? {
+      return Err(ProcedureError::OtherError(
+        ErrorCode::FileError,
+        messages::not_a_directory(&resolved),
+      ));
+    }
+    self.cwd = normalize_path(&self.cwd.join(path));
+    Ok(())
+  }
+
+  pub fn list_dir(&mut self, path: &str) -> Result<Vec<String>, ProcedureError> {
+    let path = self.resolve(path);
+    self.fs(|fs| fs.list_dir(&path))
+  }
+
+  pub fn create_dir(&mut self, path: &str) -> Result<(), ProcedureError> {
+    let path = self.resolve(path);
+    self.fs(|fs| fs.create_dir(&path))
+  }
+
+  pub fn exists(&mut self, path: &str) -> Result<bool, ProcedureError> {
+    let path = self.resolve(path);
+    self.fs(|fs| Ok(fs.exists(&path)))
+  }
+
+  pub fn remove_file(&mut self, path: &str) -> Result<(), ProcedureError> {
+    let path = self.resolve(path);
+    self.fs(|fs| fs.remove_file(&path))
+  }
+
+  pub fn copy_file(&mut self, from: &str, to: &str) -> Result<(), ProcedureError> {
+    let (from, to) = (self.resolve(from), self.resolve(to));
+    self.fs(|fs| fs.copy_file(&from, &to))
+  }
+
+  /// ファイルを開き、next line に渡すハンドルを返す
+  pub fn open_lines(&mut self, path: &str) -> Result<i64, ProcedureError> {
+    let path = self.resolve(path);
+    let reader = self.fs(|fs| fs.open_read(&path))?;
+    let handle = self.next_line_reader;
+    self.next_line_reader += 1;
+    self.line_readers.insert(handle, reader);
+    Ok(handle)
+  }
+
+  /// 次の行を改行を除いて返す。ファイルの終わりなら None
+  pub fn next_line(&mut self, handle: i64) -> Result<Option<String>, ProcedureError> {
+    self.require(Capability::Fs)?;
+    let reader = self.line_readers.get_mut(&handle).ok_or(ProcedureError::OtherError(
+      ErrorCode::FileError,
+      messages::unknown_file_handle(handle),
+    ))?;
+    let mut line = String::new();
+    let read =
+      reader.read_line(&mut line).map_err(|err| ProcedureError::OtherError(ErrorCode::FileError, err.to_string()))?;
+    if read == 0 {
+      return Ok(None);
+    }
+    if line.ends_with('\n') {
+      line.pop();
+      if line.ends_with('\r') {
+        line.pop();
+      }
+    }
+    Ok(Some(line))
+  }
+
+  pub fn close_lines(&mut self, handle: i64) -> Result<(), ProcedureError> {
+    self.line_readers.remove(&handle).map(|_| ()).ok_or(ProcedureError::OtherError(
+      ErrorCode::FileError,
+      messages::unknown_file_handle(handle),
+    ))
+  }
+
+  /// http serve のためにポートで待ち受けを始める
+  pub fn http_listen(&mut self, port: u16) -> Result<Box<dyn HttpListener>, ProcedureError> {
+    self.require(Capability::Net)?;
+    (self.http_binder)(port).map_err(|msg| ProcedureError::OtherError(ErrorCode::NetworkError, msg))
+  }
+
+  /// 次のリクエストを待つ。待つ間に Ctrl-C などで止められたらエラーにする
+  pub fn next_http_request(&mut self, listener: &mut dyn HttpListener) -> Result<HttpRequest, ProcedureError> {
+    loop {
+      if self.interrupt.load(Ordering::Relaxed) {
+        return Err(ProcedureError::OtherError(
+          ErrorCode::Interrupted,
+          messages::interrupted(),
+        ));
+      }
+      let request = listener.next_request().map_err(|msg| ProcedureError::OtherError(ErrorCode::NetworkError, msg))?;
+      if let Some(request) = request {
+        return Ok(request);
+      }
+    }
+  }
+
+  /// ファイルから表を読み込み、store get などに渡すハンドルを返す。ファイルが無ければ空の表になる
+  pub fn store_open(&mut self, path: &str) -> Result<i64, ProcedureError> {
+    let path = self.resolve(path);
+    let mut entries = BTreeMap::new();
+    if self.fs(|fs| Ok(fs.exists(&path)))? {
+      let reader = self.fs(|fs| fs.open_read(&path))?;
+      for (index, line) in reader.lines().enumerate() {
+        let line = line.map_err(|err| ProcedureError::OtherError(ErrorCode::FileError, err.to_string()))?;
+        let record = BASE64.decode(line.trim_end()).ok().and_then(|bytes| Literal::from_bytes(&bytes).ok());
+        let Some(Literal::List(record)) = record else {
+          return Err(ProcedureError::OtherError(
+            ErrorCode::FileError,
+            messages::broken_store(&path, index + 1),
+          ));
+        };
+        let [Literal::String(key), value] = &record[..] else {
+          return Err(ProcedureError::OtherError(
+            ErrorCode::FileError,
+            messages::broken_store(&path, index + 1),
+          ));
+        };
+        entries.insert(key.clone(), value.clone());
+      }
+    }
+    let handle = self.next_store;
+    self.next_store += 1;
+    self.stores.insert(handle, Store { path, entries });
+    Ok(handle)
+  }
+
+  fn store(&mut self, handle: i64) -> Result<&mut Store, ProcedureError> {
+    self.stores.get_mut(&handle).ok_or(ProcedureError::OtherError(
+      ErrorCode::FileError,
+      messages::unknown_store_handle(handle),
+    ))
+  }
+
+  /// キーの値を返す。無ければ void
+  pub fn store_get(&mut self, handle: i64, key: &str) -> Result<Literal, ProcedureError> {
+    Ok(self.store(handle)?.entries.get(key).cloned().unwrap_or(Literal::Void))
+  }
+
+  /// キーに値を入れ、ファイルに書き足す
+  pub fn store_set(&mut self, handle: i64, key: &str, value: Literal) -> Result<(), ProcedureError> {
+    let path = self.store(handle)?.path.clone();
+    let record = Literal::List(Rc::new(vec![Literal::String(key.to_owned()), value.clone()]));
+    let bytes = record.to_bytes().map_err(|value| {
+      let msg = messages::type_error("store set", 2, "int, str, boolean, list or void", &value.to_string());
+      ProcedureError::OtherError(ErrorCode::TypeMismatch, msg)
+    })?;
+    self.fs(|fs| fs.append(&path, &format!("{}\n", BASE64.encode(bytes))))?;
+    self.store(handle)?.entries.insert(key.to_owned(), value);
+    Ok(())
+  }
+
+  /// キーを辞書順に並べて返す
+  pub fn store_keys(&mut self, handle: i64) -> Result<Vec<String>, ProcedureError> {
+    Ok(self.store(handle)?.entries.keys().cloned().collect())
+  }
+
+  /// 実行が終わったら消える一時ファイル (dir なら一時ディレクトリ) を作ってパスを返す
+  pub fn create_temp(&mut self, dir: bool) -> Result<String, ProcedureError> {
+    let path = self.fs(|fs| fs.create_temp(dir))?;
+    self.temp_paths.push(path.clone());
+    Ok(path)
+  }
+
+  /// シェルを通さずにプログラムを起動し、待たずに返す。
+  /// 返り値は wait などに渡すハンドル
+  pub fn spawn(&mut self, mut request: CmdRequest) -> Result<i64, ProcedureError> {
+    self.require(Capability::Cmd)?;
+    self.apply_cwd(&mut request);
+    let child =
+      (self.process_spawner)(request).map_err(|msg| ProcedureError::OtherError(ErrorCode::CommandFailed, msg))?;
+    let handle = self.next_child;
+    self.next_child += 1;
+    self.children.insert(handle, child);
+    Ok(handle)
+  }
+
+  fn child(&mut self, handle: i64) -> Result<&mut Box<dyn ChildProcess>, ProcedureError> {
+    self.require(Capability::Cmd)?;
+    self.children.get_mut(&handle).ok_or(ProcedureError::OtherError(
+      ErrorCode::CommandFailed,
+      messages::unknown_process(handle),
+    ))
+  }
+
+  /// 子プロセスの終了を待って終了コードを返す
+  pub fn wait(&mut self, handle: i64) -> Result<i64, ProcedureError> {
+    let status = self.child(handle)?.wait();
+    self.children.remove(&handle);
+    status.map_err(|msg| ProcedureError::OtherError(ErrorCode::CommandFailed, msg))
+  }
+
+  /// 子プロセスが終了していれば終了コードを返す。まだ動いていれば None
+  pub fn try_wait(&mut self, handle: i64) -> Result<Option<i64>, ProcedureError> {
+    let status =
+      self.child(handle)?.try_wait().map_err(|msg| ProcedureError::OtherError(ErrorCode::CommandFailed, msg))?;
+    if status.is_some() {
+      self.children.remove(&handle);
+    }
+    Ok(status)
+  }
+
+  pub fn kill(&mut self, handle: i64) -> Result<(), ProcedureError> {
+    self.child(handle)?.kill();
+    self.children.remove(&handle);
+    Ok(())
   }
 
   pub fn include(&mut self, path_str: String) -> Result<Literal, ProcedureError> {
+    let (module_name, module) = self.load_module(path_str)?;
+    self.bind_exports(&module_name, module.exports, !self.options.qualified_only)?;
+
+    Ok(module.result)
+  }
+
+  /// モジュールが公開した名前のうち、names に挙げたものだけを束縛する。
+  /// 名前を挙げているので、qualified_only でもそのままの名前で束縛する。
+  pub fn include_only(&mut self, path_str: String, names: &[String]) -> Result<Literal, ProcedureError> {
+    let (module_name, module) = self.load_module(path_str)?;
+    let mut exports = vec![];
+    for name in names {
+      let Some((_, value)) = module.exports.iter().find(|(exported, _)| exported == name) else {
+        return Err(ProcedureError::OtherError(
+          ErrorCode::UndefinedName,
+          messages::not_exported(&module_name, name),
+        ));
+      };
+      exports.push((name.clone(), value.clone()));
+    }
+    self.bind_exports(&module_name, exports, true)?;
+
+    Ok(module.result)
+  }
+
+  /// 公開された名前を「モジュール名::名前」で束縛する。flat なら、そのままの名前でも束縛する
+  fn bind_exports(
+    &mut self,
+    module_name: &str,
+    exports: Vec<(String, ProcedureOrVar)>,
+    flat: bool,
+  ) -> Result<(), ProcedureError> {
+    let scope = self.get_upper_scope();
+    for (name, value) in exports {
+      let qualified = format!("{}{}{}", module_name, self.options.module_separator, name);
+      if flat {
+        bind_unless_const(&scope, &name, value.clone())?;
+        scope.borrow_mut().imported.insert(name);
+      }
+      bind_unless_const(&scope, &qualified, value)?;
+      scope.borrow_mut().imported.insert(qualified);
+    }
+    Ok(())
+  }
+
+  /// ソースの文字列をコンパイルし、子のスコープで実行して結果を返す。コンパイルの警告は warn のログに書く
+  pub fn eval(&mut self, source: &str) -> Result<Literal, ProcedureError> {
+    let code = source.lines().map(|line| line.to_owned()).collect();
+    let (block, warnings) = compile_with_warnings(code, &self.options.compile)
+      .map_err(|err| ProcedureError::OtherError(err.code(), err.to_string()))?;
+    for warning in warnings {
+      self.log(LogLevel::Warn, || warning.to_string());
+    }
+    block.execute(self).map_err(|err| ProcedureError::CausedByBlockExec(Box::new(err)))
+  }
+
+  /// モジュールを (未評価なら) 評価し、モジュール名と評価結果を返す。
+  fn load_module(&mut self, path_str: String) -> Result<(String, EvaluatedModule), ProcedureError> {
     // 祖先抽出
     let parent = if let Some(index) = path_str.rfind('/') {
       let truncated = &path_str[..index];
@@ -254,33 +1187,89 @@ impl ExecuteEnv {
     } else {
       "".to_owned()
     };
+    let module_name =
+      Path::new(&path_str).file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
 
     // コンパイル
     let mut paths = self.get_last_scope().borrow().paths.clone();
     paths.push(path_str);
-    let block = (self.includer)(&paths).map_err(ProcedureError::OtherError)?;
+    let (key, block) = (self.includer)(&paths).map_err(|(code, msg)| ProcedureError::OtherError(code, msg))?;
+    self.log(LogLevel::Debug, || format!("include {} -> {}", paths.join("/"), key));
+
+    if let Some(start) = self.including.iter().position(|including| *including == key) {
+      let mut chain = self.including[start..].to_vec();
+      chain.push(key);
+      return Err(ProcedureError::OtherError(
+        ErrorCode::IncludeCycle,
+        messages::include_cycle(&chain),
+      ));
+    }
+
+    // 評価済みなら再評価しない
+    if let Some(module) = self.modules.get(&key) {
+      return Ok((module_name, module.clone()));
+    }
 
     // 実行
+    // モジュールのスコープの下に公開用のスコープを挟み、export された名前をそこで受け取る。
+    // モジュールのスコープで定義されただけの名前は include した側からは見えない。
     let freezed = self.freeze_scope();
     self.new_scope();
+    self.new_scope();
     self.get_last_scope().borrow_mut().paths.push(parent);
-    let result = block.execute_without_scope(self).map_err(|err| ProcedureError::CausedByBlockExec(Box::new(err)))?;
+    self.including.push(key.clone());
+    let result = block.execute_without_scope(self);
+    self.forget_returning();
+    self.including.pop();
     self.back_scope();
+    let exported = self.freeze_scope();
     self.reload_scope(freezed);
+    let result = result.map_err(|err| ProcedureError::CausedByBlockExec(Box::new(err)))?;
 
-    Ok(result)
+    let module = EvaluatedModule {
+      result,
+      exports: exported.borrow().namespace.clone().into_iter().collect(),
+    };
+    self.modules.insert(key, module.clone());
+
+    Ok((module_name, module))
   }
 
   pub fn make_closure(&mut self, block: Block) -> Result<BlockLiteral, String> {
     Ok(BlockLiteral {
       scopes: self.get_last_scopes_mut().clone(),
-      block,
+      block: Rc::new(block),
+      params: Rc::default(),
+      then: Rc::default(),
+      memo: None,
     })
   }
 
   pub fn get_scopes(&self) -> Vec<ExecuteScope> {
     self.get_last_scopes().clone()
   }
+
+  /// 今見えるスコープごとに (外側から順に)、束縛された名前を並べて返す
+  pub fn scope_names(&self) -> Vec<Vec<String>> {
+    self
+      .get_last_scopes()
+      .iter()
+      .map(|scope| {
+        let mut names: Vec<String> = scope.borrow().namespace.keys().cloned().collect();
+        names.sort();
+        names
+      })
+      .collect()
+  }
+}
+
+impl Drop for ExecuteEnv {
+  /// 実行を終えるときに一時ファイルを消す。消せなくても実行の結果は変えない
+  fn drop(&mut self) {
+    for path in self.temp_paths.drain(..).rev() {
+      let _ = self.file_system.remove_all(&path);
+    }
+  }
 }
 
 #[derive(PartialEq, Eq, Debug, Clone)]
@@ -292,12 +1281,46 @@ pub enum ProcBind {
 #[derive(Debug)]
 pub enum ProcedureError {
   CausedByBlockExec(Box<BlockError>),
-  OtherError(String),
+  OtherError(ErrorCode, String),
+  /// break などによる脱出。受け止める手続きが無ければエラーとして報告する
+  Control(ControlFlow),
+}
+
+impl ProcedureError {
+  /// 脱出なら、その種類
+  pub fn control(&self) -> Option<&ControlFlow> {
+    match self {
+      ProcedureError::CausedByBlockExec(err) => err.control.as_ref(),
+      ProcedureError::OtherError(..) => None,
+      ProcedureError::Control(flow) => Some(flow),
+    }
+  }
+}
+
+/// ブロックの実行を途中で抜けて、外側の手続きに受け止めさせる脱出
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum ControlFlow {
+  /// while・for・foreach のループを終える
+  Break,
+  /// while・for・foreach のループの次の回に進む
+  Continue,
+  /// いちばん内側の、名前で呼び出した手続きを終える。値は ExecuteEnv が預かる
+  Return,
+}
+
+impl ControlFlow {
+  pub fn proc_name(&self) -> &'static str {
+    match self {
+      ControlFlow::Break => "break",
+      ControlFlow::Continue => "continue",
+      ControlFlow::Return => "return",
+    }
+  }
 }
 
 impl From<String> for ProcedureError {
   fn from(value: String) -> Self {
-    ProcedureError::OtherError(value)
+    ProcedureError::OtherError(ErrorCode::RuntimeError, value)
   }
 }
 
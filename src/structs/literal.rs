@@ -1,4 +1,9 @@
-use super::{exec_env::ExecuteScope, Block, BlockError, ExecuteEnv};
+use std::{borrow::Cow, cell::RefCell, collections::HashMap, fmt::Display, rc::Rc};
+
+use bigdecimal::BigDecimal;
+
+use super::{exec_env::ExecuteScope, Block, BlockError, ControlFlow, ExecuteEnv, ProcedureError, QuoteStyle};
+use crate::{errors::ErrorCode, messages};
 
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum Literal {
@@ -6,48 +11,367 @@ pub enum Literal {
   String(String),
   Boolean(bool),
   Block(BlockLiteral),
-  List(Vec<Literal>),
+  /// 読み出しや受け渡しで複製しないよう共有する。リストを変更する手続きは新しいリストを作る
+  List(Rc<Vec<Literal>>),
+  /// その場で書き換えられる配列。複製しても同じ中身を共有する
+  Array(Rc<RefCell<Vec<Literal>>>),
+  /// 末尾に文字列を足していく文字列。strcat と違い、足すたびに全体を複製しない
+  StringBuilder(Rc<RefCell<String>>),
+  /// 桁数に上限の無い 10 進数。1.10 と 1.1 は等しいが、表示では小数点以下の桁数を保つ
+  Decimal(BigDecimal),
   Void,
 }
 
-impl ToString for Literal {
-  fn to_string(&self) -> String {
+impl Literal {
+  /// 型の名前。型エラーのメッセージと同じ名前を使う
+  pub fn type_name(&self) -> &'static str {
+    match self {
+      Literal::Int(_) => "int",
+      Literal::String(_) => "str",
+      Literal::Boolean(_) => "boolean",
+      Literal::Block(_) => "block",
+      Literal::List(_) => "list",
+      Literal::Array(_) => "array",
+      Literal::StringBuilder(_) => "sb",
+      Literal::Decimal(_) => "dec",
+      Literal::Void => "void",
+    }
+  }
+
+  /// この値を返すブロック。ブロックならその木を、文字列・整数・真偽値・void ならリテラルのブロックを返す
+  pub fn to_block(&self) -> Option<Block> {
+    let proc_name: Rc<str> = match self {
+      Literal::Block(block) => return Some((*block.block).clone()),
+      Literal::String(s) => format!("\"{}\"", s).into(),
+      Literal::Int(_) | Literal::Boolean(_) => self.to_string().into(),
+      // 空の名前のブロックが void を返す
+      Literal::Void => "".into(),
+      Literal::List(_) | Literal::Array(_) | Literal::StringBuilder(_) | Literal::Decimal(_) => return None,
+    };
+    Some(Block {
+      proc_name,
+      args: vec![],
+      quote: QuoteStyle::None,
+    })
+  }
+
+  /// 整数・文字列・真偽値・リスト・void を、形式の版の 1 バイトに続けて型の印と中身を並べたバイト列にする。
+  /// 整数と長さは 8 バイトのリトルエンディアン。バイト列にできない値を含むと、その値を返す
+  pub fn to_bytes(&self) -> Result<Vec<u8>, Literal> {
+    fn encode(literal: &Literal, out: &mut Vec<u8>) -> Result<(), Literal> {
+      match literal {
+        Literal::Int(i) => {
+          out.push(BYTES_TAG_INT);
+          out.extend_from_slice(&i.to_le_bytes());
+        }
+        Literal::String(s) => {
+          out.push(BYTES_TAG_STRING);
+          out.extend_from_slice(&(s.len() as u64).to_le_bytes());
+          out.extend_from_slice(s.as_bytes());
+        }
+        Literal::Boolean(b) => out.extend_from_slice(&[BYTES_TAG_BOOLEAN, u8::from(*b)]),
+        Literal::List(list) => {
+          out.push(BYTES_TAG_LIST);
+          out.extend_from_slice(&(list.len() as u64).to_le_bytes());
+          for item in list.iter() {
+            encode(item, out)?;
+          }
+        }
+        Literal::Void => out.push(BYTES_TAG_VOID),
+        Literal::Block(_) | Literal::Array(_) | Literal::StringBuilder(_) | Literal::Decimal(_) => {
+          return Err(literal.clone())
+        }
+      }
+      Ok(())
+    }
+
+    let mut out = vec![BYTES_FORMAT_VERSION];
+    encode(self, &mut out)?;
+    Ok(out)
+  }
+
+  /// to_bytes で書き出したバイト列を読み戻す。読めなければ、食い違った位置を返す
+  pub fn from_bytes(bytes: &[u8]) -> Result<Literal, usize> {
+    fn take<'a>(bytes: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8], usize> {
+      let taken = bytes.get(*pos..pos.checked_add(len).ok_or(*pos)?).ok_or(*pos)?;
+      *pos += len;
+      Ok(taken)
+    }
+    fn take_u64(bytes: &[u8], pos: &mut usize) -> Result<u64, usize> {
+      let mut buf = [0; 8];
+      buf.copy_from_slice(take(bytes, pos, 8)?);
+      Ok(u64::from_le_bytes(buf))
+    }
+    fn decode(bytes: &[u8], pos: &mut usize) -> Result<Literal, usize> {
+      let start = *pos;
+      let tag = take(bytes, pos, 1)?[0];
+      Ok(match tag {
+        BYTES_TAG_INT => Literal::Int(take_u64(bytes, pos)? as i64),
+        BYTES_TAG_STRING => {
+          let len = usize::try_from(take_u64(bytes, pos)?).map_err(|_| start)?;
+          let str = std::str::from_utf8(take(bytes, pos, len)?).map_err(|_| start)?;
+          Literal::String(str.to_owned())
+        }
+        BYTES_TAG_BOOLEAN => match take(bytes, pos, 1)?[0] {
+          0 => Literal::Boolean(false),
+          1 => Literal::Boolean(true),
+          _ => return Err(*pos - 1),
+        },
+        BYTES_TAG_LIST => {
+          let len = take_u64(bytes, pos)?;
+          // 長さは信用せず、読めた分だけ確保する
+          let mut items = vec![];
+          for _ in 0..len {
+            items.push(decode(bytes, pos)?);
+          }
+          Literal::List(Rc::new(items))
+        }
+        BYTES_TAG_VOID => Literal::Void,
+        _ => return Err(start),
+      })
+    }
+
+    if bytes.first() != Some(&BYTES_FORMAT_VERSION) {
+      return Err(0);
+    }
+    let mut pos = 1;
+    let literal = decode(bytes, &mut pos)?;
+    if pos != bytes.len() {
+      return Err(pos);
+    }
+    Ok(literal)
+  }
+
+  /// 型と値を、リストや配列は要素を、ブロックは手続きの木を 1 段ずつ字下げして複数行で表す
+  pub fn inspect(&self) -> String {
+    let mut out = String::new();
+    self.inspect_into(0, &mut out);
+    out
+  }
+
+  fn inspect_into(&self, depth: usize, out: &mut String) {
+    let indent = "  ".repeat(depth);
     match self {
+      Literal::String(s) => *out += &format!("{indent}str {s:?}\n"),
+      Literal::StringBuilder(sb) => *out += &format!("{indent}sb {:?}\n", sb.borrow()),
+      Literal::List(list) => {
+        *out += &format!("{indent}list ({})\n", list.len());
+        list.iter().for_each(|item| item.inspect_into(depth + 1, out));
+      }
+      Literal::Array(array) => {
+        *out += &format!("{indent}array ({})\n", array.borrow().len());
+        array.borrow().iter().for_each(|item| item.inspect_into(depth + 1, out));
+      }
+      Literal::Block(b) => {
+        *out += &format!("{indent}block\n");
+        inspect_block(&b.block, false, depth + 1, out);
+      }
+      Literal::Void => *out += &format!("{indent}void\n"),
+      _ => *out += &format!("{indent}{} {}\n", self.type_name(), self),
+    }
+  }
+}
+
+/// ブロックの手続きの名前を木にする。@ で展開する引数は名前の前に @ を、クオートした引数は後ろに印を付ける
+fn inspect_block(block: &Block, expand: bool, depth: usize, out: &mut String) {
+  let mark = match block.quote {
+    QuoteStyle::Quote => " (quote)",
+    QuoteStyle::Closure => " (closure)",
+    QuoteStyle::Unquote => " (unquote)",
+    QuoteStyle::None => "",
+  };
+  *out += &format!(
+    "{}{}{}{}\n",
+    "  ".repeat(depth),
+    if expand { "@" } else { "" },
+    block.proc_name,
+    mark
+  );
+  for (expand, arg) in &block.args {
+    inspect_block(arg, *expand, depth + 1, out);
+  }
+}
+
+/// literal to bytes の形式の版。形式を変えたら上げる
+const BYTES_FORMAT_VERSION: u8 = 1;
+// 型の印は hash と同じ番号を使う
+const BYTES_TAG_INT: u8 = 0;
+const BYTES_TAG_STRING: u8 = 1;
+const BYTES_TAG_BOOLEAN: u8 = 2;
+const BYTES_TAG_LIST: u8 = 4;
+const BYTES_TAG_VOID: u8 = 7;
+
+impl Display for Literal {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let str = match self {
       Literal::Int(i) => i.to_string(),
       Literal::String(s) => s.clone(),
       Literal::Boolean(b) => b.to_string(),
       Literal::Block(b) => format!("Block {}", b.block.proc_name),
-      Literal::List(list) => {
-        format!(
-          "[{}]",
-          list
-            .iter()
-            .map(|l| match l {
-              Literal::String(s) => format!("{s:?}"),
-              _ => l.to_string(),
-            })
-            .collect::<Vec<String>>()
-            .join(", ")
-        )
-      }
+      Literal::List(list) => format!("[{}]", items_to_string(list)),
+      Literal::Array(array) => format!("array[{}]", items_to_string(&array.borrow())),
+      Literal::StringBuilder(sb) => sb.borrow().clone(),
+      Literal::Decimal(d) => d.to_plain_string(),
       Literal::Void => "<Void>".to_string(),
+    };
+    write!(f, "{}", str)
+  }
+}
+
+fn items_to_string(items: &[Literal]) -> String {
+  items
+    .iter()
+    .map(|l| match l {
+      Literal::String(s) => format!("{s:?}"),
+      _ => l.to_string(),
+    })
+    .collect::<Vec<String>>()
+    .join(", ")
+}
+
+/// defproc named などで宣言した、手続きの引数の名前
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct Params {
+  pub names: Vec<String>,
+  /// 後ろの引数の既定値。呼び出しで省かれた引数はこれで埋める
+  pub defaults: Vec<Literal>,
+  /// 名前の数より後の引数をリストにまとめて束縛する名前
+  pub rest: Option<String>,
+  /// bind args で前もって束縛した引数。呼び出しの引数の前に付ける
+  pub bound: Vec<Literal>,
+}
+
+impl Params {
+  /// 前もって束縛した引数を前に付け、引数の数を確かめ、省かれた引数を既定値で埋める。
+  /// 名前も残りの引数の名前も宣言していなければ何個でもよい
+  pub fn complete<'a>(&self, proc_name: &str, args: &'a [Literal]) -> Result<Cow<'a, [Literal]>, ProcedureError> {
+    let mut args = if self.bound.is_empty() {
+      Cow::Borrowed(args)
+    } else {
+      Cow::Owned(self.bound.iter().chain(args).cloned().collect())
+    };
+    if self.names.is_empty() && self.rest.is_none() {
+      return Ok(args);
+    }
+    let required = self.names.len() - self.defaults.len();
+    let too_many = self.rest.is_none() && args.len() > self.names.len();
+    if args.len() < required || too_many {
+      let msg = if self.rest.is_some() {
+        messages::arg_count_at_least(proc_name, required, args.len())
+      } else if self.defaults.is_empty() {
+        messages::arg_count(proc_name, self.names.len(), args.len())
+      } else {
+        messages::arg_count_range(proc_name, required, self.names.len(), args.len())
+      };
+      return Err(ProcedureError::OtherError(ErrorCode::ArgCountMismatch, msg));
+    }
+    if args.len() < self.names.len() {
+      let missing = &self.defaults[args.len() - required..];
+      args.to_mut().extend_from_slice(missing);
     }
+    Ok(args)
+  }
+}
+
+/// memo の表のキー。ブロックと、あとから書き換えられる配列・sb はキーにできない
+#[derive(PartialEq, Eq, Hash, Debug)]
+enum MemoKey {
+  Int(i64),
+  String(String),
+  Boolean(bool),
+  List(Vec<MemoKey>),
+  /// 値が等しければ桁数が違っても同じキーになる
+  Decimal(BigDecimal),
+  Void,
+}
+
+impl MemoKey {
+  fn new(literal: &Literal) -> Option<MemoKey> {
+    Some(match literal {
+      Literal::Int(i) => MemoKey::Int(*i),
+      Literal::String(s) => MemoKey::String(s.clone()),
+      Literal::Boolean(b) => MemoKey::Boolean(*b),
+      Literal::Block(_) | Literal::Array(_) | Literal::StringBuilder(_) => return None,
+      Literal::List(list) => MemoKey::List(list.iter().map(MemoKey::new).collect::<Option<_>>()?),
+      Literal::Decimal(d) => MemoKey::Decimal(d.clone()),
+      Literal::Void => MemoKey::Void,
+    })
+  }
+}
+
+/// memo で包んだブロックの、引数のリストから結果への表。包んだブロックを複製しても共有する
+#[derive(Debug, Clone, Default)]
+pub struct MemoTable(Rc<RefCell<HashMap<Vec<MemoKey>, Literal>>>);
+
+impl PartialEq for MemoTable {
+  fn eq(&self, other: &Self) -> bool {
+    Rc::ptr_eq(&self.0, &other.0)
   }
 }
 
+impl Eq for MemoTable {}
+
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub struct BlockLiteral {
   pub scopes: Vec<ExecuteScope>,
-  pub block: Block,
+  /// 呼び出すたびに複製しないよう共有する
+  pub block: Rc<Block>,
+  pub params: Rc<Params>,
+  /// compose でつないだブロック。結果を順に 1 つ目の引数として渡していく
+  pub then: Rc<Vec<BlockLiteral>>,
+  /// memo で包んだブロックなら、結果を覚えておく表
+  pub memo: Option<MemoTable>,
 }
 
 impl BlockLiteral {
+  /// 引数を $0, $1, ... と $args (名前を宣言していればその名前にも) に、このブロック自身を $self に束縛して実行する
+  pub fn call(&self, exec_env: &mut ExecuteEnv, proc_name: &str, args: &[Literal]) -> Result<Literal, ProcedureError> {
+    let args = self.params.complete(proc_name, args)?;
+    let key = self.memo.as_ref().and_then(|_| args.iter().map(MemoKey::new).collect::<Option<Vec<_>>>());
+    if let (Some(MemoTable(table)), Some(key)) = (&self.memo, &key) {
+      if let Some(result) = table.borrow().get(key) {
+        return Ok(result.clone());
+      }
+    }
+
+    let mut result = self
+      .execute_without_scope(exec_env, |exec_env| exec_env.defset_args(&args, self))
+      .map_err(|err| ProcedureError::CausedByBlockExec(Box::new(err)))?;
+    for next in self.then.iter() {
+      result = next.call(exec_env, proc_name, &[result])?;
+    }
+    if let (Some(MemoTable(table)), Some(key)) = (&self.memo, key) {
+      table.borrow_mut().insert(key, result.clone());
+    }
+    Ok(result)
+  }
+
+  /// 名前で呼び出された手続きとして実行する。call と違い、本体から return で抜けてきたらその値を結果にする。
+  /// 本体から抜けてきた break と continue は呼び出し元のループに届けず、ループの外で使ったエラーにする
+  pub fn call_proc(
+    &self,
+    exec_env: &mut ExecuteEnv,
+    proc_name: &str,
+    args: &[Literal],
+  ) -> Result<Literal, ProcedureError> {
+    match self.call(exec_env, proc_name, args) {
+      Err(ProcedureError::CausedByBlockExec(block_err)) if block_err.control == Some(ControlFlow::Return) => {
+        Ok(exec_env.take_returning())
+      }
+      Err(ProcedureError::CausedByBlockExec(mut block_err)) => {
+        block_err.control = None;
+        Err(ProcedureError::CausedByBlockExec(block_err))
+      }
+      result => result,
+    }
+  }
+
   pub fn execute_without_scope(
     &self,
     exec_env: &mut ExecuteEnv,
     inner_vars: impl FnOnce(&mut ExecuteEnv),
   ) -> Result<Literal, BlockError> {
-    let BlockLiteral { scopes, block } = self;
+    let BlockLiteral { scopes, block, .. } = self;
     let is_closure = !scopes.is_empty();
 
     let freezed = exec_env.freeze_scope();
@@ -56,13 +380,13 @@ impl BlockLiteral {
       exec_env.new_scopes(scopes.to_vec());
     }
     inner_vars(exec_env);
-    let result = block.execute_without_scope(exec_env)?;
+    let result = block.execute_without_scope(exec_env);
     if is_closure {
       exec_env.back_scopes();
     }
     exec_env.back_scope();
     exec_env.reload_scope(freezed);
 
-    Ok(result)
+    result
   }
 }
@@ -0,0 +1,113 @@
+//! ソースの図を、フォントに依らない SVG の箱と線で描く。
+
+use crate::{compile::SourceLayout, structs::QuoteStyle};
+
+/// 1 文字分の幅と高さ (px)
+const CELL_WIDTH: usize = 10;
+const CELL_HEIGHT: usize = 20;
+
+/// ブロックは角を丸めた長方形に手続き名を添えて、辺は文字の中心を結ぶ折れ線で描く。
+/// 引用のブロックは破線、クロージャは点線で囲み、展開する引数の辺は根元に点を打つ。
+pub fn render_svg(layout: &SourceLayout) -> String {
+  let center = |x: usize, y: usize| (x * CELL_WIDTH + CELL_WIDTH / 2, y * CELL_HEIGHT + CELL_HEIGHT / 2);
+
+  let mut svg = format!(
+    "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" font-family=\"monospace\" font-size=\"14\">\n",
+    layout.width * CELL_WIDTH,
+    layout.height * CELL_HEIGHT
+  );
+
+  for edge in &layout.edges {
+    // 一直線に並ぶ途中の点は省き、曲がり角だけを結ぶ
+    let points: Vec<String> = edge
+      .points
+      .iter()
+      .enumerate()
+      .filter(
+        |(i, _)| match (i.checked_sub(1).map(|i| edge.points[i]), edge.points.get(i + 1)) {
+          (Some((prev_x, prev_y)), Some((next_x, next_y))) => prev_x != *next_x && prev_y != *next_y,
+          _ => true,
+        },
+      )
+      .map(|(_, (x, y))| {
+        let (x, y) = center(*x, *y);
+        format!("{},{}", x, y)
+      })
+      .collect();
+    svg += &format!(
+      "  <polyline points=\"{}\" fill=\"none\" stroke=\"black\"/>\n",
+      points.join(" ")
+    );
+    if let (true, Some((x, y))) = (edge.expand, edge.points.first()) {
+      let (x, y) = center(*x, *y);
+      svg += &format!("  <circle cx=\"{}\" cy=\"{}\" r=\"3\"/>\n", x, y);
+    }
+  }
+
+  for block in &layout.blocks {
+    let (x, y) = center(block.x, block.y);
+    let dash = match block.quote {
+      QuoteStyle::Quote => " stroke-dasharray=\"6 3\"",
+      QuoteStyle::Closure => " stroke-dasharray=\"2 3\"",
+      QuoteStyle::Unquote => " stroke-width=\"2\"",
+      QuoteStyle::None => "",
+    };
+    svg += &format!(
+      "  <rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" rx=\"4\" fill=\"white\" stroke=\"black\"{}/>\n",
+      x,
+      y,
+      (block.width - 1) * CELL_WIDTH,
+      (block.height - 1) * CELL_HEIGHT,
+      dash
+    );
+
+    // 名前の行を枠の中央に並べる
+    let lines: Vec<&str> = block.proc_name.lines().collect();
+    let center_x = block.x * CELL_WIDTH + block.width * CELL_WIDTH / 2;
+    let top = block.y * CELL_HEIGHT + (block.height * CELL_HEIGHT - lines.len() * CELL_HEIGHT) / 2;
+    for (i, line) in lines.iter().enumerate() {
+      svg += &format!(
+        "  <text x=\"{}\" y=\"{}\" text-anchor=\"middle\" dominant-baseline=\"central\">{}</text>\n",
+        center_x,
+        top + i * CELL_HEIGHT + CELL_HEIGHT / 2,
+        escape(line)
+      );
+    }
+  }
+
+  svg += "</svg>\n";
+  svg
+}
+
+fn escape(text: &str) -> String {
+  text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::render_svg;
+  use crate::compile::{layout_with_config, CompileConfig};
+
+  #[test]
+  fn render() {
+    let layout = layout_with_config(
+      vec![
+        "┌───┐".to_owned(),
+        "│ < │".to_owned(),
+        "└─@─┘".to_owned(),
+        "┌─•─┐".to_owned(),
+        "│ a │".to_owned(),
+        "└───┘".to_owned(),
+      ],
+      &CompileConfig::default(),
+    )
+    .unwrap();
+    let svg = render_svg(&layout);
+
+    assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"50\" height=\"120\""));
+    assert!(svg.contains("<polyline points=\"25,50 25,70\""));
+    assert!(svg.contains("<circle cx=\"25\" cy=\"50\" r=\"3\"/>"));
+    assert!(svg.contains("<rect x=\"5\" y=\"70\" width=\"40\" height=\"40\" rx=\"4\" fill=\"white\" stroke=\"black\" stroke-dasharray=\"6 3\"/>"));
+    assert!(svg.contains(">&lt;</text>"));
+  }
+}